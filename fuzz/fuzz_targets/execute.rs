@@ -0,0 +1,24 @@
+#![no_main]
+
+use cindervm::bytecode::Backend;
+use cindervm::fuzzing::{jit_comparable, ArbitraryProgram};
+use cindervm::oracle::Differential;
+use cindervm::sandbox::Sandbox;
+use libfuzzer_sys::fuzz_target;
+
+// Validated programs must behave the same on the oracle, the interpreter
+// and, wherever it is expected to agree, the JIT; with all three running,
+// the one outvoted 2-to-1 is named as the culprit
+fuzz_target!(|input: ArbitraryProgram| {
+    let program = input.0;
+    if program.validated(&Sandbox::new()).is_err() {
+        return;
+    }
+
+    let mut backends = vec![Backend::Oracle, Backend::Interpreter];
+    if jit_comparable(&program) {
+        backends.push(Backend::Jit);
+    }
+    let runs = Differential::run(&program, &backends);
+    assert!(runs.agrees(), "backends disagree, culprit {:?}:\n{}", runs.culprit(), runs);
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use cindervm::parser::Parser;
+use cindervm::sandbox::Sandbox;
+use libfuzzer_sys::fuzz_target;
+
+// Arbitrary text must parse or fail cleanly, and whatever parses must
+// validate or fail cleanly
+fuzz_target!(|data: &[u8]| {
+    let Ok(source) = std::str::from_utf8(data) else {
+        return;
+    };
+    if let Ok(program) = Parser::parse(source) {
+        let _ = Sandbox::new().validate(&program);
+    }
+});
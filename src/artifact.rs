@@ -0,0 +1,139 @@
+//! Crash-consistent output files.
+//!
+//! Artifacts (encoded programs, checkpoints, native logs, traces) are
+//! written to a temporary file next to the destination, synced and then
+//! renamed over it. Readers of the destination only ever see the previous
+//! file or the complete new one, never a prefix left by an interrupted run.
+
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// Replace `path` with `bytes` atomically
+pub fn replace_file(path: impl AsRef<Path>, bytes: &[u8]) -> io::Result<()> {
+    replace_with(path, |file| file.write_all(bytes))
+}
+
+/// Replace `path` with whatever `write` produces. If `write` fails, the
+/// temporary file is removed and `path` is left as it was.
+///
+/// The data is synced before the rename and the directory after it, so a
+/// power loss can't leave the new name pointing at unwritten blocks.
+pub fn replace_with(path: impl AsRef<Path>, write: impl FnOnce(&mut fs::File) -> io::Result<()>) -> io::Result<()> {
+    let path = path.as_ref();
+    let partial = partial_path(path);
+    let written = fs::File::create(&partial).and_then(|mut file| {
+        write(&mut file)?;
+        file.sync_all()
+    });
+    if let Err(error) = written.and_then(|_| fs::rename(&partial, path)) {
+        let _ = fs::remove_file(&partial);
+        return Err(error);
+    }
+    sync_parent_dir(path)
+}
+
+/// Sibling of `path` the new contents are written to: hidden, and unique
+/// to this process so concurrent writers don't share it
+fn partial_path(path: &Path) -> PathBuf {
+    let name = path.file_name().map_or_else(|| "artifact".into(), |name| name.to_string_lossy());
+    path.with_file_name(format!(".{}.{}.partial", name, std::process::id()))
+}
+
+/// Make a rename in `path`'s directory durable
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()
+}
+
+/// Directories can't be opened for syncing on Windows, where the rename
+/// itself is journaled
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    /// Empty scratch directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cinder-artifact-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn interrupted_write_leaves_the_previous_file() {
+        let dir = scratch("interrupted");
+        let path = dir.join("program.cbin");
+        fs::write(&path, b"previous").unwrap();
+
+        // Half the output is written, then the run dies
+        let error = replace_with(&path, |file| {
+            file.write_all(b"CNDR\x00\x01")?;
+            Err(io::Error::new(io::ErrorKind::Interrupted, "killed"))
+        })
+        .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::Interrupted);
+        assert_eq!(fs::read(&path).unwrap(), b"previous");
+        assert_eq!(entries(&dir), ["program.cbin"]);
+
+        // Nothing there before: still nothing after
+        let fresh = dir.join("fresh.cbin");
+        assert!(replace_with(&fresh, |_| Err(io::Error::other("disk full"))).is_err());
+        assert!(!fresh.exists());
+        assert_eq!(entries(&dir), ["program.cbin"]);
+
+        replace_file(&path, b"next").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"next");
+        assert_eq!(entries(&dir), ["program.cbin"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn readers_never_see_a_partial_file() {
+        let dir = scratch("readers");
+        let path = dir.join("trace.json");
+        // Large enough that a direct write would be observed half done
+        let versions: Vec<Vec<u8>> = (b'a'..=b'd').map(|byte| vec![byte; 1 << 20]).collect();
+        replace_file(&path, &versions[0]).unwrap();
+
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (path, done, versions) = (path.clone(), done.clone(), versions.clone());
+            std::thread::spawn(move || {
+                let mut reads = 0;
+                while !done.load(Ordering::Relaxed) {
+                    let bytes = fs::read(&path).expect("the destination always exists");
+                    assert!(versions.contains(&bytes), "read {} bytes of a partial file", bytes.len());
+                    reads += 1;
+                }
+                reads
+            })
+        };
+        for round in 0..40 {
+            replace_file(&path, &versions[round % versions.len()]).unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        assert!(reader.join().unwrap() > 0);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
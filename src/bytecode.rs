@@ -1,5 +1,22 @@
+use crate::cost::{self, Complexity};
+use crate::interpreter::{self, Interpreter, InterpreterError};
+#[cfg(feature = "jit")]
+use crate::jit::{JitCompiler, JitFault};
+use crate::error::CinderError;
+use crate::natives;
+use crate::oracle;
+use crate::parser::ParseError;
+use crate::sandbox::{Sandbox, ValidatedProgram};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::hash::Hash;
+use std::panic::{self, AssertUnwindSafe};
+
+/// Number of registers addressable by `PushReg`
+pub const REGISTER_COUNT: usize = 16;
+
 /// Bytecode instruction definitions for CinderVM
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[repr(u8)]
 pub enum OpCode {
     // Operands and stack
@@ -12,26 +29,40 @@ pub enum OpCode {
     Sub = 0x11,
     Mul = 0x12,
     Div = 0x13,
+    ShlImm = 0x14,
+    ShrImm = 0x15,
+    SarImm = 0x16,
     
     // Logical operations
     Eq = 0x20,
     Lt = 0x21,
     Gt = 0x22,
+    Bool = 0x23,
+    NotBool = 0x24,
     
     // Control flow
     Jump = 0x30,
     JumpIfZero = 0x31,
     JumpIfNotZero = 0x32,
+    JumpTable = 0x33,
     
     // Memory
     Load = 0x40,
     Store = 0x41,
+    MemAdd = 0x42,
+    LoadInd = 0x43,
+    StoreInd = 0x44,
     
     // Calls and return
     CallNative = 0x50,
     Return = 0x51,
     
-    // Halt
+    // Debugging
+    Breakpoint = 0x60,
+    ReadCycles = 0x61,
+    
+    // Termination
+    Trap = 0xFE,
     Halt = 0xFF,
 }
 
@@ -45,24 +76,119 @@ impl OpCode {
             0x11 => Some(OpCode::Sub),
             0x12 => Some(OpCode::Mul),
             0x13 => Some(OpCode::Div),
+            0x14 => Some(OpCode::ShlImm),
+            0x15 => Some(OpCode::ShrImm),
+            0x16 => Some(OpCode::SarImm),
             0x20 => Some(OpCode::Eq),
             0x21 => Some(OpCode::Lt),
             0x22 => Some(OpCode::Gt),
+            0x23 => Some(OpCode::Bool),
+            0x24 => Some(OpCode::NotBool),
             0x30 => Some(OpCode::Jump),
             0x31 => Some(OpCode::JumpIfZero),
             0x32 => Some(OpCode::JumpIfNotZero),
+            0x33 => Some(OpCode::JumpTable),
             0x40 => Some(OpCode::Load),
             0x41 => Some(OpCode::Store),
+            0x42 => Some(OpCode::MemAdd),
+            0x43 => Some(OpCode::LoadInd),
+            0x44 => Some(OpCode::StoreInd),
             0x50 => Some(OpCode::CallNative),
             0x51 => Some(OpCode::Return),
+            0x60 => Some(OpCode::Breakpoint),
+            0x61 => Some(OpCode::ReadCycles),
+            0xFE => Some(OpCode::Trap),
             0xFF => Some(OpCode::Halt),
             _ => None,
         }
     }
+
+    /// Canonical assembly spelling
+    pub fn mnemonic(&self) -> &'static str {
+        MNEMONICS
+            .iter()
+            .find(|m| m.opcode == *self && !m.deprecated)
+            .map(|m| m.name)
+            .expect("every opcode has a canonical mnemonic")
+    }
+
+    /// Look up an opcode by any accepted spelling (case-insensitive)
+    pub fn from_mnemonic(name: &str) -> Option<&'static Mnemonic> {
+        MNEMONICS.iter().find(|m| m.name.eq_ignore_ascii_case(name))
+    }
+
+    /// Fixed operands the instruction takes. JUMP_TABLE also takes a
+    /// variable-length target list after its base and default.
+    pub fn operand_count(&self) -> usize {
+        match self {
+            OpCode::PushInt
+            | OpCode::PushReg
+            | OpCode::Jump
+            | OpCode::JumpIfZero
+            | OpCode::JumpIfNotZero
+            | OpCode::Load
+            | OpCode::Store
+            | OpCode::CallNative
+            | OpCode::Trap
+            | OpCode::ShlImm
+            | OpCode::ShrImm
+            | OpCode::SarImm => 1,
+            OpCode::JumpTable | OpCode::MemAdd => 2,
+            _ => 0,
+        }
+    }
 }
 
-/// Complete instruction representation
-#[derive(Debug, Clone)]
+/// An assembly spelling of an opcode
+#[derive(Debug, Clone, Copy)]
+pub struct Mnemonic {
+    pub name: &'static str,
+    pub opcode: OpCode,
+    /// Old spelling still accepted by the parser, with a warning
+    pub deprecated: bool,
+}
+
+/// Every spelling the parser accepts; exactly one non-deprecated entry per opcode
+pub static MNEMONICS: &[Mnemonic] = &[
+    Mnemonic { name: "PUSH_INT", opcode: OpCode::PushInt, deprecated: false },
+    Mnemonic { name: "PUSH_REG", opcode: OpCode::PushReg, deprecated: false },
+    Mnemonic { name: "POP", opcode: OpCode::Pop, deprecated: false },
+    Mnemonic { name: "ADD", opcode: OpCode::Add, deprecated: false },
+    Mnemonic { name: "SUB", opcode: OpCode::Sub, deprecated: false },
+    Mnemonic { name: "MUL", opcode: OpCode::Mul, deprecated: false },
+    Mnemonic { name: "DIV", opcode: OpCode::Div, deprecated: false },
+    Mnemonic { name: "SHL_IMM", opcode: OpCode::ShlImm, deprecated: false },
+    Mnemonic { name: "SHR_IMM", opcode: OpCode::ShrImm, deprecated: false },
+    Mnemonic { name: "SAR_IMM", opcode: OpCode::SarImm, deprecated: false },
+    Mnemonic { name: "EQ", opcode: OpCode::Eq, deprecated: false },
+    Mnemonic { name: "LT", opcode: OpCode::Lt, deprecated: false },
+    Mnemonic { name: "GT", opcode: OpCode::Gt, deprecated: false },
+    Mnemonic { name: "BOOL", opcode: OpCode::Bool, deprecated: false },
+    Mnemonic { name: "NOT_BOOL", opcode: OpCode::NotBool, deprecated: false },
+    Mnemonic { name: "JUMP", opcode: OpCode::Jump, deprecated: false },
+    Mnemonic { name: "JZ", opcode: OpCode::JumpIfZero, deprecated: false },
+    Mnemonic { name: "JUMP_IF_ZERO", opcode: OpCode::JumpIfZero, deprecated: true },
+    Mnemonic { name: "JNZ", opcode: OpCode::JumpIfNotZero, deprecated: false },
+    Mnemonic { name: "JUMP_IF_NOT_ZERO", opcode: OpCode::JumpIfNotZero, deprecated: true },
+    Mnemonic { name: "JUMP_TABLE", opcode: OpCode::JumpTable, deprecated: false },
+    Mnemonic { name: "LOAD", opcode: OpCode::Load, deprecated: false },
+    Mnemonic { name: "STORE", opcode: OpCode::Store, deprecated: false },
+    Mnemonic { name: "MEM_ADD", opcode: OpCode::MemAdd, deprecated: false },
+    Mnemonic { name: "LOAD_IND", opcode: OpCode::LoadInd, deprecated: false },
+    Mnemonic { name: "STORE_IND", opcode: OpCode::StoreInd, deprecated: false },
+    Mnemonic { name: "CALL_NATIVE", opcode: OpCode::CallNative, deprecated: false },
+    Mnemonic { name: "RETURN", opcode: OpCode::Return, deprecated: false },
+    Mnemonic { name: "BREAKPOINT", opcode: OpCode::Breakpoint, deprecated: false },
+    Mnemonic { name: "READ_CYCLES", opcode: OpCode::ReadCycles, deprecated: false },
+    Mnemonic { name: "TRAP", opcode: OpCode::Trap, deprecated: false },
+    Mnemonic { name: "HALT", opcode: OpCode::Halt, deprecated: false },
+];
+
+/// Complete instruction representation.
+///
+/// Instructions are totally ordered by variant declaration order, then by
+/// operands, so they can key `BTreeMap`s in analyses.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub enum Instruction {
     // Operands
     PushInt(i64),
@@ -74,33 +200,368 @@ pub enum Instruction {
     Sub,
     Mul,
     Div,
+    /// Shift left by a constant; counts are masked to 0-63
+    ShlImm(u8),
+    /// Logical (zero-filling) shift right by a constant, masked to 0-63
+    ShrImm(u8),
+    /// Arithmetic (sign-filling) shift right by a constant, masked to 0-63
+    SarImm(u8),
     
     // Logical
     Eq,
     Lt,
     Gt,
+    /// Pop a value, push 1 if it is nonzero, else 0
+    Bool,
+    /// Pop a value, push 1 if it is zero, else 0
+    NotBool,
     
     // Control flow
-    Jump(usize),
-    JumpIfZero(usize),
-    JumpIfNotZero(usize),
+    Jump(u32),
+    JumpIfZero(u32),
+    JumpIfNotZero(u32),
+    /// Pop a selector and jump to `targets[selector - base]`, or `default` if out of range
+    JumpTable(Box<JumpTable>),
     
     // Memory
-    Load(usize),  // memory offset
-    Store(usize),
+    Load(u32),  // memory offset
+    Store(u32),
+    /// Add an immediate to a memory slot in place, wrapping like ADD
+    MemAdd(u32, i32),
+    /// Pop an offset and push the memory slot at it; the offset is
+    /// checked against `memory_size` at run time
+    LoadInd,
+    /// Pop a value, then an offset, and store the value at the offset;
+    /// checked at run time like `LoadInd`
+    StoreInd,
     
     // Calls
     CallNative(u32),  // native function ID
+    /// Return from the current subroutine, or end the program at top level
     Return,
     
+    /// Hand control to the debugger: the interpreter's breakpoint handler,
+    /// or `int3` in JIT code
+    Breakpoint,
+    /// Push a monotonic timestamp: the TSC in JIT code, elapsed nanoseconds
+    /// in the interpreter. Nondeterministic, so results that depend on it
+    /// differ between runs and backends.
+    ReadCycles,
+    
+    /// Abort the program with a user error code, e.g. for `panic` or
+    /// `unreachable` in a source language. A failure, unlike `Halt`.
+    Trap(u32),
+    /// End the program, returning the top of the value stack
     Halt,
 }
 
+/// Dense multi-way branch table used by `Instruction::JumpTable`
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JumpTable {
+    /// Selector value that maps to `targets[0]`
+    pub base: i64,
+    pub default: u32,
+    pub targets: Vec<u32>,
+}
+
+impl JumpTable {
+    /// Instruction index selected by `selector`
+    pub fn resolve(&self, selector: i64) -> u32 {
+        selector
+            .checked_sub(self.base)
+            .and_then(|index| usize::try_from(index).ok())
+            .and_then(|index| self.targets.get(index).copied())
+            .unwrap_or(self.default)
+    }
+}
+
+impl Instruction {
+    pub fn opcode(&self) -> OpCode {
+        match self {
+            Instruction::PushInt(_) => OpCode::PushInt,
+            Instruction::PushReg(_) => OpCode::PushReg,
+            Instruction::Pop => OpCode::Pop,
+            Instruction::Add => OpCode::Add,
+            Instruction::Sub => OpCode::Sub,
+            Instruction::Mul => OpCode::Mul,
+            Instruction::Div => OpCode::Div,
+            Instruction::ShlImm(_) => OpCode::ShlImm,
+            Instruction::ShrImm(_) => OpCode::ShrImm,
+            Instruction::SarImm(_) => OpCode::SarImm,
+            Instruction::Eq => OpCode::Eq,
+            Instruction::Lt => OpCode::Lt,
+            Instruction::Gt => OpCode::Gt,
+            Instruction::Bool => OpCode::Bool,
+            Instruction::NotBool => OpCode::NotBool,
+            Instruction::Jump(_) => OpCode::Jump,
+            Instruction::JumpIfZero(_) => OpCode::JumpIfZero,
+            Instruction::JumpIfNotZero(_) => OpCode::JumpIfNotZero,
+            Instruction::JumpTable(_) => OpCode::JumpTable,
+            Instruction::Load(_) => OpCode::Load,
+            Instruction::Store(_) => OpCode::Store,
+            Instruction::MemAdd(..) => OpCode::MemAdd,
+            Instruction::LoadInd => OpCode::LoadInd,
+            Instruction::StoreInd => OpCode::StoreInd,
+            Instruction::CallNative(_) => OpCode::CallNative,
+            Instruction::Return => OpCode::Return,
+            Instruction::Breakpoint => OpCode::Breakpoint,
+            Instruction::ReadCycles => OpCode::ReadCycles,
+            Instruction::Trap(_) => OpCode::Trap,
+            Instruction::Halt => OpCode::Halt,
+        }
+    }
+
+    /// Number of values popped and pushed: `(pops, pushes)`
+    pub fn stack_effect(&self) -> (usize, usize) {
+        match self {
+            Instruction::PushInt(_) | Instruction::PushReg(_) => (0, 1),
+            Instruction::Pop => (1, 0),
+            
+            Instruction::Add | Instruction::Sub | Instruction::Mul | Instruction::Div => (2, 1),
+            Instruction::ShlImm(_) | Instruction::ShrImm(_) | Instruction::SarImm(_) => (1, 1),
+            Instruction::Eq | Instruction::Lt | Instruction::Gt => (2, 1),
+            Instruction::Bool | Instruction::NotBool => (1, 1),
+            
+            Instruction::Jump(_) => (0, 0),
+            Instruction::JumpIfZero(_) | Instruction::JumpIfNotZero(_) => (1, 0),
+            Instruction::JumpTable(_) => (1, 0),
+            
+            Instruction::Load(_) => (0, 1),
+            Instruction::Store(_) => (1, 0),
+            Instruction::MemAdd(..) => (0, 0),
+            Instruction::LoadInd => (1, 1),
+            Instruction::StoreInd => (2, 0),
+            
+            // Natives pop their arguments and push their result, if any;
+            // unimplemented ones do nothing
+            Instruction::CallNative(id) => {
+                natives::lookup(*id).map_or((0, 0), |native| (native.args(), native.results()))
+            }
+            Instruction::Breakpoint => (0, 0),
+            Instruction::ReadCycles => (0, 1),
+            // Return/Halt fall back to 0 on an empty stack
+            Instruction::Return | Instruction::Halt => (0, 0),
+            Instruction::Trap(_) => (0, 0),
+        }
+    }
+
+    /// Instruction indices control can reach next, within `len` instructions
+    pub fn successors(&self, pc: usize, len: usize) -> Vec<usize> {
+        let mut next = Vec::with_capacity(2);
+        match self {
+            Instruction::Jump(target) => next.push(*target as usize),
+            Instruction::JumpIfZero(target) | Instruction::JumpIfNotZero(target) => {
+                next.push(*target as usize);
+                next.push(pc + 1);
+            }
+            Instruction::JumpTable(table) => {
+                next.extend(table.targets.iter().map(|&target| target as usize));
+                next.push(table.default as usize);
+                next.sort_unstable();
+                next.dedup();
+            }
+            Instruction::Return | Instruction::Halt | Instruction::Trap(_) => {}
+            _ => next.push(pc + 1),
+        }
+        next.retain(|&target| target < len);
+        next
+    }
+}
+
+// Operands are fixed-width so the size doesn't depend on the platform;
+// new variants must not grow it past an i64 payload plus tag.
+const _: () = assert!(std::mem::size_of::<Instruction>() == 16);
+
+/// Value pushed for true by comparisons and `BOOL`/`NOT_BOOL`; false is always 0
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BoolRepr {
+    /// `1`
+    #[default]
+    One,
+    /// `-1`, all bits set, for use as a bitmask
+    AllOnes,
+}
+
+impl BoolRepr {
+    pub fn encode(self, value: bool) -> i64 {
+        match (self, value) {
+            (_, false) => 0,
+            (BoolRepr::One, true) => 1,
+            (BoolRepr::AllOnes, true) => -1,
+        }
+    }
+}
+
+/// Integer type of VM values, the `V` of `Interpreter<H, V>`: `i64` by
+/// default, or `i32` for programs written against 32-bit integers.
+/// Arithmetic wraps at the type's width.
+pub trait VmInt: Copy + Ord + Hash + Default + fmt::Debug + fmt::Display + 'static {
+    /// Shift counts are taken modulo this, as in the integer type
+    const BITS: u32;
+
+    /// The low `BITS` bits of `value`, as when a constant is pushed
+    fn from_i64(value: i64) -> Self;
+    /// Sign-extended to `i64`, for results, natives and checkpoints
+    fn to_i64(self) -> i64;
+
+    fn wrapping_add(self, other: Self) -> Self;
+    fn wrapping_sub(self, other: Self) -> Self;
+    fn wrapping_mul(self, other: Self) -> Self;
+    /// `MIN / -1` wraps to `MIN`; callers reject a zero divisor
+    fn wrapping_div(self, other: Self) -> Self;
+    fn wrapping_shl(self, count: u32) -> Self;
+    /// Shift right, filling with zeros from the top of this width
+    fn logical_shr(self, count: u32) -> Self;
+    /// Shift right, filling with copies of the sign bit
+    fn arithmetic_shr(self, count: u32) -> Self;
+}
+
+macro_rules! impl_vm_int {
+    ($int:ty, $unsigned:ty) => {
+        impl VmInt for $int {
+            const BITS: u32 = <$int>::BITS;
+
+            fn from_i64(value: i64) -> Self {
+                value as $int
+            }
+
+            fn to_i64(self) -> i64 {
+                self as i64
+            }
+
+            fn wrapping_add(self, other: Self) -> Self {
+                <$int>::wrapping_add(self, other)
+            }
+
+            fn wrapping_sub(self, other: Self) -> Self {
+                <$int>::wrapping_sub(self, other)
+            }
+
+            fn wrapping_mul(self, other: Self) -> Self {
+                <$int>::wrapping_mul(self, other)
+            }
+
+            fn wrapping_div(self, other: Self) -> Self {
+                <$int>::wrapping_div(self, other)
+            }
+
+            fn wrapping_shl(self, count: u32) -> Self {
+                <$int>::wrapping_shl(self, count)
+            }
+
+            fn logical_shr(self, count: u32) -> Self {
+                ((self as $unsigned) >> count) as $int
+            }
+
+            fn arithmetic_shr(self, count: u32) -> Self {
+                self >> count
+            }
+        }
+    };
+}
+
+impl_vm_int!(i32, u32);
+impl_vm_int!(i64, u64);
+
+/// Declared type of a memory slot, restricting the values it may hold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SlotType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    I32,
+    I64,
+}
+
+impl SlotType {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "bool" => Some(SlotType::Bool),
+            "u8" => Some(SlotType::U8),
+            "u16" => Some(SlotType::U16),
+            "u32" => Some(SlotType::U32),
+            "i32" => Some(SlotType::I32),
+            "i64" => Some(SlotType::I64),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SlotType::Bool => "bool",
+            SlotType::U8 => "u8",
+            SlotType::U16 => "u16",
+            SlotType::U32 => "u32",
+            SlotType::I32 => "i32",
+            SlotType::I64 => "i64",
+        }
+    }
+
+    /// Whether `value` is in the declared range
+    pub fn contains(&self, value: i64) -> bool {
+        match self {
+            SlotType::Bool => matches!(value, 0 | 1),
+            SlotType::U8 => u8::try_from(value).is_ok(),
+            SlotType::U16 => u16::try_from(value).is_ok(),
+            SlotType::U32 => u32::try_from(value).is_ok(),
+            SlotType::I32 => i32::try_from(value).is_ok(),
+            SlotType::I64 => true,
+        }
+    }
+}
+
+/// Named memory slot declared with `.var name: type`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlotDecl {
+    pub name: String,
+    pub offset: u32,
+    pub ty: SlotType,
+}
+
+/// Initial memory contents declared with `.data <offset> <value>...`:
+/// `values[i]` is stored at `offset + i` before the first instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DataBlock {
+    pub offset: u32,
+    pub values: Vec<i64>,
+}
+
+impl DataBlock {
+    /// One past the last slot the block initializes
+    pub fn end(&self) -> usize {
+        self.offset as usize + self.values.len()
+    }
+}
+
+/// `offset` rounded up to a multiple of `alignment` slots, as `.align`
+/// does to the `.data` cursor; `None` for a zero alignment or on overflow
+pub fn align_up(offset: u32, alignment: u32) -> Option<u32> {
+    if alignment == 0 {
+        return None;
+    }
+    match offset % alignment {
+        0 => Some(offset),
+        rest => offset.checked_add(alignment - rest),
+    }
+}
+
 /// Complete program representation
 #[derive(Debug, Clone)]
 pub struct Program {
     pub instructions: Vec<Instruction>,
     pub memory_size: usize,
+    /// `memory_size` was computed from the program rather than declared with `.memory`
+    pub memory_inferred: bool,
+    /// Number of values left for the caller by Return/Halt (`.returns`), if declared
+    pub returns: Option<usize>,
+    /// Typed memory slots declared with `.var`, in declaration order
+    pub slots: Vec<SlotDecl>,
+    /// Values the caller pushes before the first instruction (`.args`)
+    pub args: usize,
+    /// Memory initialized before execution (`.data`), in declaration order;
+    /// later blocks overwrite earlier ones where they overlap
+    pub data: Vec<DataBlock>,
 }
 
 impl Program {
@@ -108,7 +569,956 @@ impl Program {
         Self {
             instructions,
             memory_size,
+            memory_inferred: false,
+            returns: None,
+            slots: Vec::new(),
+            args: 0,
+            data: Vec::new(),
+        }
+    }
+
+    /// Instruction count, loop nesting depth and native calls, e.g. for a
+    /// judge rejecting overly complex submissions before running them
+    pub fn complexity(&self) -> Complexity {
+        cost::complexity(self)
+    }
+
+    /// Instructions control can reach from `pc`, including `pc` itself,
+    /// following jumps, jump tables and fallthrough. Empty if `pc` is out
+    /// of range.
+    pub fn reachable_from(&self, pc: usize) -> BTreeSet<usize> {
+        let len = self.instructions.len();
+        let mut reachable = BTreeSet::new();
+        let mut worklist = vec![pc];
+        while let Some(pc) = worklist.pop() {
+            if pc >= len || !reachable.insert(pc) {
+                continue;
+            }
+            worklist.extend(self.instructions[pc].successors(pc, len));
+        }
+        reachable
+    }
+
+    /// Smallest `memory_size` covering every static memory offset, every
+    /// `.var` slot and every `.data` block
+    pub fn required_memory(&self) -> usize {
+        let accessed = self.instructions.iter().filter_map(|instruction| match instruction {
+            Instruction::Load(offset) | Instruction::Store(offset) | Instruction::MemAdd(offset, _) => {
+                Some(*offset as usize + 1)
+            }
+            _ => None,
+        });
+        let declared = self.slots.iter().map(|slot| slot.offset as usize + 1);
+        let initialized = self.data.iter().map(DataBlock::end);
+        accessed.chain(declared).chain(initialized).max().unwrap_or(0)
+    }
+
+    /// Size memory to `required_memory`, as when no `.memory` is declared
+    pub fn with_inferred_memory(mut self) -> Self {
+        self.memory_size = self.required_memory();
+        self.memory_inferred = true;
+        self
+    }
+
+    /// Build a program from `(opcode, operand)` pairs. The operand must be 0
+    /// for opcodes without one and fit the instruction's operand type
+    /// otherwise; JUMP_TABLE and MEM_ADD take two operands and can't be
+    /// built this way.
+    /// Errors are `CinderError::Parse` with `line` set to the 1-based
+    /// position of the offending pair.
+    pub fn from_opcodes(ops: &[(OpCode, i64)], memory_size: usize) -> Result<Program, CinderError> {
+        let mut instructions = Vec::with_capacity(ops.len());
+        for (index, &(opcode, operand)) in ops.iter().enumerate() {
+            let name = opcode.mnemonic();
+            let error = |message: String| ParseError { line: index + 1, column: 1, message };
+            let instruction = match opcode.operand_count() {
+                0 if operand != 0 => {
+                    return Err(error(format!("{} takes no operand, got {}", name, operand)).into());
+                }
+                0 | 1 => {
+                    let out_of_range = || error(format!("operand {} out of range for {}", operand, name));
+                    let u32_operand = || u32::try_from(operand).map_err(|_| out_of_range());
+                    match opcode {
+                        OpCode::PushInt => Instruction::PushInt(operand),
+                        OpCode::PushReg => {
+                            Instruction::PushReg(u8::try_from(operand).map_err(|_| out_of_range())?)
+                        }
+                        OpCode::Jump => Instruction::Jump(u32_operand()?),
+                        OpCode::JumpIfZero => Instruction::JumpIfZero(u32_operand()?),
+                        OpCode::JumpIfNotZero => Instruction::JumpIfNotZero(u32_operand()?),
+                        OpCode::Load => Instruction::Load(u32_operand()?),
+                        OpCode::Store => Instruction::Store(u32_operand()?),
+                        OpCode::CallNative => Instruction::CallNative(u32_operand()?),
+                        OpCode::Trap => Instruction::Trap(u32_operand()?),
+                        OpCode::ShlImm | OpCode::ShrImm | OpCode::SarImm => {
+                            let count = u8::try_from(operand).map_err(|_| out_of_range())?;
+                            match opcode {
+                                OpCode::ShlImm => Instruction::ShlImm(count),
+                                OpCode::ShrImm => Instruction::ShrImm(count),
+                                _ => Instruction::SarImm(count),
+                            }
+                        }
+                        OpCode::Pop => Instruction::Pop,
+                        OpCode::Add => Instruction::Add,
+                        OpCode::Sub => Instruction::Sub,
+                        OpCode::Mul => Instruction::Mul,
+                        OpCode::Div => Instruction::Div,
+                        OpCode::Eq => Instruction::Eq,
+                        OpCode::Lt => Instruction::Lt,
+                        OpCode::Gt => Instruction::Gt,
+                        OpCode::Bool => Instruction::Bool,
+                        OpCode::NotBool => Instruction::NotBool,
+                        OpCode::Return => Instruction::Return,
+                        OpCode::Breakpoint => Instruction::Breakpoint,
+                        OpCode::ReadCycles => Instruction::ReadCycles,
+                        OpCode::LoadInd => Instruction::LoadInd,
+                        OpCode::StoreInd => Instruction::StoreInd,
+                        OpCode::Halt => Instruction::Halt,
+                        OpCode::JumpTable | OpCode::MemAdd => unreachable!("{} has two fixed operands", name),
+                    }
+                }
+                _ => {
+                    return Err(error(format!("{} needs more than one operand", name)).into());
+                }
+            };
+            instructions.push(instruction);
+        }
+        Ok(Program::new(instructions, memory_size))
+    }
+
+    /// Declaration of the slot at `offset`, if any
+    pub fn slot(&self, offset: u32) -> Option<&SlotDecl> {
+        self.slots.iter().find(|slot| slot.offset == offset)
+    }
+
+    /// Constant stores (`PUSH_INT` directly followed by `STORE`) that are
+    /// provably outside their slot's declared type, as readable messages
+    pub fn slot_type_warnings(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (pc, pair) in self.instructions.windows(2).enumerate() {
+            let [Instruction::PushInt(value), Instruction::Store(offset)] = pair else {
+                continue;
+            };
+            if let Some(slot) = self.slot(*offset) {
+                if !slot.ty.contains(*value) {
+                    warnings.push(format!(
+                        "Instruction {}: storing {} into '{}' declared as {}",
+                        pc + 1,
+                        value,
+                        slot.name,
+                        slot.ty.name()
+                    ));
+                }
+            }
+        }
+        warnings
+    }
+
+    /// Validate against `policy` once, producing a program that compilers
+    /// and interpreters accept without validating again
+    pub fn validated(&self, policy: &Sandbox) -> Result<ValidatedProgram, CinderError> {
+        policy.validate_program(self.clone())
+    }
+
+    /// Stable hash of what the program computes: `ISA_VERSION` and the
+    /// `to_bytes` encoding without slots (instructions, memory size, args,
+    /// returns, `.data`). `.var` slots and whether the memory size was
+    /// inferred don't affect it.
+    /// FNV-1a over the encoded form, so it is the same on every platform and
+    /// compiler version.
+    pub fn semantic_hash(&self) -> u64 {
+        let mut bytes = ISA_VERSION.to_le_bytes().to_vec();
+        bytes.extend_from_slice(&self.encode(true));
+        // The hashed layout has a 32-bit memory size; larger sizes are
+        // appended whole rather than truncated
+        if u32::try_from(self.memory_size).is_err() {
+            bytes.extend_from_slice(&(self.memory_size as u64).to_le_bytes());
+        }
+        fnv1a(&bytes)
+    }
+
+    /// `semantic_hash` extended with the source-level metadata it excludes:
+    /// `.var` declarations and `memory_inferred`
+    pub fn full_hash(&self) -> u64 {
+        let mut bytes = self.semantic_hash().to_le_bytes().to_vec();
+        bytes.push(self.memory_inferred as u8);
+        for slot in &self.slots {
+            bytes.extend_from_slice(&slot.offset.to_le_bytes());
+            bytes.extend_from_slice(slot.ty.name().as_bytes());
+            bytes.push(0);
+            bytes.extend_from_slice(slot.name.as_bytes());
+            bytes.push(0);
+        }
+        fnv1a(&bytes)
+    }
+
+    /// Validate under the default policy and run on `backend`, converting any
+    /// panic during validation, compilation or execution into
+    /// `ExecError::Panic` so it never unwinds into the caller. A panicking
+    /// native is reported as `InterpreterError::NativePanicked` by every
+    /// backend.
+    pub fn run_catching(&self, backend: Backend) -> std::result::Result<i64, ExecError> {
+        panic::catch_unwind(AssertUnwindSafe(|| {
+            let sandbox = Sandbox::new();
+            let validated = self
+                .validated(&sandbox)
+                .map_err(|e| ExecError::Rejected(format!("{:#}", e)))?;
+            match backend {
+                Backend::Interpreter => Interpreter::from_validated(validated)
+                    .execute()
+                    .map_err(|error| match error {
+                        InterpreterError::Trap(code) => ExecError::Trap(code),
+                        InterpreterError::DivisionByZero => ExecError::DivisionByZero,
+                        InterpreterError::InvalidMemoryAccess(_) => ExecError::OutOfBounds,
+                        other => ExecError::Interpreter(other),
+                    }),
+                #[cfg(feature = "jit")]
+                Backend::Jit => {
+                    let compiled = JitCompiler::from_validated(validated, sandbox)
+                        .compile()
+                        .map_err(|e| ExecError::Rejected(format!("{:#}", e)))?;
+                    compiled.call_checked(&[]).map_err(|fault| match fault {
+                        JitFault::Trap(code) => ExecError::Trap(code),
+                        JitFault::DivisionByZero => ExecError::DivisionByZero,
+                        JitFault::OutOfBounds => ExecError::OutOfBounds,
+                        JitFault::NativePanicked { id, message } => {
+                            ExecError::Interpreter(InterpreterError::NativePanicked { id, message })
+                        }
+                        // The default sandbox isn't deterministic
+                        JitFault::NondeterministicStore { id } => {
+                            ExecError::Interpreter(InterpreterError::NondeterministicStore { id })
+                        }
+                        // Only reported by code compiled with check_stack
+                        JitFault::StackImbalance => ExecError::Panic(fault.to_string()),
+                    })
+                }
+                Backend::Oracle => oracle::execute(validated.program()),
+            }
+        }))
+        .unwrap_or_else(|payload| Err(ExecError::Panic(interpreter::panic_message(payload.as_ref()))))
+    }
+
+    /// Binary form, format version 4: `MAGIC`, `VERSION_MARKER`, the
+    /// byte order (`LITTLE_ENDIAN`, always, for this encoder), the version
+    /// as u32, `memory_size` as u64, `args` and `returns` (`u32::MAX` if
+    /// undeclared) as u32, then the slot count and each slot as its offset,
+    /// type name and name (each string a u32 length and UTF-8 bytes), then
+    /// the block count and each `.data` block as its offset, value count and
+    /// i64 values, then each instruction as its opcode byte followed by
+    /// fixed-width operands. Every multi-byte field is little-endian.
+    ///
+    /// Versions 1 to 3 had no byte order field and were always little-endian:
+    /// version 1 started with a u32 `memory_size` right after `MAGIC` and
+    /// had neither slots nor blocks, version 2 added `VERSION_MARKER`, the
+    /// version and the slots, and version 3 the blocks.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.encode(false)
+    }
+
+    /// `to_bytes`, or with `for_hash` the oldest layout that holds the
+    /// program, without slots, which `semantic_hash` has always covered
+    fn encode(&self, for_hash: bool) -> Vec<u8> {
+        let slots: &[SlotDecl] = if for_hash { &[] } else { &self.slots };
+        let version = if !for_hash {
+            FORMAT_VERSION
+        } else if !self.data.is_empty() {
+            3
+        } else {
+            1
+        };
+        let mut out = MAGIC.to_vec();
+        if version > 1 {
+            out.extend_from_slice(&VERSION_MARKER.to_le_bytes());
+            if version > 3 {
+                out.push(LITTLE_ENDIAN);
+            }
+            out.extend_from_slice(&version.to_le_bytes());
+        }
+        if version > 3 {
+            out.extend_from_slice(&(self.memory_size as u64).to_le_bytes());
+        } else {
+            out.extend_from_slice(&(self.memory_size as u32).to_le_bytes());
+        }
+        out.extend_from_slice(&(self.args as u32).to_le_bytes());
+        let returns = self.returns.map_or(u32::MAX, |count| count as u32);
+        out.extend_from_slice(&returns.to_le_bytes());
+
+        if version > 1 {
+            out.extend_from_slice(&(slots.len() as u32).to_le_bytes());
+            for slot in slots {
+                out.extend_from_slice(&slot.offset.to_le_bytes());
+                for text in [slot.ty.name(), slot.name.as_str()] {
+                    out.extend_from_slice(&(text.len() as u32).to_le_bytes());
+                    out.extend_from_slice(text.as_bytes());
+                }
+            }
+        }
+        if version > 2 {
+            out.extend_from_slice(&(self.data.len() as u32).to_le_bytes());
+            for block in &self.data {
+                out.extend_from_slice(&block.offset.to_le_bytes());
+                out.extend_from_slice(&(block.values.len() as u32).to_le_bytes());
+                for value in &block.values {
+                    out.extend_from_slice(&value.to_le_bytes());
+                }
+            }
+        }
+
+        for instruction in &self.instructions {
+            out.push(instruction.opcode() as u8);
+            match instruction {
+                Instruction::PushInt(val) => out.extend_from_slice(&val.to_le_bytes()),
+                Instruction::PushReg(reg)
+                | Instruction::ShlImm(reg)
+                | Instruction::ShrImm(reg)
+                | Instruction::SarImm(reg) => out.push(*reg),
+                Instruction::Jump(operand)
+                | Instruction::JumpIfZero(operand)
+                | Instruction::JumpIfNotZero(operand)
+                | Instruction::Load(operand)
+                | Instruction::Store(operand)
+                | Instruction::CallNative(operand)
+                | Instruction::Trap(operand) => out.extend_from_slice(&operand.to_le_bytes()),
+                Instruction::MemAdd(offset, imm) => {
+                    out.extend_from_slice(&offset.to_le_bytes());
+                    out.extend_from_slice(&imm.to_le_bytes());
+                }
+                Instruction::JumpTable(table) => {
+                    out.extend_from_slice(&table.base.to_le_bytes());
+                    out.extend_from_slice(&table.default.to_le_bytes());
+                    out.extend_from_slice(&(table.targets.len() as u32).to_le_bytes());
+                    for target in &table.targets {
+                        out.extend_from_slice(&target.to_le_bytes());
+                    }
+                }
+                _ => {}
+            }
         }
+        out
     }
+
+    /// Decode the output of `to_bytes` in either byte order, or an older
+    /// version's. Versions newer than `FORMAT_VERSION` are rejected. The
+    /// result still has to be validated before it is run.
+    pub fn from_bytes(bytes: &[u8]) -> std::result::Result<Self, ProgramDecodeError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(ProgramDecodeError::BadMagic);
+        }
+        let mut reader = ByteReader::new(bytes, MAGIC.len());
+        let mut version = 1;
+        let mut memory_size = u64::from(reader.u32()?);
+        // All ones in either byte order
+        if memory_size == u64::from(VERSION_MARKER) {
+            // From version 4 on the byte order comes first; before, the
+            // version did, little-endian, so its first byte was 2 or 3
+            let position = reader.position;
+            let explicit_order = match reader.u8()? {
+                LITTLE_ENDIAN => true,
+                BIG_ENDIAN => {
+                    reader.big_endian = true;
+                    true
+                }
+                2 | 3 => {
+                    reader.position = position;
+                    false
+                }
+                byte => return Err(ProgramDecodeError::UnknownByteOrder { byte, position }),
+            };
+            version = reader.u32()?;
+            if !(2..=FORMAT_VERSION).contains(&version) || explicit_order != (version > 3) {
+                return Err(ProgramDecodeError::UnsupportedVersion(version));
+            }
+            memory_size = if version > 3 { reader.u64()? } else { u64::from(reader.u32()?) };
+        }
+        let memory_size = usize::try_from(memory_size).map_err(|_| ProgramDecodeError::MemoryTooLarge(memory_size))?;
+        let args = reader.u32()? as usize;
+        let returns = match reader.u32()? {
+            u32::MAX => None,
+            count => Some(count as usize),
+        };
+
+        let mut slots = Vec::new();
+        if version >= 2 {
+            for _ in 0..reader.u32()? {
+                let position = reader.position;
+                let offset = reader.u32()?;
+                let ty = SlotType::from_name(&reader.string()?)
+                    .ok_or(ProgramDecodeError::InvalidSlot { position })?;
+                let name = reader.string()?;
+                slots.push(SlotDecl { name, offset, ty });
+            }
+        }
+
+        let mut data = Vec::new();
+        if version >= 3 {
+            for _ in 0..reader.u32()? {
+                let offset = reader.u32()?;
+                let count = reader.u32()?;
+                let values = (0..count).map(|_| reader.i64()).collect::<std::result::Result<_, _>>()?;
+                data.push(DataBlock { offset, values });
+            }
+        }
+
+        let mut instructions = Vec::new();
+        while reader.position < bytes.len() {
+            let position = reader.position;
+            let byte = reader.u8()?;
+            let opcode = OpCode::from_u8(byte)
+                .ok_or(ProgramDecodeError::UnknownOpcode { byte, position })?;
+            instructions.push(match opcode {
+                OpCode::PushInt => Instruction::PushInt(reader.i64()?),
+                OpCode::PushReg => Instruction::PushReg(reader.u8()?),
+                OpCode::Pop => Instruction::Pop,
+                OpCode::Add => Instruction::Add,
+                OpCode::Sub => Instruction::Sub,
+                OpCode::Mul => Instruction::Mul,
+                OpCode::Div => Instruction::Div,
+                OpCode::ShlImm => Instruction::ShlImm(reader.u8()?),
+                OpCode::ShrImm => Instruction::ShrImm(reader.u8()?),
+                OpCode::SarImm => Instruction::SarImm(reader.u8()?),
+                OpCode::Eq => Instruction::Eq,
+                OpCode::Lt => Instruction::Lt,
+                OpCode::Gt => Instruction::Gt,
+                OpCode::Bool => Instruction::Bool,
+                OpCode::NotBool => Instruction::NotBool,
+                OpCode::Jump => Instruction::Jump(reader.u32()?),
+                OpCode::JumpIfZero => Instruction::JumpIfZero(reader.u32()?),
+                OpCode::JumpIfNotZero => Instruction::JumpIfNotZero(reader.u32()?),
+                OpCode::JumpTable => {
+                    let base = reader.i64()?;
+                    let default = reader.u32()?;
+                    let count = reader.u32()?;
+                    let targets = (0..count).map(|_| reader.u32()).collect::<std::result::Result<_, _>>()?;
+                    Instruction::JumpTable(Box::new(JumpTable { base, default, targets }))
+                }
+                OpCode::Load => Instruction::Load(reader.u32()?),
+                OpCode::Store => Instruction::Store(reader.u32()?),
+                OpCode::MemAdd => Instruction::MemAdd(reader.u32()?, reader.i32()?),
+                OpCode::LoadInd => Instruction::LoadInd,
+                OpCode::StoreInd => Instruction::StoreInd,
+                OpCode::CallNative => Instruction::CallNative(reader.u32()?),
+                OpCode::Return => Instruction::Return,
+                OpCode::Breakpoint => Instruction::Breakpoint,
+                OpCode::ReadCycles => Instruction::ReadCycles,
+                OpCode::Trap => Instruction::Trap(reader.u32()?),
+                OpCode::Halt => Instruction::Halt,
+            });
+        }
+
+        let mut program = Program::new(instructions, memory_size);
+        program.args = args;
+        program.returns = returns;
+        program.slots = slots;
+        program.data = data;
+        Ok(program)
+    }
+}
+
+/// Execution engine for `Program::run_catching`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backend {
+    Interpreter,
+    #[cfg(feature = "jit")]
+    Jit,
+    /// The reference interpreter in `oracle`, for differential testing
+    Oracle,
+}
+
+/// Failure of `Program::run_catching`
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExecError {
+    /// Failed validation, or the JIT could not compile the program
+    Rejected(String),
+    /// The interpreter stopped with an error
+    Interpreter(InterpreterError),
+    /// The program executed `TRAP` with this code, on either backend
+    Trap(u32),
+    /// `DIV` found a zero divisor, on either backend
+    DivisionByZero,
+    /// `LOAD_IND` or `STORE_IND` addressed memory outside `memory_size`, on either backend
+    OutOfBounds,
+    /// Something panicked; the payload's message, if it had one
+    Panic(String),
 }
 
+/// Instruction set revision covered by `Program::semantic_hash`; bump it
+/// when an existing opcode's behavior or encoding changes
+pub const ISA_VERSION: u32 = 1;
+
+/// 64-bit FNV-1a
+pub(crate) fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Leading bytes of an encoded program
+pub const MAGIC: &[u8; 4] = b"CNDR";
+
+/// Byte order field of a version 4 stream: multi-byte fields are
+/// little-endian. `to_bytes` always writes this one.
+pub const LITTLE_ENDIAN: u8 = 0;
+
+/// Byte order field of a version 4 stream: multi-byte fields are
+/// big-endian, e.g. written on a big-endian embedded target
+pub const BIG_ENDIAN: u8 = 1;
+
+/// Newest encoding `Program::from_bytes` reads; version 2 added `.var`
+/// slots, version 3 `.data` blocks and version 4 the byte order field and
+/// a 64-bit memory size
+pub const FORMAT_VERSION: u32 = 4;
+
+/// Written where a version 1 stream has `memory_size`, which is never this
+/// large, to announce an explicit format version
+const VERSION_MARKER: u32 = u32::MAX;
+
+/// Reasons `Program::from_bytes` rejects its input
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ProgramDecodeError {
+    #[error("Not an encoded CinderVM program (missing magic bytes)")]
+    BadMagic,
+    #[error("Unknown opcode 0x{byte:02X} at byte {position}")]
+    UnknownOpcode { byte: u8, position: usize },
+    #[error("Input ends after {length} bytes but at least {needed} are needed; the file may be truncated")]
+    Truncated { needed: usize, length: usize },
+    #[error("Unsupported program format version {0} (newest is {FORMAT_VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("Invalid .var slot at byte {position}")]
+    InvalidSlot { position: usize },
+    #[error("Unknown byte order {byte} at byte {position}")]
+    UnknownByteOrder { byte: u8, position: usize },
+    #[error("Memory size {0} does not fit in this platform's address space")]
+    MemoryTooLarge(u64),
+}
+
+/// Cursor over the fields of an encoded program, little-endian unless
+/// `big_endian` is set
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+    big_endian: bool,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8], position: usize) -> Self {
+        Self { bytes, position, big_endian: false }
+    }
+
+    fn take<const N: usize>(&mut self) -> std::result::Result<[u8; N], ProgramDecodeError> {
+        let field = self
+            .bytes
+            .get(self.position..self.position + N)
+            .ok_or_else(|| self.truncated(N))?;
+        self.position += N;
+        Ok(field.try_into().expect("slice has length N"))
+    }
+
+    /// Error for a field of `len` bytes running past the end of the input
+    fn truncated(&self, len: usize) -> ProgramDecodeError {
+        ProgramDecodeError::Truncated { needed: self.position.saturating_add(len), length: self.bytes.len() }
+    }
+
+    pub(crate) fn u8(&mut self) -> std::result::Result<u8, ProgramDecodeError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> std::result::Result<u32, ProgramDecodeError> {
+        let big_endian = self.big_endian;
+        self.take().map(|field| if big_endian { u32::from_be_bytes(field) } else { u32::from_le_bytes(field) })
+    }
+
+    pub(crate) fn i32(&mut self) -> std::result::Result<i32, ProgramDecodeError> {
+        self.u32().map(|value| value as i32)
+    }
+
+    pub(crate) fn u64(&mut self) -> std::result::Result<u64, ProgramDecodeError> {
+        let big_endian = self.big_endian;
+        self.take().map(|field| if big_endian { u64::from_be_bytes(field) } else { u64::from_le_bytes(field) })
+    }
+
+    pub(crate) fn i64(&mut self) -> std::result::Result<i64, ProgramDecodeError> {
+        self.u64().map(|value| value as i64)
+    }
+
+    /// A u32 length followed by that many UTF-8 bytes
+    fn string(&mut self) -> std::result::Result<String, ProgramDecodeError> {
+        let position = self.position;
+        let len = self.u32()? as usize;
+        let bytes = self
+            .bytes
+            .get(self.position..self.position.saturating_add(len))
+            .ok_or_else(|| self.truncated(len))?;
+        self.position += len;
+        String::from_utf8(bytes.to_vec()).map_err(|_| ProgramDecodeError::InvalidSlot { position })
+    }
+}
+
+
+/// Build a `Program` from assembly-like syntax mirroring `.cinder` files:
+/// `cinder_program!(.memory 16; push_int 1; push_int 2; add; halt)`.
+/// Mnemonics are the lowercase forms of the parser's, including deprecated
+/// aliases; jump and jump table targets are instruction indices rather than
+/// labels. Without `.memory` the size is inferred, as in the parser, which
+/// only sees static offsets; programs using `load_ind`/`store_ind` should
+/// declare it.
+#[macro_export]
+macro_rules! cinder_program {
+    (@insn push_int $v:literal) => { $crate::bytecode::Instruction::PushInt($v) };
+    (@insn push_reg $r:literal) => { $crate::bytecode::Instruction::PushReg($r) };
+    (@insn pop) => { $crate::bytecode::Instruction::Pop };
+    (@insn add) => { $crate::bytecode::Instruction::Add };
+    (@insn sub) => { $crate::bytecode::Instruction::Sub };
+    (@insn mul) => { $crate::bytecode::Instruction::Mul };
+    (@insn div) => { $crate::bytecode::Instruction::Div };
+    (@insn shl_imm $n:literal) => { $crate::bytecode::Instruction::ShlImm($n) };
+    (@insn shr_imm $n:literal) => { $crate::bytecode::Instruction::ShrImm($n) };
+    (@insn sar_imm $n:literal) => { $crate::bytecode::Instruction::SarImm($n) };
+    (@insn eq) => { $crate::bytecode::Instruction::Eq };
+    (@insn lt) => { $crate::bytecode::Instruction::Lt };
+    (@insn gt) => { $crate::bytecode::Instruction::Gt };
+    (@insn bool) => { $crate::bytecode::Instruction::Bool };
+    (@insn not_bool) => { $crate::bytecode::Instruction::NotBool };
+    (@insn jump $t:literal) => { $crate::bytecode::Instruction::Jump($t) };
+    (@insn jz $t:literal) => { $crate::bytecode::Instruction::JumpIfZero($t) };
+    (@insn jump_if_zero $t:literal) => { $crate::bytecode::Instruction::JumpIfZero($t) };
+    (@insn jnz $t:literal) => { $crate::bytecode::Instruction::JumpIfNotZero($t) };
+    (@insn jump_if_not_zero $t:literal) => { $crate::bytecode::Instruction::JumpIfNotZero($t) };
+    (@insn load $o:literal) => { $crate::bytecode::Instruction::Load($o) };
+    (@insn store $o:literal) => { $crate::bytecode::Instruction::Store($o) };
+    (@insn mem_add $o:literal $i:literal) => { $crate::bytecode::Instruction::MemAdd($o, $i) };
+    (@insn load_ind) => { $crate::bytecode::Instruction::LoadInd };
+    (@insn store_ind) => { $crate::bytecode::Instruction::StoreInd };
+    (@insn call_native $id:literal) => { $crate::bytecode::Instruction::CallNative($id) };
+    (@insn return) => { $crate::bytecode::Instruction::Return };
+    (@insn breakpoint) => { $crate::bytecode::Instruction::Breakpoint };
+    (@insn read_cycles) => { $crate::bytecode::Instruction::ReadCycles };
+    (@insn trap $code:literal) => { $crate::bytecode::Instruction::Trap($code) };
+    (@insn halt) => { $crate::bytecode::Instruction::Halt };
+
+    // Munch one `mnemonic [operands];` at a time
+    (@list [$($done:expr),*]) => { vec![$($done),*] };
+    (@list [$($done:expr),*] jump_table $base:literal $default:literal $($t:literal)+ $(; $($rest:tt)*)?) => {
+        $crate::cinder_program!(@list [$($done,)* $crate::bytecode::Instruction::JumpTable(Box::new(
+            $crate::bytecode::JumpTable { base: $base, default: $default, targets: vec![$($t),+] }
+        ))] $($($rest)*)?)
+    };
+    (@list [$($done:expr),*] $op:tt $a:literal $b:literal $(; $($rest:tt)*)?) => {
+        $crate::cinder_program!(@list [$($done,)* $crate::cinder_program!(@insn $op $a $b)] $($($rest)*)?)
+    };
+    (@list [$($done:expr),*] $op:tt $arg:literal $(; $($rest:tt)*)?) => {
+        $crate::cinder_program!(@list [$($done,)* $crate::cinder_program!(@insn $op $arg)] $($($rest)*)?)
+    };
+    (@list [$($done:expr),*] $op:tt $(; $($rest:tt)*)?) => {
+        $crate::cinder_program!(@list [$($done,)* $crate::cinder_program!(@insn $op)] $($($rest)*)?)
+    };
+
+    (. memory $size:literal; $($rest:tt)*) => {
+        $crate::bytecode::Program::new($crate::cinder_program!(@list [] $($rest)*), $size)
+    };
+    ($($rest:tt)*) => {
+        // Inferred like a parsed file without `.memory`
+        $crate::bytecode::Program::new($crate::cinder_program!(@list [] $($rest)*), 0).with_inferred_memory()
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    #[test]
+    fn macro_matches_the_parser() {
+        let built = cinder_program!(
+            .memory 4;
+            push_int 7; push_int -2; add; push_int 3; sub; push_int 2; mul; push_int 5; div;
+            shl_imm 1; shr_imm 2; sar_imm 3; push_int 1; eq; push_int 0; lt; push_int 0; gt;
+            bool; not_bool; store 0; mem_add 1 -1; load 1; jz 24; jump 24; read_cycles;
+            pop; load 0; jnz 29; jump_table -1 30 27 28 29; breakpoint; call_native 1; trap 9;
+            jump_if_zero 30; push_int 0; jump_if_not_zero 30; push_reg 2; return; halt; load_ind; store_ind
+        );
+        let parsed = Parser::parse(
+            ".memory 4
+            PUSH_INT 7
+            PUSH_INT -2
+            ADD
+            PUSH_INT 3
+            SUB
+            PUSH_INT 2
+            MUL
+            PUSH_INT 5
+            DIV
+            SHL_IMM 1
+            SHR_IMM 2
+            SAR_IMM 3
+            PUSH_INT 1
+            EQ
+            PUSH_INT 0
+            LT
+            PUSH_INT 0
+            GT
+            BOOL
+            NOT_BOOL
+            STORE 0
+            MEM_ADD 1 -1
+            LOAD 1
+            JZ 24
+            JUMP 24
+            READ_CYCLES
+            POP
+            LOAD 0
+            JNZ 29
+            JUMP_TABLE -1 30 27 28 29
+            BREAKPOINT
+            CALL_NATIVE 1
+            TRAP 9
+            JUMP_IF_ZERO 30
+            PUSH_INT 0
+            JUMP_IF_NOT_ZERO 30
+            PUSH_REG 2
+            RETURN
+            HALT
+            LOAD_IND
+            STORE_IND",
+        )
+        .unwrap();
+        assert_eq!(built.instructions, parsed.instructions);
+        assert_eq!(built.memory_size, parsed.memory_size);
+
+        // Every canonical mnemonic has a macro arm
+        let covered: BTreeSet<_> = built.instructions.iter().map(|i| i.opcode().mnemonic()).collect();
+        let canonical: BTreeSet<_> = MNEMONICS.iter().filter(|m| !m.deprecated).map(|m| m.name).collect();
+        assert_eq!(covered, canonical);
+    }
+
+    #[test]
+    fn instructions_sort_into_a_stable_order() {
+        let mut instructions = vec![
+            Instruction::Halt,
+            Instruction::PushInt(3),
+            Instruction::Load(2),
+            Instruction::PushInt(-1),
+            Instruction::Add,
+            Instruction::Load(0),
+            Instruction::Jump(5),
+        ];
+        instructions.sort();
+        assert_eq!(
+            instructions,
+            [
+                Instruction::PushInt(-1),
+                Instruction::PushInt(3),
+                Instruction::Add,
+                Instruction::Jump(5),
+                Instruction::Load(0),
+                Instruction::Load(2),
+                Instruction::Halt,
+            ]
+        );
+
+        // Usable as map keys, e.g. to number distinct instructions
+        let mut ids = std::collections::BTreeMap::new();
+        for instruction in instructions.iter().chain(&[Instruction::Load(2), Instruction::Add]) {
+            let next = ids.len();
+            ids.entry(instruction.clone()).or_insert(next);
+        }
+        assert_eq!(ids.len(), 7);
+        assert_eq!(ids[&Instruction::Load(2)], 5);
+    }
+
+    #[test]
+    fn macro_infers_memory_like_the_parser() {
+        let built = cinder_program!(push_int 1; store 3; mem_add 5 2; halt);
+        let parsed = Parser::parse("PUSH_INT 1\nSTORE 3\nMEM_ADD 5 2\nHALT").unwrap();
+        assert_eq!((built.memory_size, built.memory_inferred), (6, true));
+        assert_eq!((parsed.memory_size, parsed.memory_inferred), (6, true));
+
+        let explicit = cinder_program!(.memory 16; halt);
+        assert_eq!((explicit.memory_size, explicit.memory_inferred), (16, false));
+    }
+
+    #[test]
+    fn unknown_opcode_is_reported_with_its_position() {
+        let mut bytes = cinder_program!(push_int 1; halt).to_bytes();
+        // Between PUSH_INT 1 and HALT
+        let position = bytes.len() - 1;
+        bytes.insert(position, 0xEE);
+        assert_eq!(
+            Program::from_bytes(&bytes).unwrap_err(),
+            ProgramDecodeError::UnknownOpcode { byte: 0xEE, position }
+        );
+    }
+
+    #[test]
+    fn slots_survive_a_round_trip() {
+        let program = Parser::parse(".var count: u8\n.var total: i64\nMEM_ADD count 1\nLOAD total\nRETURN").unwrap();
+        let bytes = program.to_bytes();
+
+        let decoded = Program::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.slots, program.slots);
+        assert_eq!(decoded.instructions, program.instructions);
+        assert_eq!(decoded.memory_size, program.memory_size);
+        assert_eq!(decoded.to_bytes(), bytes);
+        assert_eq!(decoded.semantic_hash(), program.semantic_hash());
+    }
+
+    #[test]
+    fn header_names_the_byte_order() {
+        let program = cinder_program!(.memory 3; push_int 1; halt);
+        let bytes = program.to_bytes();
+        assert_eq!(&bytes[4..9], &[0xFF, 0xFF, 0xFF, 0xFF, LITTLE_ENDIAN]);
+        assert_eq!(&bytes[9..13], &FORMAT_VERSION.to_le_bytes());
+        assert_eq!(&bytes[13..21], &3u64.to_le_bytes());
+
+        let mut unknown = bytes.clone();
+        unknown[8] = 7;
+        assert_eq!(Program::from_bytes(&unknown).unwrap_err(), ProgramDecodeError::UnknownByteOrder { byte: 7, position: 8 });
+    }
+
+    #[test]
+    fn older_versions_still_decode() {
+        // Version 1: memory 3, no args, default returns, PUSH_INT 1; HALT
+        let mut v1 = MAGIC.to_vec();
+        for field in [3, 0, u32::MAX] {
+            v1.extend_from_slice(&field.to_le_bytes());
+        }
+        v1.push(OpCode::PushInt as u8);
+        v1.extend_from_slice(&1i64.to_le_bytes());
+        v1.push(OpCode::Halt as u8);
+        assert_eq!(Program::from_bytes(&v1).unwrap().to_bytes(), cinder_program!(.memory 3; push_int 1; halt).to_bytes());
+
+        // Version 2: the same with one slot `x: i64`
+        let mut v2 = MAGIC.to_vec();
+        for field in [u32::MAX, 2, 3, 0, u32::MAX, 1, 0, 3] {
+            v2.extend_from_slice(&field.to_le_bytes());
+        }
+        v2.extend_from_slice(b"i64");
+        v2.extend_from_slice(&1u32.to_le_bytes());
+        v2.push(b'x');
+        v2.extend_from_slice(&v1[16..]);
+        let decoded = Program::from_bytes(&v2).unwrap();
+        assert_eq!(decoded.slots, [SlotDecl { name: "x".to_string(), offset: 0, ty: SlotType::I64 }]);
+        assert_eq!(decoded.memory_size, 3);
+
+        // Only version 4 on has a byte order field
+        let mut v3_with_order = v2.clone();
+        v3_with_order.insert(8, LITTLE_ENDIAN);
+        assert_eq!(Program::from_bytes(&v3_with_order).unwrap_err(), ProgramDecodeError::UnsupportedVersion(2));
+    }
+
+    #[test]
+    fn memory_sizes_past_u32_are_kept_whole() {
+        let large = Program::new(vec![Instruction::Halt], (1 << 32) + 3);
+        let small = Program::new(vec![Instruction::Halt], 3);
+        assert_eq!(Program::from_bytes(&large.to_bytes()).unwrap().memory_size, (1 << 32) + 3);
+        assert_ne!(large.semantic_hash(), small.semantic_hash());
+    }
+
+    #[test]
+    fn newer_format_versions_are_rejected() {
+        let program = Parser::parse(".var x: i64\nLOAD x\nRETURN").unwrap();
+        let mut bytes = program.to_bytes();
+        bytes[9..13].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+        assert_eq!(
+            Program::from_bytes(&bytes).unwrap_err(),
+            ProgramDecodeError::UnsupportedVersion(FORMAT_VERSION + 1)
+        );
+    }
+
+    #[test]
+    fn from_opcodes_builds_a_runnable_program() {
+        let program = Program::from_opcodes(
+            &[
+                (OpCode::PushInt, 6),
+                (OpCode::Store, 0),
+                (OpCode::Load, 0),
+                (OpCode::PushInt, 7),
+                (OpCode::Mul, 0),
+                (OpCode::Return, 0),
+            ],
+            1,
+        )
+        .unwrap();
+        assert_eq!(program.run_catching(Backend::Interpreter), Ok(42));
+        #[cfg(feature = "jit")]
+        assert_eq!(program.run_catching(Backend::Jit), Ok(42));
+    }
+
+    #[test]
+    fn from_opcodes_checks_operands() {
+        let line = |ops: &[(OpCode, i64)]| match Program::from_opcodes(ops, 0) {
+            Err(CinderError::Parse(error)) => error.line,
+            other => panic!("expected a parse error, got {:?}", other.map(|p| p.instructions)),
+        };
+        assert_eq!(line(&[(OpCode::PushInt, 1), (OpCode::Add, 5)]), 2);
+        assert_eq!(line(&[(OpCode::Load, -1)]), 1);
+        assert_eq!(line(&[(OpCode::ShlImm, 256)]), 1);
+        assert_eq!(line(&[(OpCode::Halt, 0), (OpCode::MemAdd, 0)]), 2);
+    }
+
+    #[test]
+    fn hashes_keep_their_golden_values() {
+        // Published FNV-1a test vectors
+        assert_eq!(fnv1a(b""), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fnv1a(b"a"), 0xaf63_dc4c_8601_ec8c);
+
+        // A change here breaks every stored checkpoint, cache key and baseline:
+        // bump ISA_VERSION or FORMAT_VERSION instead of updating the value
+        let mut program = cinder_program!(.memory 2; push_int 7; store 1; load 1; push_int -3; mul; return);
+        assert_eq!(program.semantic_hash(), 0xdcd8_1a8d_8ea4_f0e8);
+        assert_eq!(program.full_hash(), 0x9552_fed7_48b0_4580);
+
+        program.slots.push(SlotDecl { name: "total".to_string(), offset: 0, ty: SlotType::I64 });
+        assert_eq!(program.semantic_hash(), 0xdcd8_1a8d_8ea4_f0e8);
+        assert_eq!(program.full_hash(), 0xcc2b_cd84_784c_5f41);
+    }
+
+    #[test]
+    fn reachable_from_follows_both_sides_of_a_branch() {
+        let program = cinder_program!(
+            push_int 1; jz 4; push_int 2; return;
+            push_int 3; return;
+            push_int 9; return
+        );
+        assert_eq!(program.reachable_from(0), BTreeSet::from([0, 1, 2, 3, 4, 5]));
+        assert_eq!(program.reachable_from(4), BTreeSet::from([4, 5]));
+        // Dead code is reachable only from itself
+        assert_eq!(program.reachable_from(6), BTreeSet::from([6, 7]));
+        assert!(program.reachable_from(8).is_empty());
+    }
+
+    #[test]
+    fn hand_built_big_endian_stream_decodes() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, BIG_ENDIAN]);
+        // Version 4 header: memory 2 (64-bit), no args, default returns
+        bytes.extend_from_slice(&4u32.to_be_bytes());
+        bytes.extend_from_slice(&2u64.to_be_bytes());
+        for field in [0, u32::MAX] {
+            bytes.extend_from_slice(&field.to_be_bytes());
+        }
+        // One slot `n: i64` at 0
+        bytes.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3]);
+        bytes.extend_from_slice(b"i64");
+        bytes.extend_from_slice(&[0, 0, 0, 1, b'n']);
+        // One data block: slot 1 holds 0x0102_0304_0506_0708
+        bytes.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 1]);
+        bytes.extend_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        // PUSH_INT -2; MEM_ADD 0 -3; LOAD 1; LOAD 0; ADD; ADD; RETURN
+        bytes.push(OpCode::PushInt as u8);
+        bytes.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE]);
+        bytes.push(OpCode::MemAdd as u8);
+        bytes.extend_from_slice(&[0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFD]);
+        bytes.push(OpCode::Load as u8);
+        bytes.extend_from_slice(&[0, 0, 0, 1]);
+        bytes.push(OpCode::Load as u8);
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend_from_slice(&[OpCode::Add as u8, OpCode::Add as u8, OpCode::Return as u8]);
+
+        let decoded = Program::from_bytes(&bytes).unwrap();
+        let mut expected = cinder_program!(.memory 2; push_int -2; mem_add 0 -3; load 1; load 0; add; add; return);
+        expected.slots.push(SlotDecl { name: "n".to_string(), offset: 0, ty: SlotType::I64 });
+        expected.data.push(DataBlock { offset: 1, values: vec![0x0102_0304_0506_0708] });
+        assert_eq!(decoded.to_bytes(), expected.to_bytes());
+        assert_eq!(decoded.run_catching(Backend::Interpreter), Ok(0x0102_0304_0506_0708 - 5));
+    }
+}
@@ -0,0 +1,248 @@
+//! Bounded channels between programs interleaved by a `Scheduler`.
+//!
+//! The embedder creates a `VmChannel`, attaches a `sender()` to the
+//! producing interpreter and a `receiver()` to the consuming one
+//! (`Interpreter::with_channel_sender`/`with_channel_receiver`), and spawns
+//! both. `chan_send` pops a value into the channel; `chan_recv` pushes the
+//! oldest one. A `chan_send` on a full channel or a `chan_recv` on an empty
+//! one blocks its program cooperatively: `run_for` returns
+//! `RunState::Blocked` without executing the call, and the call is retried
+//! on the program's next turn. When every scheduled program is blocked,
+//! the scheduler ends them with `InterpreterError::Deadlock`.
+//!
+//! A channel closes once all its senders are dropped, which the scheduler
+//! does when their programs end; `chan_recv` on a closed, empty channel
+//! pushes `CHAN_CLOSED` instead of blocking.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::Poll;
+
+/// Pushed by `chan_recv` once the channel is closed and drained
+pub const CHAN_CLOSED: i64 = i64::MIN;
+
+/// What a channel native does
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChannelOp {
+    Send,
+    Recv,
+}
+
+/// A bounded FIFO of values shared by the programs it is attached to
+#[derive(Debug, Clone)]
+pub struct VmChannel {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    queue: VecDeque<i64>,
+    capacity: usize,
+    /// `chan_send` on a full channel fails instead of blocking
+    nonblocking: bool,
+    /// Live `ChannelSender`s
+    senders: usize,
+}
+
+impl VmChannel {
+    /// Channel holding up to `capacity` values
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0, since no value could ever be sent.
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "channel capacity must be at least one value");
+        Self {
+            state: Arc::new(Mutex::new(State {
+                queue: VecDeque::with_capacity(capacity),
+                capacity,
+                nonblocking: false,
+                senders: 0,
+            })),
+        }
+    }
+
+    /// Make `chan_send` on a full channel fail with
+    /// `InterpreterError::ChannelFull` instead of blocking
+    pub fn nonblocking(self) -> Self {
+        self.lock().nonblocking = true;
+        self
+    }
+
+    /// A sending end; the channel stays open while any exists
+    pub fn sender(&self) -> ChannelSender {
+        self.lock().senders += 1;
+        ChannelSender { channel: self.clone() }
+    }
+
+    pub fn receiver(&self) -> ChannelReceiver {
+        ChannelReceiver { channel: self.clone() }
+    }
+
+    /// Values sent and not yet received
+    pub fn len(&self) -> usize {
+        self.lock().queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Whether every sender has been dropped
+    pub fn is_closed(&self) -> bool {
+        self.lock().senders == 0
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// Where `chan_send` puts values
+#[derive(Debug)]
+pub struct ChannelSender {
+    channel: VmChannel,
+}
+
+/// Outcome of `ChannelSender::try_send` on a full channel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Full {
+    /// Retry once there is room
+    Wait,
+    /// The channel is nonblocking
+    Fail,
+}
+
+impl ChannelSender {
+    /// Queue `value`, unless the channel is full
+    pub(crate) fn try_send(&self, value: i64) -> Result<(), Full> {
+        let mut state = self.channel.lock();
+        if state.queue.len() < state.capacity {
+            state.queue.push_back(value);
+            Ok(())
+        } else if state.nonblocking {
+            Err(Full::Fail)
+        } else {
+            Err(Full::Wait)
+        }
+    }
+}
+
+impl Clone for ChannelSender {
+    fn clone(&self) -> Self {
+        self.channel.sender()
+    }
+}
+
+impl Drop for ChannelSender {
+    fn drop(&mut self) {
+        self.channel.lock().senders -= 1;
+    }
+}
+
+/// Where `chan_recv` takes values from
+#[derive(Debug, Clone)]
+pub struct ChannelReceiver {
+    channel: VmChannel,
+}
+
+impl ChannelReceiver {
+    /// The oldest value, `CHAN_CLOSED` if there is none and never will
+    /// be, or `Pending` to retry later
+    pub(crate) fn try_recv(&self) -> Poll<i64> {
+        let mut state = self.channel.lock();
+        match state.queue.pop_front() {
+            Some(value) => Poll::Ready(value),
+            None if state.senders == 0 => Poll::Ready(CHAN_CLOSED),
+            None => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::interpreter::{Interpreter, InterpreterError};
+    use crate::parser::Parser;
+    use crate::scheduler::Scheduler;
+
+    /// Send 1..=count, then end
+    fn producer(count: i64) -> String {
+        format!(
+            "PUSH_INT 0\nSTORE 0\nloop:\nMEM_ADD 0 1\nLOAD 0\nCALL_NATIVE chan_send\n\
+             LOAD 0\nPUSH_INT {count}\nSUB\nJNZ loop\nPUSH_INT 0\nRETURN\n"
+        )
+    }
+
+    /// Sum what is received until the channel closes
+    const CONSUMER: &str = "\
+        loop:\n\
+        CALL_NATIVE chan_recv\n\
+        STORE 1\n\
+        LOAD 1\n\
+        PUSH_INT -9223372036854775808\n\
+        EQ\n\
+        JNZ done\n\
+        LOAD 0\n\
+        LOAD 1\n\
+        ADD\n\
+        STORE 0\n\
+        JUMP loop\n\
+        done:\n\
+        LOAD 0\n\
+        RETURN\n";
+
+    fn interpreter(source: &str) -> Interpreter {
+        Interpreter::new(Parser::parse(source).unwrap())
+    }
+
+    #[test]
+    fn producer_and_consumer_run_to_completion() {
+        // Small quanta and capacity, so both sides block many times
+        for (capacity, quantum) in [(1, 1), (2, 3), (16, 100)] {
+            let channel = VmChannel::new(capacity);
+            let mut scheduler = Scheduler::new(quantum);
+            scheduler.spawn(interpreter(&producer(100)).with_channel_sender(channel.sender()));
+            let consumer = scheduler.spawn(interpreter(CONSUMER).with_channel_receiver(channel.receiver()));
+
+            let finished = scheduler.run();
+            let result = finished.iter().find(|finished| finished.id == consumer).unwrap();
+            assert_eq!(result.outcome, Ok(5050), "capacity {}, quantum {}", capacity, quantum);
+            assert!(channel.is_closed() && channel.is_empty());
+        }
+    }
+
+    #[test]
+    fn both_sides_blocked_is_a_deadlock() {
+        // Each waits for the other to send first
+        let (left, right) = (VmChannel::new(1), VmChannel::new(1));
+        let waiter = "CALL_NATIVE chan_recv\nCALL_NATIVE chan_send\nPUSH_INT 1\nRETURN\n";
+        let mut scheduler = Scheduler::new(10);
+        scheduler.spawn(interpreter(waiter).with_channel_receiver(left.receiver()).with_channel_sender(right.sender()));
+        scheduler.spawn(interpreter(waiter).with_channel_receiver(right.receiver()).with_channel_sender(left.sender()));
+
+        let finished = scheduler.run();
+        assert_eq!((finished[0].id, &finished[0].outcome), (1, &Err(InterpreterError::Deadlock)));
+        // Ending it closed the channel the other one waits on
+        assert_eq!((finished[1].id, &finished[1].outcome), (0, &Ok(1)));
+        assert_eq!(right.len(), 1);
+    }
+
+    #[test]
+    fn blocking_outside_a_scheduler_is_a_deadlock() {
+        let channel = VmChannel::new(1);
+        let sender = channel.sender();
+        let mut receiving = interpreter("CALL_NATIVE chan_recv\nRETURN").with_channel_receiver(channel.receiver());
+        assert_eq!(receiving.execute(), Err(InterpreterError::Deadlock));
+        drop(sender);
+        assert_eq!(receiving.execute(), Ok(CHAN_CLOSED));
+
+        let full = VmChannel::new(1).nonblocking();
+        let mut sending = interpreter("PUSH_INT 1\nCALL_NATIVE chan_send\nPUSH_INT 2\nCALL_NATIVE chan_send\nRETURN")
+            .with_channel_sender(full.sender());
+        assert_eq!(sending.execute(), Err(InterpreterError::ChannelFull));
+        assert_eq!(full.len(), 1);
+
+        assert_eq!(interpreter("CALL_NATIVE chan_recv").execute(), Err(InterpreterError::NoChannel { id: 0x21 }));
+    }
+}
@@ -1,8 +1,28 @@
-use crate::interpreter::Interpreter;
-use crate::jit::JitCompiler;
-use crate::parser::Parser;
+use cindervm::artifact;
+use cindervm::bytecode::{Backend, ExecError, Program, VmInt, MNEMONICS};
+use cindervm::channel::VmChannel;
+use cindervm::cost::{estimate_cost, CostModel};
+use cindervm::disasm::disassemble_range;
+use cindervm::error::CinderError;
+use cindervm::formats::{self, ProgramCodec};
+use cindervm::incremental::IncrementalParser;
+use cindervm::natives::{self, KeyValueStore, KvOp, SharedStore};
+use cindervm::oracle::Differential;
+use cindervm::interpreter::{Checkpoint, Interpreter, InterpreterError, NativeCall, RunState};
+use cindervm::jit::{CompiledProgram, CpuFeatures, JitCompiler, JitOptions};
+use cindervm::parser::Parser;
 use anyhow::{Context, Result};
-use clap::{Parser as ClapParser, Subcommand};
+use clap::{Parser as ClapParser, Subcommand, ValueEnum};
+use cindervm::sandbox::{Capability, Sandbox};
+use cindervm::scheduler::Scheduler;
+use cindervm::trace::{self, ChromeTrace, Span};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(ClapParser)]
 #[command(name = "cinder")]
@@ -10,6 +30,92 @@ use clap::{Parser as ClapParser, Subcommand};
 pub struct CinderCli {
     #[command(subcommand)]
     pub command: Commands,
+    
+    /// Don't warn about deprecated mnemonics
+    #[arg(long, global = true)]
+    pub allow_deprecated: bool,
+    
+    /// Warn where `; ( in -- out )` stack comments disagree with the instruction
+    #[arg(long, global = true)]
+    pub check_stack_comments: bool,
+    
+    /// Write parse/validate/compile/run spans to this file in Chrome trace format
+    #[arg(long, global = true)]
+    pub chrome_trace: Option<String>,
+    
+    /// How errors are reported
+    #[arg(long, global = true, value_enum, default_value_t = MessageFormat::Human)]
+    pub message_format: MessageFormat,
+    
+    /// Add a native (ID or name) to the sandbox whitelist
+    #[arg(long, global = true, value_name = "NATIVE")]
+    pub allow_native: Vec<String>,
+    
+    /// Remove a native (ID or name) from the whitelist; wins over --allow-native
+    #[arg(long, global = true, value_name = "NATIVE")]
+    pub deny_native: Vec<String>,
+    
+    /// Grant a capability (storage, channels) and whitelist its natives
+    #[arg(long, global = true, value_name = "CAPABILITY", value_parser = capability)]
+    pub grant: Vec<Capability>,
+    
+    /// Reject programs whose result can vary between runs with the same inputs
+    #[arg(long, global = true)]
+    pub deterministic: bool,
+    
+    /// Back kv_get/kv_put with this JSON map, loaded before the run and saved after it
+    #[arg(long, global = true, value_name = "FILE")]
+    pub kv_file: Option<String>,
+    
+    /// Overwrite existing output files (convert -o, debug --record, --chrome-trace)
+    #[arg(long, global = true)]
+    pub force: bool,
+    
+    /// Trace opened from `--chrome-trace`, flushed when the CLI is dropped
+    #[arg(skip)]
+    trace: OnceLock<ChromeTrace>,
+    
+    /// Store opened from `--kv-file`, attached to every program the command runs
+    #[arg(skip)]
+    store: OnceLock<SharedStore>,
+}
+
+/// Error output style for `--message-format`
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MessageFormat {
+    /// Readable error chain
+    Human,
+    /// One rustc-style JSON diagnostic per line on stderr
+    Json,
+}
+
+/// Formats accepted by `convert`
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Format {
+    /// .cinder source
+    Cinder,
+    /// `(program ...)` S-expressions
+    Sexpr,
+    /// `Program::to_bytes` encoding
+    Binary,
+}
+
+impl Format {
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("sexpr") => Format::Sexpr,
+            Some("cbin") => Format::Binary,
+            _ => Format::Cinder,
+        }
+    }
+
+    fn codec(self) -> &'static dyn ProgramCodec {
+        match self {
+            Format::Cinder => &formats::Source,
+            Format::Sexpr => &formats::Sexpr,
+            Format::Binary => &formats::Binary,
+        }
+    }
 }
 
 #[derive(Subcommand)]
@@ -18,105 +124,1166 @@ pub enum Commands {
     Exec {
         /// .cinder file to execute
         file: String,
+        
+        /// Reject programs whose generated code exceeds this many bytes
+        #[arg(long)]
+        max_code_size: Option<usize>,
+        
+        /// Report hardware cycle and instruction counts for the native run
+        #[arg(long)]
+        profile_jit: bool,
+        
+        /// Verify the native frame on return and fail on a stack imbalance
+        #[arg(long, conflicts_with = "profile_jit")]
+        check_stack: bool,
+        
+        /// Run the compiled code this many times and fail unless every run
+        /// returns the same value
+        #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "profile_jit")]
+        repeat: u64,
+        
+        /// Arguments for a program declaring `.args`, first pushed deepest
+        #[arg(long, num_args = 1.., allow_hyphen_values = true)]
+        args: Vec<i64>,
+    },
+    
+    /// Run a program on the interpreter and the JIT and fail unless they agree
+    VerifyExec {
+        /// .cinder file to run
+        file: String,
+        
+        /// Also run the reference interpreter, so a disagreement names the
+        /// backend the other two outvote
+        #[arg(long)]
+        oracle: bool,
     },
     
     /// Run program using interpreter (for debugging)
     Debug {
         /// .cinder file to execute
         file: String,
+        
+        /// Fail on stores outside a `.var` slot's declared type
+        #[arg(long)]
+        check_types: bool,
+        
+        /// Log every native call to this file
+        #[arg(long, conflicts_with = "replay")]
+        record: Option<String>,
+        
+        /// Serve native calls from a log written by --record instead of running them
+        #[arg(long)]
+        replay: Option<String>,
+        
+        /// Store only touched memory slots, even for small `.memory` sizes
+        #[arg(long)]
+        sparse_memory: bool,
+        
+        /// Allocate exactly `.memory` slots instead of at least 1024
+        #[arg(long)]
+        strict_memory: bool,
+        
+        /// Wrap values to 32 bits, as in a program written for `i32`
+        #[arg(long)]
+        i32: bool,
+        
+        /// Fail when the program revisits an identical state, i.e. loops forever
+        #[arg(long)]
+        detect_loops: bool,
+        
+        /// Periodically save the interpreter state to this file, for `resume`
+        #[arg(long)]
+        checkpoint_file: Option<String>,
+        
+        /// Seconds between checkpoints
+        #[arg(long, default_value_t = 60, requires = "checkpoint_file")]
+        checkpoint_every: u64,
+    },
+    
+    /// Continue an interpreter run from a `debug --checkpoint-file` checkpoint
+    Resume {
+        /// Checkpoint to continue from; further checkpoints overwrite it
+        checkpoint: String,
+        
+        /// .cinder file the checkpoint was taken from
+        file: String,
+        
+        /// Seconds between checkpoints
+        #[arg(long, default_value_t = 60)]
+        checkpoint_every: u64,
+    },
+    
+    /// Run two programs on the interpreter, the first sending values to the
+    /// second over a channel, and print the second one's result
+    Pipe {
+        /// Program calling chan_send
+        producer: String,
+        
+        /// Program calling chan_recv
+        consumer: String,
+        
+        /// Values the channel holds before chan_send blocks
+        #[arg(long, default_value_t = 16, value_parser = clap::value_parser!(u64).range(1..))]
+        capacity: u64,
+        
+        /// Instructions each program runs before the other gets a turn
+        #[arg(long, default_value_t = 1000, value_parser = clap::value_parser!(u64).range(1..))]
+        quantum: u64,
+    },
+    
+    /// Re-run a program with the JIT whenever it or a file it includes changes
+    Watch {
+        /// .cinder file to watch
+        file: String,
+        
+        /// Milliseconds between checks for changed files
+        #[arg(long, default_value_t = 250)]
+        interval_ms: u64,
     },
     
     /// Display generated machine code
     Disassemble {
         /// .cinder file to disassemble
         file: String,
+        
+        /// Reject programs whose generated code exceeds this many bytes
+        #[arg(long)]
+        max_code_size: Option<usize>,
+        
+        /// Show numeric jump targets and native IDs instead of labels and names
+        #[arg(long)]
+        raw: bool,
+        
+        /// Only show instructions START up to (not including) END, and their machine code
+        #[arg(long, num_args = 2, value_names = ["START", "END"])]
+        range: Option<Vec<usize>>,
+        
+        /// Print the native code offset of every bytecode instruction
+        #[arg(long)]
+        dump_native_map: bool,
+    },
+    
+    /// Display static information about a program
+    Stats {
+        /// .cinder file to analyze
+        file: String,
+        
+        /// Include a static execution-cost estimate
+        #[arg(long)]
+        cost: bool,
+    },
+    
+    /// Rewrite deprecated mnemonics to their canonical spelling
+    Fmt {
+        /// .cinder file to format
+        file: String,
+        
+        /// Overwrite the file instead of printing the result
+        #[arg(long)]
+        in_place: bool,
+    },
+    
+    /// List opcodes with their mnemonics and aliases
+    Opcodes,
+    
+    /// List the natives the sandbox allows after --allow-native/--deny-native
+    Natives,
+    
+    /// Report what this machine offers the JIT
+    Doctor,
+    
+    /// Convert a program between .cinder source, binary and S-expressions
+    Convert {
+        /// Program to convert
+        file: String,
+        
+        /// Output format
+        #[arg(long)]
+        to: Format,
+        
+        /// Input format (default: from the extension, .sexpr or .cbin, else cinder)
+        #[arg(long)]
+        from: Option<Format>,
+        
+        /// Write to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+    
+    /// List example programs and their expected results
+    Examples {
+        /// Directory of .cinder files with `# expect:` headers
+        #[arg(default_value = "examples")]
+        dir: String,
+        
+        /// Run every example on both backends and check the results
+        #[arg(long)]
+        test: bool,
     },
 }
 
 impl CinderCli {
     pub fn execute(&self) -> Result<()> {
+        if let Some(path) = &self.chrome_trace {
+            self.check_output(path, "Trace")?;
+            let _ = self.trace.set(ChromeTrace::new(path));
+        }
+        
+        let Some(path) = &self.kv_file else {
+            return self.run_command();
+        };
+        let file = KvFile::load(path)?;
+        let store = SharedStore::new(file.clone());
+        store.start_audit();
+        let _ = self.store.set(store.clone());
+        
+        // Writes made before a failure are kept, like any other side effect
+        let result = self.run_command();
+        let audit = store.take_audit();
+        let reads = audit.iter().filter(|access| access.op == KvOp::Get).count();
+        file.save()?;
+        println!("🗄️  Storage: {} read(s), {} write(s), saved to {}", reads, audit.len() - reads, path);
+        result
+    }
+
+    fn run_command(&self) -> Result<()> {
         match &self.command {
-            Commands::Exec { file } => {
-                self.execute_jit(file)
+            Commands::Exec { file, max_code_size, profile_jit, check_stack, repeat, args } => {
+                let options = JitOptions {
+                    max_code_size: *max_code_size,
+                    check_stack: *check_stack,
+                    ..Default::default()
+                };
+                self.execute_jit(file, options, *profile_jit, *repeat, args)
+            }
+            
+            Commands::VerifyExec { file, oracle } => {
+                self.verify_exec(file, *oracle)
+            }
+            
+            Commands::Debug {
+                file, check_types, record, replay, sparse_memory, strict_memory, i32, detect_loops,
+                checkpoint_file, checkpoint_every,
+            } => {
+                let options = DebugOptions {
+                    check_types: *check_types,
+                    sparse_memory: *sparse_memory,
+                    strict_memory: *strict_memory,
+                    i32: *i32,
+                    detect_loops: *detect_loops,
+                    checkpoint_file: checkpoint_file.clone(),
+                    checkpoint_every: Duration::from_secs(*checkpoint_every),
+                };
+                self.execute_interpreter(file, options, record.as_deref(), replay.as_deref())
+            }
+            
+            Commands::Resume { checkpoint, file, checkpoint_every } => {
+                self.resume(checkpoint, file, Duration::from_secs(*checkpoint_every))
+            }
+            
+            Commands::Pipe { producer, consumer, capacity, quantum } => {
+                self.pipe(producer, consumer, *capacity as usize, *quantum)
+            }
+            
+            Commands::Watch { file, interval_ms } => {
+                self.watch(file, Duration::from_millis(*interval_ms))
+            }
+            
+            Commands::Disassemble { file, max_code_size, raw, range, dump_native_map } => {
+                let range = range.as_deref().map(|bounds| bounds[0]..bounds[1]);
+                let options = JitOptions { max_code_size: *max_code_size, ..Default::default() };
+                self.disassemble(file, options, *raw, range, *dump_native_map)
+            }
+            
+            Commands::Stats { file, cost } => {
+                self.stats(file, *cost)
+            }
+            
+            Commands::Fmt { file, in_place } => {
+                self.format(file, *in_place)
+            }
+            
+            Commands::Opcodes => {
+                self.list_opcodes();
+                Ok(())
+            }
+            
+            Commands::Natives => {
+                self.list_natives()
             }
             
-            Commands::Debug { file } => {
-                self.execute_interpreter(file)
+            Commands::Doctor => {
+                println!("🩺 CPU features: {}", CpuFeatures::detect());
+                println!("   The JIT compiles for baseline x86-64 unless told otherwise");
+                Ok(())
             }
             
-            Commands::Disassemble { file } => {
-                self.disassemble(file)
+            Commands::Convert { file, to, from, output } => {
+                self.convert(file, *from, *to, output.as_deref())
             }
+            
+            Commands::Examples { dir, test } => {
+                self.examples(dir, *test)
+            }
+        }
+    }
+
+    /// Print an error returned by `execute` in the requested format
+    pub fn report(&self, error: &anyhow::Error) {
+        match self.message_format {
+            MessageFormat::Human => eprintln!("Error: {:?}", error),
+            MessageFormat::Json => eprintln!("{}", diagnostic_json(error)),
         }
     }
 
-    fn execute_jit(&self, file: &str) -> Result<()> {
+    /// Refuse to overwrite an existing output file unless `--force` was given
+    fn check_output(&self, path: &str, what: &str) -> Result<()> {
+        if !self.force && Path::new(path).exists() {
+            anyhow::bail!("{} {} already exists; pass --force to overwrite it", what, path);
+        }
+        Ok(())
+    }
+
+    /// Span in the Chrome trace, if one was requested
+    fn span(&self, name: &str) -> Option<Span<'_>> {
+        self.trace.get().map(|trace| trace.span(name))
+    }
+
+    /// Store for the storage natives: the `--kv-file` one, else a fresh map
+    fn store(&self) -> SharedStore {
+        self.store.get().cloned().unwrap_or_default()
+    }
+
+    /// Default whitelist with `--grant` and `--allow-native` added and
+    /// `--deny-native` removed, in deterministic mode with `--deterministic`
+    fn sandbox(&self) -> Result<Sandbox> {
+        let mut sandbox = Sandbox::new();
+        for &capability in &self.grant {
+            sandbox.grant(capability);
+        }
+        for native in &self.allow_native {
+            sandbox.allow_native(native_id(native)?);
+        }
+        for native in &self.deny_native {
+            sandbox.deny_native(native_id(native)?);
+        }
+        if self.deterministic {
+            sandbox.require_deterministic();
+        }
+        Ok(sandbox)
+    }
+
+    /// Read and parse a program, reporting deprecated mnemonics
+    fn load(&self, file: &str) -> Result<Program> {
+        let source = fs::read_to_string(file)
+            .with_context(|| format!("Cannot read file: {}", file))?;
+        
+        if !self.allow_deprecated {
+            for warning in Parser::deprecations(&source) {
+                println!("⚠️  {}", warning);
+            }
+        }
+        
+        let program = {
+            let _span = self.span("parse");
+            Parser::parse_file(file)
+                .with_context(|| format!("Error parsing file: {}", file))?
+        };
+        
+        if self.check_stack_comments {
+            for warning in Parser::stack_comment_warnings(&source, &program) {
+                println!("⚠️  {}", warning);
+            }
+        }
+        Ok(program)
+    }
+
+    fn execute_jit(
+        &self,
+        file: &str,
+        options: JitOptions,
+        profile: bool,
+        repeat: u64,
+        args: &[i64],
+    ) -> Result<()> {
         println!("🔧 JIT compilation for: {}", file);
         
-        let program = Parser::parse_file(file)
-            .with_context(|| format!("Error parsing file: {}", file))?;
+        let program = self.load(file)?;
         
-        let mut compiler = JitCompiler::new(program);
-        let memory = compiler.compile()
-            .context("Error during JIT compilation")?;
+        for warning in program.slot_type_warnings() {
+            println!("⚠️  {}", warning);
+        }
+        
+        if args.len() != program.args {
+            anyhow::bail!(
+                "Program expects {} argument(s), {} given with --args",
+                program.args,
+                args.len()
+            );
+        }
+        
+        let sandbox = self.sandbox()?;
+        let validated = {
+            let _span = self.span("validate");
+            program.validated(&sandbox)
+                .context("Error during JIT compilation")?
+        };
+        
+        let compiled = {
+            let _span = self.span("compile");
+            JitCompiler::from_validated(validated, sandbox)
+                .with_options(options)
+                .compile()
+                .context("Error during JIT compilation")?
+                .with_store(self.store())
+        };
         
         println!("✅ Compilation successful!");
         println!("🚀 Executing native code...");
         
-        // Execute compiled code
-        unsafe {
-            type NativeFunction = unsafe extern "C" fn() -> i64;
-            let func: NativeFunction = memory.as_function();
-            let result = func();
-            println!("📊 Result: {}", result);
+        if profile {
+            return profile_run(&compiled, args);
+        }
+        
+        let mut results = Vec::new();
+        for _ in 0..repeat {
+            let _span = self.span("run");
+            results.push(compiled.call_checked(args)?);
+        }
+        report_results(&results)
+    }
+
+    fn verify_exec(&self, file: &str, oracle: bool) -> Result<()> {
+        println!("⚖️  Differential execution for: {}", file);
+        
+        let program = self.load(file)?;
+        let mut backends = vec![Backend::Interpreter, Backend::Jit];
+        if oracle {
+            backends.insert(0, Backend::Oracle);
+        }
+        
+        // Native output would repeat once per backend
+        natives::set_output(Box::new(io::sink()));
+        let runs = {
+            let _span = self.span("run");
+            Differential::run(&program, &backends)
+        };
+        natives::reset_output();
+        
+        print!("{}", runs);
+        if runs.agrees() {
+            println!("✅ All {} backends agree", backends.len());
+            return Ok(());
+        }
+        match runs.culprit() {
+            Some(backend) => anyhow::bail!("{:?} disagrees with the other backends", backend),
+            None => anyhow::bail!("Backends disagree"),
+        }
+    }
+
+    /// Poll the program's files, re-parse the changed ones incrementally
+    /// and re-run the program. Errors are reported and watching goes on.
+    fn watch(&self, file: &str, interval: Duration) -> Result<()> {
+        println!("👀 Watching {} (Ctrl-C to stop)", file);
+        
+        let sandbox = self.sandbox()?;
+        let mut parser = IncrementalParser::new(file);
+        let mut modified: HashMap<PathBuf, SystemTime> = HashMap::new();
+        // Code of the last program run, by semantic hash
+        let mut cached: Option<(u64, CompiledProgram)> = None;
+        loop {
+            let mut files: Vec<PathBuf> = parser.files().into_iter().map(Path::to_path_buf).collect();
+            if files.is_empty() {
+                files.push(fs::canonicalize(file).unwrap_or_else(|_| PathBuf::from(file)));
+            }
+            let changed: Vec<PathBuf> = files
+                .into_iter()
+                .filter(|path| match fs::metadata(path).and_then(|metadata| metadata.modified()) {
+                    Ok(time) => modified.insert(path.clone(), time) != Some(time),
+                    Err(_) => false,
+                })
+                .collect();
+            
+            let mut rebuilt = None;
+            for path in &changed {
+                rebuilt = Some(parser.update(path).cloned());
+            }
+            match rebuilt {
+                // Touched but unchanged
+                Some(Ok(_)) if parser.reparsed().is_empty() => {}
+                Some(Ok(program)) => {
+                    println!("🔁 Re-parsed {} file(s)", parser.reparsed().len());
+                    if let Err(error) = self.watch_run(program, &sandbox, &mut cached) {
+                        println!("❌ {:#}", error);
+                    }
+                }
+                Some(Err(error)) => println!("❌ {}", error),
+                None => {}
+            }
+            std::thread::sleep(interval);
+        }
+    }
+
+    /// Run a rebuilt program, compiling it only if it differs from the
+    /// cached one in more than comments, labels or layout
+    fn watch_run(&self, program: Program, sandbox: &Sandbox, cached: &mut Option<(u64, CompiledProgram)>) -> Result<()> {
+        let hash = program.semantic_hash();
+        if cached.as_ref().is_some_and(|(cached, _)| *cached == hash) {
+            println!("✅ No semantic change");
+            return Ok(());
+        }
+        if program.args > 0 {
+            anyhow::bail!("Program expects {} argument(s); watch runs it without any", program.args);
         }
         
+        let validated = program.validated(sandbox).context("Error during JIT compilation")?;
+        let compiled = JitCompiler::from_validated(validated, sandbox.clone())
+            .compile()
+            .context("Error during JIT compilation")?
+            .with_store(self.store());
+        let result = compiled.call_checked(&[]);
+        *cached = Some((hash, compiled));
+        println!("📊 Result: {}", result?);
         Ok(())
     }
 
-    fn execute_interpreter(&self, file: &str) -> Result<()> {
+    fn execute_interpreter(
+        &self,
+        file: &str,
+        options: DebugOptions,
+        record: Option<&str>,
+        replay: Option<&str>,
+    ) -> Result<()> {
         println!("🐛 Debug execution (interpreter) for: {}", file);
         
-        let program = Parser::parse_file(file)
-            .with_context(|| format!("Error parsing file: {}", file))?;
+        let program = self.load(file)?;
         
-        let mut interpreter = Interpreter::new(program);
-        let result = interpreter.execute()
-            .map_err(|e| anyhow::anyhow!("Execution error: {:?}", e))?;
+        for warning in program.slot_type_warnings() {
+            println!("⚠️  {}", warning);
+        }
+        
+        // Debugging skips validation, but not the policy's capability grants
+        self.sandbox()?.check_capabilities(&program)
+            .map_err(CinderError::from)
+            .with_context(|| format!("Error validating file: {}", file))?;
+        
+        let interpreter = Interpreter::new(program).with_store(self.store());
+        let results = if options.i32 {
+            self.debug_run(interpreter.with_value_type::<i32>(), &options, record, replay)?
+        } else {
+            self.debug_run(interpreter, &options, record, replay)?
+        };
+        
+        let results: Vec<String> = results.iter().map(|v| v.to_string()).collect();
+        println!("📊 Result: {}", results.join(", "));
+        Ok(())
+    }
+
+    /// Configure `interpreter` from the `debug` flags, run it and write the native log
+    fn debug_run<V: VmInt>(
+        &self,
+        mut interpreter: Interpreter<(), V>,
+        options: &DebugOptions,
+        record: Option<&str>,
+        replay: Option<&str>,
+    ) -> Result<Vec<i64>> {
+        interpreter.set_on_breakpoint(Box::new(|pc, stack| {
+            println!("🔴 Breakpoint at instruction {}, stack: {:?}", pc, stack);
+        }));
+        if options.check_types {
+            interpreter = interpreter.with_type_checks();
+        }
+        if options.strict_memory {
+            interpreter = interpreter.with_exact_memory();
+        }
+        if options.sparse_memory {
+            interpreter = interpreter.with_sparse_memory();
+        }
+        if options.detect_loops {
+            interpreter = interpreter.with_loop_detection();
+        }
+        if let Some(path) = record {
+            self.check_output(path, "Native log")?;
+            interpreter = interpreter.record_natives();
+        }
+        if let Some(path) = replay {
+            interpreter = interpreter.replay_natives(read_native_log(path)?);
+        }
+        let results = {
+            let _span = self.span("run");
+            match &options.checkpoint_file {
+                Some(path) => vec![run_checkpointed(&mut interpreter, path, options.checkpoint_every)?],
+                None => interpreter.execute_returning()
+                    .map_err(|e| anyhow::anyhow!("Execution error: {:?}", e))?,
+            }
+        };
+        
+        if let Some(path) = record {
+            let log: String = interpreter
+                .native_log()
+                .iter()
+                .map(|call| format!("{}\n", call))
+                .collect();
+            replace_file(path, log.as_bytes(), "native log")?;
+            println!("📝 Recorded {} native calls to {}", interpreter.native_log().len(), path);
+        }
+        Ok(results)
+    }
+
+    fn resume(&self, checkpoint: &str, file: &str, every: Duration) -> Result<()> {
+        println!("⏯️  Resuming {} from checkpoint {}", file, checkpoint);
+        
+        let program = self.load(file)?;
+        let bytes = fs::read(checkpoint)
+            .with_context(|| format!("Cannot read checkpoint: {}", checkpoint))?;
+        let state = Checkpoint::from_bytes(&bytes)
+            .with_context(|| format!("Invalid checkpoint: {}", checkpoint))?;
         
+        // The checkpoint's value width picks the interpreter's value type
+        let interpreter = Interpreter::new(program).with_store(self.store());
+        let result = match state.value_bits() {
+            32 => self.resume_run(interpreter.with_value_type::<i32>(), state, file, checkpoint, every)?,
+            _ => self.resume_run(interpreter, state, file, checkpoint, every)?,
+        };
         println!("📊 Result: {}", result);
         Ok(())
     }
 
-    fn disassemble(&self, file: &str) -> Result<()> {
+    fn resume_run<V: VmInt>(
+        &self,
+        mut interpreter: Interpreter<(), V>,
+        state: Checkpoint,
+        file: &str,
+        checkpoint: &str,
+        every: Duration,
+    ) -> Result<i64> {
+        interpreter.restore(state)
+            .with_context(|| format!("Cannot resume {} from {}", file, checkpoint))?;
+        
+        let _span = self.span("run");
+        run_checkpointed(&mut interpreter, checkpoint, every)
+    }
+
+    fn pipe(&self, producer: &str, consumer: &str, capacity: usize, quantum: u64) -> Result<()> {
+        println!("🔗 Piping {} into {}", producer, consumer);
+        
+        // The channel is what this command is for, so it is granted here
+        let mut sandbox = self.sandbox()?;
+        sandbox.grant(Capability::Channels);
+        let interpreter = |file: &str| -> Result<Interpreter> {
+            let validated = self.load(file)?
+                .validated(&sandbox)
+                .with_context(|| format!("Error validating {}", file))?;
+            Ok(Interpreter::from_validated(validated).with_store(self.store()))
+        };
+        
+        let channel = VmChannel::new(capacity);
+        let mut scheduler = Scheduler::new(quantum);
+        let sending = scheduler.spawn(interpreter(producer)?.with_channel_sender(channel.sender()));
+        scheduler.spawn(interpreter(consumer)?.with_channel_receiver(channel.receiver()));
+        
+        let _span = self.span("run");
+        for finished in scheduler.run() {
+            let file = if finished.id == sending { producer } else { consumer };
+            let result = finished.outcome
+                .map_err(|e| anyhow::anyhow!("{}: Execution error: {:?}", file, e))?;
+            if finished.id != sending {
+                println!("📊 Result: {}", result);
+            }
+        }
+        Ok(())
+    }
+
+    fn disassemble(
+        &self,
+        file: &str,
+        options: JitOptions,
+        raw: bool,
+        range: Option<Range<usize>>,
+        dump_native_map: bool,
+    ) -> Result<()> {
         println!("📖 Disassembly for: {}", file);
         
-        let program = Parser::parse_file(file)
-            .with_context(|| format!("Error parsing file: {}", file))?;
+        let program = self.load(file)?;
+        let count = program.instructions.len();
         
-        println!("\n📋 Bytecode instructions:");
-        for (idx, instruction) in program.instructions.iter().enumerate() {
-            println!("  {:04}: {:?}", idx, instruction);
+        if let Some(range) = &range {
+            if range.start > range.end || range.end > count {
+                anyhow::bail!(
+                    "Invalid range {}..{}: program has {} instructions",
+                    range.start,
+                    range.end,
+                    count
+                );
+            }
         }
         
+        println!("\n📋 Bytecode instructions:");
+        print!("{}", disassemble_range(&program, raw, range.clone().unwrap_or(0..count)));
+        
         println!("\n🔧 Generating machine code...");
-        let mut compiler = JitCompiler::new(program);
-        let memory = compiler.compile()
+        let mut compiler = JitCompiler::new(program)
+            .with_options(options)
+            .with_sandbox(self.sandbox()?);
+        let compiled = compiler.compile()
             .context("Error during JIT compilation")?;
         
-        println!("\n💾 Generated machine code ({} bytes):", memory.size());
-        unsafe {
-            let ptr = memory.as_ptr();
-            for i in 0..memory.size().min(256) {
-                if i % 16 == 0 {
-                    print!("\n  {:04X}: ", i);
+        let code = compiled.code();
+        
+        if dump_native_map {
+            println!("\n🗺️  Native offsets:");
+            for &(pc, offset) in &compiled.metadata().line_map {
+                if range.as_ref().is_none_or(|range| range.contains(&pc)) {
+                    println!("  {:>5} → {:04X}", pc, offset);
                 }
-                print!("{:02X} ", *ptr.add(i));
             }
-            println!();
         }
         
+        // Whole listing is capped; a range shows exactly its instructions' bytes
+        let native = match range {
+            Some(range) => {
+                let line_map = &compiled.metadata().line_map;
+                let offset = |pc: usize| line_map.get(pc).map_or(code.len(), |&(_, offset)| offset);
+                offset(range.start)..offset(range.end)
+            }
+            None => 0..code.len().min(256),
+        };
+        
+        println!("\n💾 Generated machine code ({} bytes):", code.len());
+        for (i, byte) in code[native.clone()].iter().enumerate() {
+            if i % 16 == 0 {
+                print!("\n  {:04X}: ", native.start + i);
+            }
+            print!("{:02X} ", byte);
+        }
+        println!();
+        
+        Ok(())
+    }
+
+    fn stats(&self, file: &str, cost: bool) -> Result<()> {
+        println!("📈 Statistics for: {}", file);
+        
+        let program = self.load(file)?;
+        
+        let complexity = program.complexity();
+        println!("  Instructions: {}", complexity.instructions);
+        println!("  Loop depth:   {}", complexity.max_loop_depth);
+        println!("  Native calls: {}", complexity.native_calls);
+        println!("  Hash:         {:016x}", program.semantic_hash());
+        if program.memory_inferred {
+            println!("  Memory size:  {} (inferred)", program.memory_size);
+        } else {
+            println!(
+                "  Memory size:  {} (declared, {} required)",
+                program.memory_size,
+                program.required_memory()
+            );
+        }
+        
+        if cost {
+            let estimate = estimate_cost(&program, &CostModel::default());
+            let format = |bound: Option<u64>| match bound {
+                Some(value) => value.to_string(),
+                None => "unbounded".to_string(),
+            };
+            println!("\n💰 Estimated cost:");
+            println!("  Minimum: {}", format(estimate.min));
+            println!("  Maximum: {}", format(estimate.max));
+            for header in &estimate.unbounded_loops {
+                println!("  ⚠️  Unbounded loop at instruction {}", header);
+            }
+        }
+        
+        Ok(())
+    }
+
+    fn format(&self, file: &str, in_place: bool) -> Result<()> {
+        let source = fs::read_to_string(file)
+            .with_context(|| format!("Cannot read file: {}", file))?;
+        let formatted = Parser::rewrite_deprecated(&source);
+        
+        if in_place {
+            replace_file(file, formatted.as_bytes(), "file")?;
+        } else {
+            print!("{}", formatted);
+        }
+        Ok(())
+    }
+
+    fn convert(&self, file: &str, from: Option<Format>, to: Format, output: Option<&str>) -> Result<()> {
+        if let Some(path) = output {
+            self.check_output(path, "Output")?;
+        }
+        let input = fs::read(file)
+            .with_context(|| format!("Cannot read file: {}", file))?;
+        let from = from.unwrap_or_else(|| Format::from_path(Path::new(file)));
+        let program = from.codec().decode(&input)
+            .with_context(|| format!("Error reading file: {}", file))?;
+        let encoded = to.codec().encode(&program);
+        
+        match output {
+            Some(path) => replace_file(path, &encoded, "output")?,
+            None => io::stdout().write_all(&encoded)?,
+        }
+        Ok(())
+    }
+
+    fn examples(&self, dir: &str, test: bool) -> Result<()> {
+        let mut files: Vec<_> = fs::read_dir(dir)
+            .with_context(|| format!("Cannot read directory: {}", dir))?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "cinder"))
+            .collect();
+        files.sort();
+        
+        if !test {
+            for path in &files {
+                let source = fs::read_to_string(path)?;
+                let expected = Expectation::parse(&source)
+                    .map_or_else(|| "no expectation".to_string(), |e| e.describe());
+                println!("  {:<24} {}", display_name(path), expected);
+            }
+            return Ok(());
+        }
+        
+        // Keep native output from interleaving with the results table
+        natives::set_output(Box::new(io::sink()));
+        
+        println!("  {:<24} {:<12} {:<12}", "example", "interpreter", "jit");
+        let mut failures = 0;
+        let mut checks = 0;
+        for path in &files {
+            let source = fs::read_to_string(path)?;
+            let Some(expected) = Expectation::parse(&source) else {
+                println!("  {:<24} ⚠️  missing `# expect:` header", display_name(path));
+                failures += 1;
+                continue;
+            };
+            
+            let program = path.to_str().and_then(|path| Parser::parse_file(path).ok());
+            let mut cells = Vec::new();
+            for backend in [Backend::Interpreter, Backend::Jit] {
+                if !expected.backends.contains(&backend) {
+                    cells.push("-".to_string());
+                    continue;
+                }
+                let actual = match &program {
+                    Some(program) => program.run_catching(backend).map_err(|e| error_kind(&e)),
+                    None => Err("ParseError".to_string()),
+                };
+                checks += 1;
+                if actual == expected.outcome {
+                    cells.push("✅".to_string());
+                } else {
+                    failures += 1;
+                    cells.push(match actual {
+                        Ok(value) => format!("❌ {}", value),
+                        Err(kind) => format!("❌ {}", kind),
+                    });
+                }
+            }
+            println!("  {:<24} {:<12} {:<12}", display_name(path), cells[0], cells[1]);
+        }
+        
+        natives::reset_output();
+        
+        if failures > 0 {
+            anyhow::bail!("{} of {} example checks failed", failures, checks);
+        }
+        println!("\n✅ {} checks passed", checks);
+        Ok(())
+    }
+
+    fn list_natives(&self) -> Result<()> {
+        println!("🔒 Effective native whitelist:");
+        for id in self.sandbox()?.allowed_natives() {
+            match natives::lookup(id) {
+                Some(native) => println!("  {:>4}  {}", id, native.name),
+                None => println!("  {:>4}  (no implementation)", id),
+            }
+        }
         Ok(())
     }
+
+    fn list_opcodes(&self) {
+        for mnemonic in MNEMONICS.iter().filter(|m| !m.deprecated) {
+            let aliases: Vec<&str> = MNEMONICS
+                .iter()
+                .filter(|alias| alias.opcode == mnemonic.opcode && alias.deprecated)
+                .map(|alias| alias.name)
+                .collect();
+            
+            print!("  0x{:02X}  {}", mnemonic.opcode as u8, mnemonic.name);
+            if !aliases.is_empty() {
+                print!("  (deprecated: {})", aliases.join(", "));
+            }
+            println!();
+        }
+    }
+}
+
+/// A native given by ID or by name
+fn native_id(native: &str) -> Result<u32> {
+    if let Ok(id) = native.parse() {
+        return Ok(id);
+    }
+    natives::lookup_name(native)
+        .map(|native| native.id)
+        .ok_or_else(|| anyhow::anyhow!("Unknown native function: {}", native))
+}
+
+/// Parse a `--grant` value
+fn capability(name: &str) -> Result<Capability, String> {
+    Capability::ALL
+        .into_iter()
+        .find(|capability| capability.name() == name)
+        .ok_or_else(|| format!("unknown capability '{}' (expected storage or channels)", name))
+}
+
+/// `--kv-file` store: a JSON object from decimal keys to integers, such as
+/// `{"1": 42}`. Clones share the entries, so the CLI keeps one to save
+/// after the natives have used the other.
+#[derive(Clone)]
+struct KvFile {
+    path: String,
+    entries: Arc<Mutex<BTreeMap<i64, i64>>>,
+}
+
+impl KvFile {
+    /// Read the store, or start an empty one if the file doesn't exist yet
+    fn load(path: &str) -> Result<Self> {
+        let entries = match fs::read_to_string(path) {
+            Ok(text) => parse_kv_json(&text).with_context(|| {
+                format!("Invalid key-value file {}: expected a JSON object of integer keys and values", path)
+            })?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(error) => return Err(error).with_context(|| format!("Cannot read key-value file: {}", path)),
+        };
+        Ok(Self { path: path.to_string(), entries: Arc::new(Mutex::new(entries)) })
+    }
+
+    /// Write the entries back, replacing the file whole
+    fn save(&self) -> Result<()> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let fields: Vec<String> = entries.iter().map(|(key, value)| format!("  \"{}\": {}", key, value)).collect();
+        let text = if fields.is_empty() {
+            "{}\n".to_string()
+        } else {
+            format!("{{\n{}\n}}\n", fields.join(",\n"))
+        };
+        replace_file(&self.path, text.as_bytes(), "key-value file")
+    }
+}
+
+impl KeyValueStore for KvFile {
+    fn get(&mut self, key: i64) -> Option<i64> {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).get(&key).copied()
+    }
+
+    fn put(&mut self, key: i64, value: i64) {
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).insert(key, value);
+    }
+
+    /// The file is read once before the run, so it can't change under it
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+}
+
+/// Parse the flat JSON object `KvFile::save` writes. Keys and values are
+/// integers, so neither can contain `,` or `:`.
+fn parse_kv_json(text: &str) -> Option<BTreeMap<i64, i64>> {
+    let body = text.trim().strip_prefix('{')?.strip_suffix('}')?.trim();
+    if body.is_empty() {
+        return Some(BTreeMap::new());
+    }
+    body.split(',')
+        .map(|field| {
+            let (key, value) = field.split_once(':')?;
+            let key = key.trim().strip_prefix('"')?.strip_suffix('"')?.parse().ok()?;
+            Some((key, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Interpreter settings chosen by `debug` flags
+struct DebugOptions {
+    check_types: bool,
+    sparse_memory: bool,
+    strict_memory: bool,
+    /// Compute with `i32` values instead of `i64`
+    i32: bool,
+    detect_loops: bool,
+    /// Save the state here every `checkpoint_every` while running
+    checkpoint_file: Option<String>,
+    checkpoint_every: Duration,
+}
+
+/// Outcome an example declares with `# expect: <value>` or
+/// `# expect-error: <kind>`, and the backends it runs on (`# backends:`)
+struct Expectation {
+    outcome: std::result::Result<i64, String>,
+    backends: Vec<Backend>,
 }
 
+impl Expectation {
+    fn parse(source: &str) -> Option<Self> {
+        let mut outcome = None;
+        let mut backends = vec![Backend::Interpreter, Backend::Jit];
+        for line in source.lines() {
+            let Some(comment) = line.trim().strip_prefix('#') else {
+                continue;
+            };
+            let comment = comment.trim();
+            if let Some(value) = comment.strip_prefix("expect:") {
+                outcome = Some(Ok(value.trim().parse().ok()?));
+            } else if let Some(kind) = comment.strip_prefix("expect-error:") {
+                outcome = Some(Err(kind.trim().to_string()));
+            } else if let Some(names) = comment.strip_prefix("backends:") {
+                backends = names
+                    .split_whitespace()
+                    .map(|name| match name {
+                        "interpreter" => Some(Backend::Interpreter),
+                        "jit" => Some(Backend::Jit),
+                        _ => None,
+                    })
+                    .collect::<Option<_>>()?;
+            }
+        }
+        Some(Self { outcome: outcome?, backends })
+    }
+
+    fn describe(&self) -> String {
+        match &self.outcome {
+            Ok(value) => value.to_string(),
+            Err(kind) => format!("error {}", kind),
+        }
+    }
+}
+
+/// Name matched by `# expect-error:`: the variant, e.g. `DivisionByZero` or `Rejected`
+fn error_kind(error: &ExecError) -> String {
+    let text = match error {
+        ExecError::Interpreter(error) => format!("{:?}", error),
+        other => format!("{:?}", other),
+    };
+    text.split(|c: char| !c.is_alphanumeric()).next().unwrap_or_default().to_string()
+}
+
+/// `error` as a diagnostic in the shape of rustc's JSON output; parse
+/// errors carry the line and column of the offending line
+fn diagnostic_json(error: &anyhow::Error) -> String {
+    let (message, spans) = match error.downcast_ref::<CinderError>() {
+        Some(CinderError::Parse(parse)) => (
+            parse.message.clone(),
+            format!("{{\"line\":{},\"column\":{}}}", parse.line, parse.column),
+        ),
+        Some(CinderError::DuplicateLabel(duplicate)) => (
+            format!("duplicate label '{}' (first defined on line {})", duplicate.name, duplicate.first_line),
+            format!(
+                "{{\"line\":{},\"column\":1}},{{\"line\":{},\"column\":1}}",
+                duplicate.second_line, duplicate.first_line
+            ),
+        ),
+        _ => (format!("{:#}", error), String::new()),
+    };
+    format!(
+        "{{\"level\":\"error\",\"message\":\"{}\",\"spans\":[{}]}}",
+        trace::escape(&message),
+        spans
+    )
+}
+
+/// Print the result common to every run, or each distinct result with
+/// how many runs returned it
+fn report_results(results: &[i64]) -> Result<()> {
+    let mut counts = BTreeMap::new();
+    for &result in results {
+        *counts.entry(result).or_insert(0) += 1;
+    }
+    
+    match counts.keys().collect::<Vec<_>>().as_slice() {
+        [result] if results.len() == 1 => println!("📊 Result: {}", result),
+        [result] => println!("📊 Result: {} (identical across {} runs)", result, results.len()),
+        _ => {
+            println!("❌ Results diverged across {} runs:", results.len());
+            for (result, runs) in &counts {
+                println!("   {}  ({} run(s))", result, runs);
+            }
+            anyhow::bail!("Program is not deterministic");
+        }
+    }
+    Ok(())
+}
+
+/// File name of an example, for tables
+fn display_name(path: &Path) -> String {
+    path.file_name().map_or_else(String::new, |name| name.to_string_lossy().into_owned())
+}
+
+/// Instructions run between checks of the checkpoint timer
+const CHECKPOINT_SLICE: u64 = 1 << 16;
+
+/// Run to completion, saving a checkpoint to `path` every `every`
+fn run_checkpointed<V: VmInt>(interpreter: &mut Interpreter<(), V>, path: &str, every: Duration) -> Result<i64> {
+    let mut last = Instant::now();
+    loop {
+        let state = interpreter.run_for(CHECKPOINT_SLICE)
+            .map_err(|e| anyhow::anyhow!("Execution error: {:?}", e))?;
+        match state {
+            RunState::Halted(result) => return Ok(result),
+            // Nothing else runs, so a wait on a channel never ends
+            RunState::Blocked => anyhow::bail!("Execution error: {:?}", InterpreterError::Deadlock),
+            RunState::Yielded => {}
+        }
+        if last.elapsed() >= every {
+            write_checkpoint(path, &interpreter.checkpoint())?;
+            last = Instant::now();
+        }
+    }
+}
+
+/// Save a checkpoint with `replace_file`
+fn write_checkpoint(path: &str, checkpoint: &Checkpoint) -> Result<()> {
+    replace_file(path, &checkpoint.to_bytes(), "checkpoint")
+}
+
+/// Write `what` to `path` with `artifact::replace_file`, so being killed
+/// mid-write leaves the previous file intact
+fn replace_file(path: &str, bytes: &[u8], what: &str) -> Result<()> {
+    artifact::replace_file(path, bytes).with_context(|| format!("Cannot write {}: {}", what, path))
+}
+
+/// Read a native call log written by `debug --record`
+fn read_native_log(path: &str) -> Result<Vec<NativeCall>> {
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Cannot read native log: {}", path))?;
+    
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(number, line)| {
+            NativeCall::parse(line)
+                .ok_or_else(|| anyhow::anyhow!("{}:{}: invalid native call: {}", path, number + 1, line))
+        })
+        .collect()
+}
+
+#[cfg(all(feature = "perf", target_os = "linux"))]
+fn profile_run(compiled: &CompiledProgram, args: &[i64]) -> Result<()> {
+    let (result, counters) = cindervm::jit::perf::measure(|| compiled.run_with_args(args))
+        .context("Failed to open perf counters (check /proc/sys/kernel/perf_event_paranoid)")?;
+    
+    println!("📊 Result: {}", result);
+    println!("⏱️  Cycles: {}", counters.cycles);
+    println!("⏱️  Instructions: {}", counters.instructions);
+    println!("⏱️  IPC: {:.2}", counters.ipc());
+    Ok(())
+}
+
+#[cfg(not(all(feature = "perf", target_os = "linux")))]
+fn profile_run(_compiled: &CompiledProgram, _args: &[i64]) -> Result<()> {
+    anyhow::bail!("--profile-jit requires Linux and a build with the `perf` feature")
+}
@@ -0,0 +1,311 @@
+use crate::bytecode::{Instruction, OpCode, Program};
+use crate::natives;
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BinaryHeap, HashMap};
+
+/// Default cost of each `kv_get` and `kv_put` call, which reach host storage
+pub const STORAGE_WEIGHT: u64 = 50;
+
+/// Per-opcode cost weights used for static cost estimation, with
+/// per-native overrides for `CALL_NATIVE`
+#[derive(Debug, Clone)]
+pub struct CostModel {
+    default_weight: u64,
+    weights: HashMap<OpCode, u64>,
+    native_weights: HashMap<u32, u64>,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        Self {
+            default_weight: 1,
+            weights: HashMap::new(),
+            native_weights: HashMap::from([
+                (natives::KV_GET, STORAGE_WEIGHT),
+                (natives::KV_PUT, STORAGE_WEIGHT),
+            ]),
+        }
+    }
+}
+
+impl CostModel {
+    /// Override the cost of a single opcode
+    pub fn with_weight(mut self, opcode: OpCode, weight: u64) -> Self {
+        self.weights.insert(opcode, weight);
+        self
+    }
+
+    /// Override the cost of calling one native
+    pub fn with_native_weight(mut self, id: u32, weight: u64) -> Self {
+        self.native_weights.insert(id, weight);
+        self
+    }
+
+    pub fn weight(&self, opcode: OpCode) -> u64 {
+        self.weights.get(&opcode).copied().unwrap_or(self.default_weight)
+    }
+
+    /// Cost of one instruction: its native's weight for `CALL_NATIVE` if
+    /// one is set, otherwise its opcode's
+    pub fn instruction_weight(&self, instruction: &Instruction) -> u64 {
+        match instruction {
+            Instruction::CallNative(id) => self
+                .native_weights
+                .get(id)
+                .copied()
+                .unwrap_or_else(|| self.weight(OpCode::CallNative)),
+            _ => self.weight(instruction.opcode()),
+        }
+    }
+}
+
+/// Static estimate of a program's execution cost
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CostEstimate {
+    /// Cost of the cheapest path from entry to termination, or `None` if it never terminates
+    pub min: Option<u64>,
+    /// Cost of the most expensive path, or `None` if a loop makes it unbounded
+    pub max: Option<u64>,
+    /// Loop headers (targets of back edges) whose trip count is unknown
+    pub unbounded_loops: Vec<usize>,
+}
+
+/// Size metrics for rejecting overly complex programs before running them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Complexity {
+    pub instructions: usize,
+    /// Deepest nesting of loops reachable from the entry; 0 without loops
+    pub max_loop_depth: usize,
+    /// `CALL_NATIVE` instructions, reachable or not
+    pub native_calls: usize,
+}
+
+/// Metrics behind `Program::complexity`
+pub fn complexity(program: &Program) -> Complexity {
+    let successors = successor_lists(program);
+    Complexity {
+        instructions: program.instructions.len(),
+        max_loop_depth: max_loop_depth(&successors),
+        native_calls: program
+            .instructions
+            .iter()
+            .filter(|instruction| matches!(instruction, Instruction::CallNative(_)))
+            .count(),
+    }
+}
+
+fn successor_lists(program: &Program) -> Vec<Vec<usize>> {
+    let len = program.instructions.len();
+    program
+        .instructions
+        .iter()
+        .enumerate()
+        .map(|(pc, instruction)| instruction.successors(pc, len))
+        .collect()
+}
+
+/// Estimate the cost range of `program` under `model` without running it
+pub fn estimate_cost(program: &Program, model: &CostModel) -> CostEstimate {
+    let len = program.instructions.len();
+    if len == 0 {
+        return CostEstimate {
+            min: Some(0),
+            max: Some(0),
+            unbounded_loops: Vec::new(),
+        };
+    }
+
+    let cost: Vec<u64> = program
+        .instructions
+        .iter()
+        .map(|instruction| model.instruction_weight(instruction))
+        .collect();
+    let successors = successor_lists(program);
+
+    let unbounded_loops = find_loop_headers(&successors);
+    let max = if unbounded_loops.is_empty() {
+        Some(longest_path(&successors, &cost))
+    } else {
+        None
+    };
+
+    CostEstimate {
+        min: shortest_path(&successors, &cost),
+        max,
+        unbounded_loops,
+    }
+}
+
+/// Targets of back edges found by a depth-first walk from the entry
+fn find_loop_headers(successors: &[Vec<usize>]) -> Vec<usize> {
+    let mut headers: Vec<usize> = find_back_edges(successors).into_iter().map(|(_, header)| header).collect();
+    headers.sort_unstable();
+    headers.dedup();
+    headers
+}
+
+/// Most loops any one instruction sits in. Each loop header's body is the
+/// header plus everything that reaches one of its back edges without
+/// passing through the header.
+fn max_loop_depth(successors: &[Vec<usize>]) -> usize {
+    let mut tails: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for (tail, header) in find_back_edges(successors) {
+        tails.entry(header).or_default().push(tail);
+    }
+
+    let mut predecessors = vec![Vec::new(); successors.len()];
+    for (node, succs) in successors.iter().enumerate() {
+        for &succ in succs {
+            predecessors[succ].push(node);
+        }
+    }
+
+    let mut depth = vec![0; successors.len()];
+    for (header, tails) in tails {
+        let mut in_body = vec![false; successors.len()];
+        in_body[header] = true;
+        let mut worklist = tails;
+        while let Some(node) = worklist.pop() {
+            if !in_body[node] {
+                in_body[node] = true;
+                worklist.extend(&predecessors[node]);
+            }
+        }
+        for (node, inside) in in_body.iter().enumerate() {
+            if *inside {
+                depth[node] += 1;
+            }
+        }
+    }
+    depth.into_iter().max().unwrap_or(0)
+}
+
+/// `(tail, header)` edges closing a cycle in a depth-first walk from the entry
+fn find_back_edges(successors: &[Vec<usize>]) -> Vec<(usize, usize)> {
+    if successors.is_empty() {
+        return Vec::new();
+    }
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum State { Unvisited, OnStack, Done }
+
+    let mut state = vec![State::Unvisited; successors.len()];
+    let mut edges = Vec::new();
+    // (node, index of next successor to visit)
+    let mut stack = vec![(0usize, 0usize)];
+    state[0] = State::OnStack;
+
+    while let Some((node, next)) = stack.last_mut() {
+        let node = *node;
+        if let Some(&succ) = successors[node].get(*next) {
+            *next += 1;
+            match state[succ] {
+                State::Unvisited => {
+                    state[succ] = State::OnStack;
+                    stack.push((succ, 0));
+                }
+                State::OnStack => edges.push((node, succ)),
+                State::Done => {}
+            }
+        } else {
+            state[node] = State::Done;
+            stack.pop();
+        }
+    }
+
+    edges
+}
+
+/// Cheapest cost from the entry to any terminating instruction (Dijkstra)
+fn shortest_path(successors: &[Vec<usize>], cost: &[u64]) -> Option<u64> {
+    let mut best = vec![u64::MAX; successors.len()];
+    let mut heap = BinaryHeap::new();
+    best[0] = cost[0];
+    heap.push(Reverse((cost[0], 0usize)));
+
+    while let Some(Reverse((total, node))) = heap.pop() {
+        if total > best[node] {
+            continue;
+        }
+        // Instructions without successors end execution (or fall off the end)
+        if successors[node].is_empty() {
+            return Some(total);
+        }
+        for &succ in &successors[node] {
+            let next = total + cost[succ];
+            if next < best[succ] {
+                best[succ] = next;
+                heap.push(Reverse((next, succ)));
+            }
+        }
+    }
+
+    // No terminating path: the program always loops
+    None
+}
+
+/// Most expensive cost from the entry to termination, for acyclic programs
+fn longest_path(successors: &[Vec<usize>], cost: &[u64]) -> u64 {
+    let mut memo: Vec<Option<u64>> = vec![None; successors.len()];
+    // Iterative post-order so long programs don't overflow the Rust stack
+    let mut stack = vec![(0usize, false)];
+
+    while let Some((node, expanded)) = stack.pop() {
+        if memo[node].is_some() {
+            continue;
+        }
+        if expanded {
+            let tail = successors[node]
+                .iter()
+                .filter_map(|&succ| memo[succ])
+                .max()
+                .unwrap_or(0);
+            memo[node] = Some(cost[node] + tail);
+        } else {
+            stack.push((node, true));
+            for &succ in &successors[node] {
+                if memo[succ].is_none() {
+                    stack.push((succ, false));
+                }
+            }
+        }
+    }
+
+    memo[0].unwrap_or(0)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cinder_program;
+
+    #[test]
+    fn storage_natives_cost_more_than_other_natives() {
+        let program = cinder_program!(.memory 0; push_int 1; call_native 0x10; push_int 2; call_native 1; return);
+        let model = CostModel::default();
+        assert_eq!(estimate_cost(&program, &model).max, Some(4 + STORAGE_WEIGHT));
+
+        let model = model.with_native_weight(natives::KV_GET, 2).with_weight(OpCode::CallNative, 3);
+        assert_eq!(estimate_cost(&program, &model).max, Some(3 + 2 + 3));
+    }
+
+    #[test]
+    fn complexity_of_nested_loops() {
+        // A loop nested in another, then a sibling loop, which adds no depth
+        let program = crate::parser::Parser::parse(
+            "PUSH_INT 3\nSTORE 0\n\
+             outer:\nPUSH_INT 4\nSTORE 1\n\
+             inner:\nLOAD 1\nCALL_NATIVE print_int\nMEM_ADD 1 -1\nLOAD 1\nJNZ inner\n\
+             MEM_ADD 0 -1\nLOAD 0\nJNZ outer\n\
+             PUSH_INT 2\nSTORE 0\n\
+             again:\nMEM_ADD 0 -1\nLOAD 0\nJNZ again\n\
+             PUSH_INT 7\nCALL_NATIVE print_int\nPUSH_INT 0\nRETURN\n",
+        )
+        .unwrap();
+        assert_eq!(
+            program.complexity(),
+            Complexity { instructions: 21, max_loop_depth: 2, native_calls: 2 }
+        );
+    }
+}
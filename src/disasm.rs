@@ -0,0 +1,123 @@
+use crate::bytecode::{Instruction, Program};
+use crate::natives;
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::ops::Range;
+
+/// Render `program` as `.cinder` source that parses back to the same program.
+///
+/// Unless `raw` is set, every jump target gets a synthetic label `L<index>`
+/// (so numbering only depends on the program), and natives and `.var`
+/// slots are named.
+pub fn disassemble(program: &Program, raw: bool) -> String {
+    disassemble_range(program, raw, 0..program.instructions.len())
+}
+
+/// Like `disassemble`, listing only the instructions in `range` (and the
+/// labels defined inside it). Directives are always included.
+pub fn disassemble_range(program: &Program, raw: bool, range: Range<usize>) -> String {
+    let labels = if raw { BTreeSet::new() } else { jump_targets(program) };
+    let target = |index: u32| -> String {
+        if labels.contains(&index) {
+            format!("L{}", index)
+        } else {
+            index.to_string()
+        }
+    };
+
+    let slot = |offset: u32| -> String {
+        match program.slot(offset) {
+            Some(slot) if !raw => slot.name.clone(),
+            _ => offset.to_string(),
+        }
+    };
+
+    let mut out = String::new();
+    if !program.memory_inferred {
+        let _ = writeln!(out, ".memory {}", program.memory_size);
+    }
+    if program.args > 0 {
+        let _ = writeln!(out, ".args {}", program.args);
+    }
+    if let Some(returns) = program.returns {
+        let _ = writeln!(out, ".returns {}", returns);
+    }
+    for slot in &program.slots {
+        let _ = writeln!(out, ".var {}: {}", slot.name, slot.ty.name());
+    }
+    for block in &program.data {
+        let values: Vec<String> = block.values.iter().map(i64::to_string).collect();
+        let _ = writeln!(out, ".data {} {}", block.offset, values.join(" "));
+    }
+
+    for (index, instruction) in program.instructions.iter().enumerate() {
+        if !range.contains(&index) {
+            continue;
+        }
+        if labels.contains(&(index as u32)) {
+            let _ = writeln!(out, "L{}:", index);
+        }
+
+        let operands = match instruction {
+            Instruction::PushInt(val) => val.to_string(),
+            Instruction::PushReg(reg) => reg.to_string(),
+            Instruction::ShlImm(count) | Instruction::ShrImm(count) | Instruction::SarImm(count) => {
+                count.to_string()
+            }
+            Instruction::Jump(t) | Instruction::JumpIfZero(t) | Instruction::JumpIfNotZero(t) => {
+                target(*t)
+            }
+            Instruction::JumpTable(table) => {
+                let mut text = format!("{} {}", table.base, target(table.default));
+                for t in &table.targets {
+                    let _ = write!(text, " {}", target(*t));
+                }
+                text
+            }
+            Instruction::Load(offset) | Instruction::Store(offset) => slot(*offset),
+            Instruction::MemAdd(offset, imm) => format!("{} {}", slot(*offset), imm),
+            Instruction::CallNative(id) => match natives::lookup(*id) {
+                Some(native) if !raw => native.name.to_string(),
+                _ => id.to_string(),
+            },
+            Instruction::Trap(code) => code.to_string(),
+            _ => String::new(),
+        };
+
+        let mnemonic = instruction.opcode().mnemonic();
+        if operands.is_empty() {
+            let _ = writeln!(out, "    {}", mnemonic);
+        } else {
+            let _ = writeln!(out, "    {} {}", mnemonic, operands);
+        }
+    }
+
+    // A label past the last instruction still has to be defined
+    let end = program.instructions.len() as u32;
+    if range.end == end as usize && labels.contains(&end) {
+        let _ = writeln!(out, "L{}:", end);
+    }
+
+    out
+}
+
+/// In-range instruction indices referenced by any jump, in ascending order
+fn jump_targets(program: &Program) -> BTreeSet<u32> {
+    let mut targets = BTreeSet::new();
+    for instruction in &program.instructions {
+        match instruction {
+            Instruction::Jump(t) | Instruction::JumpIfZero(t) | Instruction::JumpIfNotZero(t) => {
+                targets.insert(*t);
+            }
+            Instruction::JumpTable(table) => {
+                targets.insert(table.default);
+                targets.extend(table.targets.iter().copied());
+            }
+            _ => {}
+        }
+    }
+    // Out-of-range targets stay numeric so validation still reports them
+    let end = program.instructions.len() as u32;
+    targets.retain(|&t| t <= end);
+    targets
+}
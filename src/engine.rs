@@ -0,0 +1,891 @@
+//! Running many programs concurrently with bounded resources.
+//!
+//! `Engine::executor` starts a fixed pool of worker threads fed by one
+//! bounded queue. A full queue either rejects a submission or makes it
+//! wait, per `Backpressure`. Queued requests are grouped by tenant, a key
+//! the caller chooses. Workers serve the tenants in turn, one request
+//! each, so a tenant with thousands of queued programs delays another
+//! tenant's request by at most one of its own. Within a tenant, higher
+//! `Priority` requests go first, then the oldest.
+//!
+//! `Engine::reconfigure` switches tracing, storage auditing, profiling and
+//! log verbosity while programs run. The settings live in one atomic word
+//! that each run reads once when it starts, so a run never mixes old and
+//! new settings and the hot path takes no lock.
+//!
+//! `Engine::with_memo` caches the results of pure programs, ones with no
+//! native calls and no `READ_CYCLES`, so repeated runs are served without
+//! executing them.
+
+use crate::bytecode::{Instruction, Program};
+use crate::error::CinderError;
+use crate::interpreter::Interpreter;
+use crate::natives::KvAccess;
+use crate::sandbox::Sandbox;
+use crate::trace::ChromeTrace;
+use std::cell::Cell;
+use std::cmp::Ordering;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{self, AtomicU32};
+use std::sync::{mpsc, Arc, Condvar, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Runs programs under one sandbox policy
+#[derive(Clone)]
+pub struct Engine {
+    sandbox: Sandbox,
+    /// `DiagnosticsConfig::pack`ed; shared by every clone of the engine
+    diagnostics: Arc<AtomicU32>,
+    sink: Arc<dyn Diagnostics>,
+    trace: Option<Arc<ChromeTrace>>,
+    memo: Option<Arc<Memo>>,
+}
+
+impl Default for Engine {
+    fn default() -> Self {
+        Self::new(Sandbox::default())
+    }
+}
+
+impl fmt::Debug for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Engine")
+            .field("sandbox", &self.sandbox)
+            .field("diagnostics", &self.diagnostics())
+            .field("memo", &self.memo_stats())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Engine {
+    pub fn new(sandbox: Sandbox) -> Self {
+        Self {
+            sandbox,
+            diagnostics: Arc::new(AtomicU32::new(DiagnosticsConfig::default().pack())),
+            sink: Arc::new(NoDiagnostics),
+            trace: None,
+            memo: None,
+        }
+    }
+
+    /// Send log messages and run reports to `sink`
+    pub fn with_diagnostics(mut self, sink: Arc<dyn Diagnostics>) -> Self {
+        self.sink = sink;
+        self
+    }
+
+    /// Record a span per run in `trace` while `DiagnosticsConfig::trace` is set
+    pub fn with_trace(mut self, trace: Arc<ChromeTrace>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    /// Remember the results of up to `capacity` pure programs, shared by
+    /// this engine's clones and executors, evicting the least recently used
+    ///
+    /// # Panics
+    ///
+    /// If `capacity` is 0, since nothing could be remembered.
+    pub fn with_memo(mut self, capacity: usize) -> Self {
+        assert!(capacity > 0, "memo needs room for one result");
+        self.memo = Some(Arc::new(Memo { capacity, state: Mutex::new(MemoState::default()) }));
+        self
+    }
+
+    /// Hits, misses and entries of the memo, if `with_memo` enabled it
+    pub fn memo_stats(&self) -> Option<MemoStats> {
+        self.memo.as_ref().map(|memo| memo.lock().stats)
+    }
+
+    /// Apply `config` to every run that starts from now on, on this engine,
+    /// its clones and their executors. Runs already started keep the
+    /// settings they started with.
+    pub fn reconfigure(&self, config: DiagnosticsConfig) {
+        self.diagnostics.store(config.pack(), atomic::Ordering::Release);
+    }
+
+    /// Settings the next run will start with
+    pub fn diagnostics(&self) -> DiagnosticsConfig {
+        DiagnosticsConfig::unpack(self.diagnostics.load(atomic::Ordering::Acquire))
+    }
+
+    /// Validate `program` and run it on the interpreter
+    pub fn run(&self, program: &Program) -> Result<i64, CinderError> {
+        // Read once: a reconfigure during the run applies from the next one
+        let config = self.diagnostics();
+        let _span = self.trace.as_ref().filter(|_| config.trace).map(|trace| trace.span("run"));
+        let started = Instant::now();
+        self.log(config, Verbosity::Debug, || format!("running {} instructions", program.instructions.len()));
+
+        let mut report = RunReport { config, elapsed: Duration::ZERO, audit: None, instructions: None };
+        let result = self.execute(program, &mut report);
+        report.elapsed = started.elapsed();
+        match &result {
+            Ok(value) => self.log(config, Verbosity::Info, || format!("returned {}", value)),
+            Err(error) => self.log(config, Verbosity::Error, || format!("failed: {}", error)),
+        }
+        self.sink.finished(&report);
+        result
+    }
+
+    fn execute(&self, program: &Program, report: &mut RunReport) -> Result<i64, CinderError> {
+        // Profiled runs always execute, so their instruction count is real.
+        // The encoded program is the key: runs take no arguments, and two
+        // programs can't share an entry through a hash collision.
+        let memo = self.memo.as_ref().filter(|_| !report.config.profile && is_pure(program));
+        let key = memo.map(|_| program.to_bytes());
+        if let Some(value) = memo.zip(key.as_ref()).and_then(|(memo, key)| memo.get(key)) {
+            // A pure program touches no storage
+            report.audit = report.config.audit.then(Vec::new);
+            return Ok(value);
+        }
+
+        let mut interpreter = Interpreter::from_validated(program.validated(&self.sandbox)?);
+        if report.config.audit {
+            interpreter.store().start_audit();
+        }
+        let executed = Rc::new(Cell::new(0));
+        if report.config.profile {
+            let executed = executed.clone();
+            interpreter.set_on_step(Box::new(move |_, _, _| executed.set(executed.get() + 1)));
+        }
+
+        let result = interpreter.execute();
+        if report.config.audit {
+            report.audit = Some(interpreter.store().take_audit());
+        }
+        if report.config.profile {
+            report.instructions = Some(executed.get());
+        }
+        let value = result?;
+        if let Some((memo, key)) = memo.zip(key) {
+            memo.insert(key, value);
+        }
+        Ok(value)
+    }
+
+    /// Send the message `message` builds if `config` logs `level`
+    fn log(&self, config: DiagnosticsConfig, level: Verbosity, message: impl FnOnce() -> String) {
+        if level <= config.verbosity {
+            self.sink.log(level, &message());
+        }
+    }
+
+    /// Start `config.workers` threads running submitted programs
+    ///
+    /// # Panics
+    ///
+    /// If `config.workers` or `config.queue_capacity` is 0, since nothing
+    /// could run or be queued.
+    pub fn executor(&self, config: ExecutorConfig) -> Executor {
+        assert!(config.workers > 0, "executor needs at least one worker");
+        assert!(config.queue_capacity > 0, "executor queue needs room for one request");
+
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            metrics: config.metrics.clone(),
+        });
+        let workers = (0..config.workers)
+            .map(|_| {
+                let (engine, shared) = (self.clone(), shared.clone());
+                thread::spawn(move || engine.work(&shared))
+            })
+            .collect();
+        Executor { shared, workers, config }
+    }
+
+    /// Worker loop: run queued requests until the executor is dropped and
+    /// the queue is empty
+    fn work(&self, shared: &Shared) {
+        while let Some(job) = shared.take() {
+            // A dropped handle only means nobody wants the result
+            let _ = job.result.send(self.run(&job.program));
+        }
+    }
+}
+
+/// Whether every run of `program` returns the same result: natives may
+/// reach host state, and `READ_CYCLES` reads a clock
+fn is_pure(program: &Program) -> bool {
+    !program
+        .instructions
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::CallNative(_) | Instruction::ReadCycles))
+}
+
+/// Counters of an engine's memo, from `Engine::memo_stats`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MemoStats {
+    /// Runs served from the memo
+    pub hits: u64,
+    /// Runs of pure programs that had to execute
+    pub misses: u64,
+    /// Results currently remembered
+    pub entries: usize,
+}
+
+/// Results of successful pure runs, by encoded program
+#[derive(Debug)]
+struct Memo {
+    capacity: usize,
+    state: Mutex<MemoState>,
+}
+
+#[derive(Debug, Default)]
+struct MemoState {
+    /// Result and the `clock` value of its last use
+    results: HashMap<Vec<u8>, (i64, u64)>,
+    clock: u64,
+    stats: MemoStats,
+}
+
+impl Memo {
+    fn lock(&self) -> MutexGuard<'_, MemoState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn get(&self, key: &[u8]) -> Option<i64> {
+        let mut state = self.lock();
+        state.clock += 1;
+        let clock = state.clock;
+        let value = state.results.get_mut(key).map(|(value, used)| {
+            *used = clock;
+            *value
+        });
+        match value {
+            Some(_) => state.stats.hits += 1,
+            None => state.stats.misses += 1,
+        }
+        value
+    }
+
+    fn insert(&self, key: Vec<u8>, value: i64) {
+        let mut state = self.lock();
+        if state.results.len() >= self.capacity && !state.results.contains_key(&key) {
+            let oldest = state.results.iter().min_by_key(|(_, (_, used))| *used).map(|(key, _)| key.clone());
+            state.results.remove(&oldest.expect("a full memo has entries"));
+        }
+        state.clock += 1;
+        let clock = state.clock;
+        state.results.insert(key, (value, clock));
+        state.stats.entries = state.results.len();
+    }
+}
+
+/// What an `Engine` reports about each run; see `Engine::reconfigure`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DiagnosticsConfig {
+    /// Record a span per run in the engine's `ChromeTrace`, if it has one
+    pub trace: bool,
+    /// Put the run's storage accesses in its `RunReport`
+    pub audit: bool,
+    /// Count the run's executed instructions in its `RunReport`
+    pub profile: bool,
+    /// Most detailed log messages sent to `Diagnostics::log`
+    pub verbosity: Verbosity,
+}
+
+impl DiagnosticsConfig {
+    /// Everything on, at `Verbosity::Debug`
+    pub fn all() -> Self {
+        Self { trace: true, audit: true, profile: true, verbosity: Verbosity::Debug }
+    }
+
+    /// Flags in the low bits, verbosity in the second byte
+    fn pack(self) -> u32 {
+        self.trace as u32 | (self.audit as u32) << 1 | (self.profile as u32) << 2 | (self.verbosity as u32) << 8
+    }
+
+    fn unpack(bits: u32) -> Self {
+        let verbosity = match bits >> 8 {
+            0 => Verbosity::Off,
+            1 => Verbosity::Error,
+            2 => Verbosity::Info,
+            _ => Verbosity::Debug,
+        };
+        Self { trace: bits & 1 != 0, audit: bits & 2 != 0, profile: bits & 4 != 0, verbosity }
+    }
+}
+
+/// Log levels, least detailed first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Verbosity {
+    #[default]
+    Off,
+    /// Runs that failed validation or execution
+    Error,
+    /// The result of every run
+    Info,
+    /// Runs starting
+    Debug,
+}
+
+/// What a finished run measured, per the settings it started with
+#[derive(Debug, Clone, PartialEq)]
+pub struct RunReport {
+    pub config: DiagnosticsConfig,
+    pub elapsed: Duration,
+    /// Storage accesses in order, if `config.audit`
+    pub audit: Option<Vec<KvAccess>>,
+    /// Instructions executed, if `config.profile`
+    pub instructions: Option<u64>,
+}
+
+/// Receives an engine's log messages and run reports; every method
+/// defaults to doing nothing. Called from the thread running the program.
+pub trait Diagnostics: Send + Sync {
+    /// A message at `level`, sent only if the run's verbosity includes it
+    fn log(&self, _level: Verbosity, _message: &str) {}
+    /// A run ended, successfully or not
+    fn finished(&self, _report: &RunReport) {}
+}
+
+/// `Diagnostics` that discards everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoDiagnostics;
+
+impl Diagnostics for NoDiagnostics {}
+
+/// What `Executor::submit` does when the queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Backpressure {
+    /// Fail at once with `SubmitError::QueueFull`
+    Reject,
+    /// Block until there is room, failing with `SubmitError::Timeout`
+    /// after this long
+    Wait(Duration),
+}
+
+/// Order of a tenant's own queued requests; tenants still take turns
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+/// Settings for `Engine::executor`
+#[derive(Clone)]
+pub struct ExecutorConfig {
+    /// Worker threads, each running one program at a time
+    pub workers: usize,
+    /// Requests that can wait for a worker, across all tenants
+    pub queue_capacity: usize,
+    pub backpressure: Backpressure,
+    pub metrics: Arc<dyn Metrics>,
+}
+
+impl Default for ExecutorConfig {
+    fn default() -> Self {
+        Self {
+            workers: thread::available_parallelism().map_or(1, |n| n.get()),
+            queue_capacity: 1024,
+            backpressure: Backpressure::Reject,
+            metrics: Arc::new(NoMetrics),
+        }
+    }
+}
+
+/// Receives executor measurements; every method defaults to doing nothing.
+/// Calls are made with the queue locked, so they should be cheap.
+pub trait Metrics: Send + Sync {
+    /// Requests waiting for a worker, after one was queued or taken
+    fn queue_depth(&self, _depth: usize) {}
+    /// A worker took `tenant`'s request after it had been queued this long
+    fn queue_wait(&self, _tenant: &str, _waited: Duration) {}
+    /// A submission from `tenant` was refused because the queue stayed full
+    fn rejected(&self, _tenant: &str) {}
+}
+
+/// `Metrics` that discards everything
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoMetrics;
+
+impl Metrics for NoMetrics {}
+
+/// Why `Executor::submit` refused a request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum SubmitError {
+    #[error("Executor queue is full")]
+    QueueFull,
+    #[error("Executor queue stayed full for {0:?}")]
+    Timeout(Duration),
+}
+
+/// Result of a submitted program, available once a worker has run it
+#[derive(Debug)]
+pub struct Handle {
+    result: mpsc::Receiver<Result<i64, CinderError>>,
+}
+
+impl Handle {
+    /// Block until the program has run
+    ///
+    /// # Panics
+    ///
+    /// If the worker running the program panicked.
+    pub fn wait(self) -> Result<i64, CinderError> {
+        self.result.recv().expect("executor worker panicked")
+    }
+
+    /// The result if the program has already run, else `self` back
+    pub fn try_wait(self) -> Result<Result<i64, CinderError>, Self> {
+        match self.result.try_recv() {
+            Ok(result) => Ok(result),
+            Err(mpsc::TryRecvError::Empty) => Err(self),
+            Err(mpsc::TryRecvError::Disconnected) => panic!("executor worker panicked"),
+        }
+    }
+}
+
+/// Bounded worker pool started by `Engine::executor`. Dropping it stops
+/// accepting work, lets the workers finish everything already queued and
+/// joins them.
+pub struct Executor {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+    config: ExecutorConfig,
+}
+
+impl Executor {
+    /// Queue `program` for `tenant`, applying the configured backpressure
+    /// if the queue is full
+    pub fn submit(&self, tenant: &str, priority: Priority, program: Program) -> Result<Handle, SubmitError> {
+        let mut queue = self.shared.lock();
+        if queue.len >= self.config.queue_capacity {
+            queue = match self.config.backpressure {
+                Backpressure::Reject => {
+                    self.shared.metrics.rejected(tenant);
+                    return Err(SubmitError::QueueFull);
+                }
+                Backpressure::Wait(timeout) => {
+                    let (queue, waited) = self
+                        .shared
+                        .not_full
+                        .wait_timeout_while(queue, timeout, |queue| queue.len >= self.config.queue_capacity)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                    if waited.timed_out() {
+                        self.shared.metrics.rejected(tenant);
+                        return Err(SubmitError::Timeout(timeout));
+                    }
+                    queue
+                }
+            };
+        }
+
+        let (sender, receiver) = mpsc::channel();
+        queue.push(tenant, Job { priority, sequence: 0, program, queued_at: Instant::now(), result: sender });
+        self.shared.metrics.queue_depth(queue.len);
+        self.shared.not_empty.notify_one();
+        Ok(Handle { result: receiver })
+    }
+
+    /// Requests waiting for a worker
+    pub fn queued(&self) -> usize {
+        self.shared.lock().len
+    }
+}
+
+impl Drop for Executor {
+    fn drop(&mut self) {
+        self.shared.lock().closed = true;
+        self.shared.not_empty.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// State shared by an executor and its workers
+struct Shared {
+    queue: Mutex<Queue>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    metrics: Arc<dyn Metrics>,
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Queue> {
+        // Nothing panics while holding the lock except `Metrics`, and the
+        // queue is consistent between its calls
+        self.queue.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Next request in tenant order, waiting for one; `None` once the
+    /// executor is closed and drained
+    fn take(&self) -> Option<Job> {
+        let mut queue = self.lock();
+        loop {
+            if let Some((tenant, job)) = queue.pop() {
+                self.metrics.queue_wait(&tenant, job.queued_at.elapsed());
+                self.metrics.queue_depth(queue.len);
+                self.not_full.notify_one();
+                return Some(job);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.not_empty.wait(queue).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+/// Queued requests per tenant, and the order tenants are served in
+#[derive(Default)]
+struct Queue {
+    /// Tenants with queued requests; the front one is served next
+    turns: VecDeque<String>,
+    pending: HashMap<String, BinaryHeap<Job>>,
+    /// Requests across all tenants
+    len: usize,
+    /// Submissions so far, so equal priorities keep their order
+    sequence: u64,
+    closed: bool,
+}
+
+impl Queue {
+    fn push(&mut self, tenant: &str, mut job: Job) {
+        job.sequence = self.sequence;
+        self.sequence += 1;
+        let jobs = self.pending.entry(tenant.to_string()).or_default();
+        if jobs.is_empty() {
+            self.turns.push_back(tenant.to_string());
+        }
+        jobs.push(job);
+        self.len += 1;
+    }
+
+    /// The front tenant's best request; the tenant goes to the back of the
+    /// line if it has more
+    fn pop(&mut self) -> Option<(String, Job)> {
+        let tenant = self.turns.pop_front()?;
+        let jobs = self.pending.get_mut(&tenant).expect("every waiting tenant has requests");
+        let job = jobs.pop().expect("every waiting tenant has requests");
+        if jobs.is_empty() {
+            self.pending.remove(&tenant);
+        } else {
+            self.turns.push_back(tenant.clone());
+        }
+        self.len -= 1;
+        Some((tenant, job))
+    }
+}
+
+/// A queued request
+struct Job {
+    priority: Priority,
+    /// Position in submission order, assigned by `Queue::push`
+    sequence: u64,
+    program: Program,
+    queued_at: Instant,
+    result: mpsc::Sender<Result<i64, CinderError>>,
+}
+
+/// Max-heap order: higher priority first, then earlier submission
+impl Ord for Job {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.priority.cmp(&other.priority).then_with(|| other.sequence.cmp(&self.sequence))
+    }
+}
+
+impl PartialOrd for Job {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for Job {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Job {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use crate::sandbox::Capability;
+
+    /// Records the tenant of every request in the order workers took them
+    #[derive(Default)]
+    struct TakeLog {
+        tenants: Mutex<Vec<String>>,
+        rejected: Mutex<usize>,
+    }
+
+    impl Metrics for TakeLog {
+        fn queue_wait(&self, tenant: &str, _waited: Duration) {
+            self.tenants.lock().unwrap().push(tenant.to_string());
+        }
+
+        fn rejected(&self, _tenant: &str) {
+            *self.rejected.lock().unwrap() += 1;
+        }
+    }
+
+    impl TakeLog {
+        fn taken(&self) -> Vec<String> {
+            self.tenants.lock().unwrap().clone()
+        }
+    }
+
+    /// Sum of `n` down to 1, looping `n` times
+    fn countdown(n: i64) -> Program {
+        let source = include_str!("../examples/countdown.cinder").replace("PUSH_INT 10", &format!("PUSH_INT {}", n));
+        Parser::parse(&source).unwrap()
+    }
+
+    fn start(workers: usize, queue_capacity: usize, backpressure: Backpressure) -> (Executor, Arc<TakeLog>) {
+        let log = Arc::new(TakeLog::default());
+        let config = ExecutorConfig { workers, queue_capacity, backpressure, metrics: log.clone() };
+        (Engine::default().executor(config), log)
+    }
+
+    /// Submit a long program and wait until the only worker is busy with it
+    fn occupy(executor: &Executor, log: &TakeLog) -> Handle {
+        let handle = executor.submit("busy", Priority::Normal, countdown(300_000)).unwrap();
+        while log.taken().is_empty() {
+            thread::yield_now();
+        }
+        handle
+    }
+
+    #[test]
+    fn results_and_errors_come_back_through_handles() {
+        let (executor, _) = start(2, 8, Backpressure::Reject);
+        let sum = executor.submit("a", Priority::Normal, countdown(10)).unwrap();
+        let rejected = executor.submit("a", Priority::Normal, Parser::parse("PUSH_REG 99\nHALT").unwrap()).unwrap();
+        let failed = executor.submit("b", Priority::Normal, Parser::parse("PUSH_INT 1\nPUSH_INT 0\nDIV").unwrap()).unwrap();
+        assert_eq!(sum.wait().unwrap(), 55);
+        assert!(matches!(rejected.wait(), Err(CinderError::Sandbox(_))));
+        assert!(matches!(failed.wait(), Err(CinderError::Interpret(_))));
+    }
+
+    #[test]
+    fn full_queue_rejects_or_times_out() {
+        let (executor, log) = start(1, 1, Backpressure::Reject);
+        let _busy = occupy(&executor, &log);
+        let queued = executor.submit("a", Priority::Normal, countdown(3)).unwrap();
+        assert_eq!(executor.submit("a", Priority::Normal, countdown(3)).unwrap_err(), SubmitError::QueueFull);
+        assert_eq!(*log.rejected.lock().unwrap(), 1);
+        assert_eq!(queued.wait().unwrap(), 6);
+
+        let timeout = Duration::from_millis(1);
+        let (executor, log) = start(1, 1, Backpressure::Wait(timeout));
+        let _busy = occupy(&executor, &log);
+        executor.submit("a", Priority::Normal, countdown(3)).unwrap();
+        assert_eq!(executor.submit("a", Priority::Normal, countdown(3)).unwrap_err(), SubmitError::Timeout(timeout));
+        assert_eq!(*log.rejected.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn waiting_submission_goes_in_once_there_is_room() {
+        let (executor, log) = start(1, 1, Backpressure::Wait(Duration::from_secs(60)));
+        let _busy = occupy(&executor, &log);
+        executor.submit("a", Priority::Normal, countdown(3)).unwrap();
+        assert_eq!(executor.submit("a", Priority::Normal, countdown(4)).unwrap().wait().unwrap(), 10);
+    }
+
+    #[test]
+    fn queue_alternates_tenants_and_orders_each_by_priority() {
+        let mut queue = Queue::default();
+        let (sender, _) = mpsc::channel();
+        let mut push = |tenant: &str, priority, n| {
+            let job = Job { priority, sequence: 0, program: countdown(n), queued_at: Instant::now(), result: sender.clone() };
+            queue.push(tenant, job);
+        };
+        push("a", Priority::Low, 1);
+        push("a", Priority::Normal, 2);
+        push("a", Priority::High, 3);
+        push("a", Priority::Normal, 4);
+        push("b", Priority::Low, 5);
+
+        let order: Vec<(String, Program)> = std::iter::from_fn(|| queue.pop().map(|(tenant, job)| (tenant, job.program)))
+            .collect();
+        let expected = [("a", 3), ("b", 5), ("a", 2), ("a", 4), ("a", 1)];
+        assert_eq!(order.len(), expected.len());
+        for ((tenant, program), (expected_tenant, n)) in order.iter().zip(expected) {
+            assert_eq!(tenant, expected_tenant);
+            assert_eq!(program.to_bytes(), countdown(n).to_bytes());
+        }
+        assert_eq!(queue.len, 0);
+    }
+
+    #[test]
+    fn a_flooding_tenant_cannot_starve_another() {
+        let (executor, log) = start(2, 5000, Backpressure::Reject);
+        let program = countdown(1000);
+        let flood: Vec<Handle> = (0..3000)
+            .map(|_| executor.submit("flood", Priority::High, program.clone()).unwrap())
+            .collect();
+        let single = executor.submit("single", Priority::Low, countdown(10)).unwrap();
+        assert_eq!(single.wait().unwrap(), 55);
+        drop(executor);
+        assert!(flood.into_iter().all(|handle| handle.wait().unwrap() == 500_500));
+
+        // Served on its tenant's first turn, with most of the flood still queued
+        let taken = log.taken();
+        let position = taken.iter().position(|tenant| tenant == "single").unwrap();
+        let after = taken.len() - position - 1;
+        assert!(after > 2000, "only {} flood requests ran after the single one", after);
+    }
+
+    /// Keeps everything an engine reports; can silence the engine the
+    /// first time a run logs
+    #[derive(Default)]
+    struct Recorder {
+        logs: Mutex<Vec<Verbosity>>,
+        reports: Mutex<Vec<RunReport>>,
+        silence: Option<Arc<AtomicU32>>,
+    }
+
+    impl Diagnostics for Recorder {
+        fn log(&self, level: Verbosity, _message: &str) {
+            self.logs.lock().unwrap().push(level);
+            if let Some(diagnostics) = &self.silence {
+                diagnostics.store(DiagnosticsConfig::default().pack(), atomic::Ordering::Release);
+            }
+        }
+
+        fn finished(&self, report: &RunReport) {
+            self.reports.lock().unwrap().push(report.clone());
+        }
+    }
+
+    #[test]
+    fn settings_flipped_during_runs_apply_whole_to_later_runs() {
+        let path = std::env::temp_dir().join(format!("cinder-engine-trace-{}.json", std::process::id()));
+        let trace = Arc::new(ChromeTrace::new(&path));
+        let recorder = Arc::new(Recorder::default());
+        let mut policy = Sandbox::new();
+        policy.grant(Capability::Storage);
+        let engine = Engine::new(policy).with_diagnostics(recorder.clone()).with_trace(trace.clone());
+        let program = Parser::parse("PUSH_INT 5\nCALL_NATIVE kv_get\nRETURN").unwrap();
+
+        let flipping = Arc::new(atomic::AtomicBool::new(true));
+        let flipper = {
+            let (engine, flipping) = (engine.clone(), flipping.clone());
+            thread::spawn(move || {
+                let mut loud = false;
+                while flipping.load(atomic::Ordering::Relaxed) {
+                    loud = !loud;
+                    engine.reconfigure(if loud { DiagnosticsConfig::all() } else { DiagnosticsConfig::default() });
+                    thread::yield_now();
+                }
+            })
+        };
+        let executor = engine.executor(ExecutorConfig { workers: 4, ..ExecutorConfig::default() });
+        let handles: Vec<Handle> =
+            (0..400).map(|_| executor.submit("a", Priority::Normal, program.clone()).unwrap()).collect();
+        for handle in handles {
+            assert_eq!(handle.wait().unwrap(), crate::natives::KV_MISS);
+        }
+        flipping.store(false, atomic::Ordering::Relaxed);
+        flipper.join().unwrap();
+
+        let reports = recorder.reports.lock().unwrap();
+        assert_eq!(reports.len(), 400);
+        let loud = reports.iter().filter(|report| report.config == DiagnosticsConfig::all()).count();
+        for report in reports.iter() {
+            if report.config == DiagnosticsConfig::all() {
+                let access = KvAccess { op: crate::natives::KvOp::Get, key: 5, value: crate::natives::KV_MISS };
+                assert_eq!(report.audit, Some(vec![access]));
+                assert_eq!(report.instructions, Some(3));
+            } else {
+                assert_eq!(report.config, DiagnosticsConfig::default());
+                assert_eq!((&report.audit, report.instructions), (&None, None));
+            }
+        }
+        // Each loud run logged its start and its result and left one span
+        assert_eq!(recorder.logs.lock().unwrap().len(), 2 * loud);
+        trace.flush().unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap().matches("\"name\":\"run\"").count(), loud);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn a_run_keeps_the_settings_it_started_with() {
+        let engine = Engine::default();
+        let recorder = Arc::new(Recorder { silence: Some(engine.diagnostics.clone()), ..Recorder::default() });
+        let engine = engine.with_diagnostics(recorder.clone());
+        engine.reconfigure(DiagnosticsConfig::all());
+
+        // The first log line, sent as the run starts, turns everything off
+        assert_eq!(engine.run(&countdown(10)).unwrap(), 55);
+        assert_eq!(engine.diagnostics(), DiagnosticsConfig::default());
+        assert_eq!(engine.run(&countdown(10)).unwrap(), 55);
+
+        let reports = recorder.reports.lock().unwrap();
+        assert_eq!(reports[0].config, DiagnosticsConfig::all());
+        assert!(reports[0].instructions.is_some_and(|executed| executed > 10));
+        assert_eq!(reports[1].config, DiagnosticsConfig::default());
+        assert_eq!(reports[1].instructions, None);
+        assert_eq!(*recorder.logs.lock().unwrap(), [Verbosity::Debug, Verbosity::Info]);
+    }
+
+    #[test]
+    fn pure_results_are_served_from_the_memo() {
+        let engine = Engine::default().with_memo(2);
+        let stats = |hits, misses, entries| Some(MemoStats { hits, misses, entries });
+        assert_eq!(engine.run(&countdown(10)).unwrap(), 55);
+        assert_eq!(engine.run(&countdown(10)).unwrap(), 55);
+        assert_eq!(engine.memo_stats(), stats(1, 1, 1));
+
+        // Profiling bypasses the memo, so the count comes from a real run
+        engine.reconfigure(DiagnosticsConfig { profile: true, ..DiagnosticsConfig::default() });
+        assert_eq!(engine.run(&countdown(10)).unwrap(), 55);
+        assert_eq!(engine.memo_stats(), stats(1, 1, 1));
+        engine.reconfigure(DiagnosticsConfig::default());
+
+        // 10 was used after 20, so 30 evicts 20
+        engine.run(&countdown(20)).unwrap();
+        engine.run(&countdown(10)).unwrap();
+        engine.run(&countdown(30)).unwrap();
+        assert_eq!(engine.memo_stats(), stats(2, 3, 2));
+        assert_eq!(engine.run(&countdown(20)).unwrap(), 210);
+        assert_eq!(engine.memo_stats(), stats(2, 4, 2));
+        assert_eq!(Engine::default().memo_stats(), None);
+    }
+
+    #[test]
+    fn impure_or_failing_programs_are_never_memoized() {
+        let recorder = Arc::new(Recorder::default());
+        let mut policy = Sandbox::new();
+        policy.grant(Capability::Storage);
+        let engine = Engine::new(policy).with_memo(8).with_diagnostics(recorder.clone());
+        engine.reconfigure(DiagnosticsConfig { audit: true, ..DiagnosticsConfig::default() });
+
+        let storage = Parser::parse("PUSH_INT 5\nCALL_NATIVE kv_get\nRETURN").unwrap();
+        let clock = Parser::parse("READ_CYCLES\nRETURN").unwrap();
+        let failing = Parser::parse("PUSH_INT 1\nPUSH_INT 0\nDIV").unwrap();
+        for _ in 0..3 {
+            engine.run(&storage).unwrap();
+            engine.run(&clock).unwrap();
+            assert!(engine.run(&failing).is_err());
+        }
+        // Every storage run executed and recorded its access
+        let reports = recorder.reports.lock().unwrap();
+        assert!(reports.iter().step_by(3).all(|report| report.audit.as_ref().is_some_and(|audit| audit.len() == 1)));
+        // Only the failing program is pure; it was looked up but never stored
+        assert_eq!(engine.memo_stats(), Some(MemoStats { hits: 0, misses: 3, entries: 0 }));
+    }
+
+    #[test]
+    fn config_survives_packing() {
+        for bits in 0..8 {
+            for verbosity in [Verbosity::Off, Verbosity::Error, Verbosity::Info, Verbosity::Debug] {
+                let config =
+                    DiagnosticsConfig { trace: bits & 1 != 0, audit: bits & 2 != 0, profile: bits & 4 != 0, verbosity };
+                assert_eq!(DiagnosticsConfig::unpack(config.pack()), config);
+            }
+        }
+    }
+}
@@ -0,0 +1,96 @@
+//! Error type returned by the crate's public APIs
+
+use crate::bytecode::ProgramDecodeError;
+use crate::interpreter::InterpreterError;
+#[cfg(feature = "jit")]
+use crate::jit::CodegenError;
+use crate::parser::{DuplicateLabel, ExpansionBudgetExceeded, ParseError};
+use crate::sandbox::SandboxError;
+use std::io;
+
+/// Any error a public CinderVM API can return, by the stage that failed
+#[derive(Debug, thiserror::Error)]
+pub enum CinderError {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error(transparent)]
+    DuplicateLabel(#[from] DuplicateLabel),
+    #[error(transparent)]
+    Expansion(#[from] ExpansionBudgetExceeded),
+    #[error(transparent)]
+    Decode(#[from] ProgramDecodeError),
+    #[error(transparent)]
+    Sandbox(#[from] SandboxError),
+    #[cfg(feature = "jit")]
+    #[error(transparent)]
+    Codegen(#[from] CodegenError),
+    #[error("Execution error: {0:?}")]
+    Interpret(InterpreterError),
+    #[error(transparent)]
+    Io(#[from] io::Error),
+}
+
+impl From<InterpreterError> for CinderError {
+    fn from(error: InterpreterError) -> Self {
+        CinderError::Interpret(error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Program;
+    use crate::formats::{Binary, ProgramCodec};
+    use crate::interpreter::Interpreter;
+    use crate::parser::Parser;
+    use crate::sandbox::Sandbox;
+
+    #[test]
+    fn parser_errors_are_parse_duplicate_label_or_io() {
+        assert!(matches!(Parser::parse("PUSH_INT 1\nFROB"), Err(CinderError::Parse(ParseError { line: 2, .. }))));
+        assert!(matches!(
+            Parser::parse("top:\nHALT\ntop:"),
+            Err(CinderError::DuplicateLabel(DuplicateLabel { first_line: 1, second_line: 3, .. }))
+        ));
+        assert!(matches!(Parser::parse_file("examples/missing.cinder"), Err(CinderError::Io(_))));
+    }
+
+    #[test]
+    fn binary_decoding_errors_are_decode() {
+        assert!(matches!(Binary.decode(b"CNDR\x01"), Err(CinderError::Decode(_))));
+    }
+
+    #[test]
+    fn validation_errors_are_sandbox() {
+        let program = Parser::parse("PUSH_INT 1\nSTORE 5\nHALT").unwrap();
+        let program = Program { memory_size: 2, ..program };
+        assert!(matches!(
+            program.validated(&Sandbox::new()),
+            Err(CinderError::Sandbox(SandboxError::StoreOutsideData { at: 1, offset: 5, .. }))
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn compile_errors_are_codegen() {
+        use crate::jit::{JitCompiler, JitOptions};
+
+        let program = Parser::parse("PUSH_INT 1\nRETURN").unwrap();
+        let options = JitOptions { max_code_size: Some(1), ..JitOptions::default() };
+        assert!(matches!(
+            JitCompiler::new(program).with_options(options).compile(),
+            Err(CinderError::Codegen(CodegenError::CodeSizeExceeded { limit: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn interpreter_errors_convert_to_interpret() {
+        fn run(source: &str) -> Result<i64, CinderError> {
+            Ok(Interpreter::new(Parser::parse(source)?).execute()?)
+        }
+        assert!(matches!(
+            run("PUSH_INT 1\nPUSH_INT 0\nDIV\nRETURN"),
+            Err(CinderError::Interpret(InterpreterError::DivisionByZero))
+        ));
+    }
+}
@@ -0,0 +1,307 @@
+//! Program interchange formats behind a common `ProgramCodec` trait.
+//!
+//! Every codec round-trips through `Program`, so converting between any
+//! two formats is `decode` with one and `encode` with the other.
+
+use crate::bytecode::Program;
+use crate::disasm::disassemble;
+use crate::error::CinderError;
+use crate::parser::{DuplicateLabel, ParseError, Parser};
+use std::io;
+
+type Result<T, E = CinderError> = std::result::Result<T, E>;
+
+/// A representation programs can be written to and read back from
+pub trait ProgramCodec {
+    fn encode(&self, program: &Program) -> Vec<u8>;
+    fn decode(&self, input: &[u8]) -> Result<Program>;
+}
+
+/// `.cinder` source, as produced by the disassembler
+pub struct Source;
+
+/// The `Program::to_bytes` encoding
+pub struct Binary;
+
+/// S-expressions: `(program (memory 64) (push_int 5) (block L3 ...))`.
+///
+/// Directives become `(memory n)`, `(args n)`, `(returns n)`,
+/// `(var name type)` and `(data offset value...)`; instructions are lowercase mnemonics with their
+/// operands. A label opens a `(block name ...)` holding the instructions
+/// up to the next label. `;` starts a comment.
+pub struct Sexpr;
+
+impl ProgramCodec for Source {
+    fn encode(&self, program: &Program) -> Vec<u8> {
+        disassemble(program, false).into_bytes()
+    }
+
+    fn decode(&self, input: &[u8]) -> Result<Program> {
+        Parser::parse(utf8(input)?)
+    }
+}
+
+impl ProgramCodec for Binary {
+    fn encode(&self, program: &Program) -> Vec<u8> {
+        program.to_bytes()
+    }
+
+    fn decode(&self, input: &[u8]) -> Result<Program> {
+        Ok(Program::from_bytes(input)?)
+    }
+}
+
+impl ProgramCodec for Sexpr {
+    fn encode(&self, program: &Program) -> Vec<u8> {
+        let mut out = String::from("(program");
+        let mut in_block = false;
+
+        for line in disassemble(program, false).lines() {
+            let line = line.trim();
+            if let Some(label) = line.strip_suffix(':') {
+                if in_block {
+                    out.push(')');
+                }
+                out.push_str(&format!("\n  (block {}", label));
+                in_block = true;
+                continue;
+            }
+
+            let indent = if in_block { "    " } else { "  " };
+            let form = match line.strip_prefix('.') {
+                // `.var name: type` loses its colon
+                Some(directive) => directive.replacen(':', "", 1),
+                None => match line.split_once(' ') {
+                    Some((mnemonic, operands)) => {
+                        format!("{} {}", mnemonic.to_ascii_lowercase(), operands)
+                    }
+                    None => line.to_ascii_lowercase(),
+                },
+            };
+            out.push_str(&format!("\n{}({})", indent, form));
+        }
+
+        if in_block {
+            out.push(')');
+        }
+        out.push_str(")\n");
+        out.into_bytes()
+    }
+
+    fn decode(&self, input: &[u8]) -> Result<Program> {
+        let tree = read(utf8(input)?)?;
+        let forms = match &tree {
+            Node::List(items, _) if head(items) == Some("program") => &items[1..],
+            other => return Err(error_at(other.position(), "expected (program ...)")),
+        };
+
+        // Lower to .cinder source, remembering where each line came from
+        let mut lowered = Lowered::default();
+        for form in forms {
+            lowered.form(form, true)?;
+        }
+
+        Parser::parse(&lowered.source).map_err(|error| match error {
+            CinderError::Parse(parse_error) => {
+                let (line, column) = lowered.positions[parse_error.line - 1];
+                ParseError { line, column, message: parse_error.message }.into()
+            }
+            CinderError::DuplicateLabel(duplicate) => DuplicateLabel {
+                first_line: lowered.positions[duplicate.first_line - 1].0,
+                second_line: lowered.positions[duplicate.second_line - 1].0,
+                name: duplicate.name,
+            }
+            .into(),
+            other => other,
+        })
+    }
+}
+
+/// Line and column (1-based) of a token in S-expression input
+type Position = (usize, usize);
+
+enum Node {
+    Atom(String, Position),
+    List(Vec<Node>, Position),
+}
+
+impl Node {
+    fn position(&self) -> Position {
+        match self {
+            Node::Atom(_, position) | Node::List(_, position) => *position,
+        }
+    }
+}
+
+/// Text of the first element, if it is an atom
+fn head(items: &[Node]) -> Option<&str> {
+    match items.first() {
+        Some(Node::Atom(text, _)) => Some(text),
+        _ => None,
+    }
+}
+
+fn error_at((line, column): Position, message: &str) -> CinderError {
+    ParseError { line, column, message: message.to_string() }.into()
+}
+
+/// Text formats reject invalid UTF-8 as `CinderError::Io` with `InvalidData`
+fn utf8(input: &[u8]) -> Result<&str> {
+    std::str::from_utf8(input).map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error).into())
+}
+
+/// Read exactly one S-expression from `text`
+fn read(text: &str) -> Result<Node> {
+    let mut reader = Reader { chars: text.chars().peekable(), line: 1, column: 1 };
+    let node = reader.node()?;
+    reader.skip_blank();
+    if reader.chars.peek().is_some() {
+        return Err(error_at(reader.position(), "unexpected input after the program"));
+    }
+    Ok(node)
+}
+
+/// Recursive-descent reader tracking the position of every token
+struct Reader<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl Reader<'_> {
+    fn position(&self) -> Position {
+        (self.line, self.column)
+    }
+
+    fn bump(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    /// Skip whitespace and `;` comments
+    fn skip_blank(&mut self) {
+        while let Some(&c) = self.chars.peek() {
+            if c == ';' {
+                while self.chars.peek().is_some_and(|&c| c != '\n') {
+                    self.bump();
+                }
+            } else if c.is_whitespace() {
+                self.bump();
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn node(&mut self) -> Result<Node> {
+        self.skip_blank();
+        let start = self.position();
+        match self.chars.peek() {
+            None => Err(error_at(start, "unexpected end of input")),
+            Some(')') => Err(error_at(start, "unexpected ')'")),
+            Some('(') => {
+                self.bump();
+                let mut items = Vec::new();
+                loop {
+                    self.skip_blank();
+                    match self.chars.peek() {
+                        None => return Err(error_at(start, "unclosed '('")),
+                        Some(')') => {
+                            self.bump();
+                            return Ok(Node::List(items, start));
+                        }
+                        Some(_) => items.push(self.node()?),
+                    }
+                }
+            }
+            Some(_) => {
+                let mut atom = String::new();
+                while let Some(&c) = self.chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' || c == ';' {
+                        break;
+                    }
+                    atom.push(c);
+                    self.bump();
+                }
+                Ok(Node::Atom(atom, start))
+            }
+        }
+    }
+}
+
+/// `.cinder` source generated from S-expression forms
+#[derive(Default)]
+struct Lowered {
+    source: String,
+    /// S-expression position of each generated line
+    positions: Vec<Position>,
+}
+
+impl Lowered {
+    fn line(&mut self, text: String, position: Position) {
+        self.source.push_str(&text);
+        self.source.push('\n');
+        self.positions.push(position);
+    }
+
+    /// Lower one directive, block or instruction; blocks only at top level
+    fn form(&mut self, node: &Node, top_level: bool) -> Result<()> {
+        let position = node.position();
+        let Node::List(items, _) = node else {
+            return Err(error_at(position, "expected a form in parentheses"));
+        };
+        let Some(name) = head(items) else {
+            return Err(error_at(position, "form must start with a name"));
+        };
+
+        if name == "block" {
+            if !top_level {
+                return Err(error_at(position, "blocks cannot be nested"));
+            }
+            let label = match items.get(1) {
+                Some(Node::Atom(label, _)) => label,
+                _ => return Err(error_at(position, "block needs a label name")),
+            };
+            self.line(format!("{}:", label), position);
+            for item in &items[2..] {
+                self.form(item, false)?;
+            }
+            return Ok(());
+        }
+
+        let mut operands = Vec::new();
+        for item in &items[1..] {
+            match item {
+                Node::Atom(text, _) => operands.push(text.as_str()),
+                Node::List(_, position) => return Err(error_at(*position, "operands must be atoms")),
+            }
+        }
+
+        let text = match name {
+            "memory" | "args" | "returns" | "data" if top_level => format!(".{} {}", name, operands.join(" ")),
+            "var" if top_level => match operands.as_slice() {
+                [slot, ty] => format!(".var {}: {}", slot, ty),
+                _ => return Err(error_at(position, "expected (var name type)")),
+            },
+            "memory" | "args" | "returns" | "var" | "data" => {
+                return Err(error_at(position, "directives are not allowed inside a block"));
+            }
+            mnemonic => {
+                let mut text = mnemonic.to_ascii_uppercase();
+                for operand in operands {
+                    text.push(' ');
+                    text.push_str(operand);
+                }
+                text
+            }
+        };
+        self.line(text, position);
+        Ok(())
+    }
+}
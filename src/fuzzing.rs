@@ -0,0 +1,108 @@
+use crate::bytecode::{Instruction, JumpTable, Program};
+use crate::natives;
+use arbitrary::{Arbitrary, Result, Unstructured};
+
+/// Upper bound on generated program length
+pub const MAX_INSTRUCTIONS: usize = 256;
+/// Upper bound on generated memory size
+pub const MAX_MEMORY: usize = 64;
+
+/// A structurally plausible program for fuzzing.
+///
+/// Every jump goes strictly forward, so generated programs always terminate
+/// and need no fuel limit. Static memory offsets are in bounds (indirect
+/// ones are whatever is on the stack, so they usually fault) and stack depth is
+/// tracked along the straight-line path, but programs are not guaranteed to
+/// pass validation (branches can still join at mismatched depths);
+/// callers should validate and skip rejected ones.
+#[derive(Debug, Clone)]
+pub struct ArbitraryProgram(pub Program);
+
+impl<'a> Arbitrary<'a> for ArbitraryProgram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> Result<Self> {
+        let memory_size = u.int_in_range(0..=MAX_MEMORY)?;
+        let len = u.int_in_range(1..=MAX_INSTRUCTIONS)?;
+
+        let mut instructions = Vec::with_capacity(len);
+        let mut depth = 0usize;
+        for pc in 0..len - 1 {
+            let insn = arbitrary_instruction(u, pc, len, memory_size, depth)?;
+            let (pops, pushes) = insn.stack_effect();
+            depth = depth - pops + pushes;
+            instructions.push(insn);
+        }
+        instructions.push(Instruction::Halt);
+
+        Ok(Self(Program::new(instructions, memory_size)))
+    }
+}
+
+/// One instruction at `pc` that doesn't underflow a stack of `depth` values
+fn arbitrary_instruction(
+    u: &mut Unstructured,
+    pc: usize,
+    len: usize,
+    memory_size: usize,
+    depth: usize,
+) -> Result<Instruction> {
+    // Forward targets only: anything after `pc`, up to the final Halt
+    let target = |u: &mut Unstructured| -> Result<u32> {
+        Ok(u.int_in_range(pc + 1..=len - 1)? as u32)
+    };
+
+    let insn = match u.int_in_range(0..=19u8)? {
+        0..=3 => Instruction::PushInt(u.arbitrary()?),
+        4 if depth >= 1 => Instruction::Pop,
+        5 if depth >= 2 => u.choose(&[Instruction::Add, Instruction::Sub, Instruction::Mul])?.clone(),
+        6 if depth >= 2 => Instruction::Div,
+        7 if depth >= 2 => u.choose(&[Instruction::Eq, Instruction::Lt, Instruction::Gt])?.clone(),
+        7 if depth >= 1 => u.choose(&[Instruction::Bool, Instruction::NotBool])?.clone(),
+        8 => Instruction::Jump(target(u)?),
+        9 if depth >= 1 => Instruction::JumpIfZero(target(u)?),
+        10 if depth >= 1 => Instruction::JumpIfNotZero(target(u)?),
+        11 if depth >= 1 => {
+            let count = u.int_in_range(0..=8)?;
+            let targets = (0..count).map(|_| target(u)).collect::<Result<_>>()?;
+            Instruction::JumpTable(Box::new(JumpTable {
+                base: u.int_in_range(-4..=4)?,
+                default: target(u)?,
+                targets,
+            }))
+        }
+        12 if memory_size > 0 => Instruction::Load(u.int_in_range(0..=memory_size - 1)? as u32),
+        13 if memory_size > 0 && depth >= 1 => {
+            Instruction::Store(u.int_in_range(0..=memory_size - 1)? as u32)
+        }
+        // Whitelisted but unimplemented, so it exercises the call path
+        // without writing to stdout
+        14 => Instruction::CallNative(natives::PRINT_STR),
+        15 => Instruction::Return,
+        16 if depth >= 1 => {
+            let count = u.arbitrary()?;
+            u.choose(&[Instruction::ShlImm(count), Instruction::ShrImm(count), Instruction::SarImm(count)])?.clone()
+        }
+        17 if memory_size > 0 => {
+            Instruction::MemAdd(u.int_in_range(0..=memory_size - 1)? as u32, u.arbitrary()?)
+        }
+        18 if depth >= 1 => Instruction::LoadInd,
+        19 if depth >= 2 => Instruction::StoreInd,
+        _ => Instruction::PushInt(u.arbitrary()?),
+    };
+    Ok(insn)
+}
+
+/// Whether the JIT is expected to agree with the interpreter on `program`.
+///
+/// JIT memory accesses don't yet share the interpreter's layout.
+pub fn jit_comparable(program: &Program) -> bool {
+    program.instructions.iter().all(|insn| {
+        !matches!(
+            insn,
+            Instruction::Load(_)
+                | Instruction::Store(_)
+                | Instruction::MemAdd(..)
+                | Instruction::LoadInd
+                | Instruction::StoreInd
+        )
+    })
+}
@@ -0,0 +1,293 @@
+//! Re-parsing a program after some of its files change.
+//!
+//! Every file of the include tree is cached with its content hash and its
+//! parsed program. An edit re-parses the edited file and the files that
+//! include it, since their labels and memory layout depend on its length,
+//! and splices every other file's program from the cache. The result is
+//! the program `Parser::parse_file` would return; its `semantic_hash` tells
+//! downstream caches whether anything that runs has changed.
+
+use crate::bytecode::{fnv1a, Program};
+use crate::error::CinderError;
+use crate::parser::{Expansion, IncludeLoader, ParseLimits, Parser};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// A file of the include tree, keyed by its canonical path
+struct Fragment {
+    content: String,
+    content_hash: u64,
+    /// Canonical paths of the files it includes directly
+    includes: Vec<PathBuf>,
+    /// Its program, with includes spliced in; `None` once it or anything
+    /// it includes has changed, or if it failed to parse
+    program: Option<Program>,
+}
+
+/// Parser that keeps the files of a program between edits
+pub struct IncrementalParser {
+    root: PathBuf,
+    fragments: HashMap<PathBuf, Fragment>,
+    /// Canonical paths parsed by the last `parse` or `update`
+    reparsed: Vec<PathBuf>,
+    limits: ParseLimits,
+}
+
+/// `IncludeLoader` serving unchanged files from the cache
+struct Cache<'a> {
+    fragments: &'a mut HashMap<PathBuf, Fragment>,
+    reparsed: &'a mut Vec<PathBuf>,
+}
+
+impl Cache<'_> {
+    /// Cached program of the file at `path` (canonically the last of
+    /// `expansion.open`), parsing it if it is new or stale
+    fn fetch(&mut self, path: &Path, expansion: &mut Expansion) -> Result<Program, CinderError> {
+        let canonical = expansion.open.last().expect("the file being loaded is open").clone();
+        let content = match self.fragments.get(&canonical) {
+            Some(Fragment { program: Some(program), .. }) => return Ok(program.clone()),
+            Some(fragment) => fragment.content.clone(),
+            None => fs::read_to_string(path)?,
+        };
+
+        self.fragments.insert(canonical.clone(), Fragment {
+            content_hash: fnv1a(content.as_bytes()),
+            content: content.clone(),
+            includes: Vec::new(),
+            program: None,
+        });
+        self.reparsed.push(canonical.clone());
+        let program = Parser::parse_source(&content, Some(path), expansion, self)?;
+        if let Some(fragment) = self.fragments.get_mut(&canonical) {
+            fragment.program = Some(program.clone());
+        }
+        Ok(program)
+    }
+}
+
+impl IncludeLoader for Cache<'_> {
+    fn load(&mut self, path: &Path, expansion: &mut Expansion) -> Result<Program, CinderError> {
+        // The includer was inserted before parsing, so record the edge now
+        if let [.., includer, included] = expansion.open.as_slice() {
+            if let Some(fragment) = self.fragments.get_mut(includer) {
+                if !fragment.includes.contains(included) {
+                    fragment.includes.push(included.clone());
+                }
+            }
+        }
+        self.fetch(path, expansion)
+    }
+}
+
+impl IncrementalParser {
+    /// Parser for the program whose main file is `root`; nothing is read
+    /// until the first `parse` or `update`
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            fragments: HashMap::new(),
+            reparsed: Vec::new(),
+            limits: ParseLimits::default(),
+        }
+    }
+
+    /// Bound the include tree by `limits` instead of `ParseLimits::default()`
+    pub fn with_limits(mut self, limits: ParseLimits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    /// The program as of the cached file contents, parsing only the files
+    /// that changed since the last call and the files including them
+    pub fn parse(&mut self) -> Result<&Program, CinderError> {
+        self.reparsed.clear();
+        let root = fs::canonicalize(&self.root)?;
+        if self.fragments.get(&root).is_some_and(|fragment| fragment.program.is_some()) {
+            return Ok(self.fragments[&root].program.as_ref().expect("checked above"));
+        }
+        let mut expansion = Expansion::new(Some(root.clone()), self.limits);
+        Cache { fragments: &mut self.fragments, reparsed: &mut self.reparsed }.fetch(&self.root, &mut expansion)?;
+
+        // Files no longer included are dropped
+        let reachable = Self::reachable(&self.fragments, &root);
+        self.fragments.retain(|path, _| reachable.contains(path));
+        Ok(self.fragments[&root].program.as_ref().expect("a parsed root has a program"))
+    }
+
+    /// Re-read `path` after an edit and return the updated program. A file
+    /// whose content hash is unchanged is not re-parsed, and a file outside
+    /// the program is ignored.
+    pub fn update(&mut self, path: impl AsRef<Path>) -> Result<&Program, CinderError> {
+        let canonical = fs::canonicalize(path.as_ref())?;
+        if let Some(fragment) = self.fragments.get_mut(&canonical) {
+            let content = fs::read_to_string(path.as_ref())?;
+            let content_hash = fnv1a(content.as_bytes());
+            if content_hash != fragment.content_hash {
+                fragment.content = content;
+                fragment.content_hash = content_hash;
+                self.invalidate(&canonical);
+            }
+        }
+        self.parse()
+    }
+
+    /// Canonical paths of the files read so far, to watch for changes
+    pub fn files(&self) -> Vec<&Path> {
+        let mut files: Vec<&Path> = self.fragments.keys().map(PathBuf::as_path).collect();
+        files.sort();
+        files
+    }
+
+    /// Canonical paths of the files the last `parse` or `update` parsed,
+    /// includers before the files they include
+    pub fn reparsed(&self) -> &[PathBuf] {
+        &self.reparsed
+    }
+
+    /// Drop the program of `changed` and of every file that includes it,
+    /// directly or not
+    fn invalidate(&mut self, changed: &Path) {
+        if let Some(fragment) = self.fragments.get_mut(changed) {
+            fragment.program = None;
+        }
+        let mut stale = vec![changed.to_path_buf()];
+        while let Some(path) = stale.pop() {
+            for (includer, fragment) in &mut self.fragments {
+                if fragment.program.is_some() && fragment.includes.contains(&path) {
+                    fragment.program = None;
+                    stale.push(includer.clone());
+                }
+            }
+        }
+    }
+
+    fn reachable(fragments: &HashMap<PathBuf, Fragment>, root: &Path) -> Vec<PathBuf> {
+        let mut reachable = vec![root.to_path_buf()];
+        let mut next = 0;
+        while let Some(path) = reachable.get(next) {
+            let includes = fragments.get(path).map(|fragment| fragment.includes.clone()).unwrap_or_default();
+            for included in includes {
+                if !reachable.contains(&included) {
+                    reachable.push(included);
+                }
+            }
+            next += 1;
+        }
+        reachable
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Empty scratch directory for one test
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("cinder-incremental-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// What a program or error looks like, for comparing two parses
+    fn outcome(result: Result<&Program, &CinderError>) -> Result<(Vec<u8>, String), String> {
+        result
+            .map(|program| (program.to_bytes(), format!("{:?}", program.instructions)))
+            .map_err(|error| error.to_string())
+    }
+
+    #[test]
+    fn only_edited_files_and_their_includers_are_reparsed() {
+        let dir = scratch("reparse");
+        let main = dir.join("main.cinder");
+        fs::write(&main, ".include \"lib.cinder\"\n.include \"util.cinder\"\nLOAD 0\nRETURN\n").unwrap();
+        fs::write(dir.join("lib.cinder"), "PUSH_INT 1\nSTORE 0\n").unwrap();
+        fs::write(dir.join("util.cinder"), "PUSH_INT 2\nPOP\n").unwrap();
+        let canonical = |name: &str| fs::canonicalize(dir.join(name)).unwrap();
+
+        let mut parser = IncrementalParser::new(&main);
+        parser.parse().unwrap();
+        assert_eq!(parser.reparsed().len(), 3);
+        assert_eq!(parser.files().len(), 3);
+
+        fs::write(dir.join("util.cinder"), "PUSH_INT 3\nPOP\nPUSH_INT 4\nPOP\n").unwrap();
+        parser.update(dir.join("util.cinder")).unwrap();
+        assert_eq!(parser.reparsed(), [canonical("main.cinder"), canonical("util.cinder")]);
+
+        // Same content: nothing to do
+        parser.update(dir.join("util.cinder")).unwrap();
+        assert!(parser.reparsed().is_empty());
+
+        // A comment is re-parsed but runs the same
+        let before = parser.parse().unwrap().semantic_hash();
+        fs::write(dir.join("lib.cinder"), "# one\nPUSH_INT 1\nSTORE 0\n").unwrap();
+        let after = parser.update(dir.join("lib.cinder")).unwrap().semantic_hash();
+        assert_eq!(parser.reparsed(), [canonical("main.cinder"), canonical("lib.cinder")]);
+        assert_eq!(before, after);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn edit_sequences_match_a_fresh_parse() {
+        // Variants per file, including ones that add, remove or dangle
+        // labels, change lengths and memory, drop or add includes, and
+        // fail to parse
+        let variants: &[(&str, &[&str])] = &[
+            ("main.cinder", &[
+                ".include \"lib.cinder\"\n.include \"util.cinder\"\nLOAD 0\nRETURN\n",
+                "JUMP start\nPUSH_INT 0\nRETURN\nstart:\n.include \"lib.cinder\"\n.include \"util.cinder\"\nJUMP done\ndone:\nLOAD 0\nRETURN\n",
+                ".include \"util.cinder\"\nPUSH_INT 7\nRETURN\n",
+                ".include \"lib.cinder\"\nJUMP missing\n",
+                ".include \"lib.cinder\"\n.include \"lib.cinder\"\nLOAD 1\nRETURN\n",
+            ]),
+            ("lib.cinder", &[
+                "PUSH_INT 1\nSTORE 0\n",
+                "PUSH_INT 2\nJUMP skip\nPUSH_INT 99\nPOP\nskip:\nSTORE 0\n",
+                "JUMP skip\nPUSH_INT 3\nSTORE 0\n",
+                "# comment\nPUSH_INT 1\nSTORE 0\n",
+                "PUSH_INT 5\nSTORE 1\nLOAD 1\nSTORE 0\n",
+            ]),
+            ("util.cinder", &[
+                "PUSH_INT 10\nSTORE 0\n",
+                "loop:\nMEM_ADD 0 -1\nLOAD 0\nJNZ loop\n",
+                ".data 0 4\nLOAD 0\nPOP\n",
+                "PUSH_INT\n",
+                ".include \"leaf.cinder\"\nPUSH_INT 1\nSTORE 0\n",
+            ]),
+            ("leaf.cinder", &[
+                "PUSH_INT 4\nPOP\n",
+                "x:\nJUMP y\ny:\nJUMP x\n",
+                "MEM_ADD 2 1\n",
+            ]),
+        ];
+        let dir = scratch("sequences");
+        for (name, texts) in variants {
+            fs::write(dir.join(name), texts[0]).unwrap();
+        }
+        let main = dir.join("main.cinder");
+        let mut parser = IncrementalParser::new(&main);
+        parser.parse().unwrap();
+
+        // xorshift64, so every run edits the same way
+        let mut state = 0x2545_f491_4f6c_dd1d_u64;
+        let mut next = |bound: usize| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            (state % bound as u64) as usize
+        };
+        let mut failures = 0;
+        for step in 0..300 {
+            let (name, texts) = variants[next(variants.len())];
+            fs::write(dir.join(name), texts[next(texts.len())]).unwrap();
+
+            let incremental = outcome(parser.update(dir.join(name)).as_ref().map(|p| *p));
+            let fresh = outcome(Parser::parse_file(main.to_str().unwrap()).as_ref());
+            assert_eq!(incremental, fresh, "step {} edited {}", step, name);
+            failures += incremental.is_err() as usize;
+        }
+        fs::remove_dir_all(&dir).unwrap();
+        assert!((50..250).contains(&failures), "{} of 300 edits failed to parse", failures);
+    }
+}
@@ -1,172 +1,1343 @@
-use crate::bytecode::{Instruction, Program};
+use crate::bytecode::{BoolRepr, ByteReader, DataBlock, Instruction, Program, ProgramDecodeError, SlotType, VmInt};
+use crate::channel::{ChannelOp, ChannelReceiver, ChannelSender, Full};
+use crate::natives::{self, NativeFn, SharedStore};
+use crate::sandbox::ValidatedProgram;
+use std::any::Any;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::OnceLock;
+use std::task::Poll;
+use std::time::Instant;
 
-/// Minimal interpreter for bytecode validation
-pub struct Interpreter {
-    stack: Vec<i64>,
-    memory: Vec<i64>,
+/// Minimal interpreter for bytecode validation.
+///
+/// `H` is embedder state passed by `&mut` to natives registered with
+/// `with_native`; plain interpreters use `()`. `V` is the value type
+/// (see `with_value_type`); results, natives and checkpoints use `i64`
+/// either way.
+pub struct Interpreter<H = (), V: VmInt = i64> {
+    stack: Vec<V>,
+    memory: Memory<V>,
     pc: usize,  // Program Counter
+    /// Return addresses of active subroutine calls; empty at top level
+    call_stack: Vec<usize>,
     program: Program,
+    host: H,
+    /// Natives registered by the host, consulted before the built-in table
+    host_natives: Vec<HostNative<H>>,
+    /// Reject stores outside a `.var` slot's declared type
+    check_types: bool,
+    /// Called after each retired instruction
+    on_step: Option<StepHook<V>>,
+    /// Called when a BREAKPOINT instruction executes
+    on_breakpoint: Option<BreakpointHook<V>>,
+    native_log: NativeLog,
+    bool_repr: BoolRepr,
+    /// Set by `with_loop_detection`
+    loop_detector: Option<LoopDetector>,
+    /// Ends of the channels `chan_send` and `chan_recv` use
+    channel_sender: Option<ChannelSender>,
+    channel_receiver: Option<ChannelReceiver>,
+    /// Set by a channel native that has to wait; the call is retried
+    blocked: bool,
+    /// Where `kv_get` and `kv_put` read and write
+    store: SharedStore,
+    /// Storage natives fail unless `store` is declared deterministic
+    deterministic: bool,
+}
+
+/// Memory sizes (in slots) above which the interpreter stores only
+/// touched slots instead of allocating them all up front
+pub const SPARSE_MEMORY_THRESHOLD: usize = 1 << 16;
+
+/// Program memory; every slot reads as 0 until stored to
+enum Memory<V> {
+    Flat(Vec<V>),
+    Sparse { len: usize, cells: HashMap<usize, V> },
+}
+
+impl<V: VmInt> Memory<V> {
+    fn new(len: usize) -> Self {
+        if len > SPARSE_MEMORY_THRESHOLD {
+            Memory::Sparse { len, cells: HashMap::new() }
+        } else {
+            Memory::Flat(vec![V::default(); len])
+        }
+    }
+
+    /// `self` with the program's `.data` blocks written in; slots past the
+    /// end are skipped (the sandbox rejects such blocks)
+    fn with_data(mut self, data: &[DataBlock]) -> Self {
+        for block in data {
+            for (offset, &value) in (block.offset as usize..).zip(&block.values) {
+                if offset < self.len() {
+                    self.set(offset, V::from_i64(value));
+                }
+            }
+        }
+        self
+    }
+
+    /// Empty memory of the same length and representation
+    fn empty_like<W>(other: &Memory<W>) -> Self {
+        match other {
+            Memory::Flat(cells) => Memory::Flat(vec![V::default(); cells.len()]),
+            Memory::Sparse { len, .. } => Memory::Sparse { len: *len, cells: HashMap::new() },
+        }
+    }
+
+    fn len(&self) -> usize {
+        match self {
+            Memory::Flat(cells) => cells.len(),
+            Memory::Sparse { len, .. } => *len,
+        }
+    }
+
+    /// Callers check `offset < len()` first
+    fn get(&self, offset: usize) -> V {
+        match self {
+            Memory::Flat(cells) => cells[offset],
+            Memory::Sparse { cells, .. } => cells.get(&offset).copied().unwrap_or_default(),
+        }
+    }
+
+    fn set(&mut self, offset: usize, value: V) {
+        match self {
+            Memory::Flat(cells) => cells[offset] = value,
+            Memory::Sparse { cells, .. } => {
+                cells.insert(offset, value);
+            }
+        }
+    }
+
+    /// Slots holding a nonzero value, in offset order
+    fn nonzero(&self) -> Vec<(usize, i64)> {
+        let mut cells: Vec<_> = match self {
+            Memory::Flat(cells) => cells.iter().map(|value| value.to_i64()).enumerate().collect(),
+            Memory::Sparse { cells, .. } => cells.iter().map(|(&offset, value)| (offset, value.to_i64())).collect(),
+        };
+        cells.retain(|&(_, value)| value != 0);
+        cells.sort_unstable();
+        cells
+    }
+
+    fn hash<S: Hasher>(&self, state: &mut S) {
+        match self {
+            Memory::Flat(cells) => cells.hash(state),
+            Memory::Sparse { cells, .. } => {
+                // Map iteration order is unspecified
+                let mut touched: Vec<_> = cells.iter().collect();
+                touched.sort_unstable();
+                touched.hash(state);
+            }
+        }
+    }
+}
+
+/// Brent's cycle detection over the interpreter state at backward jumps.
+/// Only a hash is kept per checkpoint, so memory use is constant however
+/// long the loop runs.
+struct LoopDetector {
+    /// State hash at the last checkpoint
+    saved: Option<u64>,
+    /// Backward jumps since the checkpoint
+    since_saved: u64,
+    /// Backward jumps between checkpoints; doubles each time
+    window: u64,
+}
+
+impl LoopDetector {
+    fn new() -> Self {
+        Self { saved: None, since_saved: 0, window: 1 }
+    }
+
+    /// `true` if `state` equals the checkpoint, i.e. the loop can never exit
+    fn repeats(&mut self, state: u64) -> bool {
+        if self.saved == Some(state) {
+            return true;
+        }
+        self.since_saved += 1;
+        if self.since_saved >= self.window {
+            self.saved = Some(state);
+            self.since_saved = 0;
+            self.window *= 2;
+        }
+        false
+    }
+}
+
+/// Interpreter state saved by `Interpreter::checkpoint`, for resuming a
+/// long run in another process. Natives, hooks and recorded calls are not
+/// part of it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    /// `Program::semantic_hash` of the program being run
+    program_hash: u64,
+    /// `VmInt::BITS` of the interpreter's value type
+    value_bits: u32,
+    pc: usize,
+    stack: Vec<i64>,
+    call_stack: Vec<usize>,
+    /// Nonzero memory slots; the rest read as 0
+    memory: Vec<(usize, i64)>,
+}
+
+const CHECKPOINT_MAGIC: &[u8; 4] = b"CNDK";
+
+/// Bumped whenever the checkpoint layout changes
+pub const CHECKPOINT_VERSION: u32 = 1;
+
+impl Checkpoint {
+    /// `CHECKPOINT_MAGIC`, version, program hash, value width, pc, then
+    /// the stack, call stack and nonzero memory slots, each prefixed by
+    /// its length. Integers are little-endian.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = CHECKPOINT_MAGIC.to_vec();
+        out.extend_from_slice(&CHECKPOINT_VERSION.to_le_bytes());
+        out.extend_from_slice(&self.program_hash.to_le_bytes());
+        out.push(self.value_bits as u8);
+        out.extend_from_slice(&(self.pc as u32).to_le_bytes());
+        out.extend_from_slice(&(self.stack.len() as u32).to_le_bytes());
+        for value in &self.stack {
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out.extend_from_slice(&(self.call_stack.len() as u32).to_le_bytes());
+        for return_pc in &self.call_stack {
+            out.extend_from_slice(&(*return_pc as u32).to_le_bytes());
+        }
+        out.extend_from_slice(&(self.memory.len() as u32).to_le_bytes());
+        for (offset, value) in &self.memory {
+            out.extend_from_slice(&(*offset as u32).to_le_bytes());
+            out.extend_from_slice(&value.to_le_bytes());
+        }
+        out
+    }
+
+    /// Decode the output of `to_bytes`
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CheckpointError> {
+        if !bytes.starts_with(CHECKPOINT_MAGIC) {
+            return Err(CheckpointError::BadMagic);
+        }
+        let mut reader = ByteReader::new(bytes, CHECKPOINT_MAGIC.len());
+        let version = reader.u32()?;
+        if version != CHECKPOINT_VERSION {
+            return Err(CheckpointError::UnsupportedVersion(version));
+        }
+        let program_hash = reader.u64()?;
+        let value_bits = match reader.u8()? {
+            bits @ (32 | 64) => u32::from(bits),
+            _ => return Err(CheckpointError::InvalidState),
+        };
+        let pc = reader.u32()? as usize;
+        let count = reader.u32()?;
+        let stack = (0..count).map(|_| reader.i64()).collect::<Result<_, _>>()?;
+        let count = reader.u32()?;
+        let call_stack = (0..count)
+            .map(|_| reader.u32().map(|pc| pc as usize))
+            .collect::<Result<_, _>>()?;
+        let count = reader.u32()?;
+        let memory = (0..count)
+            .map(|_| Ok((reader.u32()? as usize, reader.i64()?)))
+            .collect::<Result<_, ProgramDecodeError>>()?;
+        Ok(Self { program_hash, value_bits, pc, stack, call_stack, memory })
+    }
+
+    /// Width of the saved values; restore into an interpreter whose value
+    /// type has this many bits
+    pub fn value_bits(&self) -> u32 {
+        self.value_bits
+    }
+}
+
+/// Reasons a checkpoint cannot be decoded or restored
+#[derive(Debug, thiserror::Error)]
+pub enum CheckpointError {
+    #[error("not a CinderVM checkpoint (bad magic)")]
+    BadMagic,
+    #[error("unsupported checkpoint version {0} (expected {CHECKPOINT_VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("checkpoint ends after {length} bytes but at least {needed} are needed; it may be truncated")]
+    Truncated { needed: usize, length: usize },
+    #[error("checkpoint was taken from a different program")]
+    ProgramMismatch,
+    #[error("checkpoint state is out of range for the program")]
+    InvalidState,
+    #[error("checkpoint holds {found}-bit values, the interpreter {expected}-bit")]
+    WidthMismatch { expected: u32, found: u32 },
+}
+
+impl From<ProgramDecodeError> for CheckpointError {
+    /// The fixed-width reads a checkpoint uses only fail on truncated input
+    fn from(error: ProgramDecodeError) -> Self {
+        match error {
+            ProgramDecodeError::Truncated { needed, length } => CheckpointError::Truncated { needed, length },
+            _ => CheckpointError::InvalidState,
+        }
+    }
+}
+
+/// Outcome of `Interpreter::run_for`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunState {
+    /// The budget ran out; call `run_for` again to continue
+    Yielded,
+    /// A channel native has to wait for another program before any
+    /// instruction can run; call `run_for` again once it may have progressed
+    Blocked,
+    /// The program ended with this result
+    Halted(i64),
 }
 
-#[derive(Debug)]
+/// Callback receiving the pc and instruction just executed, and the resulting stack
+pub type StepHook<V = i64> = Box<dyn FnMut(usize, &Instruction, &[V])>;
+
+/// Callback receiving the pc of a BREAKPOINT and the stack when it is hit
+pub type BreakpointHook<V = i64> = Box<dyn FnMut(usize, &[V])>;
+
+/// Native implemented by the embedder, with access to its state
+pub struct HostNative<H> {
+    pub id: u32,
+    /// Values popped from the stack, passed deepest first
+    pub args: usize,
+    pub func: fn(&mut H, &[i64]),
+}
+
+#[derive(Debug, PartialEq, Eq)]
 pub enum InterpreterError {
     StackUnderflow,
     StackOverflow,
     InvalidMemoryAccess(usize),
     InvalidJumpTarget(usize),
     DivisionByZero,
+    /// Fewer values on the stack at termination than `.returns` declares
+    MissingReturnValues { expected: usize, found: usize },
+    /// Store outside a slot's declared type (with type checks enabled)
+    SlotTypeViolation { slot: String, ty: SlotType, value: i64 },
+    /// A native panicked; its arguments were consumed, `pc` still points at the call
+    NativePanicked { id: u32, message: String },
+    /// A replayed run made a native call the recording doesn't have next
+    ReplayMismatch { expected: Option<NativeCall>, found: NativeCall },
+    /// With loop detection, the state at this backward jump target recurred
+    /// with no native call or READ_CYCLES in between
+    DetectedInfiniteLoop(usize),
+    /// The program executed `TRAP` with this error code
+    Trap(u32),
+    /// A channel native was called without that end of a channel attached
+    NoChannel { id: u32 },
+    /// `chan_send` on a full nonblocking channel
+    ChannelFull,
+    /// The program waits on a channel no other running program can serve
+    Deadlock,
+    /// A storage native was called, in a program validated in
+    /// deterministic mode, on a store not declared deterministic
+    NondeterministicStore { id: u32 },
+}
+
+/// A native call captured by `record_natives`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NativeCall {
+    pub id: u32,
+    pub args: Vec<i64>,
+    /// Value the native pushed, for natives that return one
+    pub result: Option<i64>,
+}
+
+impl NativeCall {
+    /// Parse the `id arg... [-> result]` form produced by `Display`
+    pub fn parse(line: &str) -> Option<Self> {
+        let (call, result) = match line.split_once("->") {
+            Some((call, result)) => (call, Some(result.trim().parse().ok()?)),
+            None => (line, None),
+        };
+        let mut fields = call.split_whitespace();
+        let id = fields.next()?.parse().ok()?;
+        let args = fields.map(|field| field.parse().ok()).collect::<Option<_>>()?;
+        Some(Self { id, args, result })
+    }
+}
+
+impl fmt::Display for NativeCall {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.id)?;
+        for arg in &self.args {
+            write!(f, " {}", arg)?;
+        }
+        if let Some(result) = self.result {
+            write!(f, " -> {}", result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why `run_steps` returned
+enum Stop {
+    /// The program is over
+    Ended,
+    /// The instruction limit was reached
+    Limit,
+    /// A channel native has to wait; `progressed` if instructions ran first
+    Blocked { progressed: bool },
+}
+
+/// Whether native calls are executed live, logged, or served from a log
+enum NativeLog {
+    Off,
+    Recording(Vec<NativeCall>),
+    Replaying(VecDeque<NativeCall>),
 }
 
 impl Interpreter {
+    /// Create an interpreter with at least 1024 memory slots (see `with_host`)
     pub fn new(program: Program) -> Self {
+        Self::with_host(program, ())
+    }
+
+    /// Create an interpreter with exactly `memory_size` slots, so its bounds
+    /// match the sandbox's
+    pub fn strict(program: Program) -> Self {
+        Self::new(program).with_exact_memory()
+    }
+
+    /// Create an interpreter for a program already checked by the sandbox.
+    /// If the policy was deterministic, so must be the store attached with
+    /// `with_store`.
+    pub fn from_validated(validated: ValidatedProgram) -> Self {
+        let deterministic = validated.is_deterministic();
+        Self { deterministic, ..Self::new(validated.into_program()) }
+    }
+}
+
+impl<H> Interpreter<H> {
+    /// Create an interpreter whose host natives receive `host`.
+    ///
+    /// Memory gets at least 1024 slots, more than the sandbox allows for a
+    /// smaller `memory_size`, so unvalidated programs can access slots the
+    /// JIT would reject; `with_exact_memory` removes the minimum.
+    pub fn with_host(program: Program, host: H) -> Self {
         let memory_size = program.memory_size.max(1024); // Minimum 1024 bytes
         Self {
             stack: Vec::new(),
-            memory: vec![0; memory_size],
+            memory: Memory::new(memory_size).with_data(&program.data),
             pc: 0,
+            call_stack: Vec::new(),
             program,
+            host,
+            host_natives: Vec::new(),
+            check_types: false,
+            on_step: None,
+            on_breakpoint: None,
+            native_log: NativeLog::Off,
+            bool_repr: BoolRepr::default(),
+            loop_detector: None,
+            channel_sender: None,
+            channel_receiver: None,
+            blocked: false,
+            store: SharedStore::default(),
+            deterministic: false,
+        }
+    }
+}
+
+impl<H, V: VmInt> Interpreter<H, V> {
+    /// Choose the value comparisons push for true
+    pub fn with_bool_repr(mut self, bool_repr: BoolRepr) -> Self {
+        self.bool_repr = bool_repr;
+        self
+    }
+
+    /// Compute with `W` values instead, e.g. `i32` to run programs written
+    /// for 32-bit integers. Input, memory and stack contents are truncated
+    /// to `W`; call it before installing hooks, which take `&[W]`.
+    ///
+    /// # Panics
+    ///
+    /// If a step or breakpoint hook is installed.
+    pub fn with_value_type<W: VmInt>(self) -> Interpreter<H, W> {
+        assert!(
+            self.on_step.is_none() && self.on_breakpoint.is_none(),
+            "with_value_type must be called before installing hooks"
+        );
+        let mut memory = Memory::empty_like(&self.memory);
+        for (offset, value) in self.memory.nonzero() {
+            memory.set(offset, W::from_i64(value));
+        }
+        Interpreter {
+            stack: self.stack.into_iter().map(|value| W::from_i64(value.to_i64())).collect(),
+            memory,
+            pc: self.pc,
+            call_stack: self.call_stack,
+            program: self.program,
+            host: self.host,
+            host_natives: self.host_natives,
+            check_types: self.check_types,
+            on_step: None,
+            on_breakpoint: None,
+            native_log: self.native_log,
+            bool_repr: self.bool_repr,
+            loop_detector: self.loop_detector,
+            channel_sender: self.channel_sender,
+            channel_receiver: self.channel_receiver,
+            blocked: self.blocked,
+            store: self.store,
+            deterministic: self.deterministic,
+        }
+    }
+
+    /// Send the values popped by `chan_send` to `sender`'s channel
+    pub fn with_channel_sender(mut self, sender: ChannelSender) -> Self {
+        self.channel_sender = Some(sender);
+        self
+    }
+
+    /// Push the values `chan_recv` takes from `receiver`'s channel
+    pub fn with_channel_receiver(mut self, receiver: ChannelReceiver) -> Self {
+        self.channel_receiver = Some(receiver);
+        self
+    }
+
+    /// Back `kv_get` and `kv_put` with `store` instead of this interpreter's
+    /// own empty `MemoryStore`
+    pub fn with_store(mut self, store: SharedStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// The store the storage natives use
+    pub fn store(&self) -> &SharedStore {
+        &self.store
+    }
+
+    /// Log every native call (ID and arguments) while still executing it
+    pub fn record_natives(mut self) -> Self {
+        self.native_log = NativeLog::Recording(Vec::new());
+        self
+    }
+
+    /// Calls logged so far by `record_natives`
+    pub fn native_log(&self) -> &[NativeCall] {
+        match &self.native_log {
+            NativeLog::Recording(log) => log,
+            _ => &[],
         }
     }
 
+    /// Skip native calls, checking each against `log` in order instead.
+    /// Natives that return a value get the recorded result back, so the
+    /// replayed run sees exactly what the recorded one saw.
+    pub fn replay_natives(mut self, log: Vec<NativeCall>) -> Self {
+        self.native_log = NativeLog::Replaying(log.into());
+        self
+    }
+
+    /// Allocate exactly `memory_size` slots, so accesses the sandbox would
+    /// reject fail with `InvalidMemoryAccess`. Discards memory contents, so
+    /// call it before running and before `with_sparse_memory`.
+    pub fn with_exact_memory(mut self) -> Self {
+        self.memory = Memory::new(self.program.memory_size).with_data(&self.program.data);
+        self
+    }
+
+    /// Back memory with a map of touched slots regardless of its size
+    pub fn with_sparse_memory(mut self) -> Self {
+        self.memory = Memory::Sparse { len: self.memory.len(), cells: HashMap::new() }.with_data(&self.program.data);
+        self
+    }
+
+    /// Check every store into a `.var` slot against its declared type
+    pub fn with_type_checks(mut self) -> Self {
+        self.check_types = true;
+        self
+    }
+
+    /// Fail with `DetectedInfiniteLoop` when the whole state (pc, stack,
+    /// call stack and memory) repeats at a backward jump. Native calls and
+    /// READ_CYCLES can change what happens next, so they restart the check;
+    /// only loops that compute nothing new are caught.
+    pub fn with_loop_detection(mut self) -> Self {
+        self.loop_detector = Some(LoopDetector::new());
+        self
+    }
+
+    /// Handle `CALL_NATIVE id` with `func`, replacing any built-in native.
+    /// The ID must still be whitelisted for the program to validate.
+    pub fn with_native(mut self, id: u32, args: usize, func: fn(&mut H, &[i64])) -> Self {
+        self.host_natives.retain(|native| native.id != id);
+        self.host_natives.push(HostNative { id, args, func });
+        self
+    }
+
+    /// Install a callback invoked after every executed instruction
+    pub fn set_on_step(&mut self, on_step: StepHook<V>) {
+        self.on_step = Some(on_step);
+    }
+
+    /// Install a callback invoked at every BREAKPOINT; without one the
+    /// instruction does nothing. Execution resumes after the callback returns.
+    pub fn set_on_breakpoint(&mut self, on_breakpoint: BreakpointHook<V>) {
+        self.on_breakpoint = Some(on_breakpoint);
+    }
+
+    pub fn host(&self) -> &H {
+        &self.host
+    }
+
+    pub fn host_mut(&mut self) -> &mut H {
+        &mut self.host
+    }
+
+    pub fn into_host(self) -> H {
+        self.host
+    }
+
+    /// Push `input` onto the value stack (first element deepest) and execute
+    pub fn execute_with_input(&mut self, input: &[i64]) -> Result<i64, InterpreterError> {
+        self.stack.extend(input.iter().map(|&value| V::from_i64(value)));
+        self.execute()
+    }
+
     pub fn execute(&mut self) -> Result<i64, InterpreterError> {
+        self.run()?;
+        Ok(self.pop_result())
+    }
+
+    /// The top of the stack as the program's result, 0 if it is empty
+    fn pop_result(&mut self) -> i64 {
+        self.stack.pop().map_or(0, VmInt::to_i64)
+    }
+
+    /// Execute and return every value declared by `.returns`, deepest first.
+    /// Programs without the directive return a single value, like `execute`.
+    pub fn execute_returning(&mut self) -> Result<Vec<i64>, InterpreterError> {
+        self.run()?;
+
+        let Some(expected) = self.program.returns else {
+            return Ok(vec![self.pop_result()]);
+        };
+
+        if self.stack.len() < expected {
+            return Err(InterpreterError::MissingReturnValues {
+                expected,
+                found: self.stack.len(),
+            });
+        }
+        let results = self.stack.split_off(self.stack.len() - expected);
+        Ok(results.into_iter().map(VmInt::to_i64).collect())
+    }
+
+    /// Execute at most `budget` instructions, then yield. State is kept, so
+    /// the next call resumes exactly where this one stopped; results match
+    /// an uninterrupted `execute`. Once `Halted` is returned the program is
+    /// over and the interpreter should not be resumed. A channel native that
+    /// has to wait ends the slice early: with `Yielded` if instructions ran
+    /// before it, `Blocked` if none could.
+    pub fn run_for(&mut self, budget: u64) -> Result<RunState, InterpreterError> {
+        Ok(match self.run_steps(Some(budget))? {
+            Stop::Ended => RunState::Halted(self.pop_result()),
+            Stop::Limit | Stop::Blocked { progressed: true } => RunState::Yielded,
+            Stop::Blocked { progressed: false } => RunState::Blocked,
+        })
+    }
+
+    /// Snapshot of the execution state, e.g. between `run_for` slices
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            program_hash: self.program.semantic_hash(),
+            value_bits: V::BITS,
+            pc: self.pc,
+            stack: self.stack.iter().map(|value| value.to_i64()).collect(),
+            call_stack: self.call_stack.clone(),
+            memory: self.memory.nonzero(),
+        }
+    }
+
+    /// Continue from `checkpoint` instead of the start of the program; call
+    /// on a fresh interpreter. Fails if it was taken from a program with a
+    /// different semantic hash, or with a different value type.
+    pub fn restore(&mut self, checkpoint: Checkpoint) -> Result<(), CheckpointError> {
+        if checkpoint.program_hash != self.program.semantic_hash() {
+            return Err(CheckpointError::ProgramMismatch);
+        }
+        if checkpoint.value_bits != V::BITS {
+            return Err(CheckpointError::WidthMismatch { expected: V::BITS, found: checkpoint.value_bits });
+        }
+        let len = self.program.instructions.len();
+        if checkpoint.pc > len
+            || checkpoint.call_stack.iter().any(|&pc| pc > len)
+            || checkpoint.memory.iter().any(|&(offset, _)| offset >= self.memory.len())
+        {
+            return Err(CheckpointError::InvalidState);
+        }
+        
+        self.pc = checkpoint.pc;
+        self.stack = checkpoint.stack.into_iter().map(V::from_i64).collect();
+        self.call_stack = checkpoint.call_stack;
+        // The checkpoint has every nonzero slot, including `.data` ones
+        self.memory = Memory::empty_like(&self.memory);
+        for (offset, value) in checkpoint.memory {
+            self.memory.set(offset, V::from_i64(value));
+        }
+        Ok(())
+    }
+
+    /// Run until Return/Halt or the end of the program, leaving results on
+    /// the stack. Nothing else runs meanwhile, so waiting on a channel is a
+    /// deadlock.
+    fn run(&mut self) -> Result<(), InterpreterError> {
+        match self.run_steps(None)? {
+            Stop::Blocked { .. } => Err(InterpreterError::Deadlock),
+            _ => Ok(()),
+        }
+    }
+
+    /// Execute up to `limit` instructions (unlimited if `None`)
+    fn run_steps(&mut self, limit: Option<u64>) -> Result<Stop, InterpreterError> {
+        let mut executed = 0;
         while self.pc < self.program.instructions.len() {
-            let instruction = &self.program.instructions[self.pc];
+            if limit.is_some_and(|limit| executed >= limit) {
+                return Ok(Stop::Limit);
+            }
             
-            match instruction {
-                Instruction::PushInt(val) => {
-                    self.stack.push(*val);
-                    self.pc += 1;
-                }
-                
-                Instruction::PushReg(_reg) => {
-                    // For simplicity, ignore registers in interpreter
-                    // In JIT we will use real registers
-                    return Err(InterpreterError::StackUnderflow);
+            let pc = self.pc;
+            let input = matches!(
+                self.program.instructions[pc],
+                Instruction::CallNative(_) | Instruction::ReadCycles
+            );
+            let running = self.step()?;
+            if std::mem::take(&mut self.blocked) {
+                return Ok(Stop::Blocked { progressed: executed > 0 });
+            }
+            executed += 1;
+            
+            if self.loop_detector.is_some() {
+                if input {
+                    self.loop_detector = Some(LoopDetector::new());
+                } else if running && self.pc <= pc {
+                    let state = self.state_hash();
+                    if self.loop_detector.as_mut().is_some_and(|detector| detector.repeats(state)) {
+                        return Err(InterpreterError::DetectedInfiniteLoop(self.pc));
+                    }
                 }
-                
-                Instruction::Pop => {
-                    self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.pc += 1;
+            }
+            
+            if let Some(on_step) = &mut self.on_step {
+                on_step(pc, &self.program.instructions[pc], &self.stack);
+            }
+            if !running {
+                return Ok(Stop::Ended);
+            }
+        }
+        
+        Ok(Stop::Ended)
+    }
+
+    /// Hash of everything that determines the rest of the run, except natives
+    fn state_hash(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.pc.hash(&mut hasher);
+        self.stack.hash(&mut hasher);
+        self.call_stack.hash(&mut hasher);
+        self.memory.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    /// `value` in the configured boolean representation
+    fn encode_bool(&self, value: bool) -> V {
+        V::from_i64(self.bool_repr.encode(value))
+    }
+
+    /// With type checking on, reject writing `val` outside its slot's type
+    fn check_slot_type(&self, offset: usize, val: V) -> Result<(), InterpreterError> {
+        if !self.check_types {
+            return Ok(());
+        }
+        match self.program.slot(offset as u32) {
+            Some(slot) if !slot.ty.contains(val.to_i64()) => Err(InterpreterError::SlotTypeViolation {
+                slot: slot.name.clone(),
+                ty: slot.ty,
+                value: val.to_i64(),
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Run channel native `id` on the attached channel end, or `Pending`
+    /// if it has to wait for another program
+    fn channel_call(&self, id: u32, op: ChannelOp, args: &[i64]) -> Result<Poll<Option<i64>>, InterpreterError> {
+        match op {
+            ChannelOp::Send => {
+                let sender = self.channel_sender.as_ref().ok_or(InterpreterError::NoChannel { id })?;
+                match sender.try_send(args[0]) {
+                    Ok(()) => Ok(Poll::Ready(None)),
+                    Err(Full::Wait) => Ok(Poll::Pending),
+                    Err(Full::Fail) => Err(InterpreterError::ChannelFull),
                 }
-                
-                Instruction::Add => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(a + b);
-                    self.pc += 1;
+            }
+            ChannelOp::Recv => {
+                let receiver = self.channel_receiver.as_ref().ok_or(InterpreterError::NoChannel { id })?;
+                Ok(receiver.try_recv().map(Some))
+            }
+        }
+    }
+
+    /// Memory index popped by LOAD_IND/STORE_IND, if it is below the
+    /// program's `memory_size` (not the 1024-slot minimum, which the JIT
+    /// doesn't have). Negative offsets are reported as `usize::MAX`.
+    fn indirect_offset(&self, offset: V) -> Result<usize, InterpreterError> {
+        let offset = usize::try_from(offset.to_i64()).unwrap_or(usize::MAX);
+        if offset >= self.program.memory_size {
+            return Err(InterpreterError::InvalidMemoryAccess(offset));
+        }
+        Ok(offset)
+    }
+
+    /// Execute the instruction at `pc`; `false` once the program has ended
+    fn step(&mut self) -> Result<bool, InterpreterError> {
+        let instruction = &self.program.instructions[self.pc];
+        
+        match instruction {
+            Instruction::PushInt(val) => {
+                self.stack.push(V::from_i64(*val));
+                self.pc += 1;
+            }
+            
+            Instruction::PushReg(_reg) => {
+                // For simplicity, ignore registers in interpreter
+                // In JIT we will use real registers
+                return Err(InterpreterError::StackUnderflow);
+            }
+            
+            Instruction::Pop => {
+                self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.pc += 1;
+            }
+            
+            Instruction::Add => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(a.wrapping_add(b));
+                self.pc += 1;
+            }
+            
+            Instruction::Sub => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(a.wrapping_sub(b));
+                self.pc += 1;
+            }
+            
+            Instruction::Mul => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(a.wrapping_mul(b));
+                self.pc += 1;
+            }
+            
+            Instruction::Div => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                if b == V::default() {
+                    return Err(InterpreterError::DivisionByZero);
                 }
-                
-                Instruction::Sub => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(a - b);
-                    self.pc += 1;
+                self.stack.push(a.wrapping_div(b));
+                self.pc += 1;
+            }
+            
+            Instruction::ShlImm(count) | Instruction::ShrImm(count) | Instruction::SarImm(count) => {
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let count = u32::from(*count) % V::BITS;
+                let result = match instruction {
+                    Instruction::ShlImm(_) => a.wrapping_shl(count),
+                    Instruction::ShrImm(_) => a.logical_shr(count),
+                    _ => a.arithmetic_shr(count),
+                };
+                self.stack.push(result);
+                self.pc += 1;
+            }
+            
+            Instruction::Eq => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(self.encode_bool(a == b));
+                self.pc += 1;
+            }
+            
+            Instruction::Lt => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(self.encode_bool(a < b));
+                self.pc += 1;
+            }
+            
+            Instruction::Gt => {
+                let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(self.encode_bool(a > b));
+                self.pc += 1;
+            }
+            
+            Instruction::Bool => {
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(self.encode_bool(a != V::default()));
+                self.pc += 1;
+            }
+            
+            Instruction::NotBool => {
+                let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.stack.push(self.encode_bool(a == V::default()));
+                self.pc += 1;
+            }
+            
+            Instruction::Jump(target) => {
+                let target = *target as usize;
+                if target >= self.program.instructions.len() {
+                    return Err(InterpreterError::InvalidJumpTarget(target));
                 }
-                
-                Instruction::Mul => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(a * b);
+                self.pc = target;
+            }
+            
+            Instruction::JumpIfZero(target) => {
+                let target = *target as usize;
+                let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                if val == V::default() {
+                    if target >= self.program.instructions.len() {
+                        return Err(InterpreterError::InvalidJumpTarget(target));
+                    }
+                    self.pc = target;
+                } else {
                     self.pc += 1;
                 }
-                
-                Instruction::Div => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    if b == 0 {
-                        return Err(InterpreterError::DivisionByZero);
+            }
+            
+            Instruction::JumpIfNotZero(target) => {
+                let target = *target as usize;
+                let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                if val != V::default() {
+                    if target >= self.program.instructions.len() {
+                        return Err(InterpreterError::InvalidJumpTarget(target));
                     }
-                    self.stack.push(a / b);
+                    self.pc = target;
+                } else {
                     self.pc += 1;
                 }
-                
-                Instruction::Eq => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(if a == b { 1 } else { 0 });
-                    self.pc += 1;
+            }
+            
+            Instruction::JumpTable(table) => {
+                let selector = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let target = table.resolve(selector.to_i64()) as usize;
+                if target >= self.program.instructions.len() {
+                    return Err(InterpreterError::InvalidJumpTarget(target));
                 }
-                
-                Instruction::Lt => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(if a < b { 1 } else { 0 });
-                    self.pc += 1;
+                self.pc = target;
+            }
+            
+            Instruction::Load(offset) => {
+                let offset = *offset as usize;
+                if offset >= self.memory.len() {
+                    return Err(InterpreterError::InvalidMemoryAccess(offset));
                 }
-                
-                Instruction::Gt => {
-                    let b = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    let a = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.stack.push(if a > b { 1 } else { 0 });
-                    self.pc += 1;
+                self.stack.push(self.memory.get(offset));
+                self.pc += 1;
+            }
+            
+            Instruction::Store(offset) => {
+                let offset = *offset as usize;
+                if offset >= self.memory.len() {
+                    return Err(InterpreterError::InvalidMemoryAccess(offset));
                 }
-                
-                Instruction::Jump(target) => {
-                    if *target >= self.program.instructions.len() {
-                        return Err(InterpreterError::InvalidJumpTarget(*target));
-                    }
-                    self.pc = *target;
+                let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                self.check_slot_type(offset, val)?;
+                self.memory.set(offset, val);
+                self.pc += 1;
+            }
+            
+            Instruction::MemAdd(offset, imm) => {
+                let offset = *offset as usize;
+                if offset >= self.memory.len() {
+                    return Err(InterpreterError::InvalidMemoryAccess(offset));
                 }
-                
-                Instruction::JumpIfZero(target) => {
-                    let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    if val == 0 {
-                        if *target >= self.program.instructions.len() {
-                            return Err(InterpreterError::InvalidJumpTarget(*target));
-                        }
-                        self.pc = *target;
-                    } else {
-                        self.pc += 1;
-                    }
+                let val = self.memory.get(offset).wrapping_add(V::from_i64((*imm).into()));
+                self.check_slot_type(offset, val)?;
+                self.memory.set(offset, val);
+                self.pc += 1;
+            }
+            
+            Instruction::LoadInd => {
+                let offset = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let offset = self.indirect_offset(offset)?;
+                self.stack.push(self.memory.get(offset));
+                self.pc += 1;
+            }
+            
+            Instruction::StoreInd => {
+                let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let offset = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
+                let offset = self.indirect_offset(offset)?;
+                self.check_slot_type(offset, val)?;
+                self.memory.set(offset, val);
+                self.pc += 1;
+            }
+            
+            Instruction::CallNative(id) => {
+                // Natives without an implementation are ignored;
+                // the whitelist is enforced by the sandbox
+                let id = *id;
+                let host_native = self.host_natives
+                    .iter()
+                    .find(|native| native.id == id)
+                    .map(|native| (native.func, native.args));
+                let builtin = natives::lookup(id);
+                let arity = match (host_native, builtin) {
+                    (Some((_, args)), _) => args,
+                    (None, Some(native)) => native.args(),
+                    (None, None) => 0,
+                };
+                if self.stack.len() < arity {
+                    return Err(InterpreterError::StackUnderflow);
                 }
+                let args: Vec<i64> = self.stack
+                    .split_off(self.stack.len() - arity)
+                    .into_iter()
+                    .map(VmInt::to_i64)
+                    .collect();
                 
-                Instruction::JumpIfNotZero(target) => {
-                    let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    if val != 0 {
-                        if *target >= self.program.instructions.len() {
-                            return Err(InterpreterError::InvalidJumpTarget(*target));
-                        }
-                        self.pc = *target;
-                    } else {
-                        self.pc += 1;
+                if let NativeLog::Replaying(log) = &mut self.native_log {
+                    // The recorded run already performed the side effect;
+                    // only the call itself has to match
+                    let matches = log.front().is_some_and(|expected| {
+                        expected.id == id && expected.args == args
+                    });
+                    if !matches {
+                        return Err(InterpreterError::ReplayMismatch {
+                            expected: log.pop_front(),
+                            found: NativeCall { id, args, result: None },
+                        });
                     }
-                }
-                
-                Instruction::Load(offset) => {
-                    if *offset >= self.memory.len() {
-                        return Err(InterpreterError::InvalidMemoryAccess(*offset));
+                    if let Some(result) = log.pop_front().and_then(|call| call.result) {
+                        self.stack.push(V::from_i64(result));
                     }
-                    self.stack.push(self.memory[*offset]);
                     self.pc += 1;
+                    return Ok(true);
                 }
                 
-                Instruction::Store(offset) => {
-                    if *offset >= self.memory.len() {
-                        return Err(InterpreterError::InvalidMemoryAccess(*offset));
+                // Host natives shadow builtins and return nothing
+                let called = if let Some((func, _)) = host_native {
+                    let host = &mut self.host;
+                    panic::catch_unwind(AssertUnwindSafe(|| func(host, &args)))
+                        .map(|_| None)
+                        .map_err(|payload| panic_message(payload.as_ref()))
+                } else if let Some(native) = builtin {
+                    match native.func {
+                        NativeFn::Channel(op) => match self.channel_call(id, op, &args)? {
+                            Poll::Ready(result) => Ok(result),
+                            Poll::Pending => {
+                                // Undo the pop; the call is retried as if it hadn't run
+                                self.stack.extend(args.into_iter().map(V::from_i64));
+                                self.blocked = true;
+                                return Ok(true);
+                            }
+                        },
+                        NativeFn::Storage(_) if self.deterministic && !self.store.is_deterministic() => {
+                            return Err(InterpreterError::NondeterministicStore { id });
+                        }
+                        _ => native.call(&args, &self.store),
                     }
-                    let val = self.stack.pop().ok_or(InterpreterError::StackUnderflow)?;
-                    self.memory[*offset] = val;
-                    self.pc += 1;
-                }
+                } else {
+                    Ok(None)
+                };
                 
-                Instruction::CallNative(_id) => {
-                    // In interpreter, ignore native calls
-                    // In JIT we will implement the whitelist
-                    self.pc += 1;
+                let result = called.map_err(|message| InterpreterError::NativePanicked { id, message })?;
+                if let NativeLog::Recording(log) = &mut self.native_log {
+                    log.push(NativeCall { id, args, result });
                 }
-                
-                Instruction::Return => {
-                    // Return value from stack or 0
-                    return Ok(self.stack.pop().unwrap_or(0));
+                if let Some(result) = result {
+                    self.stack.push(V::from_i64(result));
                 }
-                
-                Instruction::Halt => {
-                    return Ok(self.stack.pop().unwrap_or(0));
+                self.pc += 1;
+            }
+            
+            Instruction::Return => {
+                // Return from the current subroutine; at top level
+                // (empty call stack) this ends the program like Halt.
+                // Return values stay on the stack for the caller.
+                match self.call_stack.pop() {
+                    Some(return_pc) => self.pc = return_pc,
+                    None => return Ok(false),
+                }
+            }
+            
+            Instruction::Breakpoint => {
+                if let Some(on_breakpoint) = &mut self.on_breakpoint {
+                    on_breakpoint(self.pc, &self.stack);
                 }
+                self.pc += 1;
+            }
+            
+            Instruction::ReadCycles => {
+                self.stack.push(V::from_i64(monotonic_nanos()));
+                self.pc += 1;
+            }
+            
+            Instruction::Trap(code) => {
+                // pc stays on the TRAP, like other errors
+                return Err(InterpreterError::Trap(*code));
+            }
+            
+            Instruction::Halt => {
+                // Always ends the program, regardless of call depth
+                return Ok(false);
             }
         }
         
-        Ok(self.stack.pop().unwrap_or(0))
+        Ok(true)
+    }
+}
+
+/// Nanoseconds since the first call in this process, for READ_CYCLES
+pub(crate) fn monotonic_nanos() -> i64 {
+    static ORIGIN: OnceLock<Instant> = OnceLock::new();
+    let origin = ORIGIN.get_or_init(Instant::now);
+    i64::try_from(origin.elapsed().as_nanos()).unwrap_or(i64::MAX)
+}
+
+/// Text of a panic payload, when it is a string
+pub(crate) fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "<non-string panic payload>".to_string()
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    fn run(source: &str, input: &[i64]) -> Result<i64, InterpreterError> {
+        let program = Parser::parse(source).expect("program parses");
+        Interpreter::new(program).execute_with_input(input)
+    }
+
+    const SWITCH: &str = "
+        .args 1
+        JUMP_TABLE 0 other zero one two
+        zero:
+        PUSH_INT 10
+        RETURN
+        one:
+        PUSH_INT 20
+        RETURN
+        two:
+        PUSH_INT 30
+        RETURN
+        other:
+        PUSH_INT -1
+        RETURN
+    ";
+
+    #[test]
+    fn jump_table_dispatches_to_each_arm() {
+        assert_eq!(run(SWITCH, &[0]), Ok(10));
+        assert_eq!(run(SWITCH, &[1]), Ok(20));
+        assert_eq!(run(SWITCH, &[2]), Ok(30));
+        assert_eq!(run(SWITCH, &[3]), Ok(-1));
+        assert_eq!(run(SWITCH, &[-1]), Ok(-1));
+    }
+
+    #[test]
+    fn host_native_mutates_host_state() {
+        struct Counter {
+            calls: u32,
+            total: i64,
+        }
+
+        let program = Parser::parse("PUSH_INT 5\nCALL_NATIVE 2\nPUSH_INT 7\nCALL_NATIVE 2\nHALT").unwrap();
+        let mut interpreter = Interpreter::with_host(program, Counter { calls: 0, total: 0 })
+            .with_native(2, 1, |counter, args| {
+                counter.calls += 1;
+                counter.total += args[0];
+            });
+        interpreter.execute().unwrap();
+        let counter = interpreter.into_host();
+        assert_eq!((counter.calls, counter.total), (2, 12));
+    }
+
+    #[test]
+    fn step_hook_fires_once_per_executed_instruction() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // The jump skips pc 2, which must not be reported
+        let program = Parser::parse("PUSH_INT 1\nJUMP 3\nPUSH_INT 2\nPUSH_INT 3\nADD\nHALT").unwrap();
+        let retired = Rc::new(RefCell::new(Vec::new()));
+        let log = Rc::clone(&retired);
+        let mut interpreter = Interpreter::new(program);
+        interpreter.set_on_step(Box::new(move |pc, instruction, stack| {
+            log.borrow_mut().push((pc, instruction.opcode().mnemonic(), stack.to_vec()));
+        }));
+        assert_eq!(interpreter.execute(), Ok(4));
+        assert_eq!(
+            *retired.borrow(),
+            [
+                (0, "PUSH_INT", vec![1]),
+                (1, "JUMP", vec![1]),
+                (3, "PUSH_INT", vec![1, 3]),
+                (4, "ADD", vec![4]),
+                (5, "HALT", vec![4]),
+            ]
+        );
+    }
+
+    #[test]
+    fn read_cycles_never_decreases() {
+        // 1 if the first read is later than the second
+        let source = "READ_CYCLES\nREAD_CYCLES\nGT\nRETURN";
+        for _ in 0..100 {
+            assert_eq!(run(source, &[]), Ok(0));
+        }
+    }
+
+    #[test]
+    fn panicking_native_is_reported() {
+        let program = Parser::parse("PUSH_INT 3\nCALL_NATIVE 9\nHALT").unwrap();
+        let mut interpreter = Interpreter::new(program).with_native(9, 1, |_, args| {
+            panic!("native rejected {}", args[0]);
+        });
+        assert_eq!(
+            interpreter.execute(),
+            Err(InterpreterError::NativePanicked { id: 9, message: "native rejected 3".to_string() })
+        );
+    }
+
+    fn run_i32(source: &str, input: &[i64]) -> Result<i64, InterpreterError> {
+        let program = Parser::parse(source).expect("program parses");
+        Interpreter::new(program).with_value_type::<i32>().execute_with_input(input)
+    }
+
+    #[test]
+    fn i32_arithmetic_wraps_at_32_bits() {
+        assert_eq!(run_i32("PUSH_INT 2147483647\nPUSH_INT 1\nADD", &[]), Ok(i32::MIN.into()));
+        assert_eq!(run_i32("PUSH_INT -2147483648\nPUSH_INT 1\nSUB", &[]), Ok(i32::MAX.into()));
+        assert_eq!(run_i32("PUSH_INT 65536\nPUSH_INT 65536\nMUL", &[]), Ok(0));
+        assert_eq!(run_i32("PUSH_INT -2147483648\nPUSH_INT -1\nDIV", &[]), Ok(i32::MIN.into()));
+        // The same programs don't wrap with i64 values
+        assert_eq!(run("PUSH_INT 2147483647\nPUSH_INT 1\nADD", &[]), Ok(1 << 31));
+        assert_eq!(run("PUSH_INT 65536\nPUSH_INT 65536\nMUL", &[]), Ok(1 << 32));
+    }
+
+    #[test]
+    fn i32_constants_and_input_are_truncated() {
+        assert_eq!(run_i32("PUSH_INT 4294967301", &[]), Ok(5));
+        assert_eq!(run_i32(".args 1\nRETURN", &[(1 << 32) | 7]), Ok(7));
+    }
+
+    #[test]
+    fn i32_shifts_use_32_bit_counts_and_fill() {
+        assert_eq!(run_i32("PUSH_INT -1\nSHR_IMM 28", &[]), Ok(15));
+        assert_eq!(run_i32("PUSH_INT 1\nSHL_IMM 31", &[]), Ok(i32::MIN.into()));
+        // Counts are taken modulo 32
+        assert_eq!(run_i32("PUSH_INT 1\nSHL_IMM 33", &[]), Ok(2));
+        assert_eq!(run_i32("PUSH_INT -8\nSAR_IMM 1", &[]), Ok(-4));
+    }
+
+    #[test]
+    fn i32_mem_add_wraps_in_memory() {
+        let source = ".memory 1\nPUSH_INT 2147483647\nSTORE 0\nMEM_ADD 0 1\nLOAD 0";
+        assert_eq!(run_i32(source, &[]), Ok(i32::MIN.into()));
+    }
+
+    #[test]
+    fn checkpoints_restore_only_into_the_same_width() {
+        let program = Parser::parse("PUSH_INT 2147483647\nPUSH_INT 1\nADD\nHALT").unwrap();
+        let mut narrow = Interpreter::new(program.clone()).with_value_type::<i32>();
+        assert_eq!(narrow.run_for(1), Ok(RunState::Yielded));
+        let checkpoint = narrow.checkpoint();
+        assert_eq!(checkpoint.value_bits(), 32);
+
+        let mut wide = Interpreter::new(program.clone());
+        assert!(matches!(
+            wide.restore(checkpoint.clone()),
+            Err(CheckpointError::WidthMismatch { expected: 64, found: 32 })
+        ));
+
+        let mut resumed = Interpreter::new(program).with_value_type::<i32>();
+        resumed.restore(checkpoint).unwrap();
+        assert_eq!(resumed.execute(), Ok(i32::MIN.into()));
+    }
+
+    #[test]
+    fn run_for_in_small_budgets_matches_an_uninterrupted_run() {
+        let source = include_str!("../examples/countdown.cinder");
+        let uninterrupted = run(source, &[]).unwrap();
+
+        for budget in [1, 3, 7] {
+            let mut interpreter = Interpreter::new(Parser::parse(source).unwrap());
+            let mut slices = 0;
+            let result = loop {
+                slices += 1;
+                match interpreter.run_for(budget) {
+                    Ok(RunState::Yielded) => continue,
+                    Ok(RunState::Halted(result)) => break result,
+                    other => panic!("budget {}: {:?}", budget, other),
+                }
+            };
+            assert_eq!(result, uninterrupted, "budget {}", budget);
+            assert!(slices > 10, "budget {} finished in {} slices", budget, slices);
+        }
+    }
+
+    fn detecting_loops(source: &str) -> Interpreter {
+        Interpreter::new(Parser::parse(source).expect("program parses")).with_loop_detection()
+    }
+
+    #[test]
+    fn pure_infinite_loop_is_detected() {
+        let mut interpreter = detecting_loops("PUSH_INT 1\nspin:\nPUSH_INT 2\nPOP\nJUMP spin\n");
+        assert_eq!(interpreter.execute(), Err(InterpreterError::DetectedInfiniteLoop(1)));
+    }
+
+    #[test]
+    fn loops_that_change_state_or_call_natives_are_not_flagged() {
+        let countdown = include_str!("../examples/countdown.cinder");
+        assert_eq!(detecting_loops(countdown).execute(), Ok(55));
+
+        // PRINT_STR has no implementation, so the loop prints nothing
+        let mut calling = detecting_loops("spin:\nCALL_NATIVE 2\nJUMP spin\n");
+        assert_eq!(calling.run_for(10_000), Ok(RunState::Yielded));
+    }
+
+    #[test]
+    fn replay_pushes_recorded_native_results() {
+        let call = NativeCall::parse("16 5 -> 42").unwrap();
+        assert_eq!(call, NativeCall { id: natives::KV_GET, args: vec![5], result: Some(42) });
+        assert_eq!(call.to_string(), "16 5 -> 42");
+
+        // The store is never consulted: the log stands in for it
+        let program = crate::cinder_program!(push_int 5; call_native 16; push_int 1; add; return);
+        let mut interpreter = Interpreter::new(program).replay_natives(vec![call]);
+        assert_eq!(interpreter.execute(), Ok(43));
+    }
+
+    #[test]
+    fn strict_memory_rejects_loads_past_memory_size() {
+        let program = Parser::parse(".memory 16\nLOAD 500\nRETURN\n").unwrap();
+        // The lenient default rounds memory up to 1024 slots and hides the bug
+        assert_eq!(Interpreter::new(program.clone()).execute(), Ok(0));
+        assert_eq!(
+            Interpreter::strict(program).execute(),
+            Err(InterpreterError::InvalidMemoryAccess(500))
+        );
+    }
+
+    #[test]
+    fn trap_surfaces_its_code() {
+        assert_eq!(run("PUSH_INT 1\nTRAP 7\nRETURN\n", &[]), Err(InterpreterError::Trap(7)));
+        // Only a trap that executes aborts
+        assert_eq!(run("PUSH_INT 1\nJNZ done\nTRAP 7\ndone:\nPUSH_INT 5\nRETURN\n", &[]), Ok(5));
+    }
+}
@@ -1,141 +1,790 @@
-use crate::bytecode::{Instruction, Program};
+use crate::bytecode::{BoolRepr, Instruction, JumpTable, Program};
+use crate::error::CinderError;
+use crate::jit::cpu::CpuFeatures;
 use crate::jit::memory::{ExecutableMemory, MemoryError};
-use crate::sandbox::Sandbox;
-use anyhow::{Context, Result};
+use crate::natives::{self, NativeFn, NativeFunction, SharedStore};
+use crate::sandbox::{Sandbox, ValidatedProgram};
+use std::sync::Arc;
+
+type Result<T, E = CodegenError> = std::result::Result<T, E>;
+
+/// Errors specific to machine code generation
+#[derive(Debug, thiserror::Error)]
+pub enum CodegenError {
+    #[error("Generated code size {size} exceeds limit of {limit} bytes")]
+    CodeSizeExceeded { size: usize, limit: usize },
+    #[error("Operand {operand} of instruction {pc} does not fit in 32 bits (limit {limit})")]
+    OperandOutOfRange { pc: usize, operand: i64, limit: i64 },
+    #[error("Memory offset {offset} of instruction {pc} is beyond the 32-bit frame displacement")]
+    OffsetTooLarge { pc: usize, offset: usize },
+    #[error("Memory of {slots} slots exceeds the {limit} slots compiled code keeps on the stack")]
+    FrameTooLarge { slots: usize, limit: usize },
+    #[error("JIT supports at most one return value, program declares {returns}")]
+    TooManyReturns { returns: usize },
+    #[error("Too many arguments: {args}")]
+    TooManyArguments { args: usize },
+    #[error("Invalid memory access: offset {offset}")]
+    InvalidMemoryAccess { offset: usize },
+    #[error("Disallowed native call: {id}")]
+    DisallowedNative { id: u32 },
+    #[error("Instruction {pc} ({instruction}) has no machine code implementation")]
+    Unsupported { pc: usize, instruction: String },
+    #[error("Code compiled for {required} cannot run on this CPU ({available})")]
+    CpuFeaturesUnavailable { required: CpuFeatures, available: CpuFeatures },
+    #[error("Cannot allocate executable memory")]
+    Memory(#[from] MemoryError),
+}
+
+/// Abnormal end of compiled code, reported by `CompiledProgram::call_checked`
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum JitFault {
+    #[error("Stack imbalance: compiled code returned with a corrupted frame")]
+    StackImbalance,
+    #[error("Program trapped with code {0}")]
+    Trap(u32),
+    #[error("Division by zero")]
+    DivisionByZero,
+    #[error("Indirect memory access out of bounds")]
+    OutOfBounds,
+    #[error("Native function {id} panicked: {message}")]
+    NativePanicked { id: u32, message: String },
+    #[error("Native function {id} needs a deterministic key-value store in deterministic mode")]
+    NondeterministicStore { id: u32 },
+}
+
+/// Written below the saved registers by `check_stack` code and verified on return
+const STACK_SENTINEL: u64 = 0x5AFE_C1DE_5AFE_C1DE;
+
+/// Status word bits: the stack check failed
+const STATUS_IMBALANCE: u64 = 1;
+/// Status word bits: `TRAP` ran, with its code in the high 32 bits
+const STATUS_TRAP: u64 = 2;
+/// Status word bits: `DIV` found a zero divisor
+const STATUS_DIVISION_BY_ZERO: u64 = 4;
+/// Status word bits: `LOAD_IND`/`STORE_IND` got an offset outside memory
+const STATUS_OUT_OF_BOUNDS: u64 = 8;
+/// Status word bits: a native panicked, with its ID in the high 32 bits
+const STATUS_NATIVE_PANIC: u64 = 16;
+/// Status word bits: a storage native was refused the store, with its ID
+/// in the high 32 bits
+const STATUS_NONDETERMINISTIC_STORE: u64 = 32;
+/// Status word bits `call_native` sets; compiled code branches to the
+/// native fault stub on any of them
+const STATUS_NATIVE_FAULT: u64 = STATUS_NATIVE_PANIC | STATUS_NONDETERMINISTIC_STORE;
+
+/// Largest `memory_size` the JIT allocates in the native stack frame
+/// (512 KiB), leaving room on default-sized thread stacks
+pub const MAX_FRAME_SLOTS: usize = 1 << 16;
+
+/// Options controlling code generation
+#[derive(Debug, Clone, Default)]
+pub struct JitOptions {
+    /// Reject programs whose native code would exceed this many bytes
+    pub max_code_size: Option<usize>,
+    /// Value comparisons and BOOL/NOT_BOOL push for true
+    pub bool_repr: BoolRepr,
+    /// Verify the frame sentinel and stack pointer on return (see
+    /// `CompiledProgram::call_checked`)
+    pub check_stack: bool,
+    /// Extensions the generated code may use; baseline by default, pass
+    /// `CpuFeatures::detect()` to opt in. With `lzcnt`, BOOL and NOT_BOOL
+    /// are branchless and flag-free; `popcnt` and `bmi2` have no user yet.
+    pub cpu_features: CpuFeatures,
+}
+
+/// Generated machine code, not yet placed in executable memory
+#[derive(Debug, Clone)]
+pub struct CompiledCode {
+    pub bytes: Vec<u8>,
+    /// Offset in `bytes` where execution starts (the prologue)
+    pub entry: usize,
+    /// `(pc, native offset)` for every bytecode instruction
+    pub line_map: Vec<(usize, usize)>,
+    /// Offset of the epilogue run when control falls off the last instruction
+    pub epilogue: usize,
+    /// Offsets in `bytes` of 8-byte absolute addresses (native entry
+    /// points), which differ between processes
+    pub relocations: Vec<usize>,
+    /// Arguments the entry point reads (`.args`)
+    pub args: usize,
+    /// Compiled with `JitOptions::check_stack`
+    pub stack_checked: bool,
+    /// `JitOptions::cpu_features` the code was compiled for
+    pub cpu_features: CpuFeatures,
+}
+
+/// Stands in for absolute addresses in `CompiledCode::normalized`
+pub const ADDRESS_PLACEHOLDER: u64 = 0xADD2_E55A_ADD2_E55A;
+
+impl CompiledCode {
+    /// `bytes` with every relocation replaced by `ADDRESS_PLACEHOLDER`, so
+    /// the same program compiles to the same bytes on every run
+    pub fn normalized(&self) -> Vec<u8> {
+        let mut bytes = self.bytes.clone();
+        for &at in &self.relocations {
+            bytes[at..at + 8].copy_from_slice(&ADDRESS_PLACEHOLDER.to_le_bytes());
+        }
+        bytes
+    }
+
+    /// Metadata describing this code, without the bytes
+    pub fn metadata(&self) -> CompiledMetadata {
+        CompiledMetadata {
+            entry: self.entry,
+            line_map: self.line_map.clone(),
+            code_size: self.bytes.len(),
+            args: self.args,
+            stack_checked: self.stack_checked,
+            cpu_features: self.cpu_features,
+        }
+    }
+}
+
+/// Compilation metadata, separable from the executable pages so it can be
+/// cached and compared
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CompiledMetadata {
+    /// Offset of the entry point within the code
+    pub entry: usize,
+    /// `(pc, native offset)` for every bytecode instruction
+    pub line_map: Vec<(usize, usize)>,
+    /// Size of the generated code in bytes
+    pub code_size: usize,
+    /// Arguments the entry point reads (`.args`)
+    pub args: usize,
+    /// Compiled with `JitOptions::check_stack`
+    pub stack_checked: bool,
+    /// Extensions the code requires; check `CpuFeatures::runs_on` before
+    /// reusing cached code on another machine
+    pub cpu_features: CpuFeatures,
+}
+
+/// Signature of the code emitted by `JitCompiler`: prologue, body, epilogue
+/// returning the program's result in RAX. The prologue pushes the program's
+/// declared `.args` values from the array onto the VM stack, first deepest.
+/// The caller zeroes `status`; code compiled with `check_stack` sets
+/// `STATUS_IMBALANCE` in it if the frame was damaged, `TRAP` sets
+/// `STATUS_TRAP` with its code in the high 32 bits, `DIV` by zero sets
+/// `STATUS_DIVISION_BY_ZERO`, an out-of-range indirect access
+/// `STATUS_OUT_OF_BOUNDS` and `call_native` a `STATUS_NATIVE_FAULT` bit.
+type EntryFn = unsafe extern "C" fn(args: *const i64, status: *mut JitStatus) -> i64;
+
+/// What compiled code reports besides its result, and the host state its
+/// natives run against. The generated code only knows `word`, at offset 0;
+/// `panic` is filled in by `call_native`.
+#[repr(C)]
+struct JitStatus<'a> {
+    word: u64,
+    panic: Option<String>,
+    store: &'a SharedStore,
+    /// Storage natives fail unless `store` is declared deterministic
+    deterministic: bool,
+}
+
+/// Every `CALL_NATIVE` in compiled code goes through here, so a panicking
+/// native unwinds no further than this frame. The panic is recorded in
+/// `status` and the generated code returns through the native fault stub.
+extern "C" fn call_native(native: &NativeFunction, first: i64, second: i64, status: &mut JitStatus) -> i64 {
+    if matches!(native.func, NativeFn::Storage(_)) && status.deterministic && !status.store.is_deterministic() {
+        status.word |= (u64::from(native.id) << 32) | STATUS_NONDETERMINISTIC_STORE;
+        return 0;
+    }
+    match native.call(&[first, second][..native.args()], status.store) {
+        Ok(result) => result.unwrap_or(0),
+        Err(message) => {
+            status.word |= (u64::from(native.id) << 32) | STATUS_NATIVE_PANIC;
+            status.panic = Some(message);
+            0
+        }
+    }
+}
+
+/// Compiled program in executable memory, callable through safe entry points.
+/// Only `JitCompiler` can construct one, so the entry signature always matches
+/// what was emitted.
+pub struct CompiledProgram {
+    memory: ExecutableMemory,
+    metadata: CompiledMetadata,
+    /// Where `kv_get` and `kv_put` read and write
+    store: SharedStore,
+    /// Compiled under a deterministic policy
+    deterministic: bool,
+}
+
+impl CompiledProgram {
+    /// Back `kv_get` and `kv_put` with `store` instead of this program's
+    /// own empty `MemoryStore`. If the program was compiled under a
+    /// deterministic policy, the store must be declared deterministic.
+    pub fn with_store(mut self, store: SharedStore) -> Self {
+        self.store = store;
+        self
+    }
+
+    /// The store the storage natives use
+    pub fn store(&self) -> &SharedStore {
+        &self.store
+    }
+
+    /// Execute the compiled code and return its result
+    ///
+    /// # Panics
+    ///
+    /// If the program declares arguments; use `run_with_args`.
+    pub fn run(&self) -> i64 {
+        self.run_with_args(&[])
+    }
+
+    /// Execute with `args` on the VM stack (first element deepest), like
+    /// `Interpreter::execute_with_input`. A `TRAP` or a panicking native
+    /// returns 0 here; use `call_checked` to tell it apart.
+    ///
+    /// # Panics
+    ///
+    /// If `args.len()` differs from the program's `.args` count.
+    pub fn run_with_args(&self, args: &[i64]) -> i64 {
+        self.enter(args).0
+    }
+
+    /// Execute like `run_with_args`, failing with `JitFault::Trap` if the
+    /// program trapped, `JitFault::DivisionByZero` if it divided by zero and
+    /// `JitFault::OutOfBounds` if an indirect access left memory and
+    /// `JitFault::NativePanicked` if a native panicked, or
+    /// `JitFault::NondeterministicStore` if a storage native was refused a
+    /// store not declared deterministic. Code compiled with `JitOptions::check_stack` also
+    /// fails with `JitFault::StackImbalance` if the epilogue found the frame
+    /// sentinel overwritten or the stack pointer above the VM stack floor,
+    /// which means the generated code pushed and popped unevenly.
+    ///
+    /// # Panics
+    ///
+    /// If `args.len()` differs from the program's `.args` count.
+    pub fn call_checked(&self, args: &[i64]) -> Result<i64, JitFault> {
+        let (result, status) = self.enter(args);
+        if status.word & STATUS_IMBALANCE != 0 {
+            Err(JitFault::StackImbalance)
+        } else if status.word & STATUS_TRAP != 0 {
+            Err(JitFault::Trap((status.word >> 32) as u32))
+        } else if status.word & STATUS_DIVISION_BY_ZERO != 0 {
+            Err(JitFault::DivisionByZero)
+        } else if status.word & STATUS_OUT_OF_BOUNDS != 0 {
+            Err(JitFault::OutOfBounds)
+        } else if status.word & STATUS_NONDETERMINISTIC_STORE != 0 {
+            Err(JitFault::NondeterministicStore { id: (status.word >> 32) as u32 })
+        } else if status.word & STATUS_NATIVE_PANIC != 0 {
+            Err(JitFault::NativePanicked {
+                id: (status.word >> 32) as u32,
+                message: status.panic.unwrap_or_default(),
+            })
+        } else {
+            Ok(result)
+        }
+    }
+
+    /// Call the entry point, returning its result and status
+    fn enter(&self, args: &[i64]) -> (i64, JitStatus<'_>) {
+        assert_eq!(
+            args.len(),
+            self.metadata.args,
+            "program expects {} argument(s)",
+            self.metadata.args
+        );
+        // SAFETY: the memory holds code emitted by JitCompiler with the
+        // `EntryFn` signature at offset `entry` (always 0 today), the
+        // program passed sandbox validation before emission, and the
+        // prologue reads exactly `metadata.args` values from `args`;
+        // `status` outlives the call.
+        let mut status = JitStatus {
+            word: 0,
+            panic: None,
+            store: &self.store,
+            deterministic: self.deterministic,
+        };
+        let result = unsafe {
+            debug_assert_eq!(self.metadata.entry, 0);
+            let func: EntryFn = self.memory.as_function();
+            func(args.as_ptr(), &mut status)
+        };
+        (result, status)
+    }
+
+    /// Generated machine code
+    pub fn code(&self) -> &[u8] {
+        // SAFETY: the region is `size()` bytes, owned by `self` and fully
+        // written before construction.
+        unsafe { std::slice::from_raw_parts(self.memory.as_ptr(), self.memory.size()) }
+    }
+
+    /// Offset of the entry point within `code()`
+    pub fn entry(&self) -> usize {
+        self.metadata.entry
+    }
+
+    /// Line map, size and entry offset of the generated code
+    pub fn metadata(&self) -> &CompiledMetadata {
+        &self.metadata
+    }
+
+    /// Pin the code behind a reference count so it can be called from many
+    /// threads; the pages stay mapped until the last handle is dropped
+    pub fn into_shared(self) -> SharedCompiled {
+        SharedCompiled {
+            program: Arc::new(self),
+        }
+    }
+}
+
+/// Cloneable, thread-safe handle to compiled code.
+///
+/// Concurrent calls are sound because the emitted code is reentrant: the
+/// VM stack and memory slots live in each call's native stack frame, and
+/// the only global state it touches is through natives. A future data
+/// region shared between calls would break this; it would have to be
+/// allocated per call (or per thread) instead.
+#[derive(Clone)]
+pub struct SharedCompiled {
+    program: Arc<CompiledProgram>,
+}
+
+impl SharedCompiled {
+    /// Execute the compiled code and return its result
+    ///
+    /// # Panics
+    ///
+    /// If the program declares arguments; use `call_with_args`.
+    pub fn call(&self) -> i64 {
+        self.program.run()
+    }
+
+    /// Execute with `args` on the VM stack, see `CompiledProgram::run_with_args`
+    pub fn call_with_args(&self, args: &[i64]) -> i64 {
+        self.program.run_with_args(args)
+    }
+
+    /// The underlying compiled program
+    pub fn program(&self) -> &CompiledProgram {
+        &self.program
+    }
+}
+
+/// A 32-bit branch displacement or table entry to patch once the
+/// native offset of its target is known
+struct Fixup {
+    /// Position of the 4-byte field in the code buffer
+    at: usize,
+    target: FixupTarget,
+    /// Native offset the stored value is relative to
+    relative_to: usize,
+}
+
+/// What a `Fixup` points at
+#[derive(Debug, Clone, Copy)]
+enum FixupTarget {
+    /// A bytecode instruction
+    Instruction(usize),
+    /// The fault stub shared by every `DIV`, emitted after the epilogue
+    DivisionFault,
+    /// The fault stub shared by every `LOAD_IND` and `STORE_IND`
+    BoundsFault,
+    /// The stub every `CALL_NATIVE` branches to when the native panicked
+    NativeFault,
+}
 
 /// JIT compiler for x86-64 machine code generation
 pub struct JitCompiler {
     program: Program,
     sandbox: Sandbox,
+    options: JitOptions,
+    /// Policy hash the program is known to be valid under
+    validated_under: Option<u64>,
+    /// Extensions of the CPU `compile` loads code for
+    host_features: CpuFeatures,
 }
 
 impl JitCompiler {
+    /// Create a compiler for an unchecked program; it is validated on the first `compile`
     pub fn new(program: Program) -> Self {
         Self {
-            sandbox: Sandbox::new(&program),
             program,
+            sandbox: Sandbox::new(),
+            options: JitOptions::default(),
+            validated_under: None,
+            host_features: CpuFeatures::detect(),
+        }
+    }
+
+    /// Create a compiler for a program validated ahead of time.
+    /// Validation is only repeated if `sandbox` differs from the policy used.
+    pub fn from_validated(validated: ValidatedProgram, sandbox: Sandbox) -> Self {
+        let validated_under = Some(validated.policy_hash());
+        Self {
+            program: validated.into_program(),
+            sandbox,
+            options: JitOptions::default(),
+            validated_under,
+            host_features: CpuFeatures::detect(),
+        }
+    }
+
+    pub fn with_options(mut self, options: JitOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    /// Validate and compile under `sandbox` instead of the default policy
+    pub fn with_sandbox(mut self, sandbox: Sandbox) -> Self {
+        self.sandbox = sandbox;
+        self
+    }
+
+    /// Check `compile` against `host` instead of the detected features, so
+    /// tests can take either encoding on any machine
+    #[cfg(test)]
+    fn with_host_features(mut self, host: CpuFeatures) -> Self {
+        self.host_features = host;
+        self
+    }
+
+    /// Compile program to machine code in executable memory. Fails with
+    /// `CodegenError::CpuFeaturesUnavailable` if `JitOptions::cpu_features`
+    /// asks for an extension this CPU lacks; `compile_to_vec` doesn't check.
+    pub fn compile(&mut self) -> Result<CompiledProgram, CinderError> {
+        let required = self.options.cpu_features;
+        if !required.runs_on(&self.host_features) {
+            return Err(CodegenError::CpuFeaturesUnavailable { required, available: self.host_features }.into());
         }
+        let compiled = self.compile_to_vec()?;
+
+        let mut memory = ExecutableMemory::allocate(compiled.bytes.len())
+            .map_err(CodegenError::from)?;
+        unsafe { memory.write(0, &compiled.bytes).map_err(CodegenError::from)? };
+        memory.seal().map_err(CodegenError::from)?;
+
+        Ok(CompiledProgram {
+            memory,
+            metadata: compiled.metadata(),
+            store: SharedStore::default(),
+            deterministic: self.sandbox.is_deterministic(),
+        })
     }
 
-    /// Compile program to machine code and return executable memory
-    pub fn compile(&mut self) -> Result<ExecutableMemory> {
-        // Validate program before compilation
-        self.sandbox.validate()?;
+    /// Compile program to a relocatable byte buffer, without allocating executable memory
+    pub fn compile_to_vec(&mut self) -> Result<CompiledCode, CinderError> {
+        // Validate program before compilation, unless already done under this policy
+        let policy_hash = self.sandbox.policy_hash();
+        if self.validated_under != Some(policy_hash) {
+            self.sandbox.validate(&self.program)?;
+            self.validated_under = Some(policy_hash);
+        }
 
-        // Estimate required code size
-        let estimated_size = self.estimate_code_size();
-        let mut memory = ExecutableMemory::allocate(estimated_size)
-            .context("Cannot allocate executable memory")?;
+        // Only one value fits in RAX; there is no results buffer to spill into yet
+        if let Some(returns) = self.program.returns {
+            if returns > 1 {
+                return Err(CodegenError::TooManyReturns { returns }.into());
+            }
+        }
 
         // Generate machine code
-        let mut offset = 0;
-        offset = unsafe { self.emit_prologue(&mut memory, offset)? };
-        
+        let mut code = Vec::with_capacity(self.capacity_hint());
+        let entry = code.len();
+        self.emit_prologue(&mut code)?;
+
+        let mut line_map = Vec::with_capacity(self.program.instructions.len());
+        let mut fixups = Vec::new();
+        let mut relocations = Vec::new();
         for (idx, instruction) in self.program.instructions.iter().enumerate() {
-            offset = self.emit_instruction(&mut memory, offset, instruction, idx)?;
+            line_map.push((idx, code.len()));
+            self.emit_instruction(&mut code, &mut fixups, &mut relocations, idx, instruction)?;
+        }
+
+        // Falling off the end returns the top of the stack, or the 0
+        // pushed below the VM stack if it is empty, like the interpreter
+        let epilogue = code.len();
+        self.emit_return(&mut code)?;
+
+        let division_fault = code.len();
+        if fixups.iter().any(|fixup| matches!(fixup.target, FixupTarget::DivisionFault)) {
+            self.emit_fault(&mut code, STATUS_DIVISION_BY_ZERO)?;
+        }
+        let bounds_fault = code.len();
+        if fixups.iter().any(|fixup| matches!(fixup.target, FixupTarget::BoundsFault)) {
+            self.emit_fault(&mut code, STATUS_OUT_OF_BOUNDS)?;
+        }
+        let native_fault = code.len();
+        if fixups.iter().any(|fixup| matches!(fixup.target, FixupTarget::NativeFault)) {
+            // `call_native` already set the status bit and the native's ID
+            self.emit_fault(&mut code, 0)?;
         }
-        
-        offset = unsafe { self.emit_epilogue(&mut memory, offset)? };
 
-        Ok(memory)
+        // Second pass: branch targets now have known native offsets
+        for fixup in fixups {
+            let target = match fixup.target {
+                FixupTarget::Instruction(pc) => line_map[pc].1,
+                FixupTarget::DivisionFault => division_fault,
+                FixupTarget::BoundsFault => bounds_fault,
+                FixupTarget::NativeFault => native_fault,
+            };
+            let value = target as i64 - fixup.relative_to as i64;
+            // The instruction that emitted the field, for the error
+            let pc = line_map.partition_point(|&(_, offset)| offset <= fixup.at) - 1;
+            let value = narrow_i32(pc, value)?;
+            code[fixup.at..fixup.at + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        // Checked before any executable memory is allocated
+        self.check_code_size(code.len())?;
+
+        Ok(CompiledCode {
+            bytes: code,
+            entry,
+            line_map,
+            epilogue,
+            relocations,
+            args: self.program.args,
+            stack_checked: self.options.check_stack,
+            cpu_features: self.options.cpu_features,
+        })
     }
 
-    /// Estimate generated code size
-    fn estimate_code_size(&self) -> usize {
-        // Conservative estimate: ~20 bytes per instruction
-        self.program.instructions.len() * 20 + 100
+    /// Buffer size to reserve for the generated code: roughly its length,
+    /// not a bound on it, so limits are checked against what was emitted
+    fn capacity_hint(&self) -> usize {
+        let table_entries: usize = self.program.instructions.iter()
+            .map(|instruction| match instruction {
+                Instruction::JumpTable(table) => table.targets.len() * 4,
+                _ => 0,
+            })
+            .sum();
+        self.program.instructions.len() * 20 + table_entries + 100
+    }
+
+    /// Check the emitted code size against `max_code_size`
+    fn check_code_size(&self, size: usize) -> Result<()> {
+        if let Some(limit) = self.options.max_code_size {
+            if size > limit {
+                return Err(CodegenError::CodeSizeExceeded { size, limit });
+            }
+        }
+        Ok(())
     }
 
     /// Emit function prologue (stack setup, etc.)
-    unsafe fn emit_prologue(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_prologue(&self, code: &mut Vec<u8>) -> Result<()> {
         // push rbp
         code.push(0x55);
         // mov rbp, rsp
         code.extend_from_slice(&[0x48, 0x89, 0xE5]);
         
-        // Allocate space for local stack (16 bytes for alignment)
-        // sub rsp, 16
-        code.extend_from_slice(&[0x48, 0x83, 0xEC, 0x10]);
+        // rbx is callee-saved but used as a scratch register below
+        // push rbx
+        code.push(0x53);
+        
+        // Keep the status pointer (second argument) for TRAP and the epilogue
+        #[cfg(not(windows))]
+        code.push(0x56); // push rsi
+        #[cfg(windows)]
+        code.push(0x52); // push rdx
+        
+        if self.options.check_stack {
+            // mov rax, sentinel; push rax
+            code.extend_from_slice(&[0x48, 0xB8]);
+            code.extend_from_slice(&STACK_SENTINEL.to_le_bytes());
+            code.push(0x50);
+        }
+        
+        // Zeroed memory slots below the saved registers, slot 0 highest
+        let slots = self.program.memory_size;
+        if slots > MAX_FRAME_SLOTS {
+            return Err(CodegenError::FrameTooLarge { slots, limit: MAX_FRAME_SLOTS });
+        }
+        if slots > 0 {
+            // xor eax, eax
+            code.extend_from_slice(&[0x31, 0xC0]);
+            // mov r11d, slots
+            code.extend_from_slice(&[0x41, 0xBB]);
+            let count = u32::try_from(slots)
+                .map_err(|_| CodegenError::FrameTooLarge { slots, limit: MAX_FRAME_SLOTS })?;
+            code.extend_from_slice(&count.to_le_bytes());
+            // push rax; dec r11; jnz back to the push
+            code.extend_from_slice(&[0x50, 0x49, 0xFF, 0xCB, 0x75, 0xFA]);
+        }
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        // `.data` values over the zeroed slots (the sandbox keeps them in range)
+        for block in &self.program.data {
+            for (offset, &value) in (block.offset as usize..).zip(&block.values) {
+                if value == 0 {
+                    continue;
+                }
+                let disp = self.memory_displacement(0, offset)?;
+                // mov rax, value
+                code.extend_from_slice(&[0x48, 0xB8]);
+                code.extend_from_slice(&value.to_le_bytes());
+                // mov [rbp + disp32], rax
+                code.extend_from_slice(&[0x48, 0x89, 0x85]);
+                code.extend_from_slice(&disp.to_le_bytes());
+            }
+        }
+        
+        // push 0: popping an empty VM stack yields 0, as in the interpreter
+        code.extend_from_slice(&[0x6A, 0x00]);
+        
+        // Push the arguments from the array in the first argument register
+        for index in 0..self.program.args {
+            let disp = i32::try_from(index * 8)
+                .map_err(|_| CodegenError::TooManyArguments { args: self.program.args })?;
+            // mov rax, [rdi + disp32] / [rcx + disp32] on Windows
+            #[cfg(not(windows))]
+            code.extend_from_slice(&[0x48, 0x8B, 0x87]);
+            #[cfg(windows)]
+            code.extend_from_slice(&[0x48, 0x8B, 0x81]);
+            code.extend_from_slice(&disp.to_le_bytes());
+            // push rax
+            code.push(0x50);
+        }
+        
+        Ok(())
+    }
+
+    /// Bytes between rbp and memory slot 0: saved rbx and status pointer,
+    /// plus the sentinel under `check_stack`
+    fn frame_header(&self) -> usize {
+        if self.options.check_stack { 24 } else { 16 }
     }
 
     /// Emit function epilogue (cleanup, return)
-    unsafe fn emit_epilogue(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_epilogue(&self, code: &mut Vec<u8>) -> Result<()> {
         // Return value is in RAX (already set by instructions)
+        if self.options.check_stack {
+            self.emit_stack_check(code)?;
+        }
+        self.emit_leave(code);
+        
+        Ok(())
+    }
+
+    /// Restore the caller's rbx, rsp and rbp, and return
+    fn emit_leave(&self, code: &mut Vec<u8>) {
+        // mov rbx, [rbp - 8]
+        code.extend_from_slice(&[0x48, 0x8B, 0x5D, 0xF8]);
         // mov rsp, rbp
         code.extend_from_slice(&[0x48, 0x89, 0xEC]);
         // pop rbp
         code.push(0x5D);
         // ret
         code.push(0xC3);
+    }
+
+    /// Set `STATUS_IMBALANCE` through the saved status pointer unless the
+    /// sentinel is intact and rsp is at or below the VM stack floor.
+    /// Leaves rax (the result) untouched.
+    fn emit_stack_check(&self, code: &mut Vec<u8>) -> Result<()> {
+        let slots = self.program.memory_size;
+        let floor = slots
+            .checked_mul(8)
+            .and_then(|bytes| bytes.checked_add(self.frame_header()))
+            .and_then(|bytes| i32::try_from(bytes).ok())
+            .ok_or(CodegenError::FrameTooLarge { slots, limit: MAX_FRAME_SLOTS })?;
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        // xor edx, edx
+        code.extend_from_slice(&[0x31, 0xD2]);
+        // mov r8, sentinel
+        code.extend_from_slice(&[0x49, 0xB8]);
+        code.extend_from_slice(&STACK_SENTINEL.to_le_bytes());
+        // cmp [rbp - 24], r8
+        code.extend_from_slice(&[0x4C, 0x39, 0x45, 0xE8]);
+        // setne dl
+        code.extend_from_slice(&[0x0F, 0x95, 0xC2]);
+        // lea r8, [rbp - floor]
+        code.extend_from_slice(&[0x4C, 0x8D, 0x85]);
+        code.extend_from_slice(&(-floor).to_le_bytes());
+        // cmp rsp, r8; seta r9b (popped into the memory slots)
+        code.extend_from_slice(&[0x4C, 0x39, 0xC4]);
+        code.extend_from_slice(&[0x41, 0x0F, 0x97, 0xC1]);
+        // or dl, r9b
+        code.extend_from_slice(&[0x44, 0x08, 0xCA]);
+        // mov rcx, [rbp - 16]; or [rcx], rdx (keeping a native's panic bit)
+        code.extend_from_slice(&[0x48, 0x8B, 0x4D, 0xF0]);
+        code.extend_from_slice(&[0x48, 0x09, 0x11]);
+        
+        Ok(())
     }
 
     /// Emit code for an instruction
     fn emit_instruction(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
-        instruction: &Instruction,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
+        relocations: &mut Vec<usize>,
         pc: usize,
-    ) -> Result<usize> {
-        unsafe {
-            match instruction {
-                Instruction::PushInt(val) => self.emit_push_int(memory, offset, *val),
-                
-                Instruction::Add => self.emit_add(memory, offset),
-                Instruction::Sub => self.emit_sub(memory, offset),
-                Instruction::Mul => self.emit_mul(memory, offset),
-                Instruction::Div => self.emit_div(memory, offset),
-                
-                Instruction::Eq => self.emit_eq(memory, offset),
-                Instruction::Lt => self.emit_lt(memory, offset),
-                Instruction::Gt => self.emit_gt(memory, offset),
-                
-                Instruction::Jump(target) => {
-                    self.emit_jump(memory, offset, *target, pc)
-                }
-                
-                Instruction::JumpIfZero(target) => {
-                    self.emit_jump_if_zero(memory, offset, *target, pc)
-                }
-                
-                Instruction::JumpIfNotZero(target) => {
-                    self.emit_jump_if_not_zero(memory, offset, *target, pc)
-                }
-                
-                Instruction::Load(mem_offset) => {
-                    self.emit_load(memory, offset, *mem_offset)
-                }
-                
-                Instruction::Store(mem_offset) => {
-                    self.emit_store(memory, offset, *mem_offset)
-                }
-                
-                Instruction::CallNative(id) => {
-                    self.emit_call_native(memory, offset, *id)
-                }
-                
-                Instruction::Return => self.emit_return(memory, offset),
-                Instruction::Halt => self.emit_halt(memory, offset),
-                
-                _ => Ok(offset), // Unimplemented instructions yet
+        instruction: &Instruction,
+    ) -> Result<()> {
+        match instruction {
+            Instruction::PushInt(val) => self.emit_push_int(code, *val),
+            Instruction::Pop => self.emit_pop(code),
+            
+            Instruction::Add => self.emit_add(code),
+            Instruction::Sub => self.emit_sub(code),
+            Instruction::Mul => self.emit_mul(code),
+            Instruction::Div => self.emit_div(code, fixups),
+            Instruction::ShlImm(count) => self.emit_shift_imm(code, 0xE0, *count),
+            Instruction::ShrImm(count) => self.emit_shift_imm(code, 0xE8, *count),
+            Instruction::SarImm(count) => self.emit_shift_imm(code, 0xF8, *count),
+            
+            Instruction::Eq => self.emit_eq(code),
+            Instruction::Lt => self.emit_lt(code),
+            Instruction::Gt => self.emit_gt(code),
+            Instruction::Bool => self.emit_bool(code, false),
+            Instruction::NotBool => self.emit_bool(code, true),
+            
+            Instruction::Jump(target) => {
+                self.emit_jump(code, fixups, *target as usize)
+            }
+            
+            Instruction::JumpIfZero(target) => {
+                self.emit_jump_if_zero(code, fixups, *target as usize)
+            }
+            
+            Instruction::JumpIfNotZero(target) => {
+                self.emit_jump_if_not_zero(code, fixups, *target as usize)
+            }
+            
+            Instruction::JumpTable(table) => {
+                self.emit_jump_table(code, fixups, pc, table)
+            }
+            
+            Instruction::Load(mem_offset) => {
+                self.emit_load(code, pc, *mem_offset as usize)
             }
+            
+            Instruction::Store(mem_offset) => {
+                self.emit_store(code, pc, *mem_offset as usize)
+            }
+            
+            Instruction::MemAdd(mem_offset, imm) => {
+                self.emit_mem_add(code, pc, *mem_offset as usize, *imm)
+            }
+            
+            Instruction::LoadInd => self.emit_load_ind(code, fixups, pc),
+            Instruction::StoreInd => self.emit_store_ind(code, fixups, pc),
+            
+            Instruction::CallNative(id) => {
+                self.emit_call_native(code, fixups, relocations, pc, *id)
+            }
+            
+            Instruction::Return => self.emit_return(code),
+            Instruction::Breakpoint => self.emit_breakpoint(code),
+            Instruction::ReadCycles => self.emit_read_cycles(code),
+            Instruction::Halt => self.emit_halt(code),
+            Instruction::Trap(error_code) => self.emit_trap(code, *error_code),
+            
+            // Registers have no defined semantics yet; the interpreter rejects them too
+            Instruction::PushReg(_) => Err(CodegenError::Unsupported {
+                pc,
+                instruction: format!("{:?}", instruction),
+            }),
         }
     }
 
     // Implementations for each instruction type
-    unsafe fn emit_push_int(&self, memory: &mut ExecutableMemory, offset: usize, val: i64) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_push_int(&self, code: &mut Vec<u8>, val: i64) -> Result<()> {
         // push val (8 bytes)
         // mov rax, val
         code.extend_from_slice(&[0x48, 0xB8]);
@@ -143,13 +792,37 @@ impl JitCompiler {
         // push rax
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
+    }
+
+    fn emit_pop(&self, code: &mut Vec<u8>) -> Result<()> {
+        // add rsp, 8 (discard the top value)
+        code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x08]);
+        
+        Ok(())
     }
 
-    unsafe fn emit_add(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
+    fn emit_breakpoint(&self, code: &mut Vec<u8>) -> Result<()> {
+        // int3; without a debugger attached the process gets SIGTRAP
+        code.push(0xCC);
         
+        Ok(())
+    }
+
+    fn emit_read_cycles(&self, code: &mut Vec<u8>) -> Result<()> {
+        // rdtsc (EDX:EAX)
+        code.extend_from_slice(&[0x0F, 0x31]);
+        // shl rdx, 32
+        code.extend_from_slice(&[0x48, 0xC1, 0xE2, 0x20]);
+        // or rax, rdx
+        code.extend_from_slice(&[0x48, 0x09, 0xD0]);
+        // push rax
+        code.push(0x50);
+        
+        Ok(())
+    }
+
+    fn emit_add(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx (second operand)
         code.extend_from_slice(&[0x5B]);
         // pop rax (first operand)
@@ -159,13 +832,10 @@ impl JitCompiler {
         // push rax (result)
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_sub(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_sub(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx
         code.extend_from_slice(&[0x5B]);
         // pop rax
@@ -175,13 +845,10 @@ impl JitCompiler {
         // push rax
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_mul(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_mul(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx
         code.extend_from_slice(&[0x5B]);
         // pop rax
@@ -191,31 +858,53 @@ impl JitCompiler {
         // push rax
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_div(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    /// Signed division; a zero divisor jumps to the division fault stub
+    /// and -1 negates instead, wrapping `i64::MIN` like the interpreter
+    /// (idiv raises #DE for both)
+    fn emit_div(&self, code: &mut Vec<u8>, fixups: &mut Vec<Fixup>) -> Result<()> {
         // pop rbx (divisor)
         code.extend_from_slice(&[0x5B]);
         // pop rax (dividend)
         code.extend_from_slice(&[0x58]);
-        // cdq (extend rax to rdx:rax for signed division)
+        // test rbx, rbx; jz division fault
+        code.extend_from_slice(&[0x48, 0x85, 0xDB, 0x0F, 0x84]);
+        let at = code.len();
+        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        fixups.push(Fixup {
+            at,
+            target: FixupTarget::DivisionFault,
+            relative_to: at + 4,
+        });
+        // cmp rbx, -1; jne over the negation
+        code.extend_from_slice(&[0x48, 0x83, 0xFB, 0xFF, 0x75, 0x05]);
+        // neg rax; jmp over the division
+        code.extend_from_slice(&[0x48, 0xF7, 0xD8, 0xEB, 0x05]);
+        // cqo (extend rax to rdx:rax for signed division)
         code.extend_from_slice(&[0x48, 0x99]);
         // idiv rbx
         code.extend_from_slice(&[0x48, 0xF7, 0xFB]);
         // push rax (quotient)
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_eq(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
+    /// `modrm` selects the operation: 0xE0 shl, 0xE8 shr, 0xF8 sar (on rax)
+    fn emit_shift_imm(&self, code: &mut Vec<u8>, modrm: u8, count: u8) -> Result<()> {
+        // pop rax
+        code.push(0x58);
+        // shl/shr/sar rax, imm8 (the CPU masks to 63 as well)
+        code.extend_from_slice(&[0x48, 0xC1, modrm, count & 63]);
+        // push rax
+        code.push(0x50);
         
+        Ok(())
+    }
+
+    fn emit_eq(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx
         code.extend_from_slice(&[0x5B]);
         // pop rax
@@ -224,18 +913,12 @@ impl JitCompiler {
         code.extend_from_slice(&[0x48, 0x39, 0xD8]);
         // sete al
         code.extend_from_slice(&[0x0F, 0x94, 0xC0]);
-        // movzx rax, al
-        code.extend_from_slice(&[0x48, 0x0F, 0xB6, 0xC0]);
-        // push rax
-        code.push(0x50);
+        self.emit_push_flag(code);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_lt(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_lt(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx
         code.extend_from_slice(&[0x5B]);
         // pop rax
@@ -244,18 +927,12 @@ impl JitCompiler {
         code.extend_from_slice(&[0x48, 0x39, 0xD8]);
         // setl al
         code.extend_from_slice(&[0x0F, 0x9C, 0xC0]);
-        // movzx rax, al
-        code.extend_from_slice(&[0x48, 0x0F, 0xB6, 0xC0]);
-        // push rax
-        code.push(0x50);
+        self.emit_push_flag(code);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_gt(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_gt(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rbx
         code.extend_from_slice(&[0x5B]);
         // pop rax
@@ -264,168 +941,826 @@ impl JitCompiler {
         code.extend_from_slice(&[0x48, 0x39, 0xD8]);
         // setg al
         code.extend_from_slice(&[0x0F, 0x9F, 0xC0]);
+        self.emit_push_flag(code);
+        
+        Ok(())
+    }
+
+    /// Push the boolean in AL in the configured representation
+    fn emit_push_flag(&self, code: &mut Vec<u8>) {
         // movzx rax, al
         code.extend_from_slice(&[0x48, 0x0F, 0xB6, 0xC0]);
+        if self.options.bool_repr == BoolRepr::AllOnes {
+            // neg rax (1 -> -1, 0 -> 0)
+            code.extend_from_slice(&[0x48, 0xF7, 0xD8]);
+        }
         // push rax
         code.push(0x50);
+    }
+
+    /// Normalize the top of the stack to a boolean; `negate` flips the result
+    fn emit_bool(&self, code: &mut Vec<u8>, negate: bool) -> Result<()> {
+        // pop rax
+        code.push(0x58);
+        if self.options.cpu_features.lzcnt {
+            // lzcnt rax, rax: 64 only for zero, so bit 6 is `rax == 0`.
+            // Without LZCNT this decodes as bsr, hence the opt-in.
+            code.extend_from_slice(&[0xF3, 0x48, 0x0F, 0xBD, 0xC0]);
+            // shr rax, 6
+            code.extend_from_slice(&[0x48, 0xC1, 0xE8, 0x06]);
+            if !negate {
+                // xor eax, 1
+                code.extend_from_slice(&[0x83, 0xF0, 0x01]);
+            }
+            if self.options.bool_repr == BoolRepr::AllOnes {
+                // neg rax (1 -> -1, 0 -> 0)
+                code.extend_from_slice(&[0x48, 0xF7, 0xD8]);
+            }
+            // push rax
+            code.push(0x50);
+            return Ok(());
+        }
+        // test rax, rax
+        code.extend_from_slice(&[0x48, 0x85, 0xC0]);
+        if negate {
+            // sete al
+            code.extend_from_slice(&[0x0F, 0x94, 0xC0]);
+        } else {
+            // setne al
+            code.extend_from_slice(&[0x0F, 0x95, 0xC0]);
+        }
+        self.emit_push_flag(code);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_jump(
+    /// Emit a rel32 placeholder for `target`, relative to the end of the field
+    fn emit_rel32(&self, code: &mut Vec<u8>, fixups: &mut Vec<Fixup>, target: usize) {
+        let at = code.len();
+        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        fixups.push(Fixup {
+            at,
+            target: FixupTarget::Instruction(target),
+            relative_to: at + 4,
+        });
+    }
+
+    fn emit_jump(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
         target: usize,
-        current_pc: usize,
-    ) -> Result<usize> {
-        // For simplicity, use relative jump
-        // In complete implementation, we should calculate correct offset
-        let mut code = Vec::new();
-        
-        // jmp [relative offset]
+    ) -> Result<()> {
+        // jmp rel32
         code.push(0xE9);
-        // Placeholder for offset (will be calculated in two passes)
-        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        self.emit_rel32(code, fixups, target);
         
-        memory.write(offset, &code)?;
-        // Note: In complete implementation, we should do two passes
-        // to calculate correct offsets
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_jump_if_zero(
+    fn emit_jump_if_zero(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
         target: usize,
-        current_pc: usize,
-    ) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    ) -> Result<()> {
         // pop rax
         code.extend_from_slice(&[0x58]);
         // test rax, rax
         code.extend_from_slice(&[0x48, 0x85, 0xC0]);
-        // jz [offset]
+        // jz rel32
         code.extend_from_slice(&[0x0F, 0x84]);
-        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        self.emit_rel32(code, fixups, target);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_jump_if_not_zero(
+    fn emit_jump_if_not_zero(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
         target: usize,
-        current_pc: usize,
-    ) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    ) -> Result<()> {
         // pop rax
         code.extend_from_slice(&[0x58]);
         // test rax, rax
         code.extend_from_slice(&[0x48, 0x85, 0xC0]);
-        // jnz [offset]
+        // jnz rel32
         code.extend_from_slice(&[0x0F, 0x85]);
-        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        self.emit_rel32(code, fixups, target);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_load(
+    fn emit_jump_table(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
+        pc: usize,
+        table: &JumpTable,
+    ) -> Result<()> {
+        let len = narrow_i32(pc, i64::try_from(table.targets.len()).unwrap_or(i64::MAX))?;
+        
+        // pop rax (selector)
+        code.push(0x58);
+        // mov rcx, base
+        code.extend_from_slice(&[0x48, 0xB9]);
+        code.extend_from_slice(&table.base.to_le_bytes());
+        // sub rax, rcx
+        code.extend_from_slice(&[0x48, 0x29, 0xC8]);
+        // cmp rax, len
+        code.extend_from_slice(&[0x48, 0x3D]);
+        code.extend_from_slice(&len.to_le_bytes());
+        // jae default (unsigned, so negative indices also take it)
+        code.extend_from_slice(&[0x0F, 0x83]);
+        self.emit_rel32(code, fixups, table.default as usize);
+        
+        // lea rcx, [rip + table]
+        code.extend_from_slice(&[0x48, 0x8D, 0x0D]);
+        // movsxd (4) + add (3) + jmp (2) sit between the lea and the table
+        code.extend_from_slice(&9i32.to_le_bytes());
+        // movsxd rax, dword [rcx + rax*4]
+        code.extend_from_slice(&[0x48, 0x63, 0x04, 0x81]);
+        // add rax, rcx
+        code.extend_from_slice(&[0x48, 0x01, 0xC8]);
+        // jmp rax
+        code.extend_from_slice(&[0xFF, 0xE0]);
+        
+        // Table of target offsets, relative to the table start
+        let table_start = code.len();
+        for target in &table.targets {
+            fixups.push(Fixup {
+                at: code.len(),
+                target: FixupTarget::Instruction(*target as usize),
+                relative_to: table_start,
+            });
+            code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        }
+        
+        Ok(())
+    }
+
+    fn emit_load(
+        &self,
+        code: &mut Vec<u8>,
+        pc: usize,
         mem_offset: usize,
-    ) -> Result<usize> {
-        // Verify offset is within safe bounds
+    ) -> Result<()> {
+        // An offset past any disp32 is named as such, then the
+        // offset is verified to be within safe bounds
+        let disp = self.memory_displacement(pc, mem_offset)?;
         if mem_offset >= self.program.memory_size {
-            return Err(anyhow::anyhow!("Invalid memory access: offset {}", mem_offset));
+            return Err(CodegenError::InvalidMemoryAccess { offset: mem_offset });
         }
 
-        let mut code = Vec::new();
-        
-        // mov rax, [rbp - offset] (use local stack as memory)
-        // For simplicity, use a fixed memory area
-        // In complete implementation, we should allocate separate memory
+        // mov rax, [rbp + disp32] (memory slots live in the frame)
         code.extend_from_slice(&[0x48, 0x8B, 0x85]);
-        code.extend_from_slice(&(mem_offset as i32).to_le_bytes());
+        code.extend_from_slice(&disp.to_le_bytes());
         // push rax
         code.push(0x50);
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_store(
+    fn emit_store(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        pc: usize,
         mem_offset: usize,
-    ) -> Result<usize> {
+    ) -> Result<()> {
+        let disp = self.memory_displacement(pc, mem_offset)?;
         if mem_offset >= self.program.memory_size {
-            return Err(anyhow::anyhow!("Invalid memory access: offset {}", mem_offset));
+            return Err(CodegenError::InvalidMemoryAccess { offset: mem_offset });
         }
 
-        let mut code = Vec::new();
-        
         // pop rax
         code.extend_from_slice(&[0x58]);
-        // mov [rbp - offset], rax
+        // mov [rbp + disp32], rax
         code.extend_from_slice(&[0x48, 0x89, 0x85]);
-        code.extend_from_slice(&(mem_offset as i32).to_le_bytes());
+        code.extend_from_slice(&disp.to_le_bytes());
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_call_native(
+    fn emit_mem_add(
         &self,
-        memory: &mut ExecutableMemory,
-        offset: usize,
+        code: &mut Vec<u8>,
+        pc: usize,
+        mem_offset: usize,
+        imm: i32,
+    ) -> Result<()> {
+        let disp = self.memory_displacement(pc, mem_offset)?;
+        if mem_offset >= self.program.memory_size {
+            return Err(CodegenError::InvalidMemoryAccess { offset: mem_offset });
+        }
+
+        // add qword [rbp + disp32], imm32 (sign-extended)
+        code.extend_from_slice(&[0x48, 0x81, 0x85]);
+        code.extend_from_slice(&disp.to_le_bytes());
+        code.extend_from_slice(&imm.to_le_bytes());
+        
+        Ok(())
+    }
+
+    /// Load through an offset popped from the stack. Static `LOAD`s were
+    /// proven in range by the sandbox and need no check; this one compares
+    /// at run time and jumps to the bounds fault stub.
+    fn emit_load_ind(&self, code: &mut Vec<u8>, fixups: &mut Vec<Fixup>, pc: usize) -> Result<()> {
+        // pop rax (offset)
+        code.push(0x58);
+        self.emit_bounds_check(code, fixups, pc)?;
+        // mov rax, [rbp + rax*8 + disp32]
+        let disp = self.memory_displacement(pc, 0)?;
+        code.extend_from_slice(&[0x48, 0x8B, 0x84, 0xC5]);
+        code.extend_from_slice(&disp.to_le_bytes());
+        // push rax
+        code.push(0x50);
+        
+        Ok(())
+    }
+
+    /// Store the top of the stack through the offset below it, checked like `emit_load_ind`
+    fn emit_store_ind(&self, code: &mut Vec<u8>, fixups: &mut Vec<Fixup>, pc: usize) -> Result<()> {
+        // pop rbx (value)
+        code.push(0x5B);
+        // pop rax (offset)
+        code.push(0x58);
+        self.emit_bounds_check(code, fixups, pc)?;
+        // mov [rbp + rax*8 + disp32], rbx
+        let disp = self.memory_displacement(pc, 0)?;
+        code.extend_from_slice(&[0x48, 0x89, 0x9C, 0xC5]);
+        code.extend_from_slice(&disp.to_le_bytes());
+        
+        Ok(())
+    }
+
+    /// Jump to the bounds fault stub unless rax is a slot index, then negate
+    /// it: later slots sit at lower addresses
+    fn emit_bounds_check(&self, code: &mut Vec<u8>, fixups: &mut Vec<Fixup>, pc: usize) -> Result<()> {
+        let slots = narrow_i32(pc, self.program.memory_size as i64)?;
+        // cmp rax, slots
+        code.extend_from_slice(&[0x48, 0x3D]);
+        code.extend_from_slice(&slots.to_le_bytes());
+        // jae bounds fault (unsigned, so negative offsets also take it)
+        code.extend_from_slice(&[0x0F, 0x83]);
+        let at = code.len();
+        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        fixups.push(Fixup {
+            at,
+            target: FixupTarget::BoundsFault,
+            relative_to: at + 4,
+        });
+        // neg rax
+        code.extend_from_slice(&[0x48, 0xF7, 0xD8]);
+        
+        Ok(())
+    }
+
+    /// `[rbp + disp32]` displacement of a memory slot: slot 0 sits just
+    /// below the frame header, later slots further down.
+    /// Offsets that would truncate are `OffsetTooLarge` rather than a miscompile.
+    fn memory_displacement(&self, pc: usize, mem_offset: usize) -> Result<i32> {
+        mem_offset
+            .checked_mul(8)
+            .and_then(|bytes| bytes.checked_add(self.frame_header() + 8))
+            .and_then(|bytes| i32::try_from(bytes).ok())
+            .map(|bytes| -bytes)
+            .ok_or(CodegenError::OffsetTooLarge { pc, offset: mem_offset })
+    }
+
+
+    fn emit_call_native(
+        &self,
+        code: &mut Vec<u8>,
+        fixups: &mut Vec<Fixup>,
+        relocations: &mut Vec<usize>,
+        pc: usize,
         id: u32,
-    ) -> Result<usize> {
+    ) -> Result<()> {
         // Verify function is in whitelist
         if !self.sandbox.is_native_allowed(id) {
-            return Err(anyhow::anyhow!("Disallowed native call: {}", id));
+            return Err(CodegenError::DisallowedNative { id });
         }
 
-        // Placeholder - in complete implementation, we should have
-        // a native function table
-        let mut code = Vec::new();
+        // Whitelisted natives without an implementation are no-ops
+        let Some(native) = natives::lookup(id) else {
+            return Ok(());
+        };
+        // Compiled code can't wait for another program
+        if matches!(native.func, NativeFn::Channel(_)) {
+            return Err(CodegenError::Unsupported { pc, instruction: format!("CALL_NATIVE {}", native.name) });
+        }
+
+        // call_native(native, first, second, status): pop the arguments
+        // into the middle two registers, last one first
+        if native.args() == 2 {
+            #[cfg(not(windows))]
+            code.push(0x5A); // pop rdx
+            #[cfg(windows)]
+            code.extend_from_slice(&[0x41, 0x58]); // pop r8
+        }
+        #[cfg(not(windows))]
+        code.push(0x5E); // pop rsi
+        #[cfg(windows)]
+        code.push(0x5A); // pop rdx
+        // The status pointer saved by the prologue
+        #[cfg(not(windows))]
+        code.extend_from_slice(&[0x48, 0x8B, 0x4D, 0xF0]); // mov rcx, [rbp - 16]
+        #[cfg(windows)]
+        code.extend_from_slice(&[0x4C, 0x8B, 0x4D, 0xF0]); // mov r9, [rbp - 16]
+        // The table entry, which lives as long as the process
+        #[cfg(not(windows))]
+        code.extend_from_slice(&[0x48, 0xBF]); // mov rdi, native
+        #[cfg(windows)]
+        code.extend_from_slice(&[0x48, 0xB9]); // mov rcx, native
+        relocations.push(code.len());
+        code.extend_from_slice(&(native as *const NativeFunction as u64).to_le_bytes());
         
-        // call [function]
-        code.push(0xE8);
-        code.extend_from_slice(&[0x00, 0x00, 0x00, 0x00]);
+        // The VM stack depth is arbitrary; align rsp to 16 for the call
+        // and keep the original rsp just above the aligned top
+        // mov rax, rsp
+        code.extend_from_slice(&[0x48, 0x89, 0xE0]);
+        // and rsp, -16
+        code.extend_from_slice(&[0x48, 0x83, 0xE4, 0xF0]);
+        // push rax; push rax (two copies keep the alignment)
+        code.extend_from_slice(&[0x50, 0x50]);
+        
+        // Shadow space required by the Windows x64 ABI
+        #[cfg(windows)]
+        code.extend_from_slice(&[0x48, 0x83, 0xEC, 0x20]); // sub rsp, 32
+        
+        // mov rax, call_native
+        code.extend_from_slice(&[0x48, 0xB8]);
+        relocations.push(code.len());
+        code.extend_from_slice(&(call_native as *const () as u64).to_le_bytes());
+        // call rax
+        code.extend_from_slice(&[0xFF, 0xD0]);
+        
+        #[cfg(windows)]
+        code.extend_from_slice(&[0x48, 0x83, 0xC4, 0x20]); // add rsp, 32
+        
+        // mov rsp, [rsp] (restore the unaligned rsp)
+        code.extend_from_slice(&[0x48, 0x8B, 0x24, 0x24]);
+        
+        // mov rcx, [rbp - 16]; test byte [rcx], STATUS_NATIVE_FAULT
+        code.extend_from_slice(&[0x48, 0x8B, 0x4D, 0xF0]);
+        code.extend_from_slice(&[0xF6, 0x01, STATUS_NATIVE_FAULT as u8]);
+        // jnz native_fault
+        code.extend_from_slice(&[0x0F, 0x85]);
+        fixups.push(Fixup {
+            at: code.len(),
+            target: FixupTarget::NativeFault,
+            relative_to: code.len() + 4,
+        });
+        code.extend_from_slice(&[0; 4]);
+        
+        if native.results() == 1 {
+            code.push(0x50); // push rax (the result)
+        }
         
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        Ok(())
     }
 
-    unsafe fn emit_return(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
-        let mut code = Vec::new();
-        
+    fn emit_return(&self, code: &mut Vec<u8>) -> Result<()> {
         // pop rax (return value)
         code.extend_from_slice(&[0x58]);
-        // mov rsp, rbp
-        code.extend_from_slice(&[0x48, 0x89, 0xEC]);
-        // pop rbp
-        code.push(0x5D);
-        // ret
-        code.push(0xC3);
-        
-        memory.write(offset, &code)?;
-        Ok(offset + code.len())
+        self.emit_epilogue(code)
     }
 
-    unsafe fn emit_halt(&self, memory: &mut ExecutableMemory, offset: usize) -> Result<usize> {
+    fn emit_halt(&self, code: &mut Vec<u8>) -> Result<()> {
         // Halt is similar to return
-        self.emit_return(memory, offset)
+        self.emit_return(code)
+    }
+
+    /// Return 0 with `STATUS_TRAP` and the code set in the status word
+    fn emit_trap(&self, code: &mut Vec<u8>, error_code: u32) -> Result<()> {
+        self.emit_fault(code, (u64::from(error_code) << 32) | STATUS_TRAP)
+    }
+
+    /// Return 0 with `status` or'ed into the status word
+    fn emit_fault(&self, code: &mut Vec<u8>, status: u64) -> Result<()> {
+        // The check stores the status word, so it has to come first
+        if self.options.check_stack {
+            self.emit_stack_check(code)?;
+        }
+        // mov rcx, [rbp - 16]
+        code.extend_from_slice(&[0x48, 0x8B, 0x4D, 0xF0]);
+        // mov rax, status; or [rcx], rax
+        code.extend_from_slice(&[0x48, 0xB8]);
+        code.extend_from_slice(&status.to_le_bytes());
+        code.extend_from_slice(&[0x48, 0x09, 0x01]);
+        // xor eax, eax
+        code.extend_from_slice(&[0x31, 0xC0]);
+        self.emit_leave(code);
+        
+        Ok(())
     }
 }
 
+/// Narrow an operand of instruction `pc` to a 32-bit field. Every
+/// emitter-side conversion to i32 goes through here, so a value that
+/// would wrap is an error rather than a miscompile.
+fn narrow_i32(pc: usize, operand: i64) -> Result<i32> {
+    i32::try_from(operand).map_err(|_| CodegenError::OperandOutOfRange {
+        pc,
+        operand,
+        limit: i32::MAX.into(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compile(program: Program) -> CompiledCode {
+        JitCompiler::new(program).compile_to_vec().expect("program compiles")
+    }
+
+    #[test]
+    fn entry_points_at_the_prologue() {
+        let code = compile(Program::new(vec![Instruction::PushInt(1), Instruction::Return], 0));
+        // push rbp; mov rbp, rsp
+        assert_eq!(code.bytes[code.entry..code.entry + 4], [0x55, 0x48, 0x89, 0xE5]);
+        assert!(code.line_map[0].1 > code.entry);
+        assert_eq!(code.metadata().entry, code.entry);
+    }
+
+    #[test]
+    fn code_over_the_size_limit_is_rejected() {
+        let program = Program::new(vec![Instruction::PushInt(1); 64], 0);
+        let options = JitOptions { max_code_size: Some(128), ..JitOptions::default() };
+        let error = JitCompiler::new(program).with_options(options).compile_to_vec().unwrap_err();
+        assert!(
+            matches!(error, CinderError::Codegen(CodegenError::CodeSizeExceeded { limit: 128, .. })),
+            "{:?}",
+            error
+        );
+    }
+
+    #[test]
+    fn size_limit_applies_to_the_emitted_code() {
+        // Native calls and fault stubs are where a per-instruction guess
+        // goes wrong in either direction
+        let program = crate::parser::Parser::parse(
+            ".memory 2\n.args 2\nDIV\nPUSH_INT 7\nCALL_NATIVE 1\nLOAD_IND\nRETURN",
+        )
+        .unwrap();
+        let size = compile(program.clone()).bytes.len();
+        let limited = |limit| {
+            let options = JitOptions { max_code_size: Some(limit), ..JitOptions::default() };
+            JitCompiler::new(program.clone()).with_options(options).compile_to_vec()
+        };
+        assert_eq!(limited(size).unwrap().bytes.len(), size);
+        match limited(size - 1) {
+            Err(CinderError::Codegen(CodegenError::CodeSizeExceeded { size: reported, limit })) => {
+                assert_eq!((reported, limit), (size, size - 1));
+            }
+            other => panic!("expected CodeSizeExceeded, got {:?}", other.map(|code| code.bytes.len())),
+        }
+    }
+
+    #[test]
+    fn jump_table_dispatches_to_each_arm() {
+        let program = crate::parser::Parser::parse(
+            ".args 1
+            JUMP_TABLE 0 other zero one two
+            zero:
+            PUSH_INT 10
+            RETURN
+            one:
+            PUSH_INT 20
+            RETURN
+            two:
+            PUSH_INT 30
+            RETURN
+            other:
+            PUSH_INT -1
+            RETURN",
+        )
+        .unwrap();
+        let compiled = JitCompiler::new(program).compile().unwrap();
+        let results: Vec<i64> = [0, 1, 2, 3, -1].iter().map(|&selector| compiled.run_with_args(&[selector])).collect();
+        assert_eq!(results, [10, 20, 30, -1, -1]);
+    }
+
+    #[test]
+    fn offset_beyond_disp32_is_a_clean_error() {
+        // Slot 2^28 is 2 GiB below the frame, past a disp32
+        let offset = 1 << 28;
+        for access in [Instruction::Load(offset), Instruction::Store(offset), Instruction::MemAdd(offset, 1)] {
+            let program = Program::new(vec![Instruction::PushInt(1), access, Instruction::Return], 1);
+            assert!(matches!(
+                JitCompiler::new(program.clone()).compile(),
+                Err(CinderError::Sandbox(_))
+            ));
+
+            // The emitter checks on its own, should a program get past the sandbox
+            let mut compiler = JitCompiler::new(program);
+            compiler.validated_under = Some(compiler.sandbox.policy_hash());
+            let error = compiler.compile().err().expect("the offset is rejected");
+            assert!(
+                matches!(error, CinderError::Codegen(CodegenError::OffsetTooLarge { pc: 1, offset: 268435456 })),
+                "{:?}",
+                error
+            );
+        }
+    }
+
+    #[test]
+    fn memory_too_large_for_the_frame_is_rejected() {
+        let offset = u32::MAX - 1;
+        let program = Program::new(vec![Instruction::Load(offset), Instruction::Return], offset as usize + 1);
+        let error = JitCompiler::new(program).compile_to_vec().unwrap_err();
+        assert!(matches!(error, CinderError::Codegen(CodegenError::FrameTooLarge { .. })), "{:?}", error);
+    }
+
+    #[test]
+    fn arguments_are_passed_to_compiled_code() {
+        let mut program = Program::new(vec![Instruction::Add, Instruction::Return], 0);
+        program.args = 2;
+        let compiled = JitCompiler::new(program).compile().unwrap();
+        assert_eq!(compiled.metadata().args, 2);
+        assert_eq!(compiled.run_with_args(&[40, 2]), 42);
+        assert_eq!(compiled.run_with_args(&[i64::MAX, 1]), i64::MIN);
+    }
+
+    #[test]
+    fn arguments_keep_their_order() {
+        let mut program = Program::new(vec![Instruction::Sub, Instruction::Return], 0);
+        program.args = 2;
+        let compiled = JitCompiler::new(program).compile().unwrap();
+        assert_eq!(compiled.run_with_args(&[10, 3]), 7);
+    }
+
+    #[test]
+    #[should_panic(expected = "program expects 2 argument(s)")]
+    fn wrong_argument_count_panics() {
+        let mut program = Program::new(vec![Instruction::Add, Instruction::Return], 0);
+        program.args = 2;
+        JitCompiler::new(program).compile().unwrap().run_with_args(&[1]);
+    }
+
+    #[test]
+    fn unterminated_programs_match_the_interpreter() {
+        use crate::interpreter::Interpreter;
+        use crate::parser::Parser;
+
+        for source in [
+            "",
+            "PUSH_INT 5",
+            "PUSH_INT 5\nPUSH_INT 3\nSTORE 0",
+            ".memory 4\nPUSH_INT 1\nPUSH_INT 2",
+            "PUSH_INT 9\nPOP",
+        ] {
+            let program = Parser::parse(source).unwrap();
+            let expected = Interpreter::new(program.clone()).execute().unwrap();
+            let actual = JitCompiler::new(program).compile().unwrap().call_checked(&[]);
+            assert_eq!(actual, Ok(expected), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn shared_code_runs_on_eight_threads() {
+        // Sum 1..=n through a memory slot, so every call uses its own frame
+        let program = crate::parser::Parser::parse(
+            ".args 1
+            .var sum: i64
+            loop:
+            STORE 1
+            LOAD 1
+            JZ done
+            LOAD 0
+            LOAD 1
+            ADD
+            STORE 0
+            LOAD 1
+            PUSH_INT 1
+            SUB
+            JUMP loop
+            done:
+            LOAD 0
+            RETURN",
+        )
+        .unwrap();
+        let shared = JitCompiler::new(program).compile().unwrap().into_shared();
+
+        let handles: Vec<_> = (1..=8)
+            .map(|thread| {
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    let n = thread * 1000;
+                    (0..50).map(|_| shared.call_with_args(&[n])).all(|sum| sum == n * (n + 1) / 2)
+                })
+            })
+            .collect();
+        for handle in handles {
+            assert!(handle.join().unwrap());
+        }
+    }
+
+    #[test]
+    fn read_cycles_never_decreases() {
+        let program = Program::new(
+            vec![Instruction::ReadCycles, Instruction::ReadCycles, Instruction::Gt, Instruction::Return],
+            0,
+        );
+        let compiled = JitCompiler::new(program).compile().unwrap();
+        for _ in 0..100 {
+            assert_eq!(compiled.run(), 0);
+        }
+    }
+
+    #[test]
+    fn division_matches_the_interpreter() {
+        use crate::interpreter::{Interpreter, InterpreterError};
+
+        let mut program = Program::new(vec![Instruction::Div, Instruction::Return], 0);
+        program.args = 2;
+        let cases = [(7, 2), (-7, 2), (7, -2), (5, -1), (i64::MIN, -1), (i64::MIN, 1), (1, 0), (i64::MIN, 0)];
+        for check_stack in [false, true] {
+            let options = JitOptions { check_stack, ..JitOptions::default() };
+            let compiled = JitCompiler::new(program.clone()).with_options(options).compile().unwrap();
+            for (a, b) in cases {
+                let expected = match Interpreter::new(program.clone()).execute_with_input(&[a, b]) {
+                    Ok(value) => Ok(value),
+                    Err(InterpreterError::DivisionByZero) => Err(JitFault::DivisionByZero),
+                    Err(other) => panic!("unexpected interpreter error {:?}", other),
+                };
+                assert_eq!(compiled.call_checked(&[a, b]), expected, "{} / {}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn only_indirect_accesses_are_bounds_checked() {
+        let program = crate::parser::Parser::parse(".memory 4\nLOAD 3\nLOAD_IND\nPUSH_INT 1\nPUSH_INT 7\nSTORE_IND\nRETURN").unwrap();
+        let code = compile(program);
+        let instruction = |pc: usize| {
+            let end = code.line_map.get(pc + 1).map_or(code.epilogue, |&(_, offset)| offset);
+            &code.bytes[code.line_map[pc].1..end]
+        };
+
+        // mov rax, [rbp + disp32]; push rax, with no compare or branch
+        assert_eq!(instruction(0)[..3], [0x48, 0x8B, 0x85]);
+        assert_eq!(instruction(0).len(), 8);
+        // cmp rax, 4; jae bounds fault
+        let check = [0x48, 0x3D, 0x04, 0x00, 0x00, 0x00, 0x0F, 0x83];
+        for pc in [1, 4] {
+            assert!(instruction(pc).windows(check.len()).any(|bytes| bytes == check), "pc {}", pc);
+        }
+    }
+
+    #[test]
+    fn indirect_accesses_match_the_interpreter() {
+        use crate::interpreter::{Interpreter, InterpreterError};
+
+        let load = crate::parser::Parser::parse(".memory 4\n.args 1\nPUSH_INT 42\nSTORE 2\nLOAD_IND\nRETURN").unwrap();
+        let store = crate::parser::Parser::parse(
+            ".memory 4\n.args 2\nSTORE_IND\nLOAD 0\nLOAD 1\nADD\nLOAD 2\nADD\nLOAD 3\nADD\nRETURN",
+        )
+        .unwrap();
+        let cases: [(&Program, &[i64]); 8] = [
+            (&load, &[2]),
+            (&load, &[3]),
+            (&load, &[4]),
+            (&load, &[-1]),
+            (&store, &[0, 5]),
+            (&store, &[3, -9]),
+            (&store, &[4, 1]),
+            (&store, &[i64::MIN, 1]),
+        ];
+        for check_stack in [false, true] {
+            let options = JitOptions { check_stack, ..JitOptions::default() };
+            for (program, args) in cases {
+                let expected = match Interpreter::new(program.clone()).execute_with_input(args) {
+                    Ok(value) => Ok(value),
+                    Err(InterpreterError::InvalidMemoryAccess(_)) => Err(JitFault::OutOfBounds),
+                    Err(other) => panic!("unexpected interpreter error {:?}", other),
+                };
+                let compiled = JitCompiler::new(program.clone()).with_options(options.clone()).compile().unwrap();
+                assert_eq!(compiled.call_checked(args), expected, "{:?}", args);
+            }
+        }
+    }
+
+    #[test]
+    fn constant_shifts_match_the_interpreter() {
+        use crate::interpreter::Interpreter;
+
+        let values = [0, 1, -1, 0x5A5A, i64::MIN, i64::MAX, -0x1234_5678_9ABC];
+        for count in [0, 1, 7, 31, 32, 63, 64, 65, 127, 255] {
+            for shift in [Instruction::ShlImm(count), Instruction::ShrImm(count), Instruction::SarImm(count)] {
+                let mut program = Program::new(vec![shift.clone(), Instruction::Return], 0);
+                program.args = 1;
+                let compiled = JitCompiler::new(program.clone()).compile().unwrap();
+                for value in values {
+                    let expected = Interpreter::new(program.clone()).execute_with_input(&[value]).unwrap();
+                    assert_eq!(compiled.run_with_args(&[value]), expected, "{:?} of {}", shift, value);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn bool_takes_the_encoding_the_cpu_features_allow() {
+        use crate::interpreter::Interpreter;
+
+        const LZCNT_RAX: [u8; 5] = [0xF3, 0x48, 0x0F, 0xBD, 0xC0];
+        let lzcnt = CpuFeatures { lzcnt: true, ..CpuFeatures::baseline() };
+        let host = CpuFeatures::detect();
+        let values = [0, 1, -1, 2, 0x4000_0000_0000_0000, i64::MIN, i64::MAX];
+        for instruction in [Instruction::Bool, Instruction::NotBool] {
+            let mut program = Program::new(vec![instruction.clone(), Instruction::Return], 0);
+            program.args = 1;
+            for bool_repr in [BoolRepr::One, BoolRepr::AllOnes] {
+                for features in [CpuFeatures::baseline(), lzcnt] {
+                    let options = JitOptions { bool_repr, cpu_features: features, ..JitOptions::default() };
+                    // Pretend the host has exactly `features`, whatever it really has
+                    let mut compiler = JitCompiler::new(program.clone()).with_options(options).with_host_features(features);
+                    let code = compiler.compile_to_vec().unwrap();
+                    let uses_lzcnt = code.bytes.windows(LZCNT_RAX.len()).any(|window| window == LZCNT_RAX);
+                    assert_eq!(uses_lzcnt, features.lzcnt, "{:?} for {}", instruction, features);
+                    assert_eq!(code.metadata().cpu_features, features);
+
+                    // Only run what this CPU can actually execute
+                    if !features.runs_on(&host) {
+                        continue;
+                    }
+                    let compiled = compiler.compile().unwrap();
+                    for value in values {
+                        let expected = Interpreter::new(program.clone())
+                            .with_bool_repr(bool_repr)
+                            .execute_with_input(&[value])
+                            .unwrap();
+                        assert_eq!(compiled.run_with_args(&[value]), expected, "{:?} of {} for {}", instruction, value, features);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn code_for_a_missing_feature_is_not_loaded() {
+        let program = Program::new(vec![Instruction::PushInt(3), Instruction::NotBool, Instruction::Return], 0);
+        let lzcnt = CpuFeatures { lzcnt: true, ..CpuFeatures::baseline() };
+        let options = JitOptions { cpu_features: lzcnt, ..JitOptions::default() };
+        let mut compiler = JitCompiler::new(program).with_options(options).with_host_features(CpuFeatures::baseline());
+        assert!(matches!(
+            compiler.compile(),
+            Err(CinderError::Codegen(CodegenError::CpuFeaturesUnavailable { required, available }))
+                if required == lzcnt && available == CpuFeatures::baseline()
+        ));
+        // Relocatable code is for whichever machine loads it
+        assert!(compiler.compile_to_vec().is_ok());
+    }
+
+    #[test]
+    fn forced_underflow_is_caught_as_a_stack_imbalance() {
+        // The sandbox rejects the underflow, so skip validation as if it
+        // had passed: the emitted pops then eat into the frame header
+        let program = Program::new(vec![Instruction::Pop, Instruction::Pop, Instruction::Return], 0);
+        let unvalidated = |check_stack| {
+            let mut compiler = JitCompiler::new(program.clone())
+                .with_options(JitOptions { check_stack, ..JitOptions::default() });
+            compiler.validated_under = Some(compiler.sandbox.policy_hash());
+            compiler.compile().unwrap()
+        };
+        assert!(JitCompiler::new(program.clone()).compile().is_err());
+        assert_eq!(unvalidated(true).call_checked(&[]), Err(JitFault::StackImbalance));
+        assert!(unvalidated(false).call_checked(&[]).is_ok());
+    }
+
+    #[test]
+    fn push_reg_is_unsupported_rather_than_dropped() {
+        let program = Program::new(vec![Instruction::PushInt(1), Instruction::PushReg(0), Instruction::Return], 0);
+        assert!(matches!(
+            JitCompiler::new(program).compile_to_vec(),
+            Err(CinderError::Codegen(CodegenError::Unsupported { pc: 1, .. }))
+        ));
+    }
+
+    #[test]
+    fn mem_add_in_a_loop_matches_the_interpreter() {
+        use crate::interpreter::Interpreter;
+        use crate::parser::Parser;
+
+        // (start, immediate, iterations), including wrap-around both ways
+        let cases = [
+            (0, 1, 100),
+            (-7, -3, 9),
+            (i64::MAX - 5, 3, 4),
+            (i64::MIN + 1, i32::MIN, 2),
+            (5, i32::MAX, 3),
+        ];
+        for (start, imm, iterations) in cases {
+            let source = format!(
+                ".memory 2\n.data 0 {start}\nPUSH_INT {iterations}\nSTORE 1\n\
+                 loop:\nMEM_ADD 0 {imm}\nMEM_ADD 1 -1\nLOAD 1\nJNZ loop\nLOAD 0\nRETURN\n"
+            );
+            let program = Parser::parse(&source).unwrap();
+            let expected = Interpreter::new(program.clone()).execute().unwrap();
+            assert_eq!(expected, start.wrapping_add(imm as i64 * iterations), "{}", source);
+            assert_eq!(JitCompiler::new(program).compile().unwrap().run(), expected, "{}", source);
+        }
+    }
+}
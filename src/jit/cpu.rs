@@ -0,0 +1,63 @@
+//! Optional x86-64 instruction set extensions the JIT may use.
+//!
+//! Every encoding that needs one has a baseline fallback, taken unless
+//! `JitOptions::cpu_features` enables the extension. Only `lzcnt` has a
+//! user so far (BOOL and NOT_BOOL).
+
+use std::fmt;
+
+/// Extensions beyond the x86-64 baseline. Code compiled with a feature
+/// enabled must only run on CPUs that have it, so the set is part of
+/// `CompiledMetadata`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+pub struct CpuFeatures {
+    pub popcnt: bool,
+    pub lzcnt: bool,
+    pub bmi2: bool,
+}
+
+impl CpuFeatures {
+    /// Plain x86-64, safe on every CPU; the `JitOptions` default
+    pub fn baseline() -> Self {
+        Self::default()
+    }
+
+    /// Features of the CPU this process runs on
+    pub fn detect() -> Self {
+        #[cfg(target_arch = "x86_64")]
+        {
+            Self {
+                popcnt: std::arch::is_x86_feature_detected!("popcnt"),
+                lzcnt: std::arch::is_x86_feature_detected!("lzcnt"),
+                bmi2: std::arch::is_x86_feature_detected!("bmi2"),
+            }
+        }
+        #[cfg(not(target_arch = "x86_64"))]
+        {
+            Self::baseline()
+        }
+    }
+
+    /// Whether code compiled for `self` can run on a CPU with `available`
+    pub fn runs_on(&self, available: &CpuFeatures) -> bool {
+        (!self.popcnt || available.popcnt)
+            && (!self.lzcnt || available.lzcnt)
+            && (!self.bmi2 || available.bmi2)
+    }
+}
+
+impl fmt::Display for CpuFeatures {
+    /// Enabled feature names separated by spaces, or `baseline`
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<&str> = [("popcnt", self.popcnt), ("lzcnt", self.lzcnt), ("bmi2", self.bmi2)]
+            .iter()
+            .filter(|(_, enabled)| *enabled)
+            .map(|(name, _)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "baseline")
+        } else {
+            write!(f, "{}", names.join(" "))
+        }
+    }
+}
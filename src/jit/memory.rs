@@ -1,34 +1,45 @@
-use anyhow::{Context, Result};
 use std::ptr;
 
 #[cfg(unix)]
-use libc::{mmap, munmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
+use libc::{mmap, mprotect, munmap, MAP_ANONYMOUS, MAP_PRIVATE, PROT_EXEC, PROT_READ, PROT_WRITE};
 
 #[cfg(windows)]
-use winapi::um::memoryapi::{VirtualAlloc, VirtualFree};
+use winapi::um::memoryapi::{VirtualAlloc, VirtualFree, VirtualProtect};
 #[cfg(windows)]
-use winapi::um::winnt::{MEM_COMMIT, MEM_RELEASE, PAGE_EXECUTE_READWRITE};
+use winapi::um::winnt::{MEM_COMMIT, MEM_RELEASE, PAGE_EXECUTE_READ, PAGE_READWRITE};
 
-#[derive(Debug)]
+#[derive(Debug, thiserror::Error)]
 pub enum MemoryError {
+    #[error("Executable memory allocation failed")]
     AllocationFailed,
+    #[error("Invalid executable memory size")]
     InvalidSize,
+    #[error("Write outside memory bounds")]
+    OutOfBounds,
+    #[error("Write to memory that is already executable")]
+    Sealed,
+    #[error("Could not make memory executable")]
+    ProtectFailed,
 }
 
 /// Dynamically allocated executable memory
+///
+/// Pages are never writable and executable at once (W^X): they are mapped
+/// read-write for `write`, then `seal` flips them to read-execute for good.
 pub struct ExecutableMemory {
     ptr: *mut u8,
     size: usize,
+    sealed: bool,
 }
 
 unsafe impl Send for ExecutableMemory {}
 unsafe impl Sync for ExecutableMemory {}
 
 impl ExecutableMemory {
-    /// Allocate executable memory of specified size
-    pub fn allocate(size: usize) -> Result<Self> {
+    /// Allocate writable, not yet executable memory of specified size
+    pub fn allocate(size: usize) -> Result<Self, MemoryError> {
         if size == 0 {
-            return Err(anyhow::anyhow!(MemoryError::InvalidSize));
+            return Err(MemoryError::InvalidSize);
         }
 
         #[cfg(unix)]
@@ -37,7 +48,7 @@ impl ExecutableMemory {
                 mmap(
                     ptr::null_mut(),
                     size,
-                    PROT_READ | PROT_WRITE | PROT_EXEC,
+                    PROT_READ | PROT_WRITE,
                     MAP_PRIVATE | MAP_ANONYMOUS,
                     -1,
                     0,
@@ -45,12 +56,13 @@ impl ExecutableMemory {
             };
 
             if ptr == libc::MAP_FAILED {
-                return Err(anyhow::anyhow!(MemoryError::AllocationFailed));
+                return Err(MemoryError::AllocationFailed);
             }
 
             Ok(Self {
                 ptr: ptr as *mut u8,
                 size,
+                sealed: false,
             })
         }
 
@@ -61,17 +73,18 @@ impl ExecutableMemory {
                     ptr::null_mut(),
                     size,
                     MEM_COMMIT,
-                    PAGE_EXECUTE_READWRITE,
+                    PAGE_READWRITE,
                 )
             };
 
             if ptr.is_null() {
-                return Err(anyhow::anyhow!(MemoryError::AllocationFailed));
+                return Err(MemoryError::AllocationFailed);
             }
 
             Ok(Self {
                 ptr: ptr as *mut u8,
                 size,
+                sealed: false,
             })
         }
     }
@@ -87,9 +100,17 @@ impl ExecutableMemory {
     }
 
     /// Write data to memory
-    pub unsafe fn write(&mut self, offset: usize, data: &[u8]) -> Result<()> {
+    ///
+    /// # Safety
+    ///
+    /// The region may already be executing or referenced by function
+    /// pointers; callers must not overwrite code that can run concurrently.
+    pub unsafe fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), MemoryError> {
+        if self.sealed {
+            return Err(MemoryError::Sealed);
+        }
         if offset + data.len() > self.size {
-            return Err(anyhow::anyhow!("Write outside memory bounds"));
+            return Err(MemoryError::OutOfBounds);
         }
 
         ptr::copy_nonoverlapping(
@@ -101,9 +122,55 @@ impl ExecutableMemory {
         Ok(())
     }
 
-    /// Get a function pointer
-    pub fn as_function<T>(&self) -> T {
-        unsafe { std::mem::transmute(self.ptr) }
+    /// Drop write access and make the region executable; later writes fail
+    /// with `MemoryError::Sealed`
+    pub fn seal(&mut self) -> Result<(), MemoryError> {
+        if self.sealed {
+            return Ok(());
+        }
+
+        #[cfg(unix)]
+        let protected = unsafe { mprotect(self.ptr as *mut libc::c_void, self.size, PROT_READ | PROT_EXEC) == 0 };
+
+        #[cfg(windows)]
+        let protected = unsafe {
+            let mut previous = 0;
+            VirtualProtect(self.ptr as *mut winapi::ctypes::c_void, self.size, PAGE_EXECUTE_READ, &mut previous) != 0
+        };
+
+        if !protected {
+            return Err(MemoryError::ProtectFailed);
+        }
+        self.sealed = true;
+        Ok(())
+    }
+
+    /// Whether `seal` has made the region executable
+    pub fn is_sealed(&self) -> bool {
+        self.sealed
+    }
+
+    /// Reinterpret the start of the region as a function pointer of type `F`
+    ///
+    /// # Safety
+    ///
+    /// `F` must be a function pointer type matching the ABI and signature of
+    /// the code written to this region. Only `CompiledProgram` calls this,
+    /// with the signature the emitter generates.
+    pub(crate) unsafe fn as_function<F: Copy>(&self) -> F {
+        // `allocate` rejects empty regions; catch anything that bypassed it
+        // before it becomes a wild function pointer
+        debug_assert!(
+            self.size > 0 && !self.ptr.is_null(),
+            "as_function called on an empty executable region"
+        );
+        assert!(self.sealed, "as_function called before the region was sealed");
+        assert_eq!(
+            std::mem::size_of::<F>(),
+            std::mem::size_of::<*mut u8>(),
+            "as_function target must be a function pointer"
+        );
+        std::mem::transmute_copy(&self.ptr)
     }
 }
 
@@ -125,3 +192,65 @@ impl Drop for ExecutableMemory {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_allocation_is_rejected() {
+        assert!(matches!(ExecutableMemory::allocate(0), Err(MemoryError::InvalidSize)));
+    }
+
+    /// Permissions of the mapping containing `ptr`, as `/proc/self/maps` shows them
+    #[cfg(target_os = "linux")]
+    fn permissions(ptr: *mut u8) -> String {
+        let address = ptr as usize;
+        let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+        maps.lines()
+            .find_map(|line| {
+                let (range, rest) = line.split_once(' ')?;
+                let (start, end) = range.split_once('-')?;
+                let (start, end) = (usize::from_str_radix(start, 16).ok()?, usize::from_str_radix(end, 16).ok()?);
+                (start..end).contains(&address).then(|| rest[..4].to_string())
+            })
+            .expect("the region is mapped")
+    }
+
+    #[test]
+    fn pages_are_never_writable_and_executable() {
+        let mut memory = ExecutableMemory::allocate(64).unwrap();
+        // mov eax, 42; ret
+        unsafe { memory.write(0, &[0xB8, 42, 0, 0, 0, 0xC3]).unwrap() };
+        #[cfg(target_os = "linux")]
+        assert_eq!(permissions(memory.as_ptr()), "rw-p");
+
+        memory.seal().unwrap();
+        #[cfg(target_os = "linux")]
+        assert_eq!(permissions(memory.as_ptr()), "r-xp");
+        assert!(matches!(unsafe { memory.write(0, &[0xC3]) }, Err(MemoryError::Sealed)));
+
+        #[cfg(target_arch = "x86_64")]
+        {
+            let function: extern "C" fn() -> i32 = unsafe { memory.as_function() };
+            assert_eq!(function(), 42);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "as_function called before the region was sealed")]
+    fn as_function_on_writable_memory_is_caught() {
+        let memory = ExecutableMemory::allocate(16).unwrap();
+        let _: extern "C" fn() = unsafe { memory.as_function() };
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "as_function called on an empty executable region")]
+    fn as_function_on_an_empty_region_is_caught() {
+        // Only reachable by bypassing `allocate`; unmapping a null, empty
+        // region on drop is a harmless failed call
+        let memory = ExecutableMemory { ptr: ptr::null_mut(), size: 0, sealed: true };
+        let _: extern "C" fn() = unsafe { memory.as_function() };
+    }
+}
@@ -1,6 +1,13 @@
 mod codegen;
+mod cpu;
 mod memory;
+#[cfg(all(feature = "perf", target_os = "linux"))]
+pub mod perf;
 
-pub use codegen::JitCompiler;
+pub use codegen::{
+    CodegenError, CompiledCode, CompiledMetadata, CompiledProgram, JitCompiler, JitFault,
+    JitOptions, SharedCompiled, ADDRESS_PLACEHOLDER, MAX_FRAME_SLOTS,
+};
+pub use cpu::CpuFeatures;
 pub use memory::{ExecutableMemory, MemoryError};
 
@@ -0,0 +1,88 @@
+use perf_event_open_sys as sys;
+use std::fs::File;
+use std::io::{self, Read};
+use std::os::unix::io::FromRawFd;
+
+/// Hardware counter values read around a native execution
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PerfCounters {
+    pub instructions: u64,
+    pub cycles: u64,
+}
+
+impl PerfCounters {
+    /// Instructions retired per cycle
+    pub fn ipc(&self) -> f64 {
+        if self.cycles == 0 {
+            0.0
+        } else {
+            self.instructions as f64 / self.cycles as f64
+        }
+    }
+}
+
+/// A single user-space hardware counter for the current thread
+struct Counter {
+    file: File,
+}
+
+impl Counter {
+    fn open(config: u32) -> io::Result<Self> {
+        let mut attrs = sys::bindings::perf_event_attr {
+            size: std::mem::size_of::<sys::bindings::perf_event_attr>() as u32,
+            type_: sys::bindings::perf_type_id_PERF_TYPE_HARDWARE,
+            config: config as u64,
+            ..Default::default()
+        };
+        attrs.set_disabled(1);
+        attrs.set_exclude_kernel(1);
+        attrs.set_exclude_hv(1);
+
+        // pid 0 / cpu -1: this thread, on any CPU
+        let fd = unsafe { sys::perf_event_open(&mut attrs, 0, -1, -1, 0) };
+        if fd < 0 {
+            return Err(io::Error::from_raw_os_error(-fd));
+        }
+        Ok(Self {
+            file: unsafe { File::from_raw_fd(fd) },
+        })
+    }
+
+    fn ioctl(&self, request: unsafe fn(i32, u32) -> i32) -> io::Result<()> {
+        use std::os::unix::io::AsRawFd;
+
+        if unsafe { request(self.file.as_raw_fd(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    fn read(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.file.read_exact(&mut buf)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+}
+
+/// Run `f` with instruction and cycle counters enabled around it
+pub fn measure<T>(f: impl FnOnce() -> T) -> io::Result<(T, PerfCounters)> {
+    let mut cycles = Counter::open(sys::bindings::perf_hw_id_PERF_COUNT_HW_CPU_CYCLES)?;
+    let mut instructions = Counter::open(sys::bindings::perf_hw_id_PERF_COUNT_HW_INSTRUCTIONS)?;
+
+    for counter in [&cycles, &instructions] {
+        counter.ioctl(sys::ioctls::RESET)?;
+        counter.ioctl(sys::ioctls::ENABLE)?;
+    }
+
+    let result = f();
+
+    for counter in [&cycles, &instructions] {
+        counter.ioctl(sys::ioctls::DISABLE)?;
+    }
+
+    let counters = PerfCounters {
+        instructions: instructions.read()?,
+        cycles: cycles.read()?,
+    };
+    Ok((result, counters))
+}
@@ -0,0 +1,28 @@
+// Library code writes program output through `natives::set_output`;
+// only the CLI prints directly
+#![deny(clippy::print_stdout, clippy::print_stderr)]
+
+pub mod artifact;
+pub mod bytecode;
+pub mod channel;
+pub mod cost;
+pub mod disasm;
+pub mod engine;
+pub mod error;
+pub mod formats;
+pub mod incremental;
+pub mod interpreter;
+pub mod parser;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod natives;
+pub mod oracle;
+pub mod sandbox;
+pub mod scheduler;
+pub mod trace;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing;
@@ -1,15 +1,16 @@
-mod bytecode;
-mod interpreter;
-mod parser;
-mod jit;
-mod sandbox;
 mod cli;
 
 use clap::Parser;
 use cli::CinderCli;
+use std::process::ExitCode;
 
-fn main() -> anyhow::Result<()> {
+fn main() -> ExitCode {
     let cli = CinderCli::parse();
-    cli.execute()
+    match cli.execute() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            cli.report(&error);
+            ExitCode::FAILURE
+        }
+    }
 }
-
@@ -0,0 +1,486 @@
+//! Native functions callable from bytecode via `CALL_NATIVE`.
+//!
+//! Every native pops its arguments from the value stack; only `kv_get` and
+//! `chan_recv` push a result. The same functions back the interpreter and JIT-compiled
+//! code, always called through `NativeFunction::call` so a panic is caught
+//! on the Rust side of the boundary. Their output goes to process stdout unless an embedder
+//! calls `set_output`. The storage natives use the `SharedStore` of the
+//! interpreter or compiled program calling them, a fresh in-memory map
+//! unless the embedder attaches another with `with_store`.
+
+use crate::channel::ChannelOp;
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+pub const PRINT_INT: u32 = 0x01;
+pub const PRINT_STR: u32 = 0x02;
+pub const PRINT_HEX: u32 = 0x03;
+pub const PRINT_CHAR: u32 = 0x04;
+pub const PRINT_INT_FMT: u32 = 0x05;
+pub const KV_GET: u32 = 0x10;
+pub const KV_PUT: u32 = 0x11;
+pub const CHAN_SEND: u32 = 0x20;
+pub const CHAN_RECV: u32 = 0x21;
+
+/// Pushed by `kv_get` for a key that was never stored
+pub const KV_MISS: i64 = i64::MIN;
+
+/// `print_int_fmt` flags: numeric base in bits 0-7 (0 means 10)
+pub const FMT_BASE_MASK: i64 = 0xFF;
+/// `print_int_fmt` flags: minimum field width in bits 8-15
+pub const FMT_WIDTH_SHIFT: u32 = 8;
+/// `print_int_fmt` flags: pad with zeros instead of spaces
+pub const FMT_ZERO_PAD: i64 = 1 << 16;
+/// `print_int_fmt` flags: write a trailing newline
+pub const FMT_NEWLINE: i64 = 1 << 17;
+
+/// Native entry point, by arity.
+/// Arguments are pushed in order, so the last argument is on top of the stack.
+#[derive(Debug, Clone, Copy)]
+pub enum NativeFn {
+    Unary(fn(i64)),
+    Binary(fn(i64, i64)),
+    /// Runs against the caller's `SharedStore`; `Get` pushes a result
+    Storage(KvOp),
+    /// Implemented by the interpreter on its attached channel (see `channel`)
+    Channel(ChannelOp),
+}
+
+/// Entry in the native function table
+#[derive(Debug, Clone, Copy)]
+pub struct NativeFunction {
+    pub id: u32,
+    pub name: &'static str,
+    pub func: NativeFn,
+}
+
+impl NativeFunction {
+    /// Values popped from the stack
+    pub fn args(&self) -> usize {
+        match self.func {
+            NativeFn::Unary(_) | NativeFn::Storage(KvOp::Get) | NativeFn::Channel(ChannelOp::Send) => 1,
+            NativeFn::Binary(_) | NativeFn::Storage(KvOp::Put) => 2,
+            NativeFn::Channel(ChannelOp::Recv) => 0,
+        }
+    }
+
+    /// Values pushed after the call
+    pub fn results(&self) -> usize {
+        match self.func {
+            NativeFn::Storage(KvOp::Get) | NativeFn::Channel(ChannelOp::Recv) => 1,
+            NativeFn::Unary(_) | NativeFn::Binary(_) | NativeFn::Storage(KvOp::Put) | NativeFn::Channel(ChannelOp::Send) => 0,
+        }
+    }
+
+    /// Run the native on `args` (`args()` values, first argument first),
+    /// returning its result if it has one, or the panic message if it
+    /// panicked. Storage natives use `store`. Channel natives need the
+    /// interpreter's channel and fail here as if they had panicked.
+    pub fn call(&self, args: &[i64], store: &SharedStore) -> Result<Option<i64>, String> {
+        panic::catch_unwind(AssertUnwindSafe(|| match self.func {
+            NativeFn::Unary(func) => { func(args[0]); None }
+            NativeFn::Binary(func) => { func(args[0], args[1]); None }
+            NativeFn::Storage(KvOp::Get) => Some(store.get(args[0])),
+            NativeFn::Storage(KvOp::Put) => { store.put(args[0], args[1]); None }
+            NativeFn::Channel(_) => panic!("{} needs an interpreter with a channel", self.name),
+        }))
+        .map_err(|payload| crate::interpreter::panic_message(payload.as_ref()))
+    }
+}
+
+static NATIVES: &[NativeFunction] = &[
+    NativeFunction { id: PRINT_INT, name: "print_int", func: NativeFn::Unary(print_int) },
+    NativeFunction { id: PRINT_HEX, name: "print_hex", func: NativeFn::Unary(print_hex) },
+    NativeFunction { id: PRINT_CHAR, name: "print_char", func: NativeFn::Unary(print_char) },
+    NativeFunction { id: PRINT_INT_FMT, name: "print_int_fmt", func: NativeFn::Binary(print_int_fmt) },
+    NativeFunction { id: KV_GET, name: "kv_get", func: NativeFn::Storage(KvOp::Get) },
+    NativeFunction { id: KV_PUT, name: "kv_put", func: NativeFn::Storage(KvOp::Put) },
+    NativeFunction { id: CHAN_SEND, name: "chan_send", func: NativeFn::Channel(ChannelOp::Send) },
+    NativeFunction { id: CHAN_RECV, name: "chan_recv", func: NativeFn::Channel(ChannelOp::Recv) },
+];
+
+/// Look up an implemented native by ID
+pub fn lookup(id: u32) -> Option<&'static NativeFunction> {
+    NATIVES.iter().find(|native| native.id == id)
+}
+
+/// Look up an implemented native by name
+pub fn lookup_name(name: &str) -> Option<&'static NativeFunction> {
+    NATIVES.iter().find(|native| native.name == name)
+}
+
+/// Destination of program output; `None` means process stdout
+static OUTPUT: Mutex<Option<Box<dyn Write + Send>>> = Mutex::new(None);
+
+/// Send output of the print natives to `sink` instead of stdout, for every
+/// interpreter and compiled program in the process. Returns the previous sink.
+pub fn set_output(sink: Box<dyn Write + Send>) -> Option<Box<dyn Write + Send>> {
+    OUTPUT.lock().unwrap_or_else(|e| e.into_inner()).replace(sink)
+}
+
+/// Restore process stdout as the output sink, returning the previous one
+pub fn reset_output() -> Option<Box<dyn Write + Send>> {
+    OUTPUT.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+/// Write `text` to the output sink and flush it. Errors are dropped: a
+/// native has no way to report them to bytecode.
+fn write_output(text: &str) {
+    let mut output = OUTPUT.lock().unwrap_or_else(|e| e.into_inner());
+    let _ = match output.as_mut() {
+        Some(sink) => sink.write_all(text.as_bytes()).and_then(|_| sink.flush()),
+        None => {
+            let mut stdout = io::stdout().lock();
+            stdout.write_all(text.as_bytes()).and_then(|_| stdout.flush())
+        }
+    };
+}
+
+/// Print a value in decimal, followed by a newline
+pub fn print_int(value: i64) {
+    write_output(&format!("{}\n", value));
+}
+
+/// Print a value in hexadecimal (two's complement), followed by a newline
+pub fn print_hex(value: i64) {
+    write_output(&format!("{:#x}\n", value));
+}
+
+/// Print a value as a Unicode character, without a newline.
+/// Invalid code points print as U+FFFD.
+pub fn print_char(value: i64) {
+    let c = u32::try_from(value)
+        .ok()
+        .and_then(char::from_u32)
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    write_output(c.encode_utf8(&mut [0; 4]));
+}
+
+/// Print a value using a flags word (see the `FMT_*` constants).
+/// Bases outside 2..=36 fall back to decimal; there is no prefix such as `0x`.
+pub fn print_int_fmt(value: i64, flags: i64) {
+    let base = match flags & FMT_BASE_MASK {
+        base @ 2..=36 => base as u32,
+        _ => 10,
+    };
+    let width = ((flags >> FMT_WIDTH_SHIFT) & 0xFF) as usize;
+
+    let mut digits = Vec::new();
+    let mut magnitude = value.unsigned_abs();
+    loop {
+        let digit = (magnitude % base as u64) as u32;
+        digits.push(char::from_digit(digit, base).unwrap_or('?'));
+        magnitude /= base as u64;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    let digits: String = digits.iter().rev().collect();
+    let sign = if value < 0 { "-" } else { "" };
+
+    // Zero padding goes between the sign and the digits, like `{:0w}`
+    let text = if flags & FMT_ZERO_PAD != 0 {
+        let pad = width.saturating_sub(sign.len() + digits.len());
+        format!("{}{}{}", sign, "0".repeat(pad), digits)
+    } else {
+        format!("{:>width$}", format!("{}{}", sign, digits), width = width)
+    };
+
+    if flags & FMT_NEWLINE != 0 {
+        write_output(&format!("{}\n", text));
+    } else {
+        write_output(&text);
+    }
+}
+
+/// Host storage behind `kv_get` and `kv_put`
+pub trait KeyValueStore: Send {
+    fn get(&mut self, key: i64) -> Option<i64>;
+    fn put(&mut self, key: i64, value: i64);
+
+    /// Whether the same sequence of operations always gives the same
+    /// results. Programs validated in deterministic mode fail when they
+    /// call a storage native on a store that doesn't say so.
+    fn is_deterministic(&self) -> bool {
+        false
+    }
+}
+
+/// The default store: a map that lives as long as the `SharedStore` holding it
+#[derive(Debug, Default, Clone)]
+pub struct MemoryStore {
+    entries: HashMap<i64, i64>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl KeyValueStore for MemoryStore {
+    fn get(&mut self, key: i64) -> Option<i64> {
+        self.entries.get(&key).copied()
+    }
+
+    fn put(&mut self, key: i64, value: i64) {
+        self.entries.insert(key, value);
+    }
+
+    fn is_deterministic(&self) -> bool {
+        true
+    }
+}
+
+/// Kind of storage access in the audit log
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvOp {
+    Get,
+    Put,
+}
+
+/// One storage access; `value` is the value read (`KV_MISS` on a miss) or written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KvAccess {
+    pub op: KvOp,
+    pub key: i64,
+    pub value: i64,
+}
+
+/// A `KeyValueStore` and its audit log, shared by every interpreter and
+/// compiled program it is attached to (`with_store`). Clones are handles
+/// to the same store.
+#[derive(Clone)]
+pub struct SharedStore {
+    state: Arc<Mutex<StoreState>>,
+}
+
+struct StoreState {
+    store: Box<dyn KeyValueStore>,
+    /// Accesses recorded since `start_audit`; `None` while not auditing
+    audit: Option<Vec<KvAccess>>,
+}
+
+impl Default for SharedStore {
+    /// A fresh, empty `MemoryStore`
+    fn default() -> Self {
+        Self::new(MemoryStore::new())
+    }
+}
+
+impl std::fmt::Debug for SharedStore {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SharedStore").field("deterministic", &self.is_deterministic()).finish_non_exhaustive()
+    }
+}
+
+impl SharedStore {
+    pub fn new(store: impl KeyValueStore + 'static) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(StoreState { store: Box::new(store), audit: None })),
+        }
+    }
+
+    /// Whether the store is declared deterministic
+    pub fn is_deterministic(&self) -> bool {
+        self.lock().store.is_deterministic()
+    }
+
+    /// Record every access from now on, discarding earlier records
+    pub fn start_audit(&self) {
+        self.lock().audit = Some(Vec::new());
+    }
+
+    /// Stop auditing and return the accesses recorded since `start_audit`
+    pub fn take_audit(&self) -> Vec<KvAccess> {
+        self.lock().audit.take().unwrap_or_default()
+    }
+
+    /// Value stored under `key`, or `KV_MISS`
+    pub fn get(&self, key: i64) -> i64 {
+        let mut state = self.lock();
+        let value = state.store.get(key).unwrap_or(KV_MISS);
+        state.record(KvOp::Get, key, value);
+        value
+    }
+
+    /// Store `value` under `key`, replacing any earlier value
+    pub fn put(&self, key: i64, value: i64) {
+        let mut state = self.lock();
+        state.store.put(key, value);
+        state.record(KvOp::Put, key, value);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, StoreState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl StoreState {
+    fn record(&mut self, op: KvOp, key: i64, value: i64) {
+        if let Some(log) = self.audit.as_mut() {
+            log.push(KvAccess { op, key, value });
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::ExecError;
+    use crate::interpreter::{Interpreter, InterpreterError};
+    use crate::parser::Parser;
+    use crate::sandbox::{Capability, Sandbox};
+
+    /// Add one to the counter under key 7301 (a miss counts as 0) and return it
+    const COUNTER: &str = "\
+        PUSH_INT 7301\n\
+        CALL_NATIVE kv_get\n\
+        STORE 0\n\
+        LOAD 0\n\
+        PUSH_INT -9223372036854775808\n\
+        EQ\n\
+        JZ found\n\
+        PUSH_INT 0\n\
+        STORE 0\n\
+        found:\n\
+        MEM_ADD 0 1\n\
+        PUSH_INT 7301\n\
+        LOAD 0\n\
+        CALL_NATIVE kv_put\n\
+        LOAD 0\n\
+        RETURN\n";
+
+    /// A store that doesn't declare itself deterministic
+    struct Remote;
+
+    impl KeyValueStore for Remote {
+        fn get(&mut self, _key: i64) -> Option<i64> {
+            None
+        }
+
+        fn put(&mut self, _key: i64, _value: i64) {}
+    }
+
+    /// Forwards to stdout, except that printing `PANIC_VALUE` panics
+    struct PanickingSink;
+
+    const PANIC_VALUE: i64 = -7461;
+
+    impl Write for PanickingSink {
+        fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
+            if bytes == format!("{}\n", PANIC_VALUE).as_bytes() {
+                panic!("sink rejected {}", PANIC_VALUE);
+            }
+            io::stdout().write(bytes)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            io::stdout().flush()
+        }
+    }
+
+    #[test]
+    fn panicking_native_is_reported_by_every_backend() {
+        let source = format!("PUSH_INT 5\nPUSH_INT {}\nCALL_NATIVE print_int\nRETURN", PANIC_VALUE);
+        let program = Parser::parse(&source).unwrap();
+        let message = format!("sink rejected {}", PANIC_VALUE);
+        let expected = || InterpreterError::NativePanicked { id: PRINT_INT, message: message.clone() };
+
+        let previous = set_output(Box::new(PanickingSink));
+        let interpreted = Interpreter::new(program.clone()).execute();
+        let oracle = crate::oracle::execute(&program);
+        #[cfg(feature = "jit")]
+        let compiled: Vec<_> = [false, true]
+            .into_iter()
+            .map(|check_stack| {
+                let options = crate::jit::JitOptions { check_stack, ..Default::default() };
+                crate::jit::JitCompiler::new(program.clone())
+                    .with_options(options)
+                    .compile()
+                    .unwrap()
+                    .call_checked(&[])
+            })
+            .collect();
+        match previous {
+            Some(previous) => set_output(previous),
+            None => reset_output(),
+        };
+
+        assert_eq!(interpreted, Err(expected()));
+        assert_eq!(oracle, Err(ExecError::Interpreter(expected())));
+        #[cfg(feature = "jit")]
+        for result in compiled {
+            assert_eq!(result, Err(crate::jit::JitFault::NativePanicked { id: PRINT_INT, message: message.clone() }));
+        }
+    }
+
+    #[test]
+    fn counter_persists_across_runs_and_backends() {
+        let program = Parser::parse(COUNTER).unwrap();
+        let mut policy = Sandbox::new();
+        policy.grant(Capability::Storage);
+        let store = SharedStore::default();
+        let interpret = |store: &SharedStore| {
+            Interpreter::from_validated(program.validated(&policy).unwrap()).with_store(store.clone()).execute()
+        };
+
+        store.start_audit();
+        assert_eq!(interpret(&store), Ok(1));
+        assert_eq!(interpret(&store), Ok(2));
+        #[cfg(feature = "jit")]
+        {
+            let compiled = crate::jit::JitCompiler::from_validated(program.validated(&policy).unwrap(), policy.clone())
+                .compile()
+                .unwrap()
+                .with_store(store.clone());
+            assert_eq!(compiled.run(), 3);
+            assert_eq!(compiled.run(), 4);
+            assert_eq!(interpret(&store), Ok(5));
+        }
+        let audit = store.take_audit();
+        assert_eq!(audit[0], KvAccess { op: KvOp::Get, key: 7301, value: KV_MISS });
+        assert_eq!(audit[1], KvAccess { op: KvOp::Put, key: 7301, value: 1 });
+        assert_eq!(audit.last(), Some(&KvAccess { op: KvOp::Put, key: 7301, value: audit.len() as i64 / 2 }));
+
+        // Each interpreter without a store of its own starts empty
+        let unshared = || Interpreter::from_validated(program.validated(&policy).unwrap()).execute();
+        assert_eq!((unshared(), unshared()), (Ok(1), Ok(1)));
+    }
+
+    #[test]
+    fn deterministic_mode_follows_the_attached_store() {
+        let program = Parser::parse(COUNTER).unwrap();
+        let mut policy = Sandbox::new();
+        policy.grant(Capability::Storage);
+        policy.require_deterministic();
+        // The store isn't known yet, so validation accepts the program
+        let validated = program.validated(&policy).unwrap();
+
+        let interpret = |store: SharedStore| Interpreter::from_validated(validated.clone()).with_store(store).execute();
+        assert_eq!(interpret(SharedStore::default()), Ok(1));
+        assert_eq!(interpret(SharedStore::new(Remote)), Err(InterpreterError::NondeterministicStore { id: KV_GET }));
+
+        #[cfg(feature = "jit")]
+        {
+            use crate::jit::{JitCompiler, JitFault};
+            let compile = || JitCompiler::from_validated(validated.clone(), policy.clone()).compile().unwrap();
+            assert_eq!(compile().call_checked(&[]), Ok(1));
+            assert_eq!(
+                compile().with_store(SharedStore::new(Remote)).call_checked(&[]),
+                Err(JitFault::NondeterministicStore { id: KV_GET })
+            );
+        }
+
+        // Outside deterministic mode any store will do
+        let mut lenient = Sandbox::new();
+        lenient.grant(Capability::Storage);
+        let interpreted = Interpreter::from_validated(program.validated(&lenient).unwrap())
+            .with_store(SharedStore::new(Remote))
+            .execute();
+        assert_eq!(interpreted, Ok(1));
+    }
+}
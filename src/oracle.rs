@@ -0,0 +1,353 @@
+//! Reference interpreter for differential testing.
+//!
+//! Every opcode's behavior is one closure in a table built from `MNEMONICS`,
+//! looked up afresh for each executed instruction: no pre-decoding, no fast
+//! paths, and no options beyond what `Program::run_catching` uses. The
+//! table's entries come from `semantics`, whose match has no wildcard arm,
+//! so an opcode added without an oracle entry does not compile.
+//!
+//! `Differential` runs a program on several backends; with the oracle,
+//! the interpreter and the JIT, a bug in one of them is outvoted 2-to-1.
+
+use crate::bytecode::{Backend, ExecError, Instruction, OpCode, Program, MNEMONICS};
+use crate::interpreter::{self, InterpreterError};
+use crate::natives;
+use std::fmt;
+use std::sync::OnceLock;
+
+/// State of an oracle run
+struct Machine<'a> {
+    program: &'a Program,
+    stack: Vec<i64>,
+    /// Exactly `memory_size` slots, with the `.data` blocks written in
+    memory: Vec<i64>,
+    pc: usize,
+    /// Empty at the start of every run, like a fresh interpreter's
+    store: natives::SharedStore,
+}
+
+/// Where execution continues after an instruction
+enum Flow {
+    Next,
+    Jump(u32),
+    End,
+}
+
+/// Behavior of one opcode, given the instruction with its operands
+type Semantics = fn(&mut Machine, &Instruction) -> Result<Flow, ExecError>;
+
+/// The operand of a single-operand instruction; the table only passes an
+/// entry instructions of its own opcode
+macro_rules! operand {
+    ($instruction:expr, $variant:ident) => {
+        match $instruction {
+            Instruction::$variant(operand) => *operand,
+            other => unreachable!("{:?} dispatched to the {} entry", other, stringify!($variant)),
+        }
+    };
+}
+
+/// The oracle's definition of `opcode`
+fn semantics(opcode: OpCode) -> Semantics {
+    match opcode {
+        OpCode::PushInt => |m, i| m.push(operand!(i, PushInt)),
+        // Rejected by the sandbox; there are no registers to read
+        OpCode::PushReg => |_, _| Err(ExecError::Rejected("PUSH_REG has no defined semantics".to_string())),
+        OpCode::Pop => |m, _| m.pop().map(|_| Flow::Next),
+
+        OpCode::Add => |m, _| m.binary(i64::wrapping_add),
+        OpCode::Sub => |m, _| m.binary(i64::wrapping_sub),
+        OpCode::Mul => |m, _| m.binary(i64::wrapping_mul),
+        OpCode::Div => |m, _| {
+            let b = m.pop()?;
+            let a = m.pop()?;
+            if b == 0 {
+                return Err(ExecError::DivisionByZero);
+            }
+            m.push(a.wrapping_div(b))
+        },
+        OpCode::ShlImm => |m, i| {
+            let count = u32::from(operand!(i, ShlImm)) % 64;
+            m.unary(|a| a << count)
+        },
+        OpCode::ShrImm => |m, i| {
+            let count = u32::from(operand!(i, ShrImm)) % 64;
+            m.unary(|a| ((a as u64) >> count) as i64)
+        },
+        OpCode::SarImm => |m, i| {
+            let count = u32::from(operand!(i, SarImm)) % 64;
+            m.unary(|a| a >> count)
+        },
+
+        OpCode::Eq => |m, _| m.binary(|a, b| (a == b).into()),
+        OpCode::Lt => |m, _| m.binary(|a, b| (a < b).into()),
+        OpCode::Gt => |m, _| m.binary(|a, b| (a > b).into()),
+        OpCode::Bool => |m, _| m.unary(|a| (a != 0).into()),
+        OpCode::NotBool => |m, _| m.unary(|a| (a == 0).into()),
+
+        OpCode::Jump => |_, i| Ok(Flow::Jump(operand!(i, Jump))),
+        OpCode::JumpIfZero => |m, i| {
+            let taken = m.pop()? == 0;
+            Ok(if taken { Flow::Jump(operand!(i, JumpIfZero)) } else { Flow::Next })
+        },
+        OpCode::JumpIfNotZero => |m, i| {
+            let taken = m.pop()? != 0;
+            Ok(if taken { Flow::Jump(operand!(i, JumpIfNotZero)) } else { Flow::Next })
+        },
+        OpCode::JumpTable => |m, i| {
+            let Instruction::JumpTable(table) = i else {
+                unreachable!("{:?} dispatched to the JumpTable entry", i);
+            };
+            let selector = m.pop()?;
+            Ok(Flow::Jump(table.resolve(selector)))
+        },
+
+        OpCode::Load => |m, i| {
+            let value = *m.slot(operand!(i, Load).into())?;
+            m.push(value)
+        },
+        OpCode::Store => |m, i| {
+            let value = m.pop()?;
+            *m.slot(operand!(i, Store).into())? = value;
+            Ok(Flow::Next)
+        },
+        OpCode::MemAdd => |m, i| {
+            let Instruction::MemAdd(offset, imm) = i else {
+                unreachable!("{:?} dispatched to the MemAdd entry", i);
+            };
+            let slot = m.slot((*offset).into())?;
+            *slot = slot.wrapping_add((*imm).into());
+            Ok(Flow::Next)
+        },
+        OpCode::LoadInd => |m, _| {
+            let offset = m.pop()?;
+            let value = *m.slot(offset)?;
+            m.push(value)
+        },
+        OpCode::StoreInd => |m, _| {
+            let value = m.pop()?;
+            let offset = m.pop()?;
+            *m.slot(offset)? = value;
+            Ok(Flow::Next)
+        },
+
+        OpCode::CallNative => |m, i| {
+            // Natives without an implementation are ignored, as in the interpreter
+            let id = operand!(i, CallNative);
+            let Some(native) = natives::lookup(id) else {
+                return Ok(Flow::Next);
+            };
+            let mut args = (0..native.args()).map(|_| m.pop()).collect::<Result<Vec<_>, _>>()?;
+            args.reverse();
+            let result = native
+                .call(&args, &m.store)
+                .map_err(|message| ExecError::Interpreter(InterpreterError::NativePanicked { id, message }))?;
+            m.stack.extend(result);
+            Ok(Flow::Next)
+        },
+        // There are no subroutine calls yet, so every RETURN is at top level
+        OpCode::Return => |_, _| Ok(Flow::End),
+
+        OpCode::Breakpoint => |_, _| Ok(Flow::Next),
+        OpCode::ReadCycles => |m, _| m.push(interpreter::monotonic_nanos()),
+
+        OpCode::Trap => |_, i| Err(ExecError::Trap(operand!(i, Trap))),
+        OpCode::Halt => |_, _| Ok(Flow::End),
+    }
+}
+
+/// `semantics` for every opcode in `MNEMONICS`, indexed by opcode byte
+fn table() -> &'static [Option<Semantics>; 256] {
+    static TABLE: OnceLock<[Option<Semantics>; 256]> = OnceLock::new();
+    TABLE.get_or_init(|| {
+        let mut table = [None; 256];
+        for mnemonic in MNEMONICS {
+            table[mnemonic.opcode as usize] = Some(semantics(mnemonic.opcode));
+        }
+        table
+    })
+}
+
+impl Machine<'_> {
+    fn push(&mut self, value: i64) -> Result<Flow, ExecError> {
+        self.stack.push(value);
+        Ok(Flow::Next)
+    }
+
+    fn pop(&mut self) -> Result<i64, ExecError> {
+        self.stack.pop().ok_or(ExecError::Interpreter(InterpreterError::StackUnderflow))
+    }
+
+    fn unary(&mut self, op: impl Fn(i64) -> i64) -> Result<Flow, ExecError> {
+        let a = self.pop()?;
+        self.push(op(a))
+    }
+
+    fn binary(&mut self, op: impl Fn(i64, i64) -> i64) -> Result<Flow, ExecError> {
+        let b = self.pop()?;
+        let a = self.pop()?;
+        self.push(op(a, b))
+    }
+
+    /// The memory slot at `offset`, direct or indirect alike
+    fn slot(&mut self, offset: i64) -> Result<&mut i64, ExecError> {
+        usize::try_from(offset)
+            .ok()
+            .and_then(|offset| self.memory.get_mut(offset))
+            .ok_or(ExecError::OutOfBounds)
+    }
+}
+
+/// Run `program` on the oracle and return the top of the stack, 0 if it
+/// is empty. Errors are reported as `Program::run_catching` reports them;
+/// the program should already be validated.
+pub fn execute(program: &Program) -> Result<i64, ExecError> {
+    let mut memory = vec![0; program.memory_size];
+    for block in &program.data {
+        for (offset, &value) in (block.offset as usize..).zip(&block.values) {
+            if let Some(slot) = memory.get_mut(offset) {
+                *slot = value;
+            }
+        }
+    }
+    let mut machine = Machine { program, stack: Vec::new(), memory, pc: 0, store: Default::default() };
+
+    while let Some(instruction) = machine.program.instructions.get(machine.pc) {
+        let entry = table()[instruction.opcode() as usize].expect("every opcode has a table entry");
+        match entry(&mut machine, instruction)? {
+            Flow::Next => machine.pc += 1,
+            Flow::Jump(target) if (target as usize) < program.instructions.len() => machine.pc = target as usize,
+            Flow::Jump(target) => {
+                return Err(ExecError::Interpreter(InterpreterError::InvalidJumpTarget(target as usize)));
+            }
+            Flow::End => break,
+        }
+    }
+    Ok(machine.stack.pop().unwrap_or(0))
+}
+
+/// Results of one program on several backends
+#[derive(Debug, PartialEq, Eq)]
+pub struct Differential {
+    pub outcomes: Vec<(Backend, Result<i64, ExecError>)>,
+}
+
+impl Differential {
+    /// Run `program` through `Program::run_catching` on each of `backends`
+    pub fn run(program: &Program, backends: &[Backend]) -> Self {
+        let outcomes = backends.iter().map(|&backend| (backend, program.run_catching(backend))).collect();
+        Self { outcomes }
+    }
+
+    /// Whether every backend produced the same result or error
+    pub fn agrees(&self) -> bool {
+        self.outcomes.windows(2).all(|pair| pair[0].1 == pair[1].1)
+    }
+
+    /// The backend outvoted by all the others, which agree with each other;
+    /// `None` if everything agrees, fewer than three backends ran, or the
+    /// disagreement has no majority
+    pub fn culprit(&self) -> Option<Backend> {
+        if self.outcomes.len() < 3 {
+            return None;
+        }
+        let mut odd = self.outcomes.iter().filter(|(_, outcome)| {
+            let matching = self.outcomes.iter().filter(|(_, other)| other == outcome).count();
+            matching == 1
+        });
+        match (odd.next(), odd.next()) {
+            (Some((backend, _)), None) => Some(*backend),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Differential {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (backend, outcome) in &self.outcomes {
+            let name = format!("{:?}", backend).to_lowercase();
+            match outcome {
+                Ok(value) => writeln!(f, "  {:<12} {}", name, value)?,
+                Err(error) => writeln!(f, "  {:<12} error: {:?}", name, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+
+    /// Every backend in this build
+    fn backends() -> Vec<Backend> {
+        [
+            Backend::Oracle,
+            Backend::Interpreter,
+            #[cfg(feature = "jit")]
+            Backend::Jit,
+        ]
+        .into()
+    }
+
+    #[test]
+    fn table_has_an_entry_for_exactly_the_defined_opcodes() {
+        for byte in 0..=255u8 {
+            assert_eq!(table()[byte as usize].is_some(), OpCode::from_u8(byte).is_some(), "opcode {:#04x}", byte);
+        }
+    }
+
+    #[test]
+    fn examples_agree_on_every_backend() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/examples");
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "cinder") {
+                continue;
+            }
+            // BREAKPOINT is `int3` in JIT code
+            let source = std::fs::read_to_string(&path).unwrap();
+            let mut backends = backends();
+            if source.contains("# backends: interpreter\n") {
+                backends.retain(|&backend| matches!(backend, Backend::Oracle | Backend::Interpreter));
+            }
+            let program = Parser::parse_file(path.to_str().unwrap()).unwrap();
+            let runs = Differential::run(&program, &backends);
+            assert!(runs.agrees(), "{}:\n{}", path.display(), runs);
+        }
+    }
+
+    #[test]
+    fn edge_cases_agree_on_every_backend() {
+        let sources = [
+            "PUSH_INT -9223372036854775808\nPUSH_INT -1\nDIV\nRETURN",
+            "PUSH_INT -5\nSHL_IMM 63\nPUSH_INT -5\nSHR_IMM 64\nADD\nPUSH_INT -5\nSAR_IMM 255\nADD\nRETURN",
+            "PUSH_INT 3\nPUSH_INT 3\nEQ\nPUSH_INT -1\nPUSH_INT 0\nLT\nADD\nNOT_BOOL\nRETURN",
+            ".memory 2\nPUSH_INT -1\nLOAD_IND\nRETURN",
+            ".memory 2\nPUSH_INT 1\nPUSH_INT 5\nSTORE_IND\nMEM_ADD 1 -7\nLOAD 1\nRETURN",
+            ".memory 2\n.data 1 40\nPUSH_INT 2\nLOAD 1\nADD\nRETURN",
+            "PUSH_INT 9\nJUMP_TABLE -1 4 2 3\nPUSH_INT 1\nRETURN\nTRAP 5",
+        ];
+        for source in sources {
+            let runs = Differential::run(&Parser::parse(source).unwrap(), &backends());
+            assert!(runs.agrees(), "{}\n{}", source, runs);
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "jit")]
+    fn culprit_is_the_backend_outvoted_two_to_one() {
+        let runs = |interpreter: Result<i64, ExecError>, jit| Differential {
+            outcomes: vec![(Backend::Oracle, Ok(1)), (Backend::Interpreter, interpreter), (Backend::Jit, jit)],
+        };
+        assert_eq!(runs(Ok(1), Ok(1)).culprit(), None);
+        assert_eq!(runs(Err(ExecError::DivisionByZero), Ok(1)).culprit(), Some(Backend::Interpreter));
+        assert_eq!(runs(Ok(2), Ok(3)).culprit(), None);
+
+        // Only two results can't tell which one is wrong
+        let two = Differential { outcomes: vec![(Backend::Oracle, Ok(1)), (Backend::Interpreter, Ok(2))] };
+        assert!(!two.agrees());
+        assert_eq!(two.culprit(), None);
+    }
+}
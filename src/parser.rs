@@ -1,136 +1,1057 @@
-use crate::bytecode::{Instruction, OpCode, Program};
-use anyhow::{Context, Result};
+use crate::bytecode::{align_up, DataBlock, Instruction, JumpTable, OpCode, Program, SlotDecl, SlotType};
+use crate::error::CinderError;
+use crate::natives;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
 
 /// Parser for .cinder files
 pub struct Parser;
 
+/// A syntax error, located at the start of the offending line (1-based)
+#[derive(Debug, thiserror::Error)]
+#[error("line {line}, column {column}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+/// A label defined twice; without this the later definition would
+/// silently win
+#[derive(Debug, thiserror::Error)]
+#[error("line {second_line}: duplicate label '{name}' (first defined on line {first_line})")]
+pub struct DuplicateLabel {
+    pub name: String,
+    pub first_line: usize,
+    pub second_line: usize,
+}
+
+/// What went wrong on one line; `parse_source` locates it as a `ParseError`
+#[derive(Debug)]
+struct LineError(String);
+
+impl fmt::Display for LineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+type Result<T, E = LineError> = std::result::Result<T, E>;
+
+macro_rules! line_error {
+    ($($arg:tt)*) => {
+        LineError(format!($($arg)*))
+    };
+}
+
+/// Prefix a lower-level error with what was being parsed
+trait Context<T> {
+    fn context(self, context: &str) -> Result<T>;
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T>;
+}
+
+impl<T, E: fmt::Display> Context<T> for Result<T, E> {
+    fn context(self, context: &str) -> Result<T> {
+        self.map_err(|error| line_error!("{}: {}", context, error))
+    }
+
+    fn with_context(self, context: impl FnOnce() -> String) -> Result<T> {
+        self.map_err(|error| line_error!("{}: {}", context(), error))
+    }
+}
+
+/// Bounds on what `.include` may expand to. Cycles are rejected anyway,
+/// but a deep chain of files, or files that include the next one several
+/// times over, would otherwise exhaust the stack or grow exponentially.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Files open at once: the main file and the includes nested in it
+    pub max_include_depth: usize,
+    /// `.include` lines expanded in total, each time its file is included
+    pub max_includes: usize,
+    /// Instructions spliced in by `.include`, summed over every level of
+    /// nesting
+    pub max_included_instructions: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        Self {
+            max_include_depth: 32,
+            max_includes: 4096,
+            max_included_instructions: 1 << 20,
+        }
+    }
+}
+
+/// The `ParseLimits` budget an include tree ran out of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExpansionBudget {
+    IncludeDepth,
+    Includes,
+    IncludedInstructions,
+}
+
+impl fmt::Display for ExpansionBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ExpansionBudget::IncludeDepth => "include depth",
+            ExpansionBudget::Includes => "number of includes",
+            ExpansionBudget::IncludedInstructions => "number of included instructions",
+        })
+    }
+}
+
+/// An include tree larger than `ParseLimits` allows
+#[derive(Debug, thiserror::Error)]
+#[error("{budget} exceeds the limit of {limit} at {}", display_chain(.chain))]
+pub struct ExpansionBudgetExceeded {
+    pub budget: ExpansionBudget,
+    pub limit: usize,
+    /// Canonical paths from the main file to the include that went over
+    pub chain: Vec<PathBuf>,
+}
+
+fn display_chain(chain: &[PathBuf]) -> String {
+    chain.iter().map(|path| path.display().to_string()).collect::<Vec<_>>().join(" -> ")
+}
+
+/// Include expansion in progress, shared by every file of the tree
+pub(crate) struct Expansion {
+    /// Canonical paths of the files being parsed, outermost first, to
+    /// reject include cycles
+    pub(crate) open: Vec<PathBuf>,
+    limits: ParseLimits,
+    includes: usize,
+    included_instructions: usize,
+}
+
+impl Expansion {
+    /// Expansion of the file whose canonical path is `root`, if any
+    pub(crate) fn new(root: Option<PathBuf>, limits: ParseLimits) -> Self {
+        Self {
+            open: root.into_iter().collect(),
+            limits,
+            includes: 0,
+            included_instructions: 0,
+        }
+    }
+
+    fn exceeded(&self, budget: ExpansionBudget, limit: usize) -> CinderError {
+        ExpansionBudgetExceeded { budget, limit, chain: self.open.clone() }.into()
+    }
+}
+
+/// Directives and instructions collected so far
+#[derive(Default)]
+struct ParseState {
+    instructions: Vec<Instruction>,
+    /// Size from `.memory`, if given
+    memory_size: Option<usize>,
+    returns: Option<usize>,
+    slots: Vec<SlotDecl>,
+    args: usize,
+    data: Vec<DataBlock>,
+    /// Where `.data .` places its values: the end of the last `.data`
+    /// block, rounded up by `.align`
+    data_cursor: u32,
+    /// Line of the first LOAD_IND/STORE_IND, which memory inference can't size
+    first_indirect: Option<usize>,
+    /// Files spliced in by `.include`, in order
+    included: Vec<Included>,
+}
+
+/// A file spliced in by `.include`. Its jump targets are relocated when it
+/// is spliced, its memory once the including file's own size is known.
+struct Included {
+    /// Its instructions in the including file's list
+    instructions: Range<usize>,
+    memory_size: usize,
+    data: Vec<DataBlock>,
+}
+
+impl ParseState {
+    /// Append an included program's instructions, jumps shifted to their new position
+    fn include(&mut self, program: Program) {
+        let start = self.instructions.len();
+        let base = start as u32;
+        self.instructions.extend(program.instructions.into_iter().map(|mut instruction| {
+            shift_jumps(&mut instruction, base);
+            instruction
+        }));
+        self.included.push(Included {
+            instructions: start..self.instructions.len(),
+            memory_size: program.memory_size,
+            data: program.data,
+        });
+    }
+}
+
+/// Source of the programs `.include` splices in
+pub(crate) trait IncludeLoader {
+    /// Parse the file at `path`, whose canonical path is the last of
+    /// `expansion.open`
+    fn load(&mut self, path: &Path, expansion: &mut Expansion) -> Result<Program, CinderError>;
+}
+
+/// Reads and parses every included file afresh
+struct FromDisk;
+
+impl IncludeLoader for FromDisk {
+    fn load(&mut self, path: &Path, expansion: &mut Expansion) -> Result<Program, CinderError> {
+        let content = fs::read_to_string(path)?;
+        Parser::parse_source(&content, Some(path), expansion, self)
+    }
+}
+
 impl Parser {
-    /// Parse a .cinder file and return a Program
-    pub fn parse_file(path: &str) -> Result<Program> {
-        let content = fs::read_to_string(path)
-            .with_context(|| format!("Cannot read file: {}", path))?;
+    /// Parse a .cinder file and return a Program, resolving `.include`
+    /// paths relative to it
+    pub fn parse_file(path: &str) -> Result<Program, CinderError> {
+        Self::parse_file_with_limits(path, ParseLimits::default())
+    }
+
+    /// Parse a .cinder file like `parse_file`, with `limits` on its includes
+    pub fn parse_file_with_limits(path: &str, limits: ParseLimits) -> Result<Program, CinderError> {
+        let content = fs::read_to_string(path)?;
+        let mut expansion = Expansion::new(Some(fs::canonicalize(path)?), limits);
         
-        Self::parse(&content)
+        Self::parse_source(&content, Some(Path::new(path)), &mut expansion, &mut FromDisk)
     }
 
-    /// Parse the content of a .cinder file
-    pub fn parse(content: &str) -> Result<Program> {
-        let mut instructions = Vec::new();
-        let mut memory_size = 1024; // Default
+    /// Parse the content of a .cinder file. `.include` needs the file's
+    /// location, so it is only accepted by `parse_file`.
+    pub fn parse(content: &str) -> Result<Program, CinderError> {
+        Self::parse_source(content, None, &mut Expansion::new(None, ParseLimits::default()), &mut FromDisk)
+    }
+
+    /// Parse `content`, read from `path` if it came from a file, getting
+    /// included programs from `loader` within the budget of `expansion`.
+    pub(crate) fn parse_source(
+        content: &str,
+        path: Option<&Path>,
+        expansion: &mut Expansion,
+        loader: &mut dyn IncludeLoader,
+    ) -> Result<Program, CinderError> {
+        let mut includes = Self::parse_includes(content, path, expansion, loader)?;
+        let labels = Self::collect_labels(content, &includes)?;
+        let mut state = ParseState::default();
         
-        for line in content.lines() {
-            let line = line.trim();
+        for (number, raw) in source_lines(content).enumerate() {
+            let line = code_of(raw);
             
             // Ignore comments and empty lines
             if line.is_empty() || line.starts_with('#') {
                 continue;
             }
             
-            // Parse special directives
-            if line.starts_with(".memory") {
-                let parts: Vec<&str> = line.split_whitespace().collect();
-                if parts.len() == 2 {
-                    memory_size = parts[1]
-                        .parse()
-                        .context("Invalid memory size")?;
+            // Editors show these as plain spaces (or nothing), so name them
+            // rather than failing later with a baffling token
+            if let Some((at, c)) = raw.char_indices().take_while(|&(_, c)| c != ';').find(|&(_, c)| is_invisible(c)) {
+                return Err(ParseError {
+                    line: number + 1,
+                    column: at + 1,
+                    message: format!("Invisible character U+{:04X} ({}); use a plain space", c as u32, invisible_name(c)),
                 }
+                .into());
+            }
+            
+            // Labels were already collected
+            if Self::label_name(line).is_some() {
                 continue;
             }
             
-            // Parse instructions
-            let parts: Vec<&str> = line.split_whitespace().collect();
-            if parts.is_empty() {
+            if let Some(program) = includes.remove(&number) {
+                state.include(program);
                 continue;
             }
             
-            let opcode_str = parts[0].to_uppercase();
-            let instruction = match opcode_str.as_str() {
-                "PUSH_INT" => {
-                    let val = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("PUSH_INT requires value"))?
-                        .parse()
-                        .context("Invalid value for PUSH_INT")?;
-                    Instruction::PushInt(val)
-                }
-                
-                "PUSH_REG" => {
-                    let reg = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("PUSH_REG requires register number"))?
-                        .parse()
-                        .context("Invalid register")?;
-                    Instruction::PushReg(reg)
-                }
-                
-                "POP" => Instruction::Pop,
-                "ADD" => Instruction::Add,
-                "SUB" => Instruction::Sub,
-                "MUL" => Instruction::Mul,
-                "DIV" => Instruction::Div,
-                "EQ" => Instruction::Eq,
-                "LT" => Instruction::Lt,
-                "GT" => Instruction::Gt,
-                
-                "JUMP" => {
-                    let target = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("JUMP requires target"))?
-                        .parse()
-                        .context("Invalid target for JUMP")?;
-                    Instruction::Jump(target)
-                }
-                
-                "JUMP_IF_ZERO" => {
-                    let target = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("JUMP_IF_ZERO requires target"))?
-                        .parse()
-                        .context("Invalid target for JUMP_IF_ZERO")?;
-                    Instruction::JumpIfZero(target)
-                }
-                
-                "JUMP_IF_NOT_ZERO" => {
-                    let target = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("JUMP_IF_NOT_ZERO requires target"))?
-                        .parse()
-                        .context("Invalid target for JUMP_IF_NOT_ZERO")?;
-                    Instruction::JumpIfNotZero(target)
-                }
-                
-                "LOAD" => {
-                    let offset = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("LOAD requires offset"))?
-                        .parse()
-                        .context("Invalid offset for LOAD")?;
-                    Instruction::Load(offset)
-                }
-                
-                "STORE" => {
-                    let offset = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("STORE requires offset"))?
-                        .parse()
-                        .context("Invalid offset for STORE")?;
-                    Instruction::Store(offset)
-                }
-                
-                "CALL_NATIVE" => {
-                    let id = parts.get(1)
-                        .ok_or_else(|| anyhow::anyhow!("CALL_NATIVE requires ID"))?
-                        .parse()
-                        .context("Invalid ID for CALL_NATIVE")?;
-                    Instruction::CallNative(id)
+            Self::parse_line(line, &labels, &mut state).map_err(|error| ParseError {
+                line: number + 1,
+                column: raw.len() - raw.trim_start().len() + 1,
+                message: error.0,
+            })?;
+            if state.first_indirect.is_none()
+                && matches!(state.instructions.last(), Some(Instruction::LoadInd | Instruction::StoreInd))
+            {
+                state.first_indirect = Some(number + 1);
+            }
+        }
+        
+        if let (None, Some(line)) = (state.memory_size, state.first_indirect) {
+            return Err(ParseError {
+                line,
+                column: 1,
+                message: "LOAD_IND and STORE_IND need an explicit .memory size".to_string(),
+            }
+            .into());
+        }
+        
+        let mut program = Program::new(state.instructions, state.memory_size.unwrap_or(0));
+        program.returns = state.returns;
+        program.slots = state.slots;
+        program.args = state.args;
+        program.data = state.data;
+        
+        // Without `.memory`, allocate exactly what the program addresses
+        if state.memory_size.is_none() {
+            program = program.with_inferred_memory();
+        }
+        if !state.included.is_empty() {
+            Self::link(&mut program, state.memory_size, state.included);
+        }
+        Ok(program)
+    }
+
+    /// Give each included file its own memory region after the including
+    /// file's, in include order, moving its static offsets and `.data`
+    /// there. The including file's region is its `.memory`, or what its own
+    /// instructions, slots and data address.
+    fn link(program: &mut Program, declared: Option<usize>, included: Vec<Included>) {
+        let own_memory = declared.unwrap_or_else(|| {
+            let mut own = program.clone();
+            own.instructions.clear();
+            own.instructions.extend(program.instructions.iter().enumerate().filter_map(|(pc, instruction)| {
+                let spliced = included.iter().any(|file| file.instructions.contains(&pc));
+                (!spliced).then(|| instruction.clone())
+            }));
+            own.required_memory()
+        });
+
+        let mut memory_size = own_memory;
+        for file in included {
+            let base = u32::try_from(memory_size).unwrap_or(u32::MAX);
+            for instruction in &mut program.instructions[file.instructions] {
+                shift_memory(instruction, base);
+            }
+            program.data.extend(file.data.into_iter().map(|block| DataBlock {
+                offset: block.offset.saturating_add(base),
+                values: block.values,
+            }));
+            memory_size += file.memory_size;
+        }
+
+        // The sum of the regions, which inference from the linked program
+        // could undercount, so `disassemble` writes it out
+        program.memory_size = memory_size;
+        program.memory_inferred = false;
+    }
+
+    /// Parse the file named by each `.include "path"` line of `content`,
+    /// keyed by line index. Paths are relative to `path`'s directory.
+    fn parse_includes(
+        content: &str,
+        path: Option<&Path>,
+        expansion: &mut Expansion,
+        loader: &mut dyn IncludeLoader,
+    ) -> Result<HashMap<usize, Program>, CinderError> {
+        let mut includes = HashMap::new();
+        for (number, raw) in source_lines(content).enumerate() {
+            let Some(name) = code_of(raw).strip_prefix(".include") else {
+                continue;
+            };
+            let error = |message: String| ParseError {
+                line: number + 1,
+                column: raw.len() - raw.trim_start().len() + 1,
+                message,
+            };
+            let name = name
+                .trim()
+                .strip_prefix('"')
+                .and_then(|name| name.strip_suffix('"'))
+                .ok_or_else(|| error(".include requires a quoted file name".to_string()))?;
+            let Some(path) = path else {
+                return Err(error(".include is only supported when parsing a file".to_string()).into());
+            };
+            
+            let target = path.parent().unwrap_or(Path::new("")).join(name);
+            let canonical = fs::canonicalize(&target).map_err(|e| error(format!("Cannot read {}: {}", name, e)))?;
+            if expansion.open.contains(&canonical) {
+                return Err(error(format!("{} is already being included", name)).into());
+            }
+            expansion.open.push(canonical);
+            let program = Self::expand(&target, expansion, loader);
+            expansion.open.pop();
+            
+            // Budget errors already name the whole chain
+            let program = program.map_err(|e| match e {
+                CinderError::Expansion(exceeded) => exceeded.into(),
+                e => CinderError::from(error(format!("In {}: {}", name, e))),
+            })?;
+            if program.args > 0 || program.returns.is_some() {
+                return Err(error(format!("{}: .args and .returns are only allowed in the main file", name)).into());
+            }
+            if program.instructions.iter().any(|i| matches!(i, Instruction::LoadInd | Instruction::StoreInd)) {
+                return Err(error(format!("{}: LOAD_IND and STORE_IND offsets cannot be relocated", name)).into());
+            }
+            includes.insert(number, program);
+        }
+        Ok(includes)
+    }
+
+    /// Load the include that was just opened, charging it to the budget
+    fn expand(target: &Path, expansion: &mut Expansion, loader: &mut dyn IncludeLoader) -> Result<Program, CinderError> {
+        let limits = expansion.limits;
+        if expansion.open.len() > limits.max_include_depth {
+            return Err(expansion.exceeded(ExpansionBudget::IncludeDepth, limits.max_include_depth));
+        }
+        expansion.includes += 1;
+        if expansion.includes > limits.max_includes {
+            return Err(expansion.exceeded(ExpansionBudget::Includes, limits.max_includes));
+        }
+        let program = loader.load(target, expansion)?;
+        expansion.included_instructions += program.instructions.len();
+        if expansion.included_instructions > limits.max_included_instructions {
+            return Err(expansion.exceeded(ExpansionBudget::IncludedInstructions, limits.max_included_instructions));
+        }
+        Ok(program)
+    }
+
+    /// Parse one directive or instruction into `state`
+    fn parse_line(line: &str, labels: &HashMap<&str, u32>, state: &mut ParseState) -> Result<()> {
+        // Parse special directives
+        if line.starts_with(".memory") {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            if parts.len() == 2 {
+                state.memory_size = Some(parts[1]
+                    .parse()
+                    .context("Invalid memory size")?);
+            }
+            return Ok(());
+        }
+        
+        if line.starts_with(".data") {
+            // .data <offset> <value>... initializes memory from offset on;
+            // an offset of `.` continues at the data cursor
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let offset = match *parts.get(1)
+                .ok_or_else(|| line_error!(".data requires an offset"))?
+            {
+                "." => state.data_cursor,
+                offset => Self::parse_offset(offset, &state.slots)
+                    .context("Invalid offset for .data")?,
+            };
+            let values = parts[2..]
+                .iter()
+                .map(|value| value.parse().with_context(|| format!("Invalid .data value: {}", value)))
+                .collect::<Result<Vec<i64>>>()?;
+            if values.is_empty() {
+                return Err(line_error!(".data requires at least one value"));
+            }
+            let block = DataBlock { offset, values };
+            state.data_cursor = u32::try_from(block.end())
+                .map_err(|_| line_error!(".data block ends past the largest offset"))?;
+            state.data.push(block);
+            return Ok(());
+        }
+        
+        if line.starts_with(".align") {
+            // .align <slots> pads the data cursor to a multiple of slots
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let alignment = parts
+                .get(1)
+                .ok_or_else(|| line_error!(".align requires an alignment"))?
+                .parse()
+                .context("Invalid alignment for .align")?;
+            state.data_cursor = align_up(state.data_cursor, alignment)
+                .ok_or_else(|| line_error!(".align requires a nonzero alignment that keeps the offset in range"))?;
+            return Ok(());
+        }
+        
+        if line.starts_with(".returns") {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let count = parts
+                .get(1)
+                .ok_or_else(|| line_error!(".returns requires a value count"))?
+                .parse()
+                .context("Invalid value count for .returns")?;
+            state.returns = Some(count);
+            return Ok(());
+        }
+        
+        if line.starts_with(".args") {
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            state.args = parts
+                .get(1)
+                .ok_or_else(|| line_error!(".args requires an argument count"))?
+                .parse()
+                .context("Invalid argument count for .args")?;
+            return Ok(());
+        }
+        
+        if let Some(decl) = line.strip_prefix(".var") {
+            // .var <name>: <type> declares the next memory slot
+            let (name, ty) = decl
+                .split_once(':')
+                .ok_or_else(|| line_error!(".var requires `name: type`"))?;
+            let name = name.trim();
+            if !Self::is_identifier(name) {
+                return Err(line_error!("Invalid .var name: {}", name));
+            }
+            if state.slots.iter().any(|slot| slot.name == name) {
+                return Err(line_error!("Duplicate .var: {}", name));
+            }
+            let ty = SlotType::from_name(ty.trim())
+                .ok_or_else(|| line_error!("Unknown .var type: {}", ty.trim()))?;
+            state.slots.push(SlotDecl {
+                name: name.to_string(),
+                offset: state.slots.len() as u32,
+                ty,
+            });
+            return Ok(());
+        }
+        
+        // Parse instructions
+        let parts: Vec<&str> = line.split_whitespace().collect();
+        if parts.is_empty() {
+            return Ok(());
+        }
+        
+        let opcode = OpCode::from_mnemonic(parts[0])
+            .ok_or_else(|| line_error!("Unknown instruction: {}", parts[0].to_ascii_uppercase()))?
+            .opcode;
+        let instruction = match opcode {
+            OpCode::PushInt => {
+                let val = parts.get(1)
+                    .ok_or_else(|| line_error!("PUSH_INT requires value"))?
+                    .parse()
+                    .context("Invalid value for PUSH_INT")?;
+                Instruction::PushInt(val)
+            }
+            
+            OpCode::PushReg => {
+                let reg = parts.get(1)
+                    .ok_or_else(|| line_error!("PUSH_REG requires register number"))?
+                    .parse()
+                    .context("Invalid register")?;
+                Instruction::PushReg(reg)
+            }
+            
+            OpCode::Pop => Instruction::Pop,
+            OpCode::Add => Instruction::Add,
+            OpCode::Sub => Instruction::Sub,
+            OpCode::Mul => Instruction::Mul,
+            OpCode::Div => Instruction::Div,
+            
+            OpCode::ShlImm | OpCode::ShrImm | OpCode::SarImm => {
+                let mnemonic = opcode.mnemonic();
+                let count = parts.get(1)
+                    .ok_or_else(|| line_error!("{} requires shift count", mnemonic))?
+                    .parse()
+                    .with_context(|| format!("Invalid shift count for {}", mnemonic))?;
+                match opcode {
+                    OpCode::ShlImm => Instruction::ShlImm(count),
+                    OpCode::ShrImm => Instruction::ShrImm(count),
+                    _ => Instruction::SarImm(count),
                 }
-                
-                "RETURN" => Instruction::Return,
-                "HALT" => Instruction::Halt,
-                
-                _ => {
-                    return Err(anyhow::anyhow!("Unknown instruction: {}", opcode_str));
+            }
+            
+            OpCode::Eq => Instruction::Eq,
+            OpCode::Lt => Instruction::Lt,
+            OpCode::Gt => Instruction::Gt,
+            OpCode::Bool => Instruction::Bool,
+            OpCode::NotBool => Instruction::NotBool,
+            
+            OpCode::Jump => {
+                let target = parts.get(1)
+                    .ok_or_else(|| line_error!("JUMP requires target"))?;
+                let target = Self::parse_target(target, labels)
+                    .context("Invalid target for JUMP")?;
+                Instruction::Jump(target)
+            }
+            
+            OpCode::JumpIfZero => {
+                let target = parts.get(1)
+                    .ok_or_else(|| line_error!("JZ requires target"))?;
+                let target = Self::parse_target(target, labels)
+                    .context("Invalid target for JZ")?;
+                Instruction::JumpIfZero(target)
+            }
+            
+            OpCode::JumpIfNotZero => {
+                let target = parts.get(1)
+                    .ok_or_else(|| line_error!("JNZ requires target"))?;
+                let target = Self::parse_target(target, labels)
+                    .context("Invalid target for JNZ")?;
+                Instruction::JumpIfNotZero(target)
+            }
+            
+            OpCode::JumpTable => {
+                // JUMP_TABLE <base> <default> <target>...
+                let base = parts.get(1)
+                    .ok_or_else(|| line_error!("JUMP_TABLE requires base"))?
+                    .parse()
+                    .context("Invalid base for JUMP_TABLE")?;
+                let default = parts.get(2)
+                    .ok_or_else(|| line_error!("JUMP_TABLE requires default target"))?;
+                let default = Self::parse_target(default, labels)
+                    .context("Invalid default target for JUMP_TABLE")?;
+                let targets = parts[3..]
+                    .iter()
+                    .map(|target| Self::parse_target(target, labels))
+                    .collect::<Result<Vec<u32>>>()
+                    .context("Invalid target for JUMP_TABLE")?;
+                if targets.is_empty() {
+                    return Err(line_error!("JUMP_TABLE requires at least one target"));
                 }
+                Instruction::JumpTable(Box::new(JumpTable { base, default, targets }))
+            }
+            
+            OpCode::Load => {
+                let offset = parts.get(1)
+                    .ok_or_else(|| line_error!("LOAD requires offset"))?;
+                let offset = Self::parse_offset(offset, &state.slots)
+                    .context("Invalid offset for LOAD")?;
+                Instruction::Load(offset)
+            }
+            
+            OpCode::Store => {
+                let offset = parts.get(1)
+                    .ok_or_else(|| line_error!("STORE requires offset"))?;
+                let offset = Self::parse_offset(offset, &state.slots)
+                    .context("Invalid offset for STORE")?;
+                Instruction::Store(offset)
+            }
+            
+            OpCode::MemAdd => {
+                let offset = parts.get(1)
+                    .ok_or_else(|| line_error!("MEM_ADD requires offset"))?;
+                let offset = Self::parse_offset(offset, &state.slots)
+                    .context("Invalid offset for MEM_ADD")?;
+                let imm = parts.get(2)
+                    .ok_or_else(|| line_error!("MEM_ADD requires an immediate"))?
+                    .parse()
+                    .context("Invalid immediate for MEM_ADD")?;
+                Instruction::MemAdd(offset, imm)
+            }
+            
+            OpCode::LoadInd => Instruction::LoadInd,
+            OpCode::StoreInd => Instruction::StoreInd,
+            
+            OpCode::CallNative => {
+                let id = parts.get(1)
+                    .ok_or_else(|| line_error!("CALL_NATIVE requires ID"))?;
+                let id = Self::parse_native(id)
+                    .context("Invalid ID for CALL_NATIVE")?;
+                Instruction::CallNative(id)
+            }
+            
+            OpCode::Return => Instruction::Return,
+            OpCode::Breakpoint => Instruction::Breakpoint,
+            OpCode::ReadCycles => Instruction::ReadCycles,
+            
+            OpCode::Trap => {
+                let code = parts.get(1)
+                    .ok_or_else(|| line_error!("TRAP requires an error code"))?
+                    .parse()
+                    .context("Invalid error code for TRAP")?;
+                Instruction::Trap(code)
+            }
+            
+            OpCode::Halt => Instruction::Halt,
+        };
+        
+        state.instructions.push(instruction);
+        Ok(())
+    }
+
+    /// Deprecated mnemonics used in `content`, as `line N: OLD is deprecated, use NEW`
+    pub fn deprecations(content: &str) -> Vec<String> {
+        let mut warnings = Vec::new();
+        for (number, line) in source_lines(content).enumerate() {
+            let Some(token) = line.split_whitespace().next() else {
+                continue;
             };
+            if let Some(mnemonic) = OpCode::from_mnemonic(token).filter(|m| m.deprecated) {
+                warnings.push(format!(
+                    "line {}: {} is deprecated, use {}",
+                    number + 1,
+                    mnemonic.name,
+                    mnemonic.opcode.mnemonic()
+                ));
+            }
+        }
+        warnings
+    }
+
+    /// Forth-style stack comments (`ADD ; ( a b -- sum )`) whose declared
+    /// effect disagrees with the instruction, as `line N: ...` messages.
+    /// `program` must be the result of parsing `content`. Lines after the
+    /// first `.include` aren't checked, since its instructions aren't in `content`.
+    pub fn stack_comment_warnings(content: &str, program: &Program) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let mut index = 0;
+        
+        for (number, raw) in source_lines(content).enumerate() {
+            let line = code_of(raw);
+            if line.starts_with(".include") {
+                break;
+            }
+            if line.is_empty() || line.starts_with('#') || line.starts_with('.') || Self::label_name(line).is_some() {
+                continue;
+            }
+            let Some(instruction) = program.instructions.get(index) else {
+                break;
+            };
+            index += 1;
             
-            instructions.push(instruction);
+            let Some((declared_pops, declared_pushes)) = raw
+                .split_once(';')
+                .and_then(|(_, comment)| Self::stack_comment(comment))
+            else {
+                continue;
+            };
+            let (pops, pushes) = instruction.stack_effect();
+            if (declared_pops, declared_pushes) != (pops, pushes) {
+                warnings.push(format!(
+                    "line {}: stack comment declares {} in, {} out but {} pops {} and pushes {}",
+                    number + 1,
+                    declared_pops,
+                    declared_pushes,
+                    instruction.opcode().mnemonic(),
+                    pops,
+                    pushes
+                ));
+            }
         }
         
-        Ok(Program::new(instructions, memory_size))
+        warnings
+    }
+
+    /// Inputs and outputs named by `( a b -- c )`, if `comment` has that form
+    fn stack_comment(comment: &str) -> Option<(usize, usize)> {
+        let inner = comment.trim().strip_prefix('(')?.strip_suffix(')')?;
+        let (inputs, outputs) = inner.split_once("--")?;
+        Some((inputs.split_whitespace().count(), outputs.split_whitespace().count()))
+    }
+
+    /// `content` with deprecated mnemonics replaced by their canonical
+    /// spelling; comments, labels and layout are kept
+    pub fn rewrite_deprecated(content: &str) -> String {
+        let mut out = String::with_capacity(content.len());
+        for line in content.split_inclusive(['\n', '\r']) {
+            let start = line.len() - line.trim_start_matches(|c: char| c.is_whitespace() || c == BOM).len();
+            let token = line[start..].split_whitespace().next().unwrap_or("");
+            match OpCode::from_mnemonic(token).filter(|m| m.deprecated) {
+                Some(mnemonic) => {
+                    out.push_str(&line[..start]);
+                    out.push_str(mnemonic.opcode.mnemonic());
+                    out.push_str(&line[start + token.len()..]);
+                }
+                None => out.push_str(line),
+            }
+        }
+        out
+    }
+
+    /// Map each `name:` label to the index of the instruction that follows
+    /// it, counting each of `includes` as its instructions.
+    /// A label defined twice refers to its last definition.
+    fn collect_labels<'a>(
+        content: &'a str,
+        includes: &HashMap<usize, Program>,
+    ) -> Result<HashMap<&'a str, u32>, DuplicateLabel> {
+        let mut labels = HashMap::new();
+        // Line of each definition, for duplicate reports
+        let mut defined_at = HashMap::new();
+        let mut index = 0;
+        
+        for (number, line) in source_lines(content).enumerate() {
+            if let Some(program) = includes.get(&number) {
+                index += program.instructions.len() as u32;
+                continue;
+            }
+            let line = code_of(line);
+            if line.is_empty() || line.starts_with('#') || line.starts_with('.') {
+                continue;
+            }
+            match Self::label_name(line) {
+                Some(name) => {
+                    if let Some(&first_line) = defined_at.get(name) {
+                        return Err(DuplicateLabel {
+                            name: name.to_string(),
+                            first_line,
+                            second_line: number + 1,
+                        });
+                    }
+                    defined_at.insert(name, number + 1);
+                    labels.insert(name, index);
+                }
+                None => index += 1,
+            }
+        }
+        
+        Ok(labels)
+    }
+
+    /// `name` if the line is a label definition `name:`
+    fn label_name(line: &str) -> Option<&str> {
+        let name = line.strip_suffix(':')?;
+        Self::is_identifier(name).then_some(name)
+    }
+
+    /// Label and variable names: a letter or `_`, then letters, digits or `_`
+    fn is_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// A jump target: an instruction index or a label name
+    fn parse_target(token: &str, labels: &HashMap<&str, u32>) -> Result<u32> {
+        if let Ok(index) = token.parse() {
+            return Ok(index);
+        }
+        labels
+            .get(token)
+            .copied()
+            .ok_or_else(|| line_error!("Unknown label: {}", token))
+    }
+
+    /// A memory offset: numeric or the name of a `.var` declared earlier
+    fn parse_offset(token: &str, slots: &[SlotDecl]) -> Result<u32> {
+        if let Ok(offset) = token.parse() {
+            return Ok(offset);
+        }
+        slots
+            .iter()
+            .find(|slot| slot.name == token)
+            .map(|slot| slot.offset)
+            .ok_or_else(|| line_error!("Unknown variable: {}", token))
+    }
+
+    /// A native function: numeric ID or name from the native table
+    fn parse_native(token: &str) -> Result<u32> {
+        if let Ok(id) = token.parse() {
+            return Ok(id);
+        }
+        natives::lookup_name(token)
+            .map(|native| native.id)
+            .ok_or_else(|| line_error!("Unknown native function: {}", token))
+    }
+}
+
+const BOM: char = '\u{FEFF}';
+
+/// Lines of `content` without a leading byte order mark; `\n`, `\r\n` and
+/// a lone `\r` all end a line
+fn source_lines(content: &str) -> impl Iterator<Item = &str> {
+    content
+        .strip_prefix(BOM)
+        .unwrap_or(content)
+        .split_terminator('\n')
+        .flat_map(|line| line.strip_suffix('\r').unwrap_or(line).split('\r'))
+}
+
+/// Move the jump targets of an included instruction to its position
+fn shift_jumps(instruction: &mut Instruction, by: u32) {
+    match instruction {
+        Instruction::Jump(target) | Instruction::JumpIfZero(target) | Instruction::JumpIfNotZero(target) => {
+            *target = target.saturating_add(by);
+        }
+        Instruction::JumpTable(table) => {
+            for target in table.targets.iter_mut().chain(std::iter::once(&mut table.default)) {
+                *target = target.saturating_add(by);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Move the static memory offset of an included instruction to its file's region
+fn shift_memory(instruction: &mut Instruction, by: u32) {
+    if let Instruction::Load(offset) | Instruction::Store(offset) | Instruction::MemAdd(offset, _) = instruction {
+        *offset = offset.saturating_add(by);
+    }
+}
+
+/// `line` without its `;` comment and surrounding whitespace
+fn code_of(line: &str) -> &str {
+    line.split(';').next().unwrap_or(line).trim()
+}
+
+/// Whitespace other than ASCII, and zero-width characters
+fn is_invisible(c: char) -> bool {
+    (c.is_whitespace() && !c.is_ascii_whitespace())
+        || matches!(c, '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{00AD}' | BOM)
+}
+
+fn invisible_name(c: char) -> &'static str {
+    match c {
+        '\u{00A0}' => "no-break space",
+        '\u{202F}' => "narrow no-break space",
+        '\u{2000}'..='\u{200A}' => "typographic space",
+        '\u{200B}' => "zero width space",
+        '\u{200C}' => "zero width non-joiner",
+        '\u{200D}' => "zero width joiner",
+        '\u{2060}' => "word joiner",
+        '\u{00AD}' => "soft hyphen",
+        BOM => "byte order mark",
+        '\u{2028}' => "line separator",
+        '\u{2029}' => "paragraph separator",
+        _ => "Unicode whitespace",
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comments_are_ignored_on_every_kind_of_line() {
+        let source = ".memory 16 ; c\nPUSH_INT 1 ; ( -- sel )\nloop: ; ( -- )\nJUMP_TABLE 0 3 2 3 ; ( sel -- )\nHALT\nPUSH_INT 0\nRETURN\n";
+        let program = Parser::parse(source).unwrap();
+        assert_eq!(program.memory_size, 16);
+        assert_eq!(
+            program.instructions[1],
+            Instruction::JumpTable(Box::new(JumpTable { base: 0, default: 3, targets: vec![2, 3] }))
+        );
+        assert_eq!(program.instructions.len(), 5);
+        assert!(Parser::stack_comment_warnings(source, &program).is_empty());
+    }
+
+    #[test]
+    fn wrong_stack_comment_warns() {
+        let source = "PUSH_INT 1\nPUSH_INT 2\nADD ; ( a b -- )\nRETURN\n";
+        let program = Parser::parse(source).unwrap();
+        assert_eq!(
+            Parser::stack_comment_warnings(source, &program),
+            ["line 3: stack comment declares 2 in, 0 out but ADD pops 2 and pushes 1"]
+        );
+    }
+
+    #[test]
+    fn indirect_access_needs_declared_memory() {
+        let error = Parser::parse("PUSH_INT 1\nSTORE 0\nPUSH_INT 0\nLOAD_IND\nRETURN").unwrap_err();
+        assert!(
+            matches!(&error, CinderError::Parse(ParseError { line: 4, .. })),
+            "{:?}",
+            error
+        );
+        let program = Parser::parse(".memory 8\nPUSH_INT 0\nLOAD_IND\nRETURN").unwrap();
+        assert_eq!(program.memory_size, 8);
+    }
+
+    #[test]
+    fn included_data_gets_its_own_region() {
+        let dir = std::env::temp_dir().join(format!("cinder-include-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("lib.cinder"), ".data 0 7 8\nLOAD 1\nSTORE 0\nLOAD 0\n").unwrap();
+        let main = dir.join("main.cinder");
+        fs::write(&main, ".data 0 100\n.include \"lib.cinder\"\nLOAD 0\nADD\nRETURN\n").unwrap();
+
+        let program = Parser::parse_file(main.to_str().unwrap());
+        fs::remove_dir_all(&dir).unwrap();
+        let program = program.unwrap();
+
+        // The library's slots 0 and 1 moved behind the main file's slot 0
+        assert_eq!(program.memory_size, 3);
+        assert_eq!(
+            program.data,
+            [DataBlock { offset: 0, values: vec![100] }, DataBlock { offset: 1, values: vec![7, 8] }]
+        );
+        assert_eq!(
+            program.instructions[..4],
+            [Instruction::Load(2), Instruction::Store(1), Instruction::Load(1), Instruction::Load(0)]
+        );
+        assert_eq!(program.run_catching(crate::bytecode::Backend::Interpreter), Ok(108));
+    }
+
+    #[test]
+    fn align_rounds_the_next_data_offset_up() {
+        let program = Parser::parse(".data 0 1 2 3\n.align 16\n.data . 7\n.align 16\n.data . 8 9\n.align 4\n.data . 10\n").unwrap();
+        assert_eq!(
+            program.data,
+            [
+                DataBlock { offset: 0, values: vec![1, 2, 3] },
+                DataBlock { offset: 16, values: vec![7] },
+                DataBlock { offset: 32, values: vec![8, 9] },
+                DataBlock { offset: 36, values: vec![10] },
+            ]
+        );
+        assert_eq!(program.memory_size, 37);
+
+        // An aligned cursor stays put, and an explicit offset moves it
+        let program = Parser::parse(".data 16 1\n.align 1\n.data . 2\n.align 16\n.data . 3\n").unwrap();
+        let offsets: Vec<u32> = program.data.iter().map(|block| block.offset).collect();
+        assert_eq!(offsets, [16, 17, 32]);
+
+        for source in [".align 0", ".align", ".align x", ".data 4294967290 1\n.align 16"] {
+            assert!(matches!(Parser::parse(source), Err(CinderError::Parse(_))), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn adversarial_include_trees_exceed_the_budget() {
+        let dir = std::env::temp_dir().join(format!("cinder-include-budget-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let parse = |main: &str, limits| Parser::parse_file_with_limits(dir.join(main).to_str().unwrap(), limits);
+
+        // A chain of 1000 files, each including the next
+        for i in 0..1000 {
+            fs::write(dir.join(format!("chain{}.cinder", i)), format!(".include \"chain{}.cinder\"\nPUSH_INT 2\n", i + 1)).unwrap();
+        }
+        fs::write(dir.join("chain1000.cinder"), "PUSH_INT 1\n").unwrap();
+        let deep = parse("chain0.cinder", ParseLimits::default());
+
+        // 30 files each including the next twice: 2^30 leaves
+        for i in 0..30 {
+            let next = format!(".include \"laugh{}.cinder\"\n", i + 1);
+            fs::write(dir.join(format!("laugh{}.cinder", i)), next.repeat(2)).unwrap();
+        }
+        fs::write(dir.join("laugh30.cinder"), "PUSH_INT 1\n").unwrap();
+        let wide = parse("laugh0.cinder", ParseLimits::default());
+        let small = ParseLimits { max_included_instructions: 100, ..ParseLimits::default() };
+        let long = parse("laugh24.cinder", small);
+        let within = parse("laugh24.cinder", ParseLimits::default());
+        fs::remove_dir_all(&dir).unwrap();
+
+        match deep {
+            Err(CinderError::Expansion(exceeded)) => {
+                assert_eq!(exceeded.budget, ExpansionBudget::IncludeDepth);
+                assert_eq!(exceeded.chain.len(), 33);
+                assert!(exceeded.chain[0].ends_with("chain0.cinder"));
+                assert!(exceeded.chain[32].ends_with("chain32.cinder"));
+                assert!(exceeded.to_string().starts_with("include depth exceeds the limit of 32 at "), "{}", exceeded);
+            }
+            other => panic!("expected the depth budget to run out, got {:?}", other.map(|p| p.instructions.len())),
+        }
+        match wide {
+            Err(CinderError::Expansion(exceeded)) => {
+                assert_eq!((exceeded.budget, exceeded.limit), (ExpansionBudget::Includes, 4096));
+                assert!(exceeded.chain[0].ends_with("laugh0.cinder"));
+            }
+            other => panic!("expected the include budget to run out, got {:?}", other.map(|p| p.instructions.len())),
+        }
+        assert!(matches!(
+            long,
+            Err(CinderError::Expansion(ExpansionBudgetExceeded { budget: ExpansionBudget::IncludedInstructions, .. }))
+        ));
+        assert_eq!(within.unwrap().instructions.len(), 64);
+    }
+
+    #[test]
+    fn include_needs_a_file() {
+        let error = Parser::parse(".include \"lib.cinder\"\nHALT").unwrap_err();
+        assert!(matches!(&error, CinderError::Parse(ParseError { line: 1, .. })), "{:?}", error);
+    }
+
+    #[test]
+    fn duplicate_label_names_both_lines() {
+        let source = "start:\nPUSH_INT 1\n\n# again\n  start:\nRETURN";
+        match Parser::parse(source) {
+            Err(CinderError::DuplicateLabel(duplicate)) => {
+                assert_eq!(duplicate.name, "start");
+                assert_eq!((duplicate.first_line, duplicate.second_line), (1, 5));
+                assert_eq!(
+                    duplicate.to_string(),
+                    "line 5: duplicate label 'start' (first defined on line 1)"
+                );
+            }
+            other => panic!("expected a duplicate label error, got {:?}", other.map(|p| p.instructions)),
+        }
+    }
+}
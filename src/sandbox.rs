@@ -1,63 +1,203 @@
-use crate::bytecode::{Instruction, Program};
-use anyhow::Result;
+use crate::bytecode::{fnv1a, Instruction, Program, REGISTER_COUNT};
+use crate::error::CinderError;
+use crate::natives;
+
+/// Reasons `Sandbox::validate` rejects a program; `at` is the instruction index
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum SandboxError {
+    #[error("Invalid jump at instruction {at}: target {target} exceeds bounds")]
+    InvalidJump { at: usize, target: u32 },
+    #[error("Invalid jump table at instruction {at}: target {target} exceeds bounds")]
+    InvalidJumpTable { at: usize, target: u32 },
+    #[error("Invalid register at instruction {at}: register {register} exceeds register file ({REGISTER_COUNT})")]
+    InvalidRegister { at: usize, register: u8 },
+    #[error("Disallowed native call at instruction {at}: function {id} is not in whitelist")]
+    DisallowedNative { at: usize, id: u32 },
+    #[error("Invalid memory access at instruction {at}: offset {offset} exceeds allocated memory ({memory_size})")]
+    InvalidMemoryAccess { at: usize, offset: u32, memory_size: usize },
+    #[error(
+        "Store outside the data region at instruction {at}: offset {offset} exceeds allocated memory \
+         ({memory_size}); data and code must stay disjoint"
+    )]
+    StoreOutsideData { at: usize, offset: u32, memory_size: usize },
+    #[error("Data block at offset {offset} ends at {end}, past allocated memory ({memory_size})")]
+    DataOutsideMemory { offset: u32, end: usize, memory_size: usize },
+    #[error("Stack underflow at instruction {at}: {instruction} pops {pops} value(s) but only {depth} guaranteed")]
+    StackUnderflow { at: usize, instruction: String, pops: usize, depth: usize },
+    #[error("Native call at instruction {at}: function {id} needs the {capability} capability, which is not granted")]
+    MissingCapability { at: usize, id: u32, capability: Capability },
+    #[error("Nondeterministic instruction at {at} in deterministic mode: {reason}")]
+    Nondeterministic { at: usize, reason: &'static str },
+}
+
+/// Groups of natives a policy must grant explicitly; whitelisting one of
+/// their natives alone does not allow it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// `kv_get` and `kv_put`, backed by the host's `KeyValueStore`
+    Storage,
+    /// `chan_send` and `chan_recv`, on channels the host attaches
+    Channels,
+}
+
+impl Capability {
+    pub const ALL: [Capability; 2] = [Capability::Storage, Capability::Channels];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Capability::Storage => "storage",
+            Capability::Channels => "channels",
+        }
+    }
+
+    /// Natives the capability covers
+    pub fn natives(self) -> &'static [u32] {
+        match self {
+            Capability::Storage => &[natives::KV_GET, natives::KV_PUT],
+            Capability::Channels => &[natives::CHAN_SEND, natives::CHAN_RECV],
+        }
+    }
+
+    /// Capability covering native `id`, if any
+    pub fn of_native(id: u32) -> Option<Capability> {
+        Self::ALL.into_iter().find(|capability| capability.natives().contains(&id))
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
 
 /// Sandbox for validation and securing execution
+#[derive(Debug, Clone)]
 pub struct Sandbox {
-    program: Program,
     allowed_natives: Vec<u32>,
+    capabilities: Vec<Capability>,
+    deterministic: bool,
+}
+
+/// A program that passed `Sandbox::validate`, tagged with the policy it was checked against
+#[derive(Debug, Clone)]
+pub struct ValidatedProgram {
+    program: Program,
+    policy_hash: u64,
+    deterministic: bool,
+}
+
+impl ValidatedProgram {
+    pub fn program(&self) -> &Program {
+        &self.program
+    }
+
+    /// Hash of the sandbox policy the program was validated under
+    pub fn policy_hash(&self) -> u64 {
+        self.policy_hash
+    }
+
+    /// Whether the policy was in deterministic mode, so the program may
+    /// only use a store declared deterministic
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    pub fn into_program(self) -> Program {
+        self.program
+    }
+}
+
+impl Default for Sandbox {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Sandbox {
-    pub fn new(program: &Program) -> Self {
+    pub fn new() -> Self {
         // Whitelist of allowed native functions
         let allowed_natives = vec![
-            0x01, // print_int
-            0x02, // print_str
+            natives::PRINT_INT,
+            natives::PRINT_STR,
+            natives::PRINT_HEX,
+            natives::PRINT_CHAR,
+            natives::PRINT_INT_FMT,
             // Add more functions as needed
         ];
 
         Self {
-            program: program.clone(),
             allowed_natives,
+            capabilities: Vec::new(),
+            deterministic: false,
+        }
+    }
+
+    /// Validate program for security; failures are `CinderError::Sandbox`
+    pub fn validate(&self, program: &Program) -> Result<(), CinderError> {
+        self.check_capabilities(program)?;
+        self.check_instructions(program)?;
+        self.check_data_region(program)?;
+        self.check_stack_underflow(program)?;
+        Ok(())
+    }
+
+    /// Natives that need a capability the policy doesn't grant, and in
+    /// deterministic mode, instructions whose result can vary between runs.
+    /// Part of `validate`; also usable alone by callers that skip validation.
+    pub fn check_capabilities(&self, program: &Program) -> Result<(), SandboxError> {
+        for (idx, instruction) in program.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::CallNative(id) => {
+                    let Some(capability) = Capability::of_native(*id) else {
+                        continue;
+                    };
+                    if !self.capabilities.contains(&capability) {
+                        return Err(SandboxError::MissingCapability { at: idx, id: *id, capability });
+                    }
+                }
+                
+                Instruction::ReadCycles if self.deterministic => {
+                    return Err(SandboxError::Nondeterministic {
+                        at: idx,
+                        reason: "READ_CYCLES reads a clock",
+                    });
+                }
+                
+                _ => {}
+            }
         }
+        
+        Ok(())
     }
 
-    /// Validate program for security
-    pub fn validate(&self) -> Result<()> {
+    /// Jump targets, registers and natives of each instruction
+    fn check_instructions(&self, program: &Program) -> Result<(), SandboxError> {
         // Check jumps
-        for (idx, instruction) in self.program.instructions.iter().enumerate() {
+        for (idx, instruction) in program.instructions.iter().enumerate() {
             match instruction {
                 Instruction::Jump(target) |
                 Instruction::JumpIfZero(target) |
-                Instruction::JumpIfNotZero(target) => {
-                    if *target >= self.program.instructions.len() {
-                        return Err(anyhow::anyhow!(
-                            "Invalid jump at instruction {}: target {} exceeds bounds",
-                            idx,
-                            target
-                        ));
-                    }
+                Instruction::JumpIfNotZero(target)
+                    if *target as usize >= program.instructions.len() =>
+                {
+                    return Err(SandboxError::InvalidJump { at: idx, target: *target });
                 }
                 
-                Instruction::Load(offset) | Instruction::Store(offset) => {
-                    if *offset >= self.program.memory_size {
-                        return Err(anyhow::anyhow!(
-                            "Invalid memory access at instruction {}: offset {} exceeds allocated memory ({})",
-                            idx,
-                            offset,
-                            self.program.memory_size
-                        ));
+                Instruction::JumpTable(table) => {
+                    let targets = table.targets.iter().chain(std::iter::once(&table.default));
+                    for target in targets {
+                        if *target as usize >= program.instructions.len() {
+                            return Err(SandboxError::InvalidJumpTable { at: idx, target: *target });
+                        }
                     }
                 }
                 
-                Instruction::CallNative(id) => {
-                    if !self.is_native_allowed(*id) {
-                        return Err(anyhow::anyhow!(
-                            "Disallowed native call at instruction {}: function {} is not in whitelist",
-                            idx,
-                            id
-                        ));
-                    }
+                Instruction::PushReg(reg) if *reg as usize >= REGISTER_COUNT => {
+                    return Err(SandboxError::InvalidRegister { at: idx, register: *reg });
+                }
+                
+                Instruction::CallNative(id) if !self.is_native_allowed(*id) => {
+                    return Err(SandboxError::DisallowedNative { at: idx, id: *id });
                 }
                 
                 _ => {}
@@ -67,9 +207,145 @@ impl Sandbox {
         Ok(())
     }
 
-    /// Check if a native function is allowed
+    /// Every LOAD and STORE, and every `.data` block, must lie in the program's data region.
+    ///
+    /// Invariant: data and code are disjoint. The JIT keeps data slots in
+    /// the native stack frame and code in separate executable pages, and a
+    /// store that stays below `memory_size` can't reach either the code or
+    /// the frame's saved registers. The executable pages themselves are
+    /// mapped read-execute once the code is copied in (`ExecutableMemory::seal`),
+    /// so compiled code can never write to memory it executes (W^X). LOAD_IND and STORE_IND offsets aren't
+    /// known here; both backends check them against `memory_size` at run time.
+    fn check_data_region(&self, program: &Program) -> Result<(), SandboxError> {
+        if let Some(block) = program.data.iter().find(|block| block.end() > program.memory_size) {
+            return Err(SandboxError::DataOutsideMemory {
+                offset: block.offset,
+                end: block.end(),
+                memory_size: program.memory_size,
+            });
+        }
+        
+        for (idx, instruction) in program.instructions.iter().enumerate() {
+            match instruction {
+                Instruction::Load(offset) if *offset as usize >= program.memory_size => {
+                    return Err(SandboxError::InvalidMemoryAccess {
+                        at: idx,
+                        offset: *offset,
+                        memory_size: program.memory_size,
+                    });
+                }
+                
+                Instruction::Store(offset) | Instruction::MemAdd(offset, _)
+                    if *offset as usize >= program.memory_size =>
+                {
+                    return Err(SandboxError::StoreOutsideData {
+                        at: idx,
+                        offset: *offset,
+                        memory_size: program.memory_size,
+                    });
+                }
+                
+                _ => {}
+            }
+        }
+        
+        Ok(())
+    }
+
+    /// Statically reject programs where some path pops more values than it pushed.
+    /// Tracks the minimum stack depth reaching each instruction over all paths.
+    fn check_stack_underflow(&self, program: &Program) -> Result<(), SandboxError> {
+        let len = program.instructions.len();
+        let mut min_depth: Vec<Option<usize>> = vec![None; len];
+        let mut worklist = Vec::new();
+
+        // Arguments are on the stack before the first instruction
+        if len > 0 {
+            min_depth[0] = Some(program.args);
+            worklist.push(0);
+        }
+
+        while let Some(pc) = worklist.pop() {
+            let instruction = &program.instructions[pc];
+            let depth = min_depth[pc].unwrap_or(0);
+            let (pops, pushes) = instruction.stack_effect();
+
+            if depth < pops {
+                return Err(SandboxError::StackUnderflow {
+                    at: pc,
+                    instruction: format!("{:?}", instruction),
+                    pops,
+                    depth,
+                });
+            }
+
+            let out_depth = depth - pops + pushes;
+            for next in instruction.successors(pc, len) {
+                // Keep the minimum incoming depth at joins
+                if min_depth[next].is_none_or(|d| out_depth < d) {
+                    min_depth[next] = Some(out_depth);
+                    worklist.push(next);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate program and wrap it so later consumers can skip re-validation
+    pub fn validate_program(&self, program: Program) -> Result<ValidatedProgram, CinderError> {
+        self.validate(&program)?;
+        Ok(ValidatedProgram {
+            program,
+            policy_hash: self.policy_hash(),
+            deterministic: self.deterministic,
+        })
+    }
+
+    /// Hash of the effective policy; changes whenever the whitelist,
+    /// the granted capabilities or deterministic mode change.
+    /// FNV-1a over the sorted native IDs, then each granted capability's
+    /// name and a `deterministic` marker, so it is the same in every build.
+    pub fn policy_hash(&self) -> u64 {
+        let mut bytes: Vec<u8> = self.allowed_natives().iter().flat_map(|id| id.to_le_bytes()).collect();
+        for capability in Capability::ALL.into_iter().filter(|c| self.capabilities.contains(c)) {
+            bytes.extend_from_slice(capability.name().as_bytes());
+        }
+        if self.deterministic {
+            bytes.extend_from_slice(b"deterministic");
+        }
+        fnv1a(&bytes)
+    }
+
+    /// Check if a native function is allowed: whitelisted, and covered by
+    /// a granted capability if it belongs to one
     pub fn is_native_allowed(&self, id: u32) -> bool {
         self.allowed_natives.contains(&id)
+            && Capability::of_native(id).is_none_or(|capability| self.capabilities.contains(&capability))
+    }
+
+    /// Grant a capability and whitelist its natives
+    pub fn grant(&mut self, capability: Capability) {
+        if !self.capabilities.contains(&capability) {
+            self.capabilities.push(capability);
+        }
+        for &id in capability.natives() {
+            self.allow_native(id);
+        }
+    }
+
+    /// Reject programs whose result can vary between runs with the same
+    /// inputs: READ_CYCLES. The store isn't known until the program runs,
+    /// so storage natives are checked then: interpreters and compiled
+    /// programs built under this policy fail at a storage native whose
+    /// `SharedStore` isn't declared deterministic.
+    pub fn require_deterministic(&mut self) {
+        self.deterministic = true;
+    }
+
+    /// Whether `require_deterministic` was called
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
     }
 
     /// Add a native function to whitelist
@@ -78,5 +354,107 @@ impl Sandbox {
             self.allowed_natives.push(id);
         }
     }
+
+    /// Remove a native function from the whitelist
+    pub fn deny_native(&mut self, id: u32) {
+        self.allowed_natives.retain(|&allowed| allowed != id);
+    }
+
+    /// The effective whitelist, in ascending ID order
+    pub fn allowed_natives(&self) -> Vec<u32> {
+        let mut natives = self.allowed_natives.clone();
+        natives.sort_unstable();
+        natives
+    }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cinder_program;
+
+    fn rejection(program: &Program) -> SandboxError {
+        match Sandbox::new().validate(program) {
+            Err(CinderError::Sandbox(error)) => error,
+            other => panic!("expected a sandbox error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn out_of_range_register_is_rejected() {
+        let program = cinder_program!(push_reg 99; return);
+        assert_eq!(rejection(&program), SandboxError::InvalidRegister { at: 0, register: 99 });
+    }
+
+    #[test]
+    fn guaranteed_underflow_is_rejected() {
+        let program = cinder_program!(push_int 1; add; return);
+        assert_eq!(
+            rejection(&program),
+            SandboxError::StackUnderflow { at: 1, instruction: "Add".to_string(), pops: 2, depth: 1 }
+        );
+    }
+
+    #[test]
+    fn underflow_on_one_branch_is_rejected() {
+        // The jump skips the second push, so ADD may see a single value
+        let program = cinder_program!(push_int 1; push_int 0; jz 4; push_int 2; add; return);
+        assert!(matches!(rejection(&program), SandboxError::StackUnderflow { at: 4, depth: 1, .. }));
+    }
+
+    #[test]
+    fn balanced_branches_are_accepted() {
+        let program = cinder_program!(
+            push_int 1; push_int 0; jz 5; push_int 2; jump 6; push_int 3; add; return
+        );
+        assert!(Sandbox::new().validate(&program).is_ok());
+    }
+
+    #[test]
+    fn stores_outside_the_data_region_are_rejected() {
+        let program = cinder_program!(.memory 2; push_int 1; store 1; push_int 2; store 2; return);
+        assert_eq!(rejection(&program), SandboxError::StoreOutsideData { at: 3, offset: 2, memory_size: 2 });
+
+        let program = cinder_program!(.memory 2; mem_add 5 1; return);
+        assert_eq!(rejection(&program), SandboxError::StoreOutsideData { at: 0, offset: 5, memory_size: 2 });
+    }
+
+    #[test]
+    fn policy_hash_has_a_golden_value() {
+        assert_eq!(Sandbox::new().policy_hash(), 0x1916_ceff_af53_9564);
+
+        let mut policy = Sandbox::new();
+        policy.deny_native(natives::PRINT_STR);
+        assert_eq!(policy.policy_hash(), 0x7cf1_7cc7_3539_0366);
+    }
+
+    #[test]
+    fn storage_natives_need_the_capability() {
+        let program = cinder_program!(push_int 1; call_native 0x10; return);
+        let mut policy = Sandbox::new();
+        policy.allow_native(natives::KV_GET);
+        assert!(!policy.is_native_allowed(natives::KV_GET));
+        assert!(matches!(
+            policy.validate(&program),
+            Err(CinderError::Sandbox(SandboxError::MissingCapability { at: 1, id: 0x10, capability: Capability::Storage }))
+        ));
+
+        let hash = policy.policy_hash();
+        policy.grant(Capability::Storage);
+        assert!(policy.validate(&program).is_ok());
+        assert_ne!(policy.policy_hash(), hash);
+    }
+
+    #[test]
+    fn deterministic_mode_rejects_the_clock() {
+        let program = cinder_program!(read_cycles; return);
+        let mut policy = Sandbox::new();
+        assert!(policy.validate(&program).is_ok());
+        policy.require_deterministic();
+        assert!(matches!(
+            policy.validate(&program),
+            Err(CinderError::Sandbox(SandboxError::Nondeterministic { at: 0, .. }))
+        ));
+    }
+}
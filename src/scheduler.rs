@@ -0,0 +1,183 @@
+//! Deterministic round-robin interleaving of many programs on one thread.
+//!
+//! Each program runs for a fixed quantum of instructions through
+//! `Interpreter::run_for`, then goes to the back of the queue. A program
+//! that never ends is simply preempted at every quantum boundary, so it
+//! cannot starve the others. Given the same programs, order and quantum,
+//! the interleaving (and so the order of native calls) is always the same;
+//! only `READ_CYCLES` can make runs differ.
+//!
+//! A program blocked on a channel (see `channel`) also goes to the back of
+//! the queue. If every program in the queue takes a turn blocked, nothing
+//! can change any more, so the program whose turn completes the round is
+//! ended with `InterpreterError::Deadlock`; ending it may close channels
+//! the others wait on.
+
+use crate::interpreter::{Interpreter, InterpreterError, RunState};
+use std::collections::VecDeque;
+
+/// How one scheduled program ended
+#[derive(Debug)]
+pub struct Finished {
+    /// Position of the program in `spawn` order, from 0
+    pub id: usize,
+    pub outcome: Result<i64, InterpreterError>,
+    /// Quanta run by the scheduler, across all programs, up to and
+    /// including the one in which this program ended
+    pub tick: u64,
+}
+
+/// Suspended interpreters run in turn, `quantum` instructions at a time
+pub struct Scheduler<H = ()> {
+    quantum: u64,
+    ready: VecDeque<(usize, Interpreter<H>)>,
+    spawned: usize,
+    tick: u64,
+    /// Turns in a row in which a program was blocked before running anything
+    blocked_turns: usize,
+}
+
+impl<H> Scheduler<H> {
+    /// # Panics
+    ///
+    /// If `quantum` is 0, since no program could make progress.
+    pub fn new(quantum: u64) -> Self {
+        assert!(quantum > 0, "scheduler quantum must be at least one instruction");
+        Self {
+            quantum,
+            ready: VecDeque::new(),
+            spawned: 0,
+            tick: 0,
+            blocked_turns: 0,
+        }
+    }
+
+    /// Queue a program behind those already added; returns its id
+    pub fn spawn(&mut self, interpreter: Interpreter<H>) -> usize {
+        let id = self.spawned;
+        self.spawned += 1;
+        self.ready.push_back((id, interpreter));
+        id
+    }
+
+    /// Programs that have not finished yet
+    pub fn pending(&self) -> usize {
+        self.ready.len()
+    }
+
+    /// Run one quantum of the program at the front of the queue. Returns
+    /// its outcome if it ended, or `None` if it was requeued or nothing
+    /// is left to run.
+    pub fn step(&mut self) -> Option<Finished> {
+        let (id, mut interpreter) = self.ready.pop_front()?;
+        self.tick += 1;
+        let outcome = match interpreter.run_for(self.quantum) {
+            Ok(RunState::Yielded) => {
+                self.blocked_turns = 0;
+                self.ready.push_back((id, interpreter));
+                return None;
+            }
+            Ok(RunState::Blocked) => {
+                // Everyone else was blocked since this program's last turn
+                self.blocked_turns += 1;
+                if self.blocked_turns <= self.ready.len() {
+                    self.ready.push_back((id, interpreter));
+                    return None;
+                }
+                Err(InterpreterError::Deadlock)
+            }
+            Ok(RunState::Halted(result)) => Ok(result),
+            Err(error) => Err(error),
+        };
+        self.blocked_turns = 0;
+        Some(Finished { id, outcome, tick: self.tick })
+    }
+
+    /// Run until every program has ended, returning outcomes in the order
+    /// the programs finished
+    pub fn run(&mut self) -> Vec<Finished> {
+        let mut finished = Vec::with_capacity(self.ready.len());
+        while self.pending() > 0 {
+            finished.extend(self.step());
+        }
+        finished
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Parser;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Native 9 appends its argument to a log shared by every program
+    type Log = Rc<RefCell<Vec<i64>>>;
+
+    fn log_native(log: &mut Log, args: &[i64]) {
+        log.borrow_mut().push(args[0]);
+    }
+
+    /// Count `tag`'s iterations down from `count`, logging the tag each time
+    fn logging_countdown(tag: i64, count: i64, log: &Log) -> Interpreter<Log> {
+        let source = format!(
+            "PUSH_INT {count}\nSTORE 0\nloop:\nPUSH_INT {tag}\nCALL_NATIVE 9\nMEM_ADD 0 -1\nLOAD 0\nJNZ loop\nPUSH_INT {tag}\nRETURN\n"
+        );
+        Interpreter::with_host(Parser::parse(&source).unwrap(), log.clone()).with_native(9, 1, log_native)
+    }
+
+    /// Outcomes and the order of native calls for the same three programs
+    fn interleave(quantum: u64) -> (Vec<(usize, i64, u64)>, Vec<i64>) {
+        let log = Log::default();
+        let mut scheduler = Scheduler::new(quantum);
+        for (tag, count) in [(1, 7), (2, 3), (3, 5)] {
+            scheduler.spawn(logging_countdown(tag, count, &log));
+        }
+        let finished = scheduler
+            .run()
+            .into_iter()
+            .map(|finished| (finished.id, finished.outcome.unwrap(), finished.tick))
+            .collect();
+        let calls = log.borrow().clone();
+        (finished, calls)
+    }
+
+    #[test]
+    fn same_programs_and_quantum_interleave_the_same_way() {
+        let (finished, calls) = interleave(4);
+        for _ in 0..5 {
+            assert_eq!(interleave(4), (finished.clone(), calls.clone()));
+        }
+        assert_eq!(finished.iter().map(|&(id, result, _)| (id, result)).collect::<Vec<_>>(), [(1, 2), (2, 3), (0, 1)]);
+        // Every program got turns before any finished
+        assert_eq!(calls[..3], [1, 2, 3]);
+
+        // The quantum is part of the input: a different one interleaves differently
+        assert_ne!(interleave(10).1, calls);
+    }
+
+    #[test]
+    fn a_program_that_never_ends_is_preempted_every_quantum() {
+        // 10 instructions each, so 3 quanta of 4
+        let straight = "PUSH_INT 1\nPOP\nPUSH_INT 1\nPOP\nPUSH_INT 1\nPOP\nPUSH_INT 1\nPOP\nPUSH_INT 3\nRETURN\n";
+        let program = |source: &str| Interpreter::new(Parser::parse(source).unwrap());
+
+        let mut scheduler = Scheduler::new(4);
+        scheduler.spawn(program("spin:\nJUMP spin\n"));
+        scheduler.spawn(program(straight));
+        scheduler.spawn(program(straight));
+
+        let mut finished = Vec::new();
+        for _ in 0..100 {
+            finished.extend(scheduler.step());
+        }
+        // Turns go spin, 1, 2, spin, 1, 2, spin, 1 (ends), 2 (ends), as if
+        // the spinner yielded like any other program
+        let ends: Vec<(usize, i64, u64)> = finished
+            .into_iter()
+            .map(|finished| (finished.id, finished.outcome.unwrap(), finished.tick))
+            .collect();
+        assert_eq!(ends, [(1, 3, 8), (2, 3, 9)]);
+        assert_eq!(scheduler.pending(), 1);
+    }
+}
@@ -0,0 +1,67 @@
+use crate::bytecode::Program;
+use crate::interpreter::Interpreter;
+
+/// Run both programs through the interpreter on every input set and panic
+/// on the first one where their results differ.
+///
+/// Intended for optimization passes and other bytecode transformations
+/// that must preserve semantics. Errors are compared as well, so a pass
+/// that turns a `DivisionByZero` into a result is reported too.
+pub fn assert_same_behavior(a: &Program, b: &Program, inputs: &[&[i64]]) {
+    for input in inputs {
+        let expected = Interpreter::new(a.clone()).execute_with_input(input);
+        let actual = Interpreter::new(b.clone()).execute_with_input(input);
+
+        assert_eq!(
+            expected, actual,
+            "Programs diverge for input {:?}",
+            input
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytecode::Instruction;
+
+    /// `x * (2 + 3)` and its constant-folded form `x * 5`
+    fn unfolded_and_folded() -> (Program, Program) {
+        let mut unfolded = Program::new(
+            vec![
+                Instruction::PushInt(2),
+                Instruction::PushInt(3),
+                Instruction::Add,
+                Instruction::Mul,
+                Instruction::Return,
+            ],
+            0,
+        );
+        unfolded.args = 1;
+        let mut folded = Program::new(vec![Instruction::PushInt(5), Instruction::Mul, Instruction::Return], 0);
+        folded.args = 1;
+        (unfolded, folded)
+    }
+
+    #[test]
+    fn const_folded_program_behaves_the_same() {
+        let (unfolded, folded) = unfolded_and_folded();
+        assert_same_behavior(&unfolded, &folded, &[&[0], &[7], &[-3], &[i64::MAX]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Programs diverge for input [7]")]
+    fn miscompiled_fold_is_reported() {
+        let (unfolded, mut folded) = unfolded_and_folded();
+        folded.instructions[0] = Instruction::PushInt(6);
+        assert_same_behavior(&unfolded, &folded, &[&[0], &[7]]);
+    }
+
+    #[test]
+    #[should_panic(expected = "Programs diverge")]
+    fn errors_are_compared() {
+        let divide = Program::new(vec![Instruction::PushInt(0), Instruction::Div, Instruction::Return], 0);
+        let identity = Program::new(vec![Instruction::Return], 0);
+        assert_same_behavior(&divide, &identity, &[&[1]]);
+    }
+}
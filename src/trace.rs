@@ -0,0 +1,139 @@
+//! Span recording in the Chrome trace event format, viewable in
+//! `chrome://tracing` or Perfetto.
+
+use crate::artifact;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// Events kept in memory; later spans are counted but dropped
+pub const MAX_EVENTS: usize = 100_000;
+
+/// A completed span ("X" phase event)
+#[derive(Debug, Clone)]
+struct Event {
+    name: String,
+    /// Microseconds since the trace started
+    start: u128,
+    duration: u128,
+    thread: u64,
+}
+
+/// Collects spans and writes them as Chrome trace JSON when flushed or dropped
+pub struct ChromeTrace {
+    path: PathBuf,
+    origin: Instant,
+    events: Mutex<Vec<Event>>,
+    dropped: Mutex<usize>,
+}
+
+impl ChromeTrace {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            origin: Instant::now(),
+            events: Mutex::new(Vec::new()),
+            dropped: Mutex::new(0),
+        }
+    }
+
+    /// Start a span that ends when the returned guard is dropped
+    pub fn span(&self, name: impl Into<String>) -> Span<'_> {
+        Span {
+            trace: self,
+            name: name.into(),
+            start: Instant::now(),
+        }
+    }
+
+    /// Run `f` inside a span named `name`
+    pub fn in_span<T>(&self, name: &str, f: impl FnOnce() -> T) -> T {
+        let _span = self.span(name);
+        f()
+    }
+
+    fn record(&self, name: String, start: Instant) {
+        let event = Event {
+            name,
+            start: start.duration_since(self.origin).as_micros(),
+            duration: start.elapsed().as_micros(),
+            thread: thread_number(),
+        };
+
+        let mut events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        if events.len() < MAX_EVENTS {
+            events.push(event);
+        } else {
+            *self.dropped.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        }
+    }
+
+    /// Write every span recorded so far to the trace file, replacing it whole
+    pub fn flush(&self) -> io::Result<()> {
+        let events = self.events.lock().unwrap_or_else(|e| e.into_inner());
+        let dropped = *self.dropped.lock().unwrap_or_else(|e| e.into_inner());
+
+        let mut json = String::from("{\"traceEvents\":[\n");
+        for (index, event) in events.iter().enumerate() {
+            if index > 0 {
+                json.push_str(",\n");
+            }
+            json.push_str(&format!(
+                "{{\"name\":\"{}\",\"ph\":\"X\",\"ts\":{},\"dur\":{},\"pid\":{},\"tid\":{}}}",
+                escape(&event.name),
+                event.start,
+                event.duration,
+                std::process::id(),
+                event.thread
+            ));
+        }
+        json.push_str(&format!("\n],\"otherData\":{{\"droppedEvents\":{}}}}}\n", dropped));
+
+        artifact::replace_file(&self.path, json.as_bytes())
+    }
+}
+
+impl Drop for ChromeTrace {
+    fn drop(&mut self) {
+        // Best effort: a partial trace is still useful
+        let _ = self.flush();
+    }
+}
+
+/// An open span; recorded when dropped
+pub struct Span<'a> {
+    trace: &'a ChromeTrace,
+    name: String,
+    start: Instant,
+}
+
+impl Drop for Span<'_> {
+    fn drop(&mut self) {
+        self.trace.record(std::mem::take(&mut self.name), self.start);
+    }
+}
+
+/// Small sequential number for the current thread; trace viewers expect numeric IDs
+fn thread_number() -> u64 {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    thread_local! {
+        static NUMBER: u64 = NEXT.fetch_add(1, Ordering::Relaxed);
+    }
+    NUMBER.with(|number| *number)
+}
+
+/// Escape a string for a JSON string literal
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out
+}
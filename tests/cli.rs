@@ -0,0 +1,279 @@
+//! End-to-end checks of the `cindervm` command-line tool
+#![cfg(feature = "cli")]
+
+use std::process::Command;
+
+/// Run `cindervm` from the crate root, returning whether it succeeded and its stdout
+fn cindervm(args: &[&str]) -> (bool, String) {
+    let output = Command::new(env!("CARGO_BIN_EXE_cindervm"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cindervm runs");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    if !output.status.success() {
+        eprintln!("{}{}", stdout, String::from_utf8_lossy(&output.stderr));
+    }
+    (output.status.success(), stdout)
+}
+
+/// Run `cindervm`, expecting it to fail, and return its stderr
+fn cindervm_error(args: &[&str]) -> String {
+    let output = Command::new(env!("CARGO_BIN_EXE_cindervm"))
+        .args(args)
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .expect("cindervm runs");
+    assert!(!output.status.success(), "{:?} succeeded", args);
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+/// Lines after the one containing `header`, up to the next blank-line-separated section
+fn section<'a>(output: &'a str, header: &str) -> Vec<&'a str> {
+    output
+        .lines()
+        .skip_while(|line| !line.contains(header))
+        .skip(1)
+        .skip_while(|line| line.is_empty())
+        .take_while(|line| !line.is_empty())
+        .collect()
+}
+
+/// `(pc, offset)` pairs printed by `disassemble --dump-native-map`
+fn native_map(output: &str) -> Vec<(usize, usize)> {
+    section(output, "Native offsets")
+        .iter()
+        .map(|line| {
+            let (pc, offset) = line.split_once('→').expect("`pc → offset` line");
+            (pc.trim().parse().unwrap(), usize::from_str_radix(offset.trim(), 16).unwrap())
+        })
+        .collect()
+}
+
+#[test]
+fn disassemble_range_prints_only_those_instructions() {
+    let (ok, out) = cindervm(&["disassemble", "examples/countdown.cinder", "--range", "2", "5", "--dump-native-map"]);
+    assert!(ok);
+
+    let listing: Vec<&str> = section(&out, "Bytecode instructions")
+        .into_iter()
+        .filter(|line| line.starts_with("    "))
+        .map(str::trim)
+        .collect();
+    assert_eq!(listing, ["LOAD counter", "JZ L13", "LOAD sum"]);
+
+    // The machine code starts at pc 2 and stops where pc 5 begins
+    let map = native_map(&out);
+    let (_, full) = cindervm(&["disassemble", "examples/countdown.cinder", "--dump-native-map"]);
+    let end = native_map(&full)[5].1;
+    let dump = section(&out, "Generated machine code");
+    assert!(dump[0].trim_start().starts_with(&format!("{:04X}:", map[0].1)), "{:?}", dump);
+    let bytes: usize = dump.iter().map(|line| line.split_whitespace().count() - 1).sum();
+    assert_eq!(bytes, end - map[0].1);
+}
+
+#[test]
+fn native_map_has_one_ascending_entry_per_instruction() {
+    let (ok, out) = cindervm(&["disassemble", "examples/countdown.cinder", "--dump-native-map"]);
+    assert!(ok);
+    let map = native_map(&out);
+    let pcs: Vec<usize> = map.iter().map(|&(pc, _)| pc).collect();
+    let program = cindervm::parser::Parser::parse_file(concat!(env!("CARGO_MANIFEST_DIR"), "/examples/countdown.cinder"))
+        .unwrap();
+    assert_eq!(pcs, (0..program.instructions.len()).collect::<Vec<_>>());
+    assert!(map.windows(2).all(|pair| pair[0].1 < pair[1].1), "{:?}", map);
+}
+
+#[test]
+fn verify_exec_runs_three_backends_with_the_oracle() {
+    let (ok, out) = cindervm(&["verify-exec", "--oracle", "examples/indirect.cinder"]);
+    assert!(ok);
+    for backend in ["oracle", "interpreter", "jit"] {
+        assert!(out.lines().any(|line| line.split_whitespace().eq([backend, "140"])), "{}", out);
+    }
+    assert!(out.contains("All 3 backends agree"), "{}", out);
+}
+
+#[test]
+fn checkpoints_are_replaced_whole_and_resume() {
+    let dir = std::env::temp_dir().join(format!("cinder-checkpoint-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let source = std::fs::read_to_string("examples/countdown.cinder").unwrap().replace("PUSH_INT 10", "PUSH_INT 20000");
+    let program = dir.join("countdown.cinder");
+    std::fs::write(&program, source).unwrap();
+    let checkpoint = dir.join("countdown.ckpt");
+    let (program, checkpoint) = (program.to_str().unwrap(), checkpoint.to_str().unwrap());
+
+    let (ok, out) = cindervm(&["debug", program, "--checkpoint-file", checkpoint, "--checkpoint-every", "0"]);
+    assert!(ok && out.contains("Result: 200010000"), "{}", out);
+    let mut files: Vec<_> = std::fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+    files.sort();
+    let (resumed, out) = cindervm(&["resume", checkpoint, program]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    // No temporary file is left behind
+    assert_eq!(files, ["countdown.cinder", "countdown.ckpt"]);
+    assert!(resumed && out.contains("Result: 200010000"), "{}", out);
+}
+
+#[test]
+fn outputs_need_force_to_overwrite_and_truncation_is_named() {
+    let dir = std::env::temp_dir().join(format!("cinder-outputs-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let binary = dir.join("factorial.cbin");
+    let (log, trace) = (dir.join("calls.log"), dir.join("trace.json"));
+    let (binary, log, trace) = (binary.to_str().unwrap(), log.to_str().unwrap(), trace.to_str().unwrap());
+    let convert = ["convert", "examples/factorial.cinder", "--to", "binary", "-o", binary];
+
+    let (first, _) = cindervm(&convert);
+    let encoded = std::fs::read(binary).unwrap();
+    let refused = cindervm_error(&convert);
+    let (forced, _) = cindervm(&[&["--force"][..], &convert].concat());
+
+    let (recorded, _) = cindervm(&["debug", "examples/simple.cinder", "--record", log]);
+    let log_refused = cindervm_error(&["debug", "examples/simple.cinder", "--record", log]);
+    let (traced, _) = cindervm(&["--chrome-trace", trace, "exec", "examples/simple.cinder"]);
+    let trace_refused = cindervm_error(&["--chrome-trace", trace, "exec", "examples/simple.cinder"]);
+
+    // As left by a writer killed halfway through
+    std::fs::write(binary, &encoded[..encoded.len() / 2]).unwrap();
+    let truncated = cindervm_error(&["convert", binary, "--to", "cinder"]);
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert!(first && forced && recorded && traced);
+    for error in [&refused, &log_refused, &trace_refused] {
+        assert!(error.contains("already exists; pass --force to overwrite it"), "{}", error);
+    }
+    let expected = format!("ends after {} bytes but at least", encoded.len() / 2);
+    assert!(truncated.contains(&expected) && truncated.contains("may be truncated"), "{}", truncated);
+}
+
+#[test]
+fn natives_prints_the_effective_whitelist() {
+    // Deny wins over allow, whether the native is named or given by ID
+    let (ok, out) = cindervm(&[
+        "--allow-native", "9",
+        "--allow-native", "print_int_fmt",
+        "--deny-native", "print_hex",
+        "--deny-native", "9",
+        "--allow-native", "12",
+        "natives",
+    ]);
+    assert!(ok);
+    let listed: Vec<Vec<&str>> = section(&out, "Effective native whitelist")
+        .iter()
+        .map(|line| line.split_whitespace().collect())
+        .collect();
+    assert_eq!(
+        listed,
+        [
+            vec!["1", "print_int"],
+            vec!["2", "(no", "implementation)"],
+            vec!["4", "print_char"],
+            vec!["5", "print_int_fmt"],
+            vec!["12", "(no", "implementation)"],
+        ]
+    );
+}
+
+#[test]
+fn kv_file_keeps_a_counter_between_runs() {
+    let dir = std::env::temp_dir().join(format!("cinder-kv-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let program = dir.join("counter.cinder");
+    std::fs::write(
+        &program,
+        "PUSH_INT 7\nCALL_NATIVE kv_get\nSTORE 0\nLOAD 0\nPUSH_INT -9223372036854775808\nEQ\nJZ found\n\
+         PUSH_INT 0\nSTORE 0\nfound:\nMEM_ADD 0 1\nPUSH_INT 7\nLOAD 0\nCALL_NATIVE kv_put\nLOAD 0\nRETURN\n",
+    )
+    .unwrap();
+    let store = dir.join("store.json");
+    let (program, store) = (program.to_str().unwrap(), store.to_str().unwrap());
+
+    let storage = ["--grant", "storage", "--kv-file", store];
+    let runs = [
+        cindervm(&[&["exec", program][..], &storage].concat()),
+        cindervm(&[&["debug", program][..], &storage].concat()),
+        cindervm(&[&["exec", program][..], &storage].concat()),
+    ];
+    let (ungranted, _) = cindervm(&["exec", program, "--kv-file", store]);
+    let saved = std::fs::read_to_string(store).unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    for ((ok, out), count) in runs.into_iter().zip(1..) {
+        assert!(ok && out.contains(&format!("Result: {}", count)), "{}", out);
+        assert!(out.contains("Storage: 1 read(s), 1 write(s)"), "{}", out);
+    }
+    assert!(!ungranted, "kv natives ran without --grant storage");
+    assert_eq!(saved, "{\n  \"7\": 3\n}\n");
+}
+
+#[test]
+fn watch_reruns_after_edits_to_included_files() {
+    use std::io::{BufRead, BufReader};
+    use std::process::Stdio;
+    use std::sync::mpsc;
+    use std::time::{Duration, SystemTime};
+
+    let dir = std::env::temp_dir().join(format!("cinder-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (main, lib) = (dir.join("main.cinder"), dir.join("lib.cinder"));
+    // Replace files whole, with explicit and distinct times so coarse file
+    // system clocks still see each edit
+    let edit = |path: &std::path::Path, content: &str, seconds: u64| {
+        let partial = path.with_extension("partial");
+        std::fs::write(&partial, content).unwrap();
+        let file = std::fs::File::options().write(true).open(&partial).unwrap();
+        file.set_modified(SystemTime::now() + Duration::from_secs(seconds)).unwrap();
+        std::fs::rename(&partial, path).unwrap();
+    };
+    edit(&main, ".include \"lib.cinder\"\nRETURN\n", 0);
+    edit(&lib, "PUSH_INT 1\n", 0);
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_cindervm"))
+        .args(["watch", main.to_str().unwrap(), "--interval-ms", "20"])
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("cindervm runs");
+    let (sender, lines) = mpsc::channel();
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    std::thread::spawn(move || stdout.lines().map_while(Result::ok).try_for_each(|line| sender.send(line)));
+    let next_lines = |count: usize| -> Vec<String> {
+        (0..count).filter_map(|_| lines.recv_timeout(Duration::from_secs(10)).ok()).collect()
+    };
+
+    let started = next_lines(3);
+    edit(&lib, "PUSH_INT 2\n", 10);
+    let edited = next_lines(2);
+    edit(&main, "# a comment\n.include \"lib.cinder\"\nRETURN\n", 20);
+    let commented = next_lines(2);
+    child.kill().unwrap();
+    child.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(started[1..], ["🔁 Re-parsed 2 file(s)", "📊 Result: 1"], "{:?}", started);
+    assert_eq!(edited, ["🔁 Re-parsed 2 file(s)", "📊 Result: 2"]);
+    assert_eq!(commented, ["🔁 Re-parsed 1 file(s)", "✅ No semantic change"]);
+}
+
+#[test]
+fn repeat_reports_one_result_for_a_pure_program() {
+    let (ok, out) = cindervm(&["exec", "examples/factorial.cinder", "--repeat", "10"]);
+    assert!(ok, "{}", out);
+    let reported: Vec<&str> = out.lines().filter(|line| line.contains("Result")).collect();
+    assert_eq!(reported, ["📊 Result: 120 (identical across 10 runs)"]);
+}
+
+#[test]
+fn pipe_prints_the_consumer_result() {
+    let (producer, consumer) = ("examples/pipe/producer.cinder", "examples/pipe/consumer.cinder");
+    for capacity in ["1", "16"] {
+        let (ok, out) = cindervm(&["pipe", producer, consumer, "--capacity", capacity, "--quantum", "3"]);
+        assert!(ok);
+        assert!(out.contains("📊 Result: 385"), "{}", out);
+    }
+
+    // The consumer has no sending end
+    let error = cindervm_error(&["pipe", consumer, consumer]);
+    assert!(error.contains("consumer.cinder: Execution error: NoChannel { id: 33 }"), "{}", error);
+}
@@ -0,0 +1,215 @@
+//! Byte-exact snapshots of the x86-64 emitter.
+//!
+//! Each case compiles a small program and compares its machine code, listed
+//! per bytecode instruction, with `tests/snapshots/<name>.snap`. Native
+//! entry points are replaced by `ADDRESS_PLACEHOLDER` so the listing is the
+//! same in every process. After an intended emitter change, regenerate with
+//! `CINDER_BLESS=1 cargo test --test codegen_snapshots` and review the diff.
+#![cfg(all(feature = "jit", unix, target_arch = "x86_64"))]
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+use cindervm::bytecode::{BoolRepr, OpCode, MNEMONICS};
+use cindervm::disasm::disassemble_range;
+use cindervm::jit::{JitCompiler, JitOptions};
+use cindervm::parser::Parser;
+
+struct Case {
+    name: &'static str,
+    source: &'static str,
+    options: fn() -> JitOptions,
+}
+
+fn defaults() -> JitOptions {
+    JitOptions::default()
+}
+
+fn all_ones() -> JitOptions {
+    JitOptions { bool_repr: BoolRepr::AllOnes, ..JitOptions::default() }
+}
+
+fn checked() -> JitOptions {
+    JitOptions { check_stack: true, ..JitOptions::default() }
+}
+
+const CASES: &[Case] = &[
+    Case {
+        name: "arithmetic",
+        source: "PUSH_INT 7\nPUSH_INT -3\nADD\nPUSH_INT 2\nSUB\nPUSH_INT 6\nMUL\nPUSH_INT 4\nDIV\nPUSH_INT 140737488355327\nPOP\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "shifts",
+        source: "PUSH_INT -8\nSHL_IMM 3\nSHR_IMM 63\nSAR_IMM 0\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "compare",
+        source: "PUSH_INT 1\nPUSH_INT 2\nEQ\nPUSH_INT 3\nLT\nPUSH_INT 4\nGT\nBOOL\nNOT_BOOL\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "compare_all_ones",
+        source: "PUSH_INT 1\nPUSH_INT 2\nEQ\nPUSH_INT 3\nLT\nPUSH_INT 4\nGT\nBOOL\nNOT_BOOL\nRETURN\n",
+        options: all_ones,
+    },
+    Case {
+        name: "jumps",
+        source: "loop:\nPUSH_INT 0\nJNZ skip\nJUMP done\nskip:\nPUSH_INT 1\nJZ loop\ndone:\nPUSH_INT 9\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "jump_table",
+        source: "PUSH_INT 1\nJUMP_TABLE 0 4 2 3\nPUSH_INT 10\nRETURN\nPUSH_INT 20\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "memory",
+        source: ".var total: i64\n.var steps: i64\nPUSH_INT 5\nSTORE total\nMEM_ADD steps -1\nLOAD total\nLOAD steps\nADD\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "indirect",
+        source: ".memory 3\nPUSH_INT 2\nPUSH_INT 9\nSTORE_IND\nPUSH_INT 2\nLOAD_IND\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "natives",
+        source: "PUSH_INT 42\nCALL_NATIVE print_int\nPUSH_INT 255\nPUSH_INT 784\nCALL_NATIVE print_int_fmt\nPUSH_INT 0\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "arguments",
+        source: ".args 3\nLOAD 0\nLOAD 1\nADD\nLOAD 2\nMUL\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "debug",
+        source: "BREAKPOINT\nREAD_CYCLES\nREAD_CYCLES\nSUB\nRETURN\n",
+        options: defaults,
+    },
+    Case {
+        name: "termination",
+        source: "PUSH_INT 1\nJZ fall\nTRAP 7\nHALT\nfall:\nPUSH_INT 2\n",
+        options: defaults,
+    },
+    Case {
+        name: "check_stack",
+        source: "PUSH_INT 1\nJZ out\nTRAP 3\nout:\nPUSH_INT 4\nRETURN\n",
+        options: checked,
+    },
+];
+
+fn hex_lines(out: &mut String, bytes: &[u8]) {
+    for chunk in bytes.chunks(16) {
+        let hex: Vec<String> = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+        let _ = writeln!(out, "    {}", hex.join(" "));
+    }
+}
+
+/// Listing of the normalized code: prologue, each instruction, fall-through epilogue
+fn listing(case: &Case) -> String {
+    let program = Parser::parse(case.source).expect("snapshot source parses");
+    let compiled = JitCompiler::new(program.clone())
+        .with_options((case.options)())
+        .compile_to_vec()
+        .expect("snapshot source compiles");
+    let bytes = compiled.normalized();
+
+    let mut out = String::new();
+    let _ = writeln!(out, "; {} ({} bytes)", case.name, bytes.len());
+
+    let first = compiled.line_map.first().map_or(compiled.epilogue, |&(_, offset)| offset);
+    let _ = writeln!(out, "prologue:");
+    hex_lines(&mut out, &bytes[compiled.entry..first]);
+
+    for (i, &(pc, start)) in compiled.line_map.iter().enumerate() {
+        let end = compiled.line_map.get(i + 1).map_or(compiled.epilogue, |&(_, offset)| offset);
+        let text = disassemble_range(&program, true, pc..pc + 1);
+        let _ = writeln!(out, "{}: {}", pc, text.lines().last().unwrap_or("").trim());
+        hex_lines(&mut out, &bytes[start..end]);
+    }
+
+    let _ = writeln!(out, "epilogue:");
+    hex_lines(&mut out, &bytes[compiled.epilogue..]);
+    out
+}
+
+fn snapshot_path(name: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/snapshots")
+        .join(format!("{}.snap", name))
+}
+
+/// Line diff of the first mismatches, readable in test output
+fn diff(expected: &str, actual: &str) -> String {
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    let mut out = String::new();
+    let mut shown = 0;
+    for i in 0..expected.len().max(actual.len()) {
+        let (e, a) = (expected.get(i), actual.get(i));
+        if e == a {
+            continue;
+        }
+        if let Some(e) = e {
+            let _ = writeln!(out, "{:>4} - {}", i + 1, e);
+        }
+        if let Some(a) = a {
+            let _ = writeln!(out, "{:>4} + {}", i + 1, a);
+        }
+        shown += 1;
+        if shown == 20 {
+            let _ = writeln!(out, "     ...");
+            break;
+        }
+    }
+    out
+}
+
+#[test]
+fn emitted_code_matches_snapshots() {
+    let bless = std::env::var_os("CINDER_BLESS").is_some();
+    let mut failures = Vec::new();
+
+    for case in CASES {
+        let actual = listing(case);
+        let path = snapshot_path(case.name);
+        if bless {
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(&path, &actual).unwrap();
+            continue;
+        }
+        match std::fs::read_to_string(&path) {
+            Ok(expected) if expected == actual => {}
+            Ok(expected) => failures.push(format!("{}:\n{}", case.name, diff(&expected, &actual))),
+            Err(e) => failures.push(format!("{}: cannot read {}: {}", case.name, path.display(), e)),
+        }
+    }
+
+    assert!(
+        failures.is_empty(),
+        "machine code differs from the snapshots (CINDER_BLESS=1 to accept):\n\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn snapshots_cover_every_compiled_opcode() {
+    let mut covered = BTreeSet::new();
+    for case in CASES {
+        let program = Parser::parse(case.source).unwrap();
+        covered.extend(program.instructions.iter().map(|instr| instr.opcode() as u8));
+    }
+
+    // PUSH_REG has no machine code; compiling it is a CodegenError::Unsupported
+    let missing: Vec<&str> = MNEMONICS
+        .iter()
+        .filter(|m| !m.deprecated && m.opcode != OpCode::PushReg)
+        .filter(|m| !covered.contains(&(m.opcode as u8)))
+        .map(|m| m.name)
+        .collect();
+    assert!(missing.is_empty(), "no snapshot compiles {:?}", missing);
+}
@@ -0,0 +1,74 @@
+//! The example suite in `examples/` as cargo tests: every example passes
+//! `cindervm examples --test` on both backends, and `examples/coverage.txt`
+//! names a case for every opcode.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use cindervm::bytecode::{OpCode, MNEMONICS};
+use cindervm::parser::Parser;
+
+fn examples_dir() -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("examples")
+}
+
+/// `coverage.txt` as mnemonic -> example file name
+fn coverage_manifest() -> BTreeMap<String, String> {
+    let manifest = fs::read_to_string(examples_dir().join("coverage.txt")).expect("examples/coverage.txt exists");
+    manifest
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (mnemonic, example) = line
+                .split_once(char::is_whitespace)
+                .unwrap_or_else(|| panic!("coverage.txt: expected `MNEMONIC example`, got {:?}", line));
+            (mnemonic.to_string(), example.trim().to_string())
+        })
+        .collect()
+}
+
+#[test]
+#[cfg(feature = "cli")]
+fn examples_pass_on_both_backends() {
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_cindervm"))
+        .arg("examples")
+        .arg(examples_dir())
+        .arg("--test")
+        .output()
+        .expect("cindervm runs");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(
+        output.status.success(),
+        "example suite failed:\n{}{}",
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(stdout.contains("checks passed"), "{}", stdout);
+}
+
+#[test]
+fn every_opcode_has_a_conformance_case() {
+    let manifest = coverage_manifest();
+    let canonical: Vec<&str> = MNEMONICS.iter().filter(|m| !m.deprecated).map(|m| m.name).collect();
+
+    let missing: Vec<&&str> = canonical.iter().filter(|name| !manifest.contains_key(**name)).collect();
+    assert!(missing.is_empty(), "no conformance case in examples/coverage.txt for {:?}", missing);
+
+    for (mnemonic, example) in &manifest {
+        let opcode = OpCode::from_mnemonic(mnemonic)
+            .filter(|m| !m.deprecated)
+            .unwrap_or_else(|| panic!("coverage.txt: {} is not a canonical mnemonic", mnemonic))
+            .opcode;
+        let source = fs::read_to_string(examples_dir().join(example))
+            .unwrap_or_else(|e| panic!("coverage.txt: cannot read {} for {}: {}", example, mnemonic, e));
+        let program = Parser::parse(&source).unwrap_or_else(|e| panic!("{} does not parse: {}", example, e));
+        assert!(
+            program.instructions.iter().any(|instruction| instruction.opcode() == opcode),
+            "coverage.txt lists {} for {}, but it does not use it",
+            example,
+            mnemonic
+        );
+    }
+}
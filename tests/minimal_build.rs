@@ -0,0 +1,49 @@
+//! The core-only build: `--no-default-features` must stay free of the CLI
+//! and JIT dependencies, and the library's own tests must pass without
+//! them.
+//!
+//! Both checks run a nested cargo against a separate target directory, so
+//! they don't contend for the lock held by the outer `cargo test`.
+
+use std::path::Path;
+use std::process::Command;
+
+/// Dependencies only the `cli` and `jit` features may pull in
+const FORBIDDEN: [&str; 4] = ["clap", "anyhow", "libc", "winapi"];
+
+fn cargo(args: &[&str]) -> String {
+    let manifest_dir = env!("CARGO_MANIFEST_DIR");
+    let output = Command::new(env!("CARGO"))
+        .args(args)
+        .current_dir(manifest_dir)
+        .env("CARGO_TARGET_DIR", Path::new(manifest_dir).join("target/minimal"))
+        .output()
+        .expect("cargo runs");
+    let stdout = String::from_utf8_lossy(&output.stdout).into_owned();
+    assert!(
+        output.status.success(),
+        "cargo {} failed:\n{}{}",
+        args.join(" "),
+        stdout,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    stdout
+}
+
+#[test]
+fn core_build_has_no_cli_or_jit_dependencies() {
+    let tree = cargo(&["tree", "--no-default-features", "--edges", "normal", "--prefix", "none", "--target", "all"]);
+    for line in tree.lines() {
+        let name = line.split_whitespace().next().unwrap_or("");
+        assert!(!FORBIDDEN.contains(&name), "the core build depends on {}:\n{}", name, tree);
+    }
+    // The defaults do pull them in, so the check above can fail
+    let tree = cargo(&["tree", "--edges", "normal", "--prefix", "none"]);
+    assert!(tree.lines().any(|line| line.starts_with("clap ")), "{}", tree);
+}
+
+#[test]
+fn core_build_passes_the_library_tests() {
+    let output = cargo(&["test", "--no-default-features", "--lib", "--quiet"]);
+    assert!(output.contains("test result: ok"), "{}", output);
+}
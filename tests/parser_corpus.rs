@@ -0,0 +1,50 @@
+//! Byte-exact problem files in `tests/corpus/`: each one either parses to
+//! the same program as `clean.cinder` or fails with an error naming the
+//! offending character or token. `.gitattributes` keeps their bytes as-is.
+
+use std::path::PathBuf;
+
+use cindervm::error::CinderError;
+use cindervm::parser::Parser;
+
+fn corpus(name: &str) -> String {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/corpus").join(name);
+    String::from_utf8(std::fs::read(&path).unwrap()).expect("corpus files are UTF-8")
+}
+
+/// Error for a corpus file as `(line, message)`
+fn parse_error(name: &str) -> (usize, String) {
+    match Parser::parse(&corpus(name)) {
+        Err(CinderError::Parse(error)) => (error.line, error.message),
+        other => panic!("{} should fail to parse, got {:?}", name, other.map(|program| program.instructions)),
+    }
+}
+
+#[test]
+fn spelling_variants_parse_like_the_clean_file() {
+    let clean = Parser::parse(&corpus("clean.cinder")).unwrap().to_bytes();
+    for name in ["bom.cinder", "crlf.cinder", "cr.cinder", "tabs.cinder", "mixed.cinder"] {
+        let program = Parser::parse(&corpus(name)).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        assert_eq!(program.to_bytes(), clean, "{} parses differently", name);
+    }
+}
+
+#[test]
+fn invisible_characters_are_named() {
+    assert_eq!(
+        parse_error("nbsp.cinder"),
+        (5, "Invisible character U+00A0 (no-break space); use a plain space".to_string())
+    );
+    assert_eq!(
+        parse_error("zero_width.cinder"),
+        (6, "Invisible character U+200B (zero width space); use a plain space".to_string())
+    );
+}
+
+#[test]
+fn mnemonics_fold_ascii_case_only() {
+    // `ſ` folds to `s` under Unicode rules, so only ASCII folding rejects it
+    let (line, message) = parse_error("long_s.cinder");
+    assert_eq!(line, 7);
+    assert!(message.contains("Unknown instruction: ſTORE"), "{}", message);
+}